@@ -1,5 +1,5 @@
 use std::io::{Error, stdin};
-use clap::{command, Parser, Subcommand};
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -10,14 +10,89 @@ pub struct Args {
     #[clap(short, long, default_value_t = ("0.0.0.0:7070").to_string(), global = true)]
     pub addr: String,
 
-    #[clap(long, default_value_t = ("./wal.log".to_string()))]
+    /// Unix domain socket path, bound by the server next to the TCP address
+    /// and used by the client instead of it
+    #[arg(long, global = true)]
+    pub unix_socket: Option<String>,
+
+    /// Permissions applied to the Unix domain socket, in octal
+    #[arg(long, default_value = "660", value_parser = parse_mode)]
+    pub unix_socket_mode: u32,
+
+    /// Only listen on the Unix domain socket
+    #[arg(long, requires = "unix_socket")]
+    pub no_tcp: bool,
+
+    #[clap(long, default_value = "./wal.log")]
     pub wal: String,
 
-    #[clap(long, default_value_t = ("./storage.log".to_string()))]
+    #[clap(long, default_value = "./storage.log")]
     pub snapshot: String,
 
     #[arg(long, default_value_t = 2)]
     pub snapshot_internal: u64,
+
+    /// PEM certificate chain, served by the server or presented by the client for mutual TLS
+    #[arg(long, global = true, requires = "tls_key")]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key matching --tls-cert
+    #[arg(long, global = true, requires = "tls_cert")]
+    pub tls_key: Option<String>,
+
+    /// PEM CA bundle, on the server it enables mutual TLS and verifies client
+    /// certificates, on the client it enables TLS and verifies the server
+    #[arg(long, global = true)]
+    pub tls_ca: Option<String>,
+
+    /// Name verified against the server certificate, defaults to the host of --addr
+    #[arg(long, global = true)]
+    pub tls_server_name: Option<String>,
+
+    /// Password of the default user, required from every connection by the
+    /// server and sent by the client
+    #[arg(long, global = true)]
+    pub password: Option<String>,
+
+    /// User the client authenticates as, defaults to the default user
+    #[arg(long, global = true, requires = "password")]
+    pub username: Option<String>,
+
+    /// Additional server user as `name:password`, may be repeated
+    #[arg(long = "user", value_parser = parse_user)]
+    pub users: Vec<(String, String)>,
+
+    /// ACL file with one `user <name> <rules>` line per user, applied on top of
+    /// the command line users and written by `ACL SAVE`
+    #[arg(long)]
+    pub acl_file: Option<String>,
+
+    /// Keep keys in lexicographic order for fast RANGE, PREFIX and DELPREFIX
+    #[arg(long)]
+    pub ordered_index: bool,
+
+    /// Address for the optional Redis (RESP2/RESP3) compatible listener
+    #[arg(long)]
+    pub resp_addr: Option<String>,
+
+    /// Address for the optional memcached (text and meta protocol) listener
+    #[arg(long)]
+    pub memcache_addr: Option<String>,
+
+    /// Address for the optional HTTP/JSON gateway
+    #[arg(long)]
+    pub http_addr: Option<String>,
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0o"), 8).map_err(|e| format!("invalid octal mode: {}", e))
+}
+
+fn parse_user(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((name, password)) if !name.is_empty() => Ok((name.to_owned(), password.to_owned())),
+        _ => Err("expected name:password".to_owned()),
+    }
 }
 
 #[derive(Debug, Subcommand)]
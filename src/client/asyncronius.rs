@@ -1,100 +1,183 @@
+use futures::{stream, Stream};
+use serde::de::DeserializeOwned;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     io::{Error, ErrorKind},
-    ops::Add,
-    sync::{Arc, Mutex},
+    ops::{Add, Bound},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 use tokio::{
-    net::TcpStream,
-    sync::mpsc::{unbounded_channel, UnboundedSender},
+    io::{self, AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
 };
 
-use crate::proto::{self};
+use super::{pipeline::Pipeline, transaction::Transaction};
+use crate::{
+    proto::{self},
+    storage::{
+        index::{IndexDefinition, IndexValue},
+        queue::{Delivery, QueueInfo, QueueOptions, Receipt},
+        schedule::{Job, Target},
+        storage::{TtlFilter, WriteCondition},
+        stream::{Pending, StreamEntry, StreamId},
+        timeseries::{Aggregation, Sample, TimeSeriesInfo},
+    },
+    tls::{self, TlsConnector, TlsOptions},
+};
 
 type ReceiverQueue = Arc<Mutex<HashMap<u128, UnboundedSender<proto::FrameMessage>>>>;
 
+/// Credentials sent with `AUTH` during the `connected()` handshake.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    /// User to authenticate as, the server default user when `None`.
+    pub username: Option<String>,
+    pub password: String,
+}
+
 #[derive(Debug)]
 pub struct Client {
     tx: UnboundedSender<proto::FrameMessage>,
     queue: ReceiverQueue,
+    closed: Arc<AtomicBool>,
     msg_idx: Mutex<u128>,
+    credentials: Option<Credentials>,
+}
+
+/// Maps an error reply to an `io::Error`, authentication failures are
+/// reported as `PermissionDenied`.
+fn server_error(msg: String) -> Error {
+    let kind = match msg.split(' ').next().unwrap_or_default() {
+        "NOAUTH" | "WRONGPASS" | "NOPERM" => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Other,
+    };
+
+    Error::new(kind, msg)
+}
+
+/// Extracts the data of a single command reply.
+pub(crate) fn reply_data(reply: proto::CommandMessage) -> Result<Option<Vec<u8>>, Error> {
+    match reply {
+        proto::CommandMessage::RECV(data) => Ok(data),
+        proto::CommandMessage::ERROR(e) => Err(server_error(e)),
+        _ => Ok(None),
+    }
 }
 
 impl Client {
-    pub async fn new(addr: &str) -> Self {
-        let mut sck = TcpStream::connect(addr).await.unwrap();
+    pub async fn new(addr: &str, credentials: Option<Credentials>) -> Self {
+        let sck = TcpStream::connect(addr).await.unwrap();
         sck.set_nodelay(true).unwrap();
+
+        Self::from_stream(sck, credentials)
+    }
+
+    /// Connects to a server listening on a Unix domain socket at `path`.
+    pub async fn new_unix(path: &str, credentials: Option<Credentials>) -> Result<Self, Error> {
+        let sck = UnixStream::connect(path).await?;
+
+        Ok(Self::from_stream(sck, credentials))
+    }
+
+    /// Connects over TLS, verifying the server against the CA from `options`.
+    pub async fn new_tls(
+        addr: &str,
+        options: &TlsOptions,
+        credentials: Option<Credentials>,
+    ) -> Result<Self, Error> {
+        let connector = TlsConnector::from(tls::client_config(options)?);
+        let name = tls::server_name(addr, options)?;
+
+        let sck = TcpStream::connect(addr).await?;
+        sck.set_nodelay(true)?;
+        let sck = connector.connect(name, sck).await?;
+
+        Ok(Self::from_stream(sck, credentials))
+    }
+
+    pub fn from_stream<S>(stream: S, credentials: Option<Credentials>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (mut read, mut write) = io::split(stream);
         let q = Arc::new(Mutex::new(HashMap::<
             u128,
             UnboundedSender<proto::FrameMessage>,
         >::new()));
         let qq = q.clone();
+        let closed = Arc::new(AtomicBool::new(false));
+        let cclosed = closed.clone();
         let (ctx, mut crx) = unbounded_channel::<proto::FrameMessage>();
         let spawn_sender = ctx.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = crx.recv().await {
+                if proto::nonblocking::marshal(&msg, Box::pin(&mut write)).await.is_err() {
+                    break;
+                }
+            }
+        });
         tokio::spawn(async move {
             let qq = qq;
             loop {
-                let (read, write) = sck.split();
-                tokio::select! {
-                    msg = crx.recv() => {
-                        let _ = match msg {
-                            Some(mut msg) => proto::nonblocking::marshal(&mut msg, Box::pin(write)).await,
-                            None => Ok(()),
-                        };
-                    }
-                    msg = proto::nonblocking::unmarshal(Box::pin(read)) => {
-                        match msg {
-                            // proto::Command::PING => {
-                            //     let _ = spawn_sender.send(proto::Message::pong());
-                            // }
-                            // proto::Command::RECV => match qq.lock().unwrap().get(&msg.ts) {
-                            //     Some(tx) => {
-                            //         let _ = tx.send(msg);
-                            //     },
-                            //     None => {
-                            //         eprint!("chan not found");
-                            //     },
-                            // }
-                            // _ => {}
-                            Ok(msg) => match msg.command {
-                                proto::CommandMessage::PING() => {
-                                let _ = spawn_sender.send(proto::CommandMessage::PONG().into());
-                                },
-                                proto::CommandMessage::RECV(_) => match qq.lock().unwrap().get(&msg.ts) {
-                                        Some(tx) => {
-                                            let _ = tx.send(msg);
-                                        },
-                                        None => {
-                                            eprint!("chan not found");
-                                        },
-                                    },
-                                _ => {}
-                            },
-                            Err(e) if e.kind() == ErrorKind::Unsupported => {}
-                            Err(e) if e.kind() == ErrorKind::ConnectionAborted => {
-                                break;
-                            }
-                            Err(e) if e.kind() == ErrorKind::ConnectionReset => {
-                                break;
+                match proto::nonblocking::unmarshal(Box::pin(&mut read)).await {
+                    Ok(msg) => match msg.command {
+                        proto::CommandMessage::PING() => {
+                            let _ = spawn_sender.send(proto::CommandMessage::PONG().into());
+                        }
+                        proto::CommandMessage::RECV(_)
+                        | proto::CommandMessage::ERROR(_)
+                        | proto::CommandMessage::BATCH(_) => match qq.lock().unwrap().get(&msg.ts) {
+                            Some(tx) => {
+                                let _ = tx.send(msg);
                             }
-                            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
-                                break;
+                            None => {
+                                eprint!("chan not found");
                             }
-                            Err(e) => {
-                                eprint!("{:?}", e)
+                        },
+                        // Subscriptions stay registered until their stream is dropped.
+                        proto::CommandMessage::MESSAGE(..) => {
+                            let mut q = qq.lock().unwrap();
+                            if q.get(&msg.ts).is_some_and(|tx| tx.send(msg.clone()).is_err()) {
+                                q.remove(&msg.ts);
                             }
                         }
+                        _ => {}
+                    },
+                    Err(e) if e.kind() == ErrorKind::Unsupported => {}
+                    Err(e) if e.kind() == ErrorKind::ConnectionAborted => {
+                        break;
+                    }
+                    Err(e) if e.kind() == ErrorKind::ConnectionReset => {
+                        break;
+                    }
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                        break;
+                    }
+                    Err(e) => {
+                        eprint!("{:?}", e);
+                        break;
                     }
                 }
             }
+
+            // Wake up pending calls, the connection is gone.
+            let mut q = qq.lock().unwrap();
+            cclosed.store(true, Ordering::SeqCst);
+            q.clear();
         });
 
-        return Client {
+        Client {
             tx: ctx,
             queue: q,
+            closed,
             msg_idx: Mutex::new(0),
-        };
+            credentials,
+        }
     }
 
     pub fn ping(&self) -> Result<(), Error> {
@@ -102,8 +185,31 @@ impl Client {
         Ok(())
     }
 
+    /// Starts a pipeline sending many commands in a single frame.
+    pub fn pipeline(&self) -> Pipeline<'_> {
+        Pipeline::new(self)
+    }
+
+    /// Starts a transaction whose commands are run atomically by the server.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
     async fn rpc(&self, msg: proto::FrameMessage) -> Result<Option<Vec<u8>>, Error> {
-        // let t = SystemTime::now();
+        reply_data(self.call(msg).await?.command)
+    }
+
+    /// `rpc` for commands always answered with a msgpack encoded result.
+    async fn rpc_decode<T: DeserializeOwned>(&self, command: proto::CommandMessage) -> Result<T, Error> {
+        match self.rpc(command.into()).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Err(Error::new(ErrorKind::InvalidData, "missing reply data")),
+        }
+    }
+
+    /// Sends a frame under a new `ts`, returning the receiver of the frames
+    /// the server answers it with.
+    fn send(&self, msg: proto::FrameMessage) -> Result<(proto::FrameMessage, UnboundedReceiver<proto::FrameMessage>), Error> {
         let count: u128;
         {
             let mut res = self.msg_idx.lock().unwrap();
@@ -114,28 +220,50 @@ impl Client {
         let mut msg = msg.clone();
         msg.ts = count;
 
-        let (tx, mut rx) = unbounded_channel::<proto::FrameMessage>();
-        self.queue.lock().unwrap().insert(count, tx);
+        let (tx, rx) = unbounded_channel::<proto::FrameMessage>();
+        {
+            let mut q = self.queue.lock().unwrap();
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(Error::new(ErrorKind::NotConnected, "connection closed"));
+            }
+            q.insert(count, tx);
+        }
 
         let _ = self.tx.send(msg.clone());
 
+        Ok((msg, rx))
+    }
+
+    /// Sends a frame and waits for the reply frame carrying the same `ts`.
+    pub(crate) async fn call(&self, msg: proto::FrameMessage) -> Result<proto::FrameMessage, Error> {
+        let (msg, mut rx) = self.send(msg)?;
+
         let result = match rx.recv().await {
-            Some(result) => match result.command {
-                proto::CommandMessage::RECV(data) => data,
-                _ => None,
-            },
-            None => None,
+            Some(result) => result,
+            None => return Err(Error::new(ErrorKind::ConnectionAborted, "connection closed")),
         };
 
         self.queue.lock().unwrap().remove(&msg.ts);
 
-        return Ok(result);
+        Ok(result)
     }
 
+    /// Handshake with the server, authenticating when credentials were given.
     pub async fn connected(&self) -> Result<Option<Vec<u8>>, Error> {
         let msg = proto::CommandMessage::CONNECTED().into();
+        let res = self.rpc(msg).await?;
 
-        self.rpc(msg).await
+        if let Some(credentials) = &self.credentials {
+            self.auth(credentials.username.as_deref(), &credentials.password).await?;
+        }
+
+        Ok(res)
+    }
+
+    pub async fn auth(&self, username: Option<&str>, password: &str) -> Result<(), Error> {
+        let msg = proto::CommandMessage::AUTH(username.map(|x| x.to_owned()), password.to_owned()).into();
+
+        self.rpc(msg).await.map(|_| ())
     }
 
     pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
@@ -162,19 +290,761 @@ impl Client {
             Ok(data) => {
                 match data {
                     None => Ok(None),
-                    Some(data) => {
-                        let pairs: Vec<String> = proto::resolve_pair(data)
-                            .into_iter()
-                            .map(|x| String::from_utf8(x.to_owned()).unwrap().to_owned())
-                            .collect();
-                        Ok(Some(pairs))
-                    }
+                    Some(data) => rmp_serde::from_slice(&data)
+                        .map(Some)
+                        .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
                 }
             }
             Err(e) => Err(e),
         }
     }
 
+    /// Runs a single `SCAN` call, returns the cursor to continue from (`0`
+    /// once the scan completed) and the matching keys.
+    pub async fn scan_page(
+        &self,
+        cursor: u64,
+        pattern: Option<&str>,
+        count: Option<usize>,
+        ttl: Option<TtlFilter>,
+    ) -> Result<(u64, Vec<String>), Error> {
+        let msg = proto::CommandMessage::SCAN(cursor, pattern.map(|x| x.to_owned()), count, ttl).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Ok((0, Vec::new())),
+        }
+    }
+
+    /// Streams every key matching `pattern` and `ttl`, fetching `count` keys
+    /// per round trip. Keys present for the whole scan are yielded exactly once.
+    pub fn scan(
+        &self,
+        pattern: Option<&str>,
+        count: Option<usize>,
+        ttl: Option<TtlFilter>,
+    ) -> impl Stream<Item = Result<String, Error>> + '_ {
+        let pattern = pattern.map(|x| x.to_owned());
+        stream::unfold((Some(0u64), VecDeque::new()), move |(mut cursor, mut keys)| {
+            let pattern = pattern.clone();
+            async move {
+                loop {
+                    if let Some(key) = keys.pop_front() {
+                        return Some((Ok(key), (cursor, keys)));
+                    }
+                    match self.scan_page(cursor?, pattern.as_deref(), count, ttl).await {
+                        Ok((next, page)) => {
+                            keys.extend(page);
+                            cursor = (next != 0).then_some(next);
+                        }
+                        Err(e) => return Some((Err(e), (None, keys))),
+                    }
+                }
+            }
+        })
+    }
+
+    pub async fn expire(&self, key: &str, exp: Option<Duration>) -> Result<bool, Error> {
+        let msg = proto::CommandMessage::EXPIRE(key.to_owned(), exp).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Ok(false),
+        }
+    }
+
+    /// Writes the value only when `cond` holds. Returns whether it was written
+    /// and, when `get` is set, the value the key held before.
+    pub async fn put_if(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        exp: Option<Duration>,
+        cond: WriteCondition,
+        get: bool,
+    ) -> Result<(bool, Option<Vec<u8>>), Error> {
+        let msg = proto::CommandMessage::PUTIF(key.to_owned(), data, exp, cond, get).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Ok((false, None)),
+        }
+    }
+
+    /// Writes the value only if the key does not exist, returns whether it was written.
+    pub async fn put_nx(&self, key: &str, data: Vec<u8>, exp: Option<Duration>) -> Result<bool, Error> {
+        Ok(self.put_if(key, data, exp, WriteCondition::IfAbsent, false).await?.0)
+    }
+
+    /// Writes the value only if the key exists, returns whether it was written.
+    pub async fn put_xx(&self, key: &str, data: Vec<u8>, exp: Option<Duration>) -> Result<bool, Error> {
+        Ok(self.put_if(key, data, exp, WriteCondition::IfPresent, false).await?.0)
+    }
+
+    /// Reads the value together with its version, for use with `cas`.
+    pub async fn gets(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        let msg = proto::CommandMessage::GETS(key.to_owned()).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the value only if the key is still at version `expected`.
+    /// Returns whether it was written and the new version, or the current
+    /// version on a mismatch, `None` when the key does not exist.
+    pub async fn cas(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        expected: u64,
+        exp: Option<Duration>,
+    ) -> Result<(bool, Option<u64>), Error> {
+        let msg = proto::CommandMessage::CAS(key.to_owned(), data, expected, exp).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Ok((false, None)),
+        }
+    }
+
+    /// Atomically adds `delta` to an integer counter and returns the new value.
+    /// A missing key starts at zero and expires after `ttl`, the expiration of
+    /// an existing key is left as is.
+    pub async fn incr_by(&self, key: &str, delta: i64, ttl: Option<Duration>) -> Result<i64, Error> {
+        let msg = proto::CommandMessage::INCR(key.to_owned(), delta, ttl).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Err(Error::new(ErrorKind::InvalidData, "missing counter value")),
+        }
+    }
+
+    pub async fn incr(&self, key: &str, ttl: Option<Duration>) -> Result<i64, Error> {
+        self.incr_by(key, 1, ttl).await
+    }
+
+    pub async fn decr(&self, key: &str, ttl: Option<Duration>) -> Result<i64, Error> {
+        self.incr_by(key, -1, ttl).await
+    }
+
+    /// Floating point variant of `incr_by`.
+    pub async fn incr_by_float(&self, key: &str, delta: f64, ttl: Option<Duration>) -> Result<f64, Error> {
+        let msg = proto::CommandMessage::INCRFLOAT(key.to_owned(), delta, ttl).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Err(Error::new(ErrorKind::InvalidData, "missing counter value")),
+        }
+    }
+
+    /// Writes the value and returns the one it replaced.
+    pub async fn getset(&self, key: &str, data: Vec<u8>, exp: Option<Duration>) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.put_if(key, data, exp, WriteCondition::Always, true).await?.1)
+    }
+
+    /// Reads several keys in one round trip, misses are `None`.
+    pub async fn mget(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        let msg = proto::CommandMessage::MGET(keys.iter().map(|x| x.to_string()).collect()).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Writes several keys in one round trip, all sharing the `exp` TTL.
+    pub async fn mset(&self, entries: Vec<(&str, Vec<u8>)>, exp: Option<Duration>) -> Result<(), Error> {
+        self.mset_ex(entries.into_iter().map(|(key, data)| (key, data, exp)).collect())
+            .await
+    }
+
+    /// Writes several keys in one round trip, each with its own TTL.
+    pub async fn mset_ex(&self, entries: Vec<(&str, Vec<u8>, Option<Duration>)>) -> Result<(), Error> {
+        let entries = entries
+            .into_iter()
+            .map(|(key, data, exp)| (key.to_owned(), data, exp))
+            .collect();
+        let msg = proto::CommandMessage::MSET(entries).into();
+
+        self.rpc(msg).await.map(|_| ())
+    }
+
+    /// Deletes several keys in one round trip, returns whether each key existed.
+    pub async fn mdel(&self, keys: &[&str]) -> Result<Vec<bool>, Error> {
+        let msg = proto::CommandMessage::MDEL(keys.iter().map(|x| x.to_string()).collect()).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Key-value pairs with keys in `[start, end)` in lexicographic order.
+    pub async fn range(&self, start: &str, end: Option<&str>, limit: Option<usize>) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let msg = proto::CommandMessage::RANGE(start.to_owned(), end.map(|x| x.to_owned()), limit).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Key-value pairs under `prefix` in lexicographic order. Pass the last
+    /// key of the previous page as `after` to continue a listing.
+    pub async fn prefix(&self, prefix: &str, after: Option<&str>, limit: Option<usize>) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let msg = proto::CommandMessage::PREFIX(prefix.to_owned(), after.map(|x| x.to_owned()), limit).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Deletes every key under `prefix`, returns the number of deleted keys.
+    pub async fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        let msg = proto::CommandMessage::DELPREFIX(prefix.to_owned()).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Ok(0),
+        }
+    }
+
+    /// Pushes the values one after another to the head of the list, returns its length.
+    pub async fn lpush(&self, key: &str, values: Vec<Vec<u8>>) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::LPUSH(key.to_owned(), values)).await
+    }
+
+    /// Pushes the values to the tail of the list, returns its length.
+    pub async fn rpush(&self, key: &str, values: Vec<Vec<u8>>) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::RPUSH(key.to_owned(), values)).await
+    }
+
+    /// Removes and returns up to `count` values from the head of the list.
+    pub async fn lpop(&self, key: &str, count: usize) -> Result<Vec<Vec<u8>>, Error> {
+        self.rpc_decode(proto::CommandMessage::LPOP(key.to_owned(), count)).await
+    }
+
+    /// Removes and returns up to `count` values from the tail of the list.
+    pub async fn rpop(&self, key: &str, count: usize) -> Result<Vec<Vec<u8>>, Error> {
+        self.rpc_decode(proto::CommandMessage::RPOP(key.to_owned(), count)).await
+    }
+
+    /// Values between the inclusive indexes, negative ones counting from the end.
+    pub async fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<Vec<u8>>, Error> {
+        self.rpc_decode(proto::CommandMessage::LRANGE(key.to_owned(), start, stop)).await
+    }
+
+    pub async fn llen(&self, key: &str) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::LLEN(key.to_owned())).await
+    }
+
+    /// Keeps only the values between the inclusive indexes.
+    pub async fn ltrim(&self, key: &str, start: i64, stop: i64) -> Result<(), Error> {
+        self.rpc(proto::CommandMessage::LTRIM(key.to_owned(), start, stop).into()).await.map(|_| ())
+    }
+
+    /// Sets the fields of the hash, returns how many of them were new.
+    pub async fn hset(&self, key: &str, fields: Vec<(&str, Vec<u8>)>) -> Result<usize, Error> {
+        let fields = fields.into_iter().map(|(field, data)| (field.to_owned(), data)).collect();
+        self.rpc_decode(proto::CommandMessage::HSET(key.to_owned(), fields)).await
+    }
+
+    pub async fn hget(&self, key: &str, field: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.rpc_decode(proto::CommandMessage::HGET(key.to_owned(), field.to_owned())).await
+    }
+
+    /// Removes the fields of the hash, returns how many of them existed.
+    pub async fn hdel(&self, key: &str, fields: &[&str]) -> Result<usize, Error> {
+        let fields = fields.iter().map(|x| x.to_string()).collect();
+        self.rpc_decode(proto::CommandMessage::HDEL(key.to_owned(), fields)).await
+    }
+
+    pub async fn hgetall(&self, key: &str) -> Result<HashMap<String, Vec<u8>>, Error> {
+        let fields: Vec<(String, Vec<u8>)> = self.rpc_decode(proto::CommandMessage::HGETALL(key.to_owned())).await?;
+        Ok(fields.into_iter().collect())
+    }
+
+    /// Atomically adds `delta` to the integer held by the field and returns the new value.
+    pub async fn hincr_by(&self, key: &str, field: &str, delta: i64) -> Result<i64, Error> {
+        self.rpc_decode(proto::CommandMessage::HINCRBY(key.to_owned(), field.to_owned(), delta)).await
+    }
+
+    /// Floating point variant of `hincr_by`.
+    pub async fn hincr_by_float(&self, key: &str, field: &str, delta: f64) -> Result<f64, Error> {
+        self.rpc_decode(proto::CommandMessage::HINCRBYFLOAT(key.to_owned(), field.to_owned(), delta)).await
+    }
+
+    /// Adds the members to the set, returns how many of them were new.
+    pub async fn sadd(&self, key: &str, members: Vec<Vec<u8>>) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::SADD(key.to_owned(), members)).await
+    }
+
+    /// Removes the members from the set, returns how many of them existed.
+    pub async fn srem(&self, key: &str, members: Vec<Vec<u8>>) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::SREM(key.to_owned(), members)).await
+    }
+
+    pub async fn sismember(&self, key: &str, member: &[u8]) -> Result<bool, Error> {
+        self.rpc_decode(proto::CommandMessage::SISMEMBER(key.to_owned(), member.to_vec())).await
+    }
+
+    pub async fn smembers(&self, key: &str) -> Result<HashSet<Vec<u8>>, Error> {
+        let members: Vec<Vec<u8>> = self.rpc_decode(proto::CommandMessage::SMEMBERS(key.to_owned())).await?;
+        Ok(members.into_iter().collect())
+    }
+
+    /// Members present in every one of the sets.
+    pub async fn sinter(&self, keys: &[&str]) -> Result<HashSet<Vec<u8>>, Error> {
+        let keys = keys.iter().map(|x| x.to_string()).collect();
+        let members: Vec<Vec<u8>> = self.rpc_decode(proto::CommandMessage::SINTER(keys)).await?;
+        Ok(members.into_iter().collect())
+    }
+
+    /// Members present in any of the sets.
+    pub async fn sunion(&self, keys: &[&str]) -> Result<HashSet<Vec<u8>>, Error> {
+        let keys = keys.iter().map(|x| x.to_string()).collect();
+        let members: Vec<Vec<u8>> = self.rpc_decode(proto::CommandMessage::SUNION(keys)).await?;
+        Ok(members.into_iter().collect())
+    }
+
+    /// Sets the scores of the members, returns how many of them were new.
+    pub async fn zadd(&self, key: &str, members: Vec<(f64, Vec<u8>)>) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::ZADD(key.to_owned(), members)).await
+    }
+
+    /// Adds `delta` to the score of the member and returns the new score.
+    pub async fn zincr_by(&self, key: &str, member: &[u8], delta: f64) -> Result<f64, Error> {
+        self.rpc_decode(proto::CommandMessage::ZINCRBY(key.to_owned(), delta, member.to_vec())).await
+    }
+
+    /// Position of the member by ascending score, or descending when `reverse` is set.
+    pub async fn zrank(&self, key: &str, member: &[u8], reverse: bool) -> Result<Option<usize>, Error> {
+        self.rpc_decode(proto::CommandMessage::ZRANK(key.to_owned(), member.to_vec(), reverse)).await
+    }
+
+    /// Members and their scores between the inclusive ranks, negative ones
+    /// counting from the end.
+    pub async fn zrange(&self, key: &str, start: i64, stop: i64, reverse: bool) -> Result<Vec<(Vec<u8>, f64)>, Error> {
+        self.rpc_decode(proto::CommandMessage::ZRANGE(key.to_owned(), start, stop, reverse)).await
+    }
+
+    /// Members and their scores between `min` and `max` by ascending score.
+    pub async fn zrange_by_score(
+        &self,
+        key: &str,
+        min: Bound<f64>,
+        max: Bound<f64>,
+        offset: usize,
+        count: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, f64)>, Error> {
+        self.rpc_decode(proto::CommandMessage::ZRANGEBYSCORE(key.to_owned(), min, max, offset, count)).await
+    }
+
+    /// Removes the members, returns how many of them existed.
+    pub async fn zrem(&self, key: &str, members: Vec<Vec<u8>>) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::ZREM(key.to_owned(), members)).await
+    }
+
+    /// Removes the members between the inclusive ranks, returns how many were removed.
+    pub async fn zrem_range_by_rank(&self, key: &str, start: i64, stop: i64) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::ZREMRANGEBYRANK(key.to_owned(), start, stop)).await
+    }
+
+    /// Removes the members scored between `min` and `max`, returns how many were removed.
+    pub async fn zrem_range_by_score(&self, key: &str, min: Bound<f64>, max: Bound<f64>) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::ZREMRANGEBYSCORE(key.to_owned(), min, max)).await
+    }
+
+    pub async fn zscore(&self, key: &str, member: &[u8]) -> Result<Option<f64>, Error> {
+        self.rpc_decode(proto::CommandMessage::ZSCORE(key.to_owned(), member.to_vec())).await
+    }
+
+    pub async fn zcard(&self, key: &str) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::ZCARD(key.to_owned())).await
+    }
+
+    /// Appends an entry to the stream and returns its ID, generated by the
+    /// server when `id` is `None`. With `max_len` the oldest entries are trimmed.
+    pub async fn xadd(
+        &self,
+        key: &str,
+        id: Option<StreamId>,
+        fields: Vec<(&str, Vec<u8>)>,
+        max_len: Option<usize>,
+    ) -> Result<StreamId, Error> {
+        let fields = fields.into_iter().map(|(field, data)| (field.to_owned(), data)).collect();
+        self.rpc_decode(proto::CommandMessage::XADD(key.to_owned(), id, fields, max_len)).await
+    }
+
+    pub async fn xlen(&self, key: &str) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::XLEN(key.to_owned())).await
+    }
+
+    /// Up to `count` entries with IDs between the inclusive `start` and `end`,
+    /// from the newest when `reverse` is set.
+    pub async fn xrange(
+        &self,
+        key: &str,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<StreamEntry>, Error> {
+        self.rpc_decode(proto::CommandMessage::XRANGE(key.to_owned(), start, end, count, reverse)).await
+    }
+
+    /// Trims the stream to its newest `max_len` entries, returns how many were removed.
+    pub async fn xtrim(&self, key: &str, max_len: usize) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::XTRIM(key.to_owned(), max_len)).await
+    }
+
+    /// Entries of each stream after the ID given for it, `None` meaning
+    /// entries added from now on. With `block` the server waits up to that
+    /// long for an entry when there is none, zero waiting indefinitely. Other
+    /// commands of the connection are served meanwhile.
+    pub async fn xread(
+        &self,
+        streams: &[(&str, Option<StreamId>)],
+        count: Option<usize>,
+        block: Option<Duration>,
+    ) -> Result<Vec<(String, Vec<StreamEntry>)>, Error> {
+        let streams = streams.iter().map(|(key, id)| (key.to_string(), *id)).collect();
+        self.rpc_decode(proto::CommandMessage::XREAD(streams, count, block)).await
+    }
+
+    /// Creates a consumer group delivering the entries after `id`, or the
+    /// ones added from now on when `None`. A missing stream is created when
+    /// `create` is set.
+    pub async fn xgroup_create(&self, key: &str, group: &str, id: Option<StreamId>, create: bool) -> Result<(), Error> {
+        self.rpc_decode(proto::CommandMessage::XGROUPCREATE(key.to_owned(), group.to_owned(), id, create)).await
+    }
+
+    /// Removes the consumer group, returns whether it existed.
+    pub async fn xgroup_destroy(&self, key: &str, group: &str) -> Result<bool, Error> {
+        self.rpc_decode(proto::CommandMessage::XGROUPDESTROY(key.to_owned(), group.to_owned())).await
+    }
+
+    /// Delivers entries the group did not deliver yet to the consumer, which
+    /// holds them as pending until acknowledged. Given an ID, the pending
+    /// entries of the consumer after it are returned instead. `block` works
+    /// as for `xread`.
+    pub async fn xreadgroup(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+        id: Option<StreamId>,
+        count: Option<usize>,
+        block: Option<Duration>,
+    ) -> Result<Vec<StreamEntry>, Error> {
+        let msg = proto::CommandMessage::XREADGROUP(key.to_owned(), group.to_owned(), consumer.to_owned(), id, count, block);
+        self.rpc_decode(msg).await
+    }
+
+    /// Acknowledges entries of the group, returns how many of them were pending.
+    pub async fn xack(&self, key: &str, group: &str, ids: &[StreamId]) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::XACK(key.to_owned(), group.to_owned(), ids.to_vec())).await
+    }
+
+    /// Pending entries of the group with IDs between the inclusive `start`
+    /// and `end`, only those of `consumer` when given.
+    pub async fn xpending(
+        &self,
+        key: &str,
+        group: &str,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        consumer: Option<&str>,
+    ) -> Result<Vec<(StreamId, Pending)>, Error> {
+        let msg = proto::CommandMessage::XPENDING(
+            key.to_owned(),
+            group.to_owned(),
+            start,
+            end,
+            count,
+            consumer.map(|x| x.to_owned()),
+        );
+        self.rpc_decode(msg).await
+    }
+
+    /// Adds elements to the HyperLogLog, returns whether its estimated
+    /// cardinality may have changed.
+    pub async fn pfadd(&self, key: &str, elements: Vec<Vec<u8>>) -> Result<bool, Error> {
+        self.rpc_decode(proto::CommandMessage::PFADD(key.to_owned(), elements)).await
+    }
+
+    /// Estimated number of distinct elements added to any of the keys.
+    pub async fn pfcount(&self, keys: &[&str]) -> Result<u64, Error> {
+        let keys = keys.iter().map(|x| x.to_string()).collect();
+        self.rpc_decode(proto::CommandMessage::PFCOUNT(keys)).await
+    }
+
+    /// Merges the source HyperLogLogs into `key`.
+    pub async fn pfmerge(&self, key: &str, sources: &[&str]) -> Result<(), Error> {
+        let sources = sources.iter().map(|x| x.to_string()).collect();
+        self.rpc_decode(proto::CommandMessage::PFMERGE(key.to_owned(), sources)).await
+    }
+
+    /// Creates a Bloom filter holding `capacity` items at the given false
+    /// positive rate, failing when the key exists.
+    pub async fn bf_reserve(&self, key: &str, error_rate: f64, capacity: u64) -> Result<(), Error> {
+        self.rpc_decode(proto::CommandMessage::BFRESERVE(key.to_owned(), error_rate, capacity)).await
+    }
+
+    /// Adds items to the filter, creating a small one when missing, and
+    /// returns for each whether it was new.
+    pub async fn bf_add(&self, key: &str, items: Vec<Vec<u8>>) -> Result<Vec<bool>, Error> {
+        self.rpc_decode(proto::CommandMessage::BFADD(key.to_owned(), items)).await
+    }
+
+    /// Whether each of the items may have been added to the filter.
+    pub async fn bf_exists(&self, key: &str, items: Vec<Vec<u8>>) -> Result<Vec<bool>, Error> {
+        self.rpc_decode(proto::CommandMessage::BFEXISTS(key.to_owned(), items)).await
+    }
+
+    /// Creates a Count-Min sketch, `CountMinSketch::dimensions` sizes one for
+    /// an error bound.
+    pub async fn cms_init(&self, key: &str, width: u32, depth: u32) -> Result<(), Error> {
+        self.rpc_decode(proto::CommandMessage::CMSINIT(key.to_owned(), width, depth)).await
+    }
+
+    /// Increments the counts of the items, returns their new estimates.
+    pub async fn cms_incr_by(&self, key: &str, items: Vec<(Vec<u8>, u64)>) -> Result<Vec<u64>, Error> {
+        self.rpc_decode(proto::CommandMessage::CMSINCRBY(key.to_owned(), items)).await
+    }
+
+    pub async fn cms_query(&self, key: &str, items: Vec<Vec<u8>>) -> Result<Vec<u64>, Error> {
+        self.rpc_decode(proto::CommandMessage::CMSQUERY(key.to_owned(), items)).await
+    }
+
+    /// Creates a list of the `k` most frequent items counted by a `width`
+    /// by `depth` sketch.
+    pub async fn topk_reserve(&self, key: &str, k: u32, width: u32, depth: u32) -> Result<(), Error> {
+        self.rpc_decode(proto::CommandMessage::TOPKRESERVE(key.to_owned(), k, width, depth)).await
+    }
+
+    /// Counts the items, returns for each the item it pushed out of the list.
+    pub async fn topk_add(&self, key: &str, items: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        self.rpc_decode(proto::CommandMessage::TOPKADD(key.to_owned(), items)).await
+    }
+
+    pub async fn topk_query(&self, key: &str, items: Vec<Vec<u8>>) -> Result<Vec<bool>, Error> {
+        self.rpc_decode(proto::CommandMessage::TOPKQUERY(key.to_owned(), items)).await
+    }
+
+    /// Items of the list with their estimated counts, highest first.
+    pub async fn topk_list(&self, key: &str) -> Result<Vec<(Vec<u8>, u64)>, Error> {
+        self.rpc_decode(proto::CommandMessage::TOPKLIST(key.to_owned())).await
+    }
+
+    /// Writes the JSON text at the path, `$` replacing or creating the whole
+    /// document. `cond` requires the path to be missing or present, returns
+    /// whether it was written.
+    pub async fn json_set(&self, key: &str, path: &str, json: &[u8], cond: WriteCondition) -> Result<bool, Error> {
+        self.rpc_decode(proto::CommandMessage::JSONSET(key.to_owned(), path.to_owned(), json.to_vec(), cond)).await
+    }
+
+    /// JSON text of the node at the path, or of an object keyed by path when
+    /// given several, `None` when the key is missing.
+    pub async fn json_get(&self, key: &str, paths: &[&str]) -> Result<Option<Vec<u8>>, Error> {
+        let paths = paths.iter().map(|x| x.to_string()).collect();
+        self.rpc_decode(proto::CommandMessage::JSONGET(key.to_owned(), paths)).await
+    }
+
+    /// Removes the node at the path, `$` removing the key, returns how many were removed.
+    pub async fn json_del(&self, key: &str, path: &str) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::JSONDEL(key.to_owned(), path.to_owned())).await
+    }
+
+    /// Appends JSON texts to the array at the path, returns its new length.
+    pub async fn json_arr_append(&self, key: &str, path: &str, values: Vec<Vec<u8>>) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::JSONARRAPPEND(key.to_owned(), path.to_owned(), values)).await
+    }
+
+    /// Adds `delta` to the number at the path, returns the JSON text of the result.
+    pub async fn json_num_incr_by(&self, key: &str, path: &str, delta: f64) -> Result<Vec<u8>, Error> {
+        self.rpc_decode(proto::CommandMessage::JSONNUMINCRBY(key.to_owned(), path.to_owned(), delta)).await
+    }
+
+    /// Indexes the keys starting with `prefix` by the scalar at the JSON
+    /// `path` of their values, which may be JSON documents or JSON strings.
+    pub async fn index_create(&self, name: &str, prefix: &str, path: &str) -> Result<(), Error> {
+        self.rpc_decode(proto::CommandMessage::INDEXCREATE(name.to_owned(), prefix.to_owned(), path.to_owned())).await
+    }
+
+    /// Removes the index, returns whether it existed.
+    pub async fn index_drop(&self, name: &str) -> Result<bool, Error> {
+        self.rpc_decode(proto::CommandMessage::INDEXDROP(name.to_owned())).await
+    }
+
+    /// Name, key prefix and path of every index.
+    pub async fn index_list(&self) -> Result<Vec<IndexDefinition>, Error> {
+        self.rpc_decode(proto::CommandMessage::INDEXLIST()).await
+    }
+
+    /// Keys whose indexed field is between `min` and `max`, ordered by the
+    /// field value and then by key.
+    pub async fn index_query(
+        &self,
+        name: &str,
+        min: Bound<IndexValue>,
+        max: Bound<IndexValue>,
+        limit: Option<usize>,
+    ) -> Result<Vec<String>, Error> {
+        self.rpc_decode(proto::CommandMessage::INDEXQUERY(name.to_owned(), min, max, limit)).await
+    }
+
+    /// Keys whose indexed field equals `value`.
+    pub async fn index_get(&self, name: &str, value: IndexValue, limit: Option<usize>) -> Result<Vec<String>, Error> {
+        self.index_query(name, Bound::Included(value.clone()), Bound::Included(value), limit).await
+    }
+
+    /// Creates an empty time series keeping `retention` milliseconds of
+    /// samples, zero keeping them all.
+    pub async fn ts_create(&self, key: &str, retention: u64) -> Result<(), Error> {
+        self.rpc_decode(proto::CommandMessage::TSCREATE(key.to_owned(), retention)).await
+    }
+
+    /// Adds a sample at `timestamp`, or at the server time when `None`, and
+    /// returns its timestamp. A missing key is created with `retention`.
+    pub async fn ts_add(&self, key: &str, timestamp: Option<u64>, value: f64, retention: u64) -> Result<u64, Error> {
+        self.rpc_decode(proto::CommandMessage::TSADD(key.to_owned(), timestamp, value, retention)).await
+    }
+
+    pub async fn ts_get(&self, key: &str) -> Result<Option<Sample>, Error> {
+        self.rpc_decode(proto::CommandMessage::TSGET(key.to_owned())).await
+    }
+
+    /// Samples between the inclusive `from` and `to`, or with `aggregation`
+    /// their aggregates per bucket of the given milliseconds.
+    pub async fn ts_range(
+        &self,
+        key: &str,
+        from: u64,
+        to: u64,
+        count: Option<usize>,
+        aggregation: Option<(Aggregation, u64)>,
+    ) -> Result<Vec<Sample>, Error> {
+        self.rpc_decode(proto::CommandMessage::TSRANGE(key.to_owned(), from, to, count, aggregation)).await
+    }
+
+    /// Downsamples the samples added to `key` from now on into the existing
+    /// series at `dest`.
+    pub async fn ts_create_rule(&self, key: &str, dest: &str, aggregation: Aggregation, bucket: u64) -> Result<(), Error> {
+        self.rpc_decode(proto::CommandMessage::TSCREATERULE(key.to_owned(), dest.to_owned(), aggregation, bucket)).await
+    }
+
+    pub async fn ts_delete_rule(&self, key: &str, dest: &str) -> Result<bool, Error> {
+        self.rpc_decode(proto::CommandMessage::TSDELETERULE(key.to_owned(), dest.to_owned())).await
+    }
+
+    pub async fn ts_info(&self, key: &str) -> Result<Option<TimeSeriesInfo>, Error> {
+        self.rpc_decode(proto::CommandMessage::TSINFO(key.to_owned())).await
+    }
+
+    pub async fn queue_create(&self, key: &str, options: QueueOptions) -> Result<(), Error> {
+        self.rpc_decode(proto::CommandMessage::QCREATE(key.to_owned(), options)).await
+    }
+
+    /// Adds messages becoming visible after `delay`, returns their IDs. A
+    /// missing queue is created with the default settings.
+    pub async fn queue_push(&self, key: &str, payloads: Vec<Vec<u8>>, delay: Duration) -> Result<Vec<u64>, Error> {
+        let msg = proto::CommandMessage::QPUSH(key.to_owned(), payloads, delay.as_millis() as u64);
+        self.rpc_decode(msg).await
+    }
+
+    /// Takes up to `count` visible messages, hidden from other consumers until
+    /// acknowledged or their visibility timeout passes.
+    pub async fn queue_pop(&self, key: &str, count: usize) -> Result<Vec<Delivery>, Error> {
+        self.rpc_decode(proto::CommandMessage::QPOP(key.to_owned(), count)).await
+    }
+
+    /// Removes processed messages by the receipts of their deliveries, returns
+    /// how many were removed.
+    pub async fn queue_ack(&self, key: &str, receipts: Vec<Receipt>) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::QACK(key.to_owned(), receipts)).await
+    }
+
+    /// Hands delivered messages back to be delivered again after `delay`,
+    /// returns how many were in flight.
+    pub async fn queue_nack(&self, key: &str, receipts: Vec<Receipt>, delay: Duration) -> Result<usize, Error> {
+        let msg = proto::CommandMessage::QNACK(key.to_owned(), receipts, delay.as_millis() as u64);
+        self.rpc_decode(msg).await
+    }
+
+    pub async fn queue_info(&self, key: &str) -> Result<Option<QueueInfo>, Error> {
+        self.rpc_decode(proto::CommandMessage::QINFO(key.to_owned())).await
+    }
+
+    /// Schedules the payload for delivery to `target` after `delay`, returns
+    /// the job ID. The schedule at `key` is created when missing.
+    pub async fn schedule_add(&self, key: &str, target: Target, payload: Vec<u8>, delay: Duration) -> Result<u64, Error> {
+        let msg = proto::CommandMessage::SCHEDADD(key.to_owned(), target, payload, delay.as_millis() as u64);
+        self.rpc_decode(msg).await
+    }
+
+    /// Removes a job before its delivery, returns whether it was scheduled.
+    pub async fn schedule_cancel(&self, key: &str, id: u64) -> Result<bool, Error> {
+        self.rpc_decode(proto::CommandMessage::SCHEDCANCEL(key.to_owned(), id)).await
+    }
+
+    pub async fn schedule_list(&self, key: &str) -> Result<Option<Vec<Job>>, Error> {
+        self.rpc_decode(proto::CommandMessage::SCHEDLIST(key.to_owned())).await
+    }
+
+    /// Publishes the payload on the channel, returns the number of
+    /// subscribers it reached.
+    pub async fn publish(&self, channel: &str, payload: Vec<u8>) -> Result<usize, Error> {
+        self.rpc_decode(proto::CommandMessage::PUBLISH(channel.to_owned(), payload)).await
+    }
+
+    /// Subscribes to the channels and streams the channel and payload of the
+    /// messages published from now on. Other commands of the connection are
+    /// served meanwhile. The server forwards messages until the connection
+    /// closes, they are discarded once the stream is dropped.
+    pub async fn subscribe(&self, channels: &[&str]) -> Result<impl Stream<Item = (String, Vec<u8>)>, Error> {
+        let channels = channels.iter().map(|x| x.to_string()).collect();
+        let (_, mut rx) = self.send(proto::CommandMessage::SUBSCRIBE(channels).into())?;
+        match rx.recv().await {
+            Some(reply) => reply_data(reply.command)?,
+            None => return Err(Error::new(ErrorKind::ConnectionAborted, "connection closed")),
+        };
+
+        Ok(stream::unfold(rx, |mut rx| async move {
+            loop {
+                if let proto::CommandMessage::MESSAGE(channel, payload) = rx.recv().await?.command {
+                    return Some(((channel, payload), rx));
+                }
+            }
+        }))
+    }
+
+    /// Makes the next transaction of the connection fail when any of the keys
+    /// change before it runs. Watches are per connection and cleared by every
+    /// transaction.
+    pub async fn watch(&self, keys: &[&str]) -> Result<(), Error> {
+        let msg = proto::CommandMessage::WATCH(keys.iter().map(|x| x.to_string()).collect()).into();
+        self.rpc(msg).await.map(|_| ())
+    }
+
+    pub async fn unwatch(&self) -> Result<(), Error> {
+        self.rpc(proto::CommandMessage::UNWATCH().into()).await.map(|_| ())
+    }
+
+    /// Runs an ACL admin subcommand, e.g. `["SETUSER", "alice", "on", ">pw", "~app:*", "+@read"]`.
+    pub async fn acl(&self, args: &[&str]) -> Result<Vec<String>, Error> {
+        let msg = proto::CommandMessage::ACL(args.iter().map(|x| x.to_string()).collect()).into();
+
+        match self.rpc(msg).await? {
+            Some(data) => rmp_serde::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            None => Ok(Vec::new()),
+        }
+    }
+
     pub async fn delete(&self, key: &str) -> Result<(), Error> {
         let msg = proto::CommandMessage::DELETE(key.to_owned()).into();
 
@@ -1,4 +1,6 @@
 pub mod asyncronius;
+pub mod pipeline;
+pub mod transaction;
 
 // type ReceverQueue = Arc<Mutex<HashMap<u128, Sender<proto::Message>>>>;
 
@@ -0,0 +1,87 @@
+use std::{
+    io::{Error, ErrorKind},
+    time::Duration,
+};
+
+use super::asyncronius::{reply_data, Client};
+use crate::proto::CommandMessage;
+
+/// Queues commands and sends them to the server as a single `BATCH` frame.
+///
+/// ```ignore
+/// let replies = client
+///     .pipeline()
+///     .put("a", b"1".to_vec(), None)
+///     .get("a")
+///     .delete("a")
+///     .execute()
+///     .await?;
+/// ```
+#[derive(Debug)]
+pub struct Pipeline<'a> {
+    client: &'a Client,
+    commands: Vec<CommandMessage>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub(crate) fn new(client: &'a Client) -> Self {
+        Self {
+            client,
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub fn command(mut self, command: CommandMessage) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    pub fn get(self, key: &str) -> Self {
+        self.command(CommandMessage::GET(key.to_owned()))
+    }
+
+    pub fn put(self, key: &str, data: Vec<u8>, exp: Option<Duration>) -> Self {
+        self.command(CommandMessage::PUT(key.to_owned(), data, exp))
+    }
+
+    pub fn incr_by(self, key: &str, delta: i64, ttl: Option<Duration>) -> Self {
+        self.command(CommandMessage::INCR(key.to_owned(), delta, ttl))
+    }
+
+    pub fn expire(self, key: &str, exp: Option<Duration>) -> Self {
+        self.command(CommandMessage::EXPIRE(key.to_owned(), exp))
+    }
+
+    pub fn delete(self, key: &str) -> Self {
+        self.command(CommandMessage::DELETE(key.to_owned()))
+    }
+
+    /// Sends the queued commands and returns one reply per command, in order.
+    /// The outer error is a failure of the whole round trip, the inner ones
+    /// are errors the server returned for single commands.
+    pub async fn execute(self) -> Result<Vec<Result<Option<Vec<u8>>, Error>>, Error> {
+        if self.commands.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let count = self.commands.len();
+        let reply = self.client.call(CommandMessage::BATCH(self.commands).into()).await?;
+        match reply.command {
+            CommandMessage::BATCH(replies) if replies.len() == count => {
+                Ok(replies.into_iter().map(reply_data).collect())
+            }
+            CommandMessage::BATCH(_) => Err(Error::new(ErrorKind::InvalidData, "batch reply count mismatch")),
+            other => Err(reply_data(other).err().unwrap_or_else(|| {
+                Error::new(ErrorKind::InvalidData, "unexpected reply to batch")
+            })),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    io::{Error, ErrorKind},
+    time::Duration,
+};
+
+use super::asyncronius::{reply_data, Client};
+use crate::proto::CommandMessage;
+
+/// Queues commands and runs them on the server as a `MULTI`/`EXEC`
+/// transaction. Everything is sent in a single frame, so commands of other
+/// tasks sharing the connection never end up inside the transaction.
+///
+/// ```ignore
+/// client.watch(&["user:1"]).await?;
+/// let profile = client.get("user:1").await?;
+/// let replies = client
+///     .transaction()
+///     .put("user:1", updated, None)
+///     .put("email:alice@example.com", b"user:1".to_vec(), None)
+///     .execute()
+///     .await?;
+/// if replies.is_none() {
+///     // user:1 changed since the WATCH, retry
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    client: &'a Client,
+    commands: Vec<CommandMessage>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(client: &'a Client) -> Self {
+        Self {
+            client,
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub fn command(mut self, command: CommandMessage) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    pub fn get(self, key: &str) -> Self {
+        self.command(CommandMessage::GET(key.to_owned()))
+    }
+
+    pub fn put(self, key: &str, data: Vec<u8>, exp: Option<Duration>) -> Self {
+        self.command(CommandMessage::PUT(key.to_owned(), data, exp))
+    }
+
+    pub fn incr_by(self, key: &str, delta: i64, ttl: Option<Duration>) -> Self {
+        self.command(CommandMessage::INCR(key.to_owned(), delta, ttl))
+    }
+
+    pub fn expire(self, key: &str, exp: Option<Duration>) -> Self {
+        self.command(CommandMessage::EXPIRE(key.to_owned(), exp))
+    }
+
+    pub fn delete(self, key: &str) -> Self {
+        self.command(CommandMessage::DELETE(key.to_owned()))
+    }
+
+    /// Runs the queued commands atomically and returns one reply per command,
+    /// in order, or `None` when a key watched by the connection changed and
+    /// nothing was run. Commands the server refused to queue fail the whole
+    /// transaction with their error.
+    pub async fn execute(self) -> Result<Option<Vec<Result<Option<Vec<u8>>, Error>>>, Error> {
+        let count = self.commands.len() + 2;
+        let mut commands = Vec::with_capacity(count);
+        commands.push(CommandMessage::MULTI());
+        commands.extend(self.commands);
+        commands.push(CommandMessage::EXEC());
+
+        let reply = self.client.call(CommandMessage::BATCH(commands).into()).await?;
+        let mut replies = match reply.command {
+            CommandMessage::BATCH(replies) if replies.len() == count => replies,
+            CommandMessage::BATCH(_) => return Err(Error::new(ErrorKind::InvalidData, "batch reply count mismatch")),
+            other => {
+                return Err(reply_data(other).err().unwrap_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "unexpected reply to transaction")
+                }))
+            }
+        };
+
+        let exec = replies.pop().unwrap();
+        for queued in replies {
+            reply_data(queued)?;
+        }
+
+        match exec {
+            CommandMessage::BATCH(replies) => Ok(Some(replies.into_iter().map(reply_data).collect())),
+            other => reply_data(other).map(|_| None),
+        }
+    }
+}
@@ -1,4 +1,3 @@
-#![feature(hash_extract_if)]
 extern crate core;
 
 pub mod client;
@@ -9,3 +8,4 @@ pub mod persistance;
 pub mod storage;
 
 pub mod cli;
+pub mod tls;
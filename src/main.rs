@@ -11,18 +11,51 @@ use cachetcp::{
         snapshot,
         wal::{self, WalWritter},
     },
-    server,
+    server::{self, auth::Authenticator},
     storage::storage::Storage,
+    tls,
 };
 use clap::Parser;
 use rand::{thread_rng, Rng};
 use rand::prelude::SliceRandom;
 use serde::{Deserialize, Serialize};
-use tokio::{fs, net::TcpListener, time::interval};
-use cachetcp::proto::CommandMessage::DELETE;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use tokio::{
+    fs,
+    net::{TcpListener, UnixListener},
+    time::interval,
+};
+
+/// Binds a Unix domain socket, replacing a stale socket file left by a previous run.
+/// The socket is bound inside a directory only the server can access and moved to
+/// `path` once its permissions are set, so it is never reachable with the default ones.
+fn bind_unix(path: &str, mode: u32) -> Result<UnixListener, std::io::Error> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "path exists and is not a socket"));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let private = format!("{}.{}", path, std::process::id());
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = format!("{}/s", private);
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    std::fs::remove_dir(&private)?;
+
+    bound
+}
 
 async fn handle_server(args: &cli::Args) -> Result<(), std::io::Error> {
-    let storage = Arc::new(Storage::new());
+    let storage = Arc::new(match args.ordered_index {
+        true => Storage::with_ordered_index(),
+        false => Storage::new(),
+    });
     let ss = snapshot::SnapshotCreator::new(&args.snapshot).await;
     let mut wal = wal::WriteAheadLog::new(&args.wal).await;
     let w = WalWritter::new(wal.tx());
@@ -35,29 +68,135 @@ async fn handle_server(args: &cli::Args) -> Result<(), std::io::Error> {
     println!("Regenerated keys snapshot: {:?}", keys);
     println!("Regenerated WAL entries: {:?}", replayed);
 
-    let listener = TcpListener::bind(args.addr.clone())
-        .await
-        .expect("failed to bind port");
+    let auth = Arc::new(Authenticator::new(
+        args.password.clone(),
+        args.users.clone(),
+        args.acl_file.clone(),
+    )?);
+    if auth.required() {
+        println!("Authentication required");
+    }
+
+    let listener = match args.no_tcp {
+        true => None,
+        false => {
+            let listener = TcpListener::bind(args.addr.clone())
+                .await
+                .expect("failed to bind port");
+            println!("Starting server on {}", args.addr);
+            Some(listener)
+        }
+    };
+
+    let acceptor = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let acceptor = tls::acceptor(cert, key, args.tls_ca.as_deref())?;
+            println!(
+                "TLS enabled{}",
+                if args.tls_ca.is_some() { " with client certificate verification" } else { "" }
+            );
+            Some(acceptor)
+        }
+        _ => None,
+    };
+
+    let unix_listener = match &args.unix_socket {
+        Some(path) => {
+            let listener = bind_unix(path, args.unix_socket_mode).expect("failed to bind unix socket");
+            println!("Starting server on unix:{}", path);
+            Some(listener)
+        }
+        None => None,
+    };
 
-    println!("Starting server on {}", args.addr);
+    let resp_listener = match &args.resp_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .expect("failed to bind RESP port");
+            println!("Starting RESP listener on {}", addr);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let memcache_listener = match &args.memcache_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .expect("failed to bind memcached port");
+            println!("Starting memcached listener on {}", addr);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let http_listener = match &args.http_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .expect("failed to bind HTTP port");
+            println!("Starting HTTP gateway on {}", addr);
+            Some(listener)
+        }
+        None => None,
+    };
 
     let mut ticker = interval(Duration::from_secs(args.snapshot_internal * 60));
-    let mut tx = wal.tx();
+    let tx = wal.tx();
     loop {
         tokio::select! {
-            res = server::functional::initiate_client(&listener, &storage, &w) => {
+            res = async {
+                match (&listener, &acceptor) {
+                    (Some(l), Some(a)) => server::functional::initiate_tls_client(l, a, &storage, &w, &auth).await,
+                    (Some(l), None) => server::functional::initiate_client(l, &storage, &w, &auth).await,
+                    (None, _) => std::future::pending().await,
+                }
+            } => {
                 res.expect("Failed initiate client")
             }
+            res = async {
+                match &unix_listener {
+                    Some(l) => server::functional::initiate_unix_client(l, &storage, &w, &auth).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                res.expect("Failed initiate unix client")
+            }
+            res = async {
+                match &resp_listener {
+                    Some(l) => server::resp::initiate_client(l, acceptor.as_ref(), &storage, &w, &auth).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                res.expect("Failed initiate RESP client")
+            }
+            res = async {
+                match &memcache_listener {
+                    Some(l) => server::memcache::initiate_client(l, acceptor.as_ref(), &storage, &w, &auth).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                res.expect("Failed initiate memcached client")
+            }
+            res = async {
+                match &http_listener {
+                    Some(l) => server::http::initiate_client(l, acceptor.as_ref(), &storage, &w, &auth).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                res.expect("Failed initiate HTTP client")
+            }
             _ = ticker.tick() => {
                 let keys = ss.snapshot(&storage).await?;
                 wal.clear().await?;
 
                 println!("Snapshot created: {:?} keys saved", keys);
             },
-            res = wal.write() => {
-                res.expect("Failed to write WAL entry")
+            Some(msg) = wal.recv() => {
+                wal.write_to_log(msg.into()).await.expect("Failed to write WAL entry")
             },
-            _ = storage.expire(&mut tx) => {},
+            _ = storage.expiring() => storage.expire(&tx).await,
         }
     }
 }
@@ -76,15 +215,29 @@ async fn handle_client(args: &cli::Args) -> Result<(), std::io::Error> {
     let test_data = fs::read_to_string("./test_data.json").await?;
     let test_data: Vec<TestData> = serde_json::from_str(test_data.as_str())?;
 
-    let c = client::asyncronius::Client::new(&args.addr).await;
+    let credentials = args.password.clone().map(|password| client::asyncronius::Credentials {
+        username: args.username.clone(),
+        password,
+    });
+    let c = match (&args.unix_socket, &args.tls_ca) {
+        (Some(path), _) => client::asyncronius::Client::new_unix(path, credentials).await?,
+        (None, Some(ca)) => {
+            let options = tls::TlsOptions {
+                ca: ca.clone(),
+                identity: args.tls_cert.clone().zip(args.tls_key.clone()),
+                server_name: args.tls_server_name.clone(),
+            };
+            client::asyncronius::Client::new_tls(&args.addr, &options, credentials).await?
+        }
+        (None, None) => client::asyncronius::Client::new(&args.addr, credentials).await,
+    };
 
     let mut i: u64 = 1;
-    let _ = c.connected().await;
+    c.connected().await?;
     let mut rng = thread_rng();
 
     loop {
         sleep(Duration::from_millis(rand::thread_rng().gen_range(10..50)));
-        let s = i.to_be_bytes();
         let buf = serde_json::to_vec(&test_data.choose(&mut rng)).unwrap();
         let t = SystemTime::now();
         let res = c.put(format!("{}", i).as_str(), buf, Some(Duration::from_secs(i % 40 + 1))).await;
@@ -104,11 +257,11 @@ async fn handle_client(args: &cli::Args) -> Result<(), std::io::Error> {
             }
         }
 
-        i = i + 1;
+        i += 1;
     }
 }
 
-async fn handle_testing(args: &cli::Args) -> Result<(), std::io::Error> {
+async fn handle_testing(_args: &cli::Args) -> Result<(), std::io::Error> {
     // let ctrl = ExpirationController::new();
     // let t = SystemTime::now();
     // loop {
@@ -139,6 +292,54 @@ async fn main() -> std::io::Result<()> {
         // cli::Runtime::Client => cli::start_interactive(),
         cli::Runtime::Testing => handle_testing(&args).await,
         cli::Runtime::Server => handle_server(&args).await,
-        _ => handle_server(&args).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("cachetcp-unix-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("cachetcp.sock").to_str().unwrap().to_owned()
+    }
+
+    fn mode(path: &str) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[tokio::test]
+    async fn bind_unix_sets_mode_and_replaces_stale_sockets() {
+        let path = socket_path("rebind");
+
+        let listener = bind_unix(&path, 0o660).unwrap();
+        assert_eq!(mode(&path), 0o660);
+        // The staging directory is gone once the socket is in place.
+        assert!(std::fs::metadata(format!("{}.{}", path, std::process::id())).is_err());
+        drop(listener);
+        assert!(std::fs::symlink_metadata(&path).unwrap().file_type().is_socket());
+
+        let listener = bind_unix(&path, 0o600).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        let connecting = tokio::net::UnixStream::connect(&path);
+        let (accepted, connected) = tokio::join!(listener.accept(), connecting);
+        accepted.unwrap();
+        connected.unwrap();
+
+        std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_unix_keeps_other_files() {
+        let path = socket_path("file");
+        std::fs::write(&path, b"data").unwrap();
+
+        let err = bind_unix(&path, 0o600).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+
+        std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap()).unwrap();
     }
 }
@@ -5,12 +5,20 @@ use tokio::{
     io::{AsyncSeekExt, AsyncWriteExt},
 };
 
-use crate::proto::CommandMessage::PUT;
+use crate::proto::CommandMessage::{self, ENTRY, INDEXCREATE, PUT, PUTFLAGS, VALUE};
 use crate::proto::FrameMessage;
-use crate::storage::storage::Storage;
+use crate::storage::storage::{Item, Storage, StoreMode};
 
 use super::read_log_entry;
 
+/// Frame prefixed with its length, as read back by `read_log_entry`.
+fn framed(cmd: FrameMessage) -> Vec<u8> {
+    let cmd: Vec<u8> = cmd.into();
+    let mut buf: Vec<u8> = cmd.len().to_be_bytes().to_vec();
+    buf.extend(cmd);
+    buf
+}
+
 pub struct SnapshotCreator {
     path: String,
 }
@@ -33,13 +41,20 @@ impl SnapshotCreator {
         let keys = cc.keys().await.unwrap();
         fw.rewind().await?;
 
+        // Only index definitions are stored, restoring the entries rebuilds them.
+        for (name, prefix, path) in cc.index_list().await {
+            fw.write_all(&framed(INDEXCREATE(name, prefix, path).into())).await?;
+        }
+
         for key in keys.clone().into_iter() {
-            let res = cc.read(&key).await.unwrap();
-            let cmd: FrameMessage = PUT(key.clone(), res, None).into();
-            let cmd: Vec<u8> = cmd.into();
-            let mut buf: Vec<u8> = cmd.len().to_be_bytes().to_vec();
-            buf.extend(cmd);
-            fw.write_all(&buf).await?;
+            let cmd: FrameMessage = match cc.read_item(&key).await {
+                Some(item) => CommandMessage::entry(&key, &item).into(),
+                None => match cc.read_value(&key).await {
+                    Some((value, ttl, version)) => VALUE(key.clone(), value, ttl, version).into(),
+                    None => continue,
+                },
+            };
+            fw.write_all(&framed(cmd)).await?;
         }
 
         fw.flush().await?;
@@ -53,29 +68,33 @@ impl SnapshotCreator {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(self.path.clone())
             .await?;
-        loop {
-            match read_log_entry(reader.try_clone().await?).await? {
-                Some(x) => {
-                    result = result + 1;
-                    match x.command {
-                        PUT(key, data, exp) => {
-                            match exp {
-                                None => {
-                                    cc.write(&key, data).await
-                                }
-                                Some(x) => {
-                                    cc.write_ex(&key, data, exp.unwrap()).await
-                                }
-                            }
-                        }
-                        _ => None,
-                    };
+        while let Some(x) = read_log_entry(reader.try_clone().await?).await? {
+            result += 1;
+            match x.command {
+                PUT(key, data, exp) => match exp {
+                    None => cc.write(&key, data).await,
+                    Some(exp) => cc.write_ex(&key, data, exp).await,
+                },
+                PUTFLAGS(key, data, exp, flags) => {
+                    cc.store(&key, data, exp, flags, StoreMode::Set).await;
+                    None
+                }
+                ENTRY(key, value, ttl, flags, version) => {
+                    cc.restore(&key, Item { value, flags, version, ttl }).await;
+                    None
+                }
+                VALUE(key, value, ttl, version) => {
+                    cc.restore_value(&key, value, ttl, 0, version).await;
+                    None
                 }
-                None => {
-                    break;
+                INDEXCREATE(name, prefix, path) => {
+                    let _ = cc.index_create(&name, &prefix, &path).await;
+                    None
                 }
+                _ => None,
             };
         }
 
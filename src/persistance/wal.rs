@@ -1,5 +1,4 @@
 use std::{io::Error, pin::Pin, sync::Arc};
-use std::time::Duration;
 
 use tokio::{
     fs::{File, OpenOptions},
@@ -10,7 +9,13 @@ use tokio::{
     },
 };
 
-use crate::{proto, storage::storage::Storage};
+use crate::{
+    proto,
+    storage::{
+        storage::{now_millis, Item, Storage, StoreMode},
+        value::Change,
+    },
+};
 
 use super::{read_log_entry};
 
@@ -41,15 +46,10 @@ impl WriteAheadLog {
         Self { fw, fr, tx, rx }
     }
 
-    pub async fn write(&mut self) -> Result<(), Error> {
-        match self.rx.recv().await {
-            Some(msg) => {
-                let buf: Vec<u8> = msg.into();
-
-                self.write_to_log(buf).await
-            }
-            None => Ok(()),
-        }
+    /// Next entry to append. Cancel safe, unlike a write in progress, so it
+    /// is the only part awaited next to other branches of a `select!`.
+    pub async fn recv(&mut self) -> Option<proto::FrameMessage> {
+        self.rx.recv().await
     }
 
     pub async fn write_to_log(
@@ -68,29 +68,77 @@ impl WriteAheadLog {
 
     pub async fn replay(&self, cc: &Arc<Storage>) -> Result<u64, Error> {
         use proto::CommandMessage::*;
-        let mut result = 0u64;
-        loop {
-            match self.read_log_entry().await? {
-                Some(x) => {
-                    match x.command {
-                        PUT(key, data, exp) => {
-                            match exp {
-                                None => {
-                                    cc.write(&key, data).await
-                                }
-                                Some(x) => {
-                                    cc.write_ex(&key, data, exp.unwrap()).await
-                                }
+        let result = 0u64;
+        while let Some(x) = self.read_log_entry().await? {
+            let commands = match x.command {
+                BATCH(commands) => commands,
+                command => vec![command],
+            };
+            for command in commands {
+                match command {
+                    PUT(key, data, exp) => match exp {
+                        None => cc.write(&key, data).await,
+                        Some(exp) => cc.write_ex(&key, data, exp).await,
+                    },
+                    DELETE(key) => {
+                        cc.delete(&key).await;
+                        None
+                    }
+                    PUTFLAGS(key, data, exp, flags) => {
+                        cc.store(&key, data, exp, flags, StoreMode::Set).await;
+                        None
+                    }
+                    EXPIRE(key, exp) => {
+                        cc.set_expiration(&key, exp).await;
+                        None
+                    }
+                    ENTRY(key, value, ttl, flags, version) => {
+                        cc.restore(&key, Item { value, flags, version, ttl }).await;
+                        None
+                    }
+                    APPLIED(version, command) => {
+                        let (now, command) = match *command {
+                            AT(now, command) => (Some(now), *command),
+                            command => (None, command),
+                        };
+                        // A record can still be queued for the log when a
+                        // snapshot is taken, it then lands in the fresh log
+                        // although the snapshot already holds its effect.
+                        if let (Some(version), Some(key)) = (version, command.applied_key()) {
+                            if cc.version(key).await.is_some_and(|current| current >= version) {
+                                continue;
                             }
                         }
-                        DELETE(key) => cc.delete(&key).await,
-                        _ => None,
-                    };
-                }
-                None => {
-                    break;
-                }
-            };
+                        if let Some(key) = apply(cc, command, now).await {
+                            if let Some(version) = version {
+                                cc.restore_version(&key, version).await;
+                            }
+                        }
+                        None
+                    }
+                    VALUE(key, value, ttl, version) => {
+                        cc.restore_value(&key, value, ttl, 0, version).await;
+                        None
+                    }
+                    MSET(entries) => {
+                        cc.write_many(entries).await;
+                        None
+                    }
+                    MDEL(keys) => {
+                        cc.delete_many(&keys).await;
+                        None
+                    }
+                    INDEXCREATE(name, prefix, path) => {
+                        let _ = cc.index_create(&name, &prefix, &path).await;
+                        None
+                    }
+                    INDEXDROP(name) => {
+                        cc.index_drop(&name).await;
+                        None
+                    }
+                    _ => None,
+                };
+            }
         }
 
         Ok(result)
@@ -120,8 +168,170 @@ impl WalWritter {
         Self { tx }
     }
 
+    /// Writer collecting entries instead of logging them, the caller hands
+    /// them to the log at once with `write_batch`.
+    pub fn buffered() -> (Self, UnboundedReceiver<proto::FrameMessage>) {
+        let (tx, rx) = unbounded_channel();
+
+        (Self { tx }, rx)
+    }
+
     pub fn write(&self, msg: &proto::FrameMessage) {
         let msg = msg.clone();
         let _ = self.tx.send(msg);
     }
+
+    /// Logs a collection command that changed its key.
+    pub fn write_applied(&self, command: &proto::CommandMessage, change: Change) {
+        if change.is_changed() {
+            self.write(&command.applied(change).into());
+        }
+    }
+
+    /// Logs the state of written entries, with their versions, as a single frame.
+    pub fn write_entries<'a>(&self, entries: impl IntoIterator<Item = (&'a str, &'a Item)>) {
+        let mut commands: Vec<proto::CommandMessage> = entries
+            .into_iter()
+            .map(|(key, item)| proto::CommandMessage::entry(key, item))
+            .collect();
+
+        match commands.len() {
+            0 => {}
+            1 => self.write(&commands.remove(0).into()),
+            _ => self.write(&proto::CommandMessage::BATCH(commands).into()),
+        }
+    }
+
+    /// Logs the entries collected by a `buffered` writer as a single frame.
+    pub fn write_batch(&self, rx: &mut UnboundedReceiver<proto::FrameMessage>) {
+        let mut commands = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            match msg.command {
+                proto::CommandMessage::BATCH(x) => commands.extend(x),
+                x => commands.push(x),
+            }
+        }
+
+        match commands.len() {
+            0 => {}
+            1 => self.write(&commands.remove(0).into()),
+            _ => self.write(&proto::CommandMessage::BATCH(commands).into()),
+        }
+    }
+}
+
+/// Runs a collection command logged as `APPLIED` again and returns its key,
+/// at the time it was logged with, if any.
+async fn apply(cc: &Arc<Storage>, command: proto::CommandMessage, now: Option<u64>) -> Option<String> {
+    use proto::CommandMessage::*;
+    // Commands only reach the log after they succeeded, so type errors can
+    // not happen here.
+    let key = match command {
+        LPUSH(key, values) => cc.list_push(&key, values, true).await.map(|_| key),
+        RPUSH(key, values) => cc.list_push(&key, values, false).await.map(|_| key),
+        LPOP(key, count) => cc.list_pop(&key, count, true).await.map(|_| key),
+        RPOP(key, count) => cc.list_pop(&key, count, false).await.map(|_| key),
+        LTRIM(key, start, stop) => cc.list_trim(&key, start, stop).await.map(|_| key),
+        HSET(key, fields) => cc.hash_set(&key, fields).await.map(|_| key),
+        HDEL(key, fields) => cc.hash_delete(&key, &fields).await.map(|_| key),
+        HINCRBY(key, field, delta) => cc.hash_incr_by(&key, &field, delta).await.map(|_| key),
+        HINCRBYFLOAT(key, field, delta) => cc.hash_incr_by_float(&key, &field, delta).await.map(|_| key),
+        SADD(key, members) => cc.set_add(&key, members).await.map(|_| key),
+        SREM(key, members) => cc.set_remove(&key, &members).await.map(|_| key),
+        ZADD(key, members) => cc.sorted_set_add(&key, members).await.map(|_| key),
+        ZINCRBY(key, delta, member) => cc.sorted_set_incr_by(&key, member, delta).await.map(|_| key),
+        ZREM(key, members) => cc.sorted_set_remove(&key, &members).await.map(|_| key),
+        ZREMRANGEBYRANK(key, start, stop) => cc.sorted_set_remove_range(&key, start, stop).await.map(|_| key),
+        ZREMRANGEBYSCORE(key, min, max) => cc.sorted_set_remove_by_score(&key, min, max).await.map(|_| key),
+        XADD(key, id, fields, max_len) => cc.stream_add(&key, id, fields, max_len).await.map(|_| key),
+        XTRIM(key, max_len) => cc.stream_trim(&key, max_len).await.map(|_| key),
+        XGROUPCREATE(key, group, id, create) => cc.stream_group_create(&key, &group, id, create).await.map(|_| key),
+        XGROUPDESTROY(key, group) => cc.stream_group_destroy(&key, &group).await.map(|_| key),
+        XREADGROUP(key, group, consumer, id, count, _) => {
+            cc.stream_read_group(&key, &group, &consumer, id, count, None, now, |_, _| {}).await.map(|_| key)
+        }
+        XACK(key, group, ids) => cc.stream_ack(&key, &group, &ids).await.map(|_| key),
+        PFADD(key, elements) => cc.hyperloglog_add(&key, elements).await.map(|_| key),
+        PFMERGE(key, sources) => cc.hyperloglog_merge(&key, &sources).await.map(|_| key),
+        BFRESERVE(key, error_rate, capacity) => cc.bloom_reserve(&key, error_rate, capacity).await.map(|_| key),
+        BFADD(key, items) => cc.bloom_add(&key, items).await.map(|_| key),
+        CMSINIT(key, width, depth) => cc.count_min_init(&key, width, depth).await.map(|_| key),
+        CMSINCRBY(key, items) => cc.count_min_incr_by(&key, items).await.map(|_| key),
+        TOPKRESERVE(key, k, width, depth) => cc.top_k_reserve(&key, k, width, depth).await.map(|_| key),
+        TOPKADD(key, items) => cc.top_k_add(&key, items).await.map(|_| key),
+        JSONSET(key, path, json, cond) => cc.json_set(&key, &path, &json, cond).await.map(|_| key),
+        JSONDEL(key, path) => cc.json_delete(&key, &path).await.map(|_| key),
+        JSONARRAPPEND(key, path, values) => cc.json_array_append(&key, &path, &values).await.map(|_| key),
+        JSONNUMINCRBY(key, path, delta) => cc.json_number_incr_by(&key, &path, delta).await.map(|_| key),
+        TSCREATE(key, retention) => cc.time_series_create(&key, retention).await.map(|_| key),
+        TSADD(key, timestamp, value, retention) => cc.time_series_add(&key, timestamp, value, retention).await.map(|_| key),
+        TSCREATERULE(key, dest, aggregation, bucket) => {
+            cc.time_series_create_rule(&key, &dest, aggregation, bucket).await.map(|_| key)
+        }
+        TSDELETERULE(key, dest) => cc.time_series_delete_rule(&key, &dest).await.map(|_| key),
+        QCREATE(key, options) => cc.queue_create(&key, options).await.map(|_| key),
+        QPUSH(key, payloads, delay) => {
+            cc.queue_push(&key, payloads, delay, now.unwrap_or_else(now_millis)).await.map(|_| key)
+        }
+        QPOP(key, count) => cc.queue_pop(&key, count, now.unwrap_or_else(now_millis)).await.map(|_| key),
+        QACK(key, receipts) => cc.queue_ack(&key, &receipts).await.map(|_| key),
+        QNACK(key, receipts, delay) => {
+            cc.queue_nack(&key, &receipts, delay, now.unwrap_or_else(now_millis)).await.map(|_| key)
+        }
+        QTIMEOUT(key, now) => cc.queue_timeout(&key, now).await.map(|_| key),
+        SCHEDADD(key, target, payload, delay) => {
+            cc.schedule_add(&key, target, payload, delay, now.unwrap_or_else(now_millis)).await.map(|_| key)
+        }
+        SCHEDCANCEL(key, id) => cc.schedule_cancel(&key, id).await.map(|_| key),
+        SCHEDFIRE(key, now) => cc.schedule_fire(&key, now).await.map(|_| key),
+        _ => return None,
+    };
+
+    key.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistance::snapshot::SnapshotCreator;
+
+    fn path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("cachetcp-wal-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_owned()
+    }
+
+    async fn push(cc: &Arc<Storage>, wal: &mut WriteAheadLog, value: &[u8]) -> proto::FrameMessage {
+        let command = proto::CommandMessage::LPUSH("l".to_owned(), vec![value.to_vec()]);
+        let (_, change) = cc.list_push("l", vec![value.to_vec()], true).await.unwrap();
+        WalWritter::new(wal.tx()).write_applied(&command, change);
+        wal.recv().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn replay_skips_records_the_snapshot_holds() {
+        let dir = path("snapshot");
+        let ss = SnapshotCreator::new(&format!("{}/snap.log", dir)).await;
+        let mut wal = WriteAheadLog::new(&format!("{}/wal.log", dir)).await;
+        let cc = Arc::new(Storage::new());
+
+        push(&cc, &mut wal, b"a").await;
+        // Applied but still queued while the snapshot is taken, so it is
+        // written to the cleared log.
+        let queued = push(&cc, &mut wal, b"b").await;
+        ss.snapshot(&cc).await.unwrap();
+        wal.clear().await.unwrap();
+        wal.write_to_log(queued.into()).await.unwrap();
+        let after = push(&cc, &mut wal, b"c").await;
+        wal.write_to_log(after.into()).await.unwrap();
+
+        let restored = Arc::new(Storage::new());
+        ss.restore(&restored).await.unwrap();
+        assert_eq!(restored.list_len("l").await, Ok(2));
+        wal.replay(&restored).await.unwrap();
+        assert_eq!(restored.list_len("l").await, Ok(3));
+        assert_eq!(restored.list_range("l", 0, -1).await.unwrap(), vec![b"c".to_vec(), b"b".to_vec(), b"a".to_vec()]);
+        assert_eq!(restored.version("l").await, cc.version("l").await);
+    }
 }
@@ -0,0 +1,389 @@
+use std::io::{Error, ErrorKind};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// Longest key accepted by the memcached protocol.
+pub const MAX_KEY_LEN: usize = 250;
+
+/// Largest value accepted in a single storage command.
+const MAX_VALUE_LEN: usize = 64 * 1024 * 1024;
+
+/// Longest command line accepted.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreCommand {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    Cas(u64),
+}
+
+/// Flags of a meta command, each token is a single flag character optionally
+/// followed by a value (e.g. `v`, `T30`, `Oopaque`).
+#[derive(Clone, Debug, Default)]
+pub struct MetaFlags(pub Vec<(u8, String)>);
+
+impl MetaFlags {
+    pub fn has(&self, flag: u8) -> bool {
+        self.0.iter().any(|(x, _)| *x == flag)
+    }
+
+    pub fn get(&self, flag: u8) -> Option<&str> {
+        self.0.iter().find(|(x, _)| *x == flag).map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Request {
+    Get {
+        keys: Vec<String>,
+        cas: bool,
+        touch: Option<i64>,
+    },
+    Store {
+        command: StoreCommand,
+        key: String,
+        flags: u32,
+        exptime: i64,
+        data: Vec<u8>,
+        noreply: bool,
+    },
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    Arith {
+        key: String,
+        delta: u64,
+        incr: bool,
+        noreply: bool,
+    },
+    Touch {
+        key: String,
+        exptime: i64,
+        noreply: bool,
+    },
+    MetaGet {
+        key: String,
+        flags: MetaFlags,
+    },
+    MetaSet {
+        key: String,
+        data: Vec<u8>,
+        flags: MetaFlags,
+    },
+    MetaDelete {
+        key: String,
+        flags: MetaFlags,
+    },
+    MetaArith {
+        key: String,
+        flags: MetaFlags,
+    },
+    MetaDebug {
+        key: String,
+    },
+    MetaNoop,
+    FlushAll {
+        delay: i64,
+        noreply: bool,
+    },
+    Version,
+    Verbosity {
+        noreply: bool,
+    },
+    Stats,
+    Quit,
+    /// Malformed request, answered with `CLIENT_ERROR`.
+    Invalid(String),
+    /// Unknown command, answered with `ERROR`.
+    Unknown,
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<String>, Error> {
+    let mut line = Vec::new();
+    if reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return match line.len() as u64 {
+            MAX_LINE_LEN => Err(Error::new(ErrorKind::InvalidData, "line too long")),
+            _ => Err(Error::new(ErrorKind::UnexpectedEof, "unterminated line")),
+        };
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+/// Reads the data block of a storage command including its trailing `\r\n`.
+async fn read_data<R: AsyncBufRead + Unpin>(reader: &mut R, size: usize) -> Result<Option<Vec<u8>>, Error> {
+    // The size is declared by the client, the buffer grows with the data
+    // actually received instead of being allocated upfront.
+    let mut data = Vec::new();
+    if reader.take(size as u64 + 2).read_to_end(&mut data).await? != size + 2 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated data block"));
+    }
+    if &data[size..] != b"\r\n" {
+        return Ok(None);
+    }
+    data.truncate(size);
+
+    Ok(Some(data))
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN && !key.bytes().any(|x| x.is_ascii_control())
+}
+
+/// Flags of a meta command, `None` when a token does not start with an ASCII
+/// letter.
+fn parse_meta_flags(tokens: &[&str]) -> Option<MetaFlags> {
+    tokens
+        .iter()
+        .map(|x| {
+            let mut chars = x.chars();
+            match chars.next() {
+                Some(flag) if flag.is_ascii_alphabetic() => Some((flag as u8, chars.as_str().to_owned())),
+                _ => None,
+            }
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(MetaFlags)
+}
+
+fn invalid(msg: &str) -> Request {
+    Request::Invalid(msg.to_owned())
+}
+
+/// Reads the next request from a memcached text protocol client. Returns `None`
+/// once the client closed the connection.
+pub async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Request>, Error> {
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let tokens: Vec<&str> = line.split_ascii_whitespace().collect();
+    if tokens.is_empty() {
+        return Ok(Some(Request::Unknown));
+    }
+
+    let noreply = tokens.last() == Some(&"noreply");
+    let request = match tokens[0] {
+        "get" | "gets" if tokens.len() >= 2 => Request::Get {
+            keys: tokens[1..].iter().map(|x| x.to_string()).collect(),
+            cas: tokens[0] == "gets",
+            touch: None,
+        },
+        "gat" | "gats" if tokens.len() >= 3 => match tokens[1].parse::<i64>() {
+            Ok(exptime) => Request::Get {
+                keys: tokens[2..].iter().map(|x| x.to_string()).collect(),
+                cas: tokens[0] == "gats",
+                touch: Some(exptime),
+            },
+            Err(_) => invalid("bad command line format"),
+        },
+        "set" | "add" | "replace" | "append" | "prepend" | "cas" => {
+            let expected = if tokens[0] == "cas" { 6 } else { 5 };
+            if tokens.len() < expected || tokens.len() > expected + 1 {
+                return Ok(Some(Request::Unknown));
+            }
+
+            let flags = tokens[2].parse::<u32>();
+            let exptime = tokens[3].parse::<i64>();
+            let size = tokens[4].parse::<usize>();
+            let (flags, exptime, size) = match (flags, exptime, size) {
+                (Ok(f), Ok(e), Ok(s)) if s <= MAX_VALUE_LEN => (f, e, s),
+                _ => return Ok(Some(invalid("bad command line format"))),
+            };
+            let command = match tokens[0] {
+                "set" => StoreCommand::Set,
+                "add" => StoreCommand::Add,
+                "replace" => StoreCommand::Replace,
+                "append" => StoreCommand::Append,
+                "prepend" => StoreCommand::Prepend,
+                _ => match tokens[5].parse::<u64>() {
+                    Ok(cas) => StoreCommand::Cas(cas),
+                    Err(_) => return Ok(Some(invalid("bad command line format"))),
+                },
+            };
+
+            let data = match read_data(reader, size).await? {
+                Some(data) => data,
+                None => return Ok(Some(invalid("bad data chunk"))),
+            };
+            if !valid_key(tokens[1]) {
+                return Ok(Some(invalid("bad command line format")));
+            }
+
+            Request::Store {
+                command,
+                key: tokens[1].to_owned(),
+                flags,
+                exptime,
+                data,
+                noreply,
+            }
+        }
+        "delete" if tokens.len() >= 2 => Request::Delete {
+            key: tokens[1].to_owned(),
+            noreply,
+        },
+        "incr" | "decr" if tokens.len() >= 3 => match tokens[2].parse::<u64>() {
+            Ok(delta) => Request::Arith {
+                key: tokens[1].to_owned(),
+                delta,
+                incr: tokens[0] == "incr",
+                noreply,
+            },
+            Err(_) => invalid("invalid numeric delta argument"),
+        },
+        "touch" if tokens.len() >= 3 => match tokens[2].parse::<i64>() {
+            Ok(exptime) => Request::Touch {
+                key: tokens[1].to_owned(),
+                exptime,
+                noreply,
+            },
+            Err(_) => invalid("invalid exptime argument"),
+        },
+        "mg" if tokens.len() >= 2 => match parse_meta_flags(&tokens[2..]) {
+            Some(flags) => Request::MetaGet {
+                key: tokens[1].to_owned(),
+                flags,
+            },
+            None => invalid("invalid flag"),
+        },
+        "ms" if tokens.len() >= 3 => {
+            let size = match tokens[2].parse::<usize>() {
+                Ok(s) if s <= MAX_VALUE_LEN => s,
+                _ => return Ok(Some(invalid("bad data chunk"))),
+            };
+            match (read_data(reader, size).await?, parse_meta_flags(&tokens[3..])) {
+                (Some(data), Some(flags)) => Request::MetaSet {
+                    key: tokens[1].to_owned(),
+                    data,
+                    flags,
+                },
+                (None, _) => invalid("bad data chunk"),
+                (_, None) => invalid("invalid flag"),
+            }
+        }
+        "md" if tokens.len() >= 2 => match parse_meta_flags(&tokens[2..]) {
+            Some(flags) => Request::MetaDelete {
+                key: tokens[1].to_owned(),
+                flags,
+            },
+            None => invalid("invalid flag"),
+        },
+        "ma" if tokens.len() >= 2 => match parse_meta_flags(&tokens[2..]) {
+            Some(flags) => Request::MetaArith {
+                key: tokens[1].to_owned(),
+                flags,
+            },
+            None => invalid("invalid flag"),
+        },
+        "me" if tokens.len() >= 2 => Request::MetaDebug {
+            key: tokens[1].to_owned(),
+        },
+        "mn" => Request::MetaNoop,
+        "flush_all" => Request::FlushAll {
+            delay: tokens.get(1).and_then(|x| x.parse::<i64>().ok()).unwrap_or(0),
+            noreply,
+        },
+        "version" => Request::Version,
+        "verbosity" => Request::Verbosity { noreply },
+        "stats" => Request::Stats,
+        "quit" => Request::Quit,
+        _ => Request::Unknown,
+    };
+
+    let key_ok = match &request {
+        Request::Get { keys, .. } => keys.iter().all(|x| valid_key(x)),
+        Request::Delete { key, .. }
+        | Request::Arith { key, .. }
+        | Request::Touch { key, .. }
+        | Request::MetaGet { key, .. }
+        | Request::MetaSet { key, .. }
+        | Request::MetaDelete { key, .. }
+        | Request::MetaArith { key, .. }
+        | Request::MetaDebug { key } => valid_key(key),
+        _ => true,
+    };
+    if !key_ok {
+        return Ok(Some(invalid("bad command line format")));
+    }
+
+    Ok(Some(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(input: &[u8]) -> Result<Option<Request>, Error> {
+        let mut reader = input;
+        read_request(&mut reader).await
+    }
+
+    #[tokio::test]
+    async fn reads_storage_commands() {
+        match read(b"set k 5 30 3\r\nabc\r\n").await.unwrap() {
+            Some(Request::Store { command, key, flags, exptime, data, noreply }) => {
+                assert_eq!((command, key.as_str(), flags, exptime), (StoreCommand::Set, "k", 5, 30));
+                assert_eq!((data.as_slice(), noreply), (&b"abc"[..], false));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        match read(b"cas k 0 0 1 42 noreply\r\nx\r\n").await.unwrap() {
+            Some(Request::Store { command, noreply, .. }) => assert_eq!((command, noreply), (StoreCommand::Cas(42), true)),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_data_blocks() {
+        let request = read(b"set k 0 0 3\r\nabcd\r\n").await.unwrap();
+        assert!(matches!(request, Some(Request::Invalid(x)) if x == "bad data chunk"));
+
+        let request = read(b"set k 0 0 67108865\r\n").await.unwrap();
+        assert!(matches!(request, Some(Request::Invalid(_))));
+
+        // A declared size larger than the data is not allocated upfront.
+        let err = read(b"set k 0 0 67108864\r\nabc").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let line = vec![b'a'; MAX_LINE_LEN as usize + 1];
+        assert_eq!(read(&line).await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_keys() {
+        let key = "k".repeat(MAX_KEY_LEN + 1);
+        let request = read(format!("get a {key}\r\n").as_bytes()).await.unwrap();
+        assert!(matches!(request, Some(Request::Invalid(_))));
+        assert!(matches!(read(b"bogus\r\n").await.unwrap(), Some(Request::Unknown)));
+    }
+
+    #[tokio::test]
+    async fn parses_meta_flags() {
+        match read(b"mg k v T30 Oabc\r\n").await.unwrap() {
+            Some(Request::MetaGet { key, flags }) => {
+                assert_eq!(key, "k");
+                assert!(flags.has(b'v'));
+                assert_eq!((flags.get(b'T'), flags.get(b'O'), flags.get(b'q')), (Some("30"), Some("abc"), None));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+
+        let request = read("mg k éT30\r\n".as_bytes()).await.unwrap();
+        assert!(matches!(request, Some(Request::Invalid(x)) if x == "invalid flag"));
+        let request = read("ms k 1 ü\r\nx\r\nmn\r\n".as_bytes()).await.unwrap();
+        assert!(matches!(request, Some(Request::Invalid(x)) if x == "invalid flag"));
+    }
+}
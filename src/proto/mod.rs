@@ -1,12 +1,22 @@
 use serde::{Deserialize, Serialize};
 use std::{
-    io::{BufReader, BufWriter, Error, Read, Write},
-    mem::size_of,
+    ops::Bound,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::io::{AsyncRead, AsyncWrite};
 
+use crate::storage::{
+    index::IndexValue,
+    queue::{QueueOptions, Receipt},
+    schedule::Target,
+    storage::{Item, TtlFilter, WriteCondition},
+    stream::StreamId,
+    timeseries::Aggregation,
+    value::{Change, Value},
+};
+
+pub mod memcache;
 pub mod nonblocking;
+pub mod resp;
 
 pub static VERSION: u8 = 1;
 
@@ -34,6 +44,304 @@ pub enum CommandMessage {
     KEYS(),
 
     RECV(Option<Vec<u8>>),
+
+    EXPIRE(String, Option<Duration>),
+    PUTFLAGS(String, Vec<u8>, Option<Duration>, u32),
+
+    AUTH(Option<String>, String),
+    ERROR(String),
+    ACL(Vec<String>),
+    /// Commands executed in order, answered with a `BATCH` of their replies.
+    BATCH(Vec<CommandMessage>),
+
+    MGET(Vec<String>),
+    /// Entries written with their own TTL.
+    MSET(Vec<(String, Vec<u8>, Option<Duration>)>),
+    MDEL(Vec<String>),
+
+    /// Cursor, glob pattern, count hint and TTL filter, answered with the next
+    /// cursor and the matching keys.
+    SCAN(u64, Option<String>, Option<usize>, Option<TtlFilter>),
+
+    /// Start key (inclusive), end key (exclusive) and limit, answered with key-value pairs.
+    RANGE(String, Option<String>, Option<usize>),
+    /// Prefix, key to continue after and limit, answered with key-value pairs.
+    PREFIX(String, Option<String>, Option<usize>),
+    /// Deletes every key under the prefix, answered with the number of deleted keys.
+    DELPREFIX(String),
+
+    /// `PUT` applied only when the condition holds, the flag asks for the
+    /// previous value. Answered with whether it was written and that value.
+    PUTIF(String, Vec<u8>, Option<Duration>, WriteCondition, bool),
+
+    /// Full entry state as logged to the WAL and snapshots: key, value, TTL,
+    /// flags and version.
+    ENTRY(String, Vec<u8>, Option<Duration>, u32, u64),
+    /// `GET` answered with the value and its version.
+    GETS(String),
+    /// Key, value, expected version and TTL. Answered with whether it was
+    /// written and the new version, or the current one on a mismatch.
+    CAS(String, Vec<u8>, u64, Option<Duration>),
+    /// Adds the delta to an integer counter, the TTL only applies when the
+    /// key is created. Answered with the new value.
+    INCR(String, i64, Option<Duration>),
+    /// Floating point variant of `INCR`.
+    INCRFLOAT(String, f64, Option<Duration>),
+
+    /// Starts queueing the commands of the connection until `EXEC` or `DISCARD`.
+    MULTI(),
+    /// Runs the queued commands atomically. Answered with a `BATCH` of their
+    /// replies, or an empty `RECV` when a watched key changed.
+    EXEC(),
+    DISCARD(),
+    /// Aborts the next `EXEC` of the connection if any of the keys change.
+    WATCH(Vec<String>),
+    UNWATCH(),
+
+    /// Write command as logged to the WAL with the version it gave its key,
+    /// `None` when it removed the key.
+    APPLIED(Option<u64>, Box<CommandMessage>),
+    /// Snapshot record of a value of any type: key, value, TTL and version.
+    VALUE(String, Value, Option<Duration>, u64),
+
+    /// Values pushed one after another, answered with the new list length.
+    LPUSH(String, Vec<Vec<u8>>),
+    RPUSH(String, Vec<Vec<u8>>),
+    /// Key and count, answered with the removed values.
+    LPOP(String, usize),
+    RPOP(String, usize),
+    /// Inclusive start and stop indexes, negative ones counting from the end.
+    LRANGE(String, i64, i64),
+    LLEN(String),
+    LTRIM(String, i64, i64),
+
+    /// Fields set on the hash, answered with the number of new fields.
+    HSET(String, Vec<(String, Vec<u8>)>),
+    HGET(String, String),
+    /// Answered with the number of removed fields.
+    HDEL(String, Vec<String>),
+    /// Answered with every field and its value.
+    HGETALL(String),
+    /// Adds the delta to the integer held by the field, answered with the new value.
+    HINCRBY(String, String, i64),
+    HINCRBYFLOAT(String, String, f64),
+
+    /// Members added to the set, answered with the number of new members.
+    SADD(String, Vec<Vec<u8>>),
+    /// Answered with the number of removed members.
+    SREM(String, Vec<Vec<u8>>),
+    SISMEMBER(String, Vec<u8>),
+    SMEMBERS(String),
+    /// Members present in every one of the sets.
+    SINTER(Vec<String>),
+    /// Members present in any of the sets.
+    SUNION(Vec<String>),
+
+    /// Scores and members, answered with the number of new members.
+    ZADD(String, Vec<(f64, Vec<u8>)>),
+    /// Adds the delta to the score of the member, answered with the new score.
+    ZINCRBY(String, f64, Vec<u8>),
+    /// Member and whether ranks are descending.
+    ZRANK(String, Vec<u8>, bool),
+    /// Inclusive start and stop ranks and whether ranks are descending,
+    /// answered with the members and their scores.
+    ZRANGE(String, i64, i64, bool),
+    /// Minimum and maximum score, offset and count.
+    ZRANGEBYSCORE(String, Bound<f64>, Bound<f64>, usize, Option<usize>),
+    ZREM(String, Vec<Vec<u8>>),
+    ZREMRANGEBYRANK(String, i64, i64),
+    ZREMRANGEBYSCORE(String, Bound<f64>, Bound<f64>),
+    ZSCORE(String, Vec<u8>),
+    ZCARD(String),
+
+    /// Key, entry ID or `None` to generate one, fields and the length to trim
+    /// the stream to, answered with the ID of the entry.
+    XADD(String, Option<StreamId>, Vec<(String, Vec<u8>)>, Option<usize>),
+    XLEN(String),
+    /// Inclusive start and end IDs, count and whether to start from the newest.
+    XRANGE(String, StreamId, StreamId, Option<usize>, bool),
+    /// Length to trim the stream to, answered with the number of removed entries.
+    XTRIM(String, usize),
+    /// Streams with the ID to read after, `None` for entries added from now
+    /// on, the count per stream and how long to block, zero for no limit.
+    XREAD(Vec<(String, Option<StreamId>)>, Option<usize>, Option<Duration>),
+    /// Key, group, ID to deliver entries after or `None` for the last entry,
+    /// and whether to create a missing stream.
+    XGROUPCREATE(String, String, Option<StreamId>, bool),
+    XGROUPDESTROY(String, String),
+    /// Key, group, consumer, `None` for entries never delivered to the group
+    /// or the ID to return pending entries of the consumer after, count and
+    /// how long to block.
+    XREADGROUP(String, String, String, Option<StreamId>, Option<usize>, Option<Duration>),
+    /// Key, group and IDs, answered with the number of acknowledged entries.
+    XACK(String, String, Vec<StreamId>),
+    /// Key, group, inclusive start and end IDs, count and consumer.
+    XPENDING(String, String, StreamId, StreamId, Option<usize>, Option<String>),
+
+    /// Answered with whether the estimated cardinality may have changed.
+    PFADD(String, Vec<Vec<u8>>),
+    /// Estimated cardinality of the union of the keys.
+    PFCOUNT(Vec<String>),
+    /// Destination and source keys.
+    PFMERGE(String, Vec<String>),
+    /// Key, false positive rate and capacity.
+    BFRESERVE(String, f64, u64),
+    /// Answered with whether each item was new to the filter.
+    BFADD(String, Vec<Vec<u8>>),
+    BFEXISTS(String, Vec<Vec<u8>>),
+    /// Key, width and depth.
+    CMSINIT(String, u32, u32),
+    /// Items with their increments, answered with their new estimated counts.
+    CMSINCRBY(String, Vec<(Vec<u8>, u64)>),
+    CMSQUERY(String, Vec<Vec<u8>>),
+    /// Key, k, width and depth of the sketch.
+    TOPKRESERVE(String, u32, u32, u32),
+    /// Answered with the item each one pushed out of the top, if any.
+    TOPKADD(String, Vec<Vec<u8>>),
+    TOPKQUERY(String, Vec<Vec<u8>>),
+    /// Answered with the items of the top and their estimated counts.
+    TOPKLIST(String),
+
+    /// Key, path, JSON text and the condition on the path existing, answered
+    /// with whether it was written.
+    JSONSET(String, String, Vec<u8>, WriteCondition),
+    /// Key and paths, answered with the JSON text of the selected nodes.
+    JSONGET(String, Vec<String>),
+    /// Answered with the number of removed nodes.
+    JSONDEL(String, String),
+    /// Key, path of an array and JSON texts to append, answered with its length.
+    JSONARRAPPEND(String, String, Vec<Vec<u8>>),
+    /// Answered with the JSON text of the resulting number.
+    JSONNUMINCRBY(String, String, f64),
+
+    /// Name, key prefix and JSON path of the indexed field.
+    INDEXCREATE(String, String, String),
+    /// Answered with whether the index existed.
+    INDEXDROP(String),
+    /// Answered with the name, prefix and path of every index.
+    INDEXLIST(),
+    /// Name, minimum and maximum field value and the number of keys to
+    /// return, answered with the keys ordered by field value.
+    INDEXQUERY(String, Bound<IndexValue>, Bound<IndexValue>, Option<usize>),
+
+    /// Key and milliseconds of samples to keep, zero to keep them all.
+    TSCREATE(String, u64),
+    /// Key, timestamp or `None` for now, value and the retention of a series
+    /// created by it, answered with the timestamp.
+    TSADD(String, Option<u64>, f64, u64),
+    /// Answered with the newest sample.
+    TSGET(String),
+    /// Inclusive start and end timestamps, count and the aggregation with its
+    /// bucket duration in milliseconds.
+    TSRANGE(String, u64, u64, Option<usize>, Option<(Aggregation, u64)>),
+    /// Source and destination keys, aggregation and bucket duration.
+    TSCREATERULE(String, String, Aggregation, u64),
+    /// Source and destination keys, answered with whether the rule existed.
+    TSDELETERULE(String, String),
+    TSINFO(String),
+
+    QCREATE(String, QueueOptions),
+    /// Key, payloads and milliseconds before they become visible, answered
+    /// with the message IDs. Logged in an `AT` record, like `QPOP` and `QNACK`.
+    QPUSH(String, Vec<Vec<u8>>, u64),
+    /// Key and count, answered with the deliveries.
+    QPOP(String, usize),
+    /// Key and receipts of the deliveries, answered with the number of
+    /// removed messages.
+    QACK(String, Vec<Receipt>),
+    /// Key, receipts of the deliveries and milliseconds before they become
+    /// visible again, answered with the number of messages in flight.
+    QNACK(String, Vec<Receipt>, u64),
+    QINFO(String),
+    /// Key and time at which the visibility timeouts of the queue were
+    /// checked, logged by the expiry loop.
+    QTIMEOUT(String, u64),
+
+    /// Key, target, payload and milliseconds before delivery, answered with
+    /// the job ID. Logged in an `AT` record, like `QPUSH`.
+    SCHEDADD(String, Target, Vec<u8>, u64),
+    /// Key and job ID, answered with whether the job was scheduled.
+    SCHEDCANCEL(String, u64),
+    SCHEDLIST(String),
+    /// Key and time at which the due jobs of the schedule were delivered,
+    /// logged by the expiry loop.
+    SCHEDFIRE(String, u64),
+
+    /// WAL record of a command run at the given server time in Unix
+    /// milliseconds, replayed with that time instead of the clock.
+    AT(u64, Box<CommandMessage>),
+
+    /// Channel and payload, answered with the number of subscribers reached.
+    PUBLISH(String, Vec<u8>),
+    /// Channels to listen on, acknowledged once subscribed. Published
+    /// payloads then follow as `MESSAGE` frames with the same `ts` until the
+    /// connection closes. Not allowed in batches and transactions.
+    SUBSCRIBE(Vec<String>),
+    /// Channel and payload of a published message.
+    MESSAGE(String, Vec<u8>),
+}
+
+impl CommandMessage {
+    pub fn entry(key: &str, item: &Item) -> Self {
+        CommandMessage::ENTRY(key.to_owned(), item.value.clone(), item.ttl, item.flags, item.version)
+    }
+
+    /// WAL record of a collection command with its effect on the key.
+    pub fn applied(&self, change: Change) -> Self {
+        CommandMessage::APPLIED(change.version(), Box::new(self.clone()))
+    }
+
+    /// Key of a collection command, which is logged as `APPLIED` with the
+    /// version it leaves the key at.
+    pub fn applied_key(&self) -> Option<&str> {
+        use CommandMessage::*;
+        match self {
+            LPUSH(key, ..) | RPUSH(key, ..) | LPOP(key, ..) | RPOP(key, ..) | LTRIM(key, ..) => Some(key),
+            HSET(key, ..) | HDEL(key, ..) | HINCRBY(key, ..) | HINCRBYFLOAT(key, ..) => Some(key),
+            SADD(key, ..) | SREM(key, ..) => Some(key),
+            ZADD(key, ..) | ZINCRBY(key, ..) | ZREM(key, ..) | ZREMRANGEBYRANK(key, ..) | ZREMRANGEBYSCORE(key, ..) => {
+                Some(key)
+            }
+            XADD(key, ..) | XTRIM(key, ..) | XGROUPCREATE(key, ..) | XGROUPDESTROY(key, ..) => Some(key),
+            XREADGROUP(key, ..) | XACK(key, ..) => Some(key),
+            PFADD(key, ..) | PFMERGE(key, ..) | BFRESERVE(key, ..) | BFADD(key, ..) => Some(key),
+            CMSINIT(key, ..) | CMSINCRBY(key, ..) | TOPKRESERVE(key, ..) | TOPKADD(key, ..) => Some(key),
+            JSONSET(key, ..) | JSONDEL(key, ..) | JSONARRAPPEND(key, ..) | JSONNUMINCRBY(key, ..) => Some(key),
+            TSCREATE(key, ..) | TSADD(key, ..) | TSCREATERULE(key, ..) | TSDELETERULE(key, ..) => Some(key),
+            QCREATE(key, ..) | QPUSH(key, ..) | QPOP(key, ..) | QACK(key, ..) | QNACK(key, ..) | QTIMEOUT(key, ..) => {
+                Some(key)
+            }
+            SCHEDADD(key, ..) | SCHEDCANCEL(key, ..) | SCHEDFIRE(key, ..) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// The command as applied at `now`, see `AT`.
+    pub fn at(&self, now: u64) -> Self {
+        CommandMessage::AT(now, Box::new(self.clone()))
+    }
+
+    /// Whether the command may wait for other connections, see `without_block`.
+    pub fn blocks(&self) -> bool {
+        matches!(
+            self,
+            CommandMessage::XREAD(_, _, Some(_))
+                | CommandMessage::XREADGROUP(_, _, _, None, _, Some(_))
+                | CommandMessage::SUBSCRIBE(_)
+        )
+    }
+
+    /// The command without its block timeout, for transactions which can not
+    /// wait on other connections.
+    pub fn without_block(self) -> Self {
+        match self {
+            CommandMessage::XREAD(streams, count, _) => CommandMessage::XREAD(streams, count, None),
+            CommandMessage::XREADGROUP(key, group, consumer, id, count, _) => {
+                CommandMessage::XREADGROUP(key, group, consumer, id, count, None)
+            }
+            command => command,
+        }
+    }
 }
 
 impl From<CommandMessage> for Vec<u8> {
@@ -42,9 +350,9 @@ impl From<CommandMessage> for Vec<u8> {
     }
 }
 
-impl Into<CommandMessage> for Vec<u8> {
-    fn into(self) -> CommandMessage {
-        rmp_serde::from_slice(&self.as_slice()).unwrap()
+impl From<Vec<u8>> for CommandMessage {
+    fn from(buf: Vec<u8>) -> CommandMessage {
+        rmp_serde::from_slice(buf.as_slice()).unwrap()
     }
 }
 
@@ -84,6 +392,31 @@ impl FrameMessage {
             command: CommandMessage::RECV(data),
         }
     }
+
+    /// Frame carrying `command` under the same `ts`, used for the commands of a batch.
+    pub fn with_command(&self, command: CommandMessage) -> FrameMessage {
+        FrameMessage {
+            ts: self.ts,
+            version: self.version,
+            command,
+        }
+    }
+
+    pub fn batch(&self, replies: Vec<CommandMessage>) -> FrameMessage {
+        FrameMessage {
+            ts: self.ts,
+            version: VERSION,
+            command: CommandMessage::BATCH(replies),
+        }
+    }
+
+    pub fn error(&self, msg: &str) -> FrameMessage {
+        FrameMessage {
+            ts: self.ts,
+            version: VERSION,
+            command: CommandMessage::ERROR(msg.to_owned()),
+        }
+    }
 }
 
 impl From<FrameMessage> for Vec<u8> {
@@ -92,9 +425,9 @@ impl From<FrameMessage> for Vec<u8> {
     }
 }
 
-impl Into<FrameMessage> for Vec<u8> {
-    fn into(self) -> FrameMessage {
-        rmp_serde::from_slice(&self.as_slice()).unwrap()
+impl From<Vec<u8>> for FrameMessage {
+    fn from(buf: Vec<u8>) -> FrameMessage {
+        rmp_serde::from_slice(buf.as_slice()).unwrap()
     }
 }
 
@@ -113,10 +446,9 @@ pub fn testing() {
         version: VERSION,
         command: CommandMessage::GET("Testing".to_owned()),
     }
-        .try_into()
-        .unwrap();
+    .into();
     println!("{:?}", buf);
 
-    let cmd: FrameMessage = buf.try_into().unwrap();
+    let cmd: FrameMessage = buf.into();
     println!("{:?}", cmd);
 }
@@ -1,4 +1,4 @@
-use std::{io::Error, mem::size_of, pin::Pin, time::SystemTime};
+use std::{io::Error, mem::size_of, pin::Pin};
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
@@ -9,11 +9,11 @@ pub async fn unmarshal<T: AsyncRead>(mut reader: Pin<Box<T>>) -> Result<FrameMes
     reader.read_exact(&mut buf).await?;
     let size = usize::from_be_bytes(buf);
 
-    let mut buf = vec![0u8; size.into()];
+    let mut buf = vec![0u8; size];
     reader.read_exact(&mut buf).await?;
     let fm: FrameMessage = buf.into();
 
-    return Ok(fm);
+    Ok(fm)
 }
 
 pub async fn marshal<T: AsyncWrite>(msg: &FrameMessage, mut w: Pin<Box<T>>) -> Result<(), Error> {
@@ -0,0 +1,265 @@
+use std::io::{Error, ErrorKind};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// Largest bulk string accepted from a RESP client.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Most elements of an array accepted from a RESP client.
+const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+/// Longest header or inline command line accepted from a RESP client.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<RespValue>),
+    Null,
+    NullArray,
+    Double(f64),
+    Boolean(bool),
+    Map(Vec<(RespValue, RespValue)>),
+    /// Out of band data, such as pub/sub messages.
+    Push(Vec<RespValue>),
+    /// Several replies to a single command, encoded one after another.
+    Replies(Vec<RespValue>),
+}
+
+impl RespValue {
+    pub fn ok() -> Self {
+        RespValue::Simple("OK".to_owned())
+    }
+
+    pub fn err(msg: &str) -> Self {
+        RespValue::Error(format!("ERR {}", msg))
+    }
+
+    pub fn bulk_str(s: &str) -> Self {
+        RespValue::Bulk(s.as_bytes().to_vec())
+    }
+
+    /// Encodes the value for the negotiated protocol version (2 or 3). RESP3-only
+    /// types are downgraded to their RESP2 equivalents.
+    pub fn encode(&self, version: u8, buf: &mut Vec<u8>) {
+        match self {
+            RespValue::Simple(s) => {
+                buf.push(b'+');
+                buf.extend_from_slice(s.as_bytes());
+                buf.extend_from_slice(b"\r\n");
+            }
+            RespValue::Error(s) => {
+                buf.push(b'-');
+                buf.extend_from_slice(s.as_bytes());
+                buf.extend_from_slice(b"\r\n");
+            }
+            RespValue::Integer(x) => {
+                buf.extend_from_slice(format!(":{}\r\n", x).as_bytes());
+            }
+            RespValue::Bulk(data) => {
+                buf.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                buf.extend_from_slice(data);
+                buf.extend_from_slice(b"\r\n");
+            }
+            RespValue::Array(items) => {
+                buf.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                items.iter().for_each(|x| x.encode(version, buf));
+            }
+            RespValue::Null if version >= 3 => buf.extend_from_slice(b"_\r\n"),
+            RespValue::Null => buf.extend_from_slice(b"$-1\r\n"),
+            RespValue::NullArray if version >= 3 => buf.extend_from_slice(b"_\r\n"),
+            RespValue::NullArray => buf.extend_from_slice(b"*-1\r\n"),
+            RespValue::Double(x) if version >= 3 => {
+                buf.extend_from_slice(format!(",{}\r\n", x).as_bytes());
+            }
+            RespValue::Double(x) => RespValue::bulk_str(&x.to_string()).encode(version, buf),
+            RespValue::Boolean(x) if version >= 3 => {
+                buf.extend_from_slice(if *x { b"#t\r\n" } else { b"#f\r\n" });
+            }
+            RespValue::Boolean(x) => RespValue::Integer(*x as i64).encode(version, buf),
+            RespValue::Map(pairs) if version >= 3 => {
+                buf.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                pairs.iter().for_each(|(k, v)| {
+                    k.encode(version, buf);
+                    v.encode(version, buf);
+                });
+            }
+            RespValue::Map(pairs) => {
+                buf.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                pairs.iter().for_each(|(k, v)| {
+                    k.encode(version, buf);
+                    v.encode(version, buf);
+                });
+            }
+            RespValue::Push(items) => {
+                let kind = if version >= 3 { '>' } else { '*' };
+                buf.extend_from_slice(format!("{}{}\r\n", kind, items.len()).as_bytes());
+                items.iter().for_each(|x| x.encode(version, buf));
+            }
+            RespValue::Replies(replies) => replies.iter().for_each(|x| x.encode(version, buf)),
+        }
+    }
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut line = Vec::new();
+    if reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return match line.len() as u64 {
+            MAX_LINE_LEN => Err(Error::new(ErrorKind::InvalidData, "line too long")),
+            _ => Err(Error::new(ErrorKind::UnexpectedEof, "unterminated line")),
+        };
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+fn parse_len(line: &[u8]) -> Result<i64, Error> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid length"))
+}
+
+/// Reads a single client command, either a RESP array of bulk strings or an
+/// inline command. Returns `None` once the client closed the connection.
+pub async fn read_command<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>, Error> {
+    loop {
+        let line = match read_line(reader).await? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if line[0] != b'*' {
+            let args: Vec<Vec<u8>> = line
+                .split(|x| x.is_ascii_whitespace())
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_vec())
+                .collect();
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        }
+
+        let count = parse_len(&line[1..])?;
+        if count <= 0 {
+            continue;
+        }
+        if count as usize > MAX_MULTIBULK_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid multibulk length"));
+        }
+
+        // Lengths are declared by the client, buffers grow with the data
+        // actually received instead of being allocated upfront.
+        let mut args = Vec::new();
+        for _ in 0..count {
+            let header = read_line(reader)
+                .await?
+                .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "truncated command"))?;
+            if header.first() != Some(&b'$') {
+                return Err(Error::new(ErrorKind::InvalidData, "expected bulk string"));
+            }
+            let size = parse_len(&header[1..])?;
+            if size < 0 || size as usize > MAX_BULK_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "invalid bulk length"));
+            }
+
+            let mut data = Vec::new();
+            let framed = size as u64 + 2;
+            if reader.take(framed).read_to_end(&mut data).await? as u64 != framed {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "truncated bulk string"));
+            }
+            if !data.ends_with(b"\r\n") {
+                return Err(Error::new(ErrorKind::InvalidData, "expected CRLF after bulk string"));
+            }
+            data.truncate(size as usize);
+            args.push(data);
+        }
+
+        return Ok(Some(args));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(input: &[u8]) -> Result<Option<Vec<Vec<u8>>>, Error> {
+        let mut reader = input;
+        read_command(&mut reader).await
+    }
+
+    fn encoded(value: RespValue, version: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        value.encode(version, &mut buf);
+        buf
+    }
+
+    #[tokio::test]
+    async fn reads_multibulk_and_inline_commands() {
+        let args = read(b"*2\r\n$3\r\nGET\r\n$5\r\nk\r\ny!\r\n").await.unwrap();
+        assert_eq!(args, Some(vec![b"GET".to_vec(), b"k\r\ny!".to_vec()]));
+
+        let args = read(b"\r\n  SET  k v\r\n").await.unwrap();
+        assert_eq!(args, Some(vec![b"SET".to_vec(), b"k".to_vec(), b"v".to_vec()]));
+
+        assert_eq!(read(b"").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_lengths() {
+        let err = read(b"*536870912\r\n").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let err = read(b"*1\r\n$536870913\r\n").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let line = vec![b'a'; MAX_LINE_LEN as usize + 1];
+        let err = read(&line).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_frames() {
+        // A declared length larger than the data is not allocated upfront.
+        let err = read(b"*1\r\n$536870912\r\nabc").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let err = read(b"*1\r\n$3\r\nabcd\r\n").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let err = read(b"*1\r\n+OK\r\n").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let err = read(b"*x\r\n").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let err = read(b"*2\r\n$1\r\na\r\n").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn downgrades_resp3_types() {
+        let map = RespValue::Map(vec![(RespValue::bulk_str("a"), RespValue::Boolean(true))]);
+        assert_eq!(encoded(map.clone(), 3), b"%1\r\n$1\r\na\r\n#t\r\n");
+        assert_eq!(encoded(map, 2), b"*2\r\n$1\r\na\r\n:1\r\n");
+        assert_eq!(encoded(RespValue::Null, 3), b"_\r\n");
+        assert_eq!(encoded(RespValue::Null, 2), b"$-1\r\n");
+
+        let push = RespValue::Push(vec![RespValue::bulk_str("message"), RespValue::Integer(1)]);
+        assert_eq!(encoded(push.clone(), 3), b">2\r\n$7\r\nmessage\r\n:1\r\n");
+        assert_eq!(encoded(push, 2), b"*2\r\n$7\r\nmessage\r\n:1\r\n");
+    }
+}
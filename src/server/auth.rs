@@ -0,0 +1,462 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error, ErrorKind},
+    sync::RwLock,
+};
+
+use ring::digest;
+
+use crate::storage::glob;
+
+/// Name of the user authenticated with a password only.
+pub const DEFAULT_USER: &str = "default";
+
+/// Commands ACL rules refer to, with the categories they belong to. Commands
+/// of every protocol map onto one of these names.
+const COMMANDS: &[(&str, &[&str])] = &[
+    ("get", &["read"]),
+    ("keys", &["read", "keyspace"]),
+    ("stats", &["read"]),
+    ("put", &["write"]),
+    ("delete", &["write", "keyspace"]),
+    ("expire", &["write", "keyspace"]),
+    ("flush", &["write", "keyspace", "dangerous"]),
+    ("index", &["write", "keyspace"]),
+    ("acl", &["admin", "dangerous"]),
+    ("publish", &["pubsub"]),
+    ("subscribe", &["pubsub"]),
+];
+
+const CATEGORIES: &[&str] = &["read", "write", "keyspace", "admin", "dangerous", "pubsub"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Rule {
+    All,
+    Category(String),
+    Command(String),
+}
+
+impl Rule {
+    fn matches(&self, command: &str) -> bool {
+        match self {
+            Rule::All => true,
+            Rule::Command(x) => x == command,
+            Rule::Category(x) => COMMANDS
+                .iter()
+                .any(|(name, categories)| *name == command && categories.contains(&x.as_str())),
+        }
+    }
+}
+
+/// Credentials and permissions of a single user. A new user is disabled and
+/// may neither run commands nor access keys until rules grant it.
+#[derive(Debug, Clone, Default)]
+struct User {
+    enabled: bool,
+    nopass: bool,
+    /// Hex encoded SHA-256 of the accepted passwords.
+    passwords: Vec<String>,
+    /// Command rules in order, the last matching one decides.
+    commands: Vec<(bool, Rule)>,
+    keys: Vec<String>,
+}
+
+fn hash(password: &str) -> String {
+    digest::digest(&digest::SHA256, password.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+/// Compares secrets without short-circuiting on the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Splits a rule into its prefix character and argument, `None` for an empty
+/// rule.
+fn split_rule(rule: &str) -> Option<(char, &str)> {
+    let mut chars = rule.chars();
+    chars.next().map(|prefix| (prefix, chars.as_str()))
+}
+
+impl User {
+    fn superuser(password: Option<&str>) -> Self {
+        let mut user = Self {
+            enabled: true,
+            commands: vec![(true, Rule::All)],
+            keys: vec!["*".to_owned()],
+            ..Default::default()
+        };
+        match password {
+            Some(password) => user.passwords.push(hash(password)),
+            None => user.nopass = true,
+        }
+
+        user
+    }
+
+    /// Applies a single rule in the `ACL SETUSER` syntax.
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        let invalid = || format!("Error in ACL SETUSER modifier '{}': Syntax error", rule);
+
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec!["*".to_owned()],
+            "resetkeys" => self.keys.clear(),
+            "allcommands" | "+@all" => self.commands = vec![(true, Rule::All)],
+            "nocommands" | "-@all" => self.commands.clear(),
+            "reset" => *self = User::default(),
+            _ => match split_rule(rule).ok_or_else(invalid)? {
+                ('>', password) => {
+                    let password = hash(password);
+                    if !self.passwords.contains(&password) {
+                        self.passwords.push(password);
+                    }
+                    self.nopass = false;
+                }
+                ('<', password) => {
+                    let password = hash(password);
+                    self.passwords.retain(|x| *x != password);
+                }
+                ('#', digest) => {
+                    if digest.len() != 64 || !digest.bytes().all(|x| x.is_ascii_hexdigit()) {
+                        return Err(invalid());
+                    }
+                    self.passwords.push(digest.to_lowercase());
+                    self.nopass = false;
+                }
+                ('~', pattern) => self.keys.push(pattern.to_owned()),
+                (sign @ ('+' | '-'), name) => {
+                    let name = name.to_lowercase();
+                    let rule = match name.strip_prefix('@') {
+                        Some(category) if CATEGORIES.contains(&category) => Rule::Category(category.to_owned()),
+                        None if COMMANDS.iter().any(|(x, _)| *x == name) => Rule::Command(name),
+                        _ => return Err(invalid()),
+                    };
+                    self.commands.push((sign == '+', rule));
+                }
+                _ => return Err(invalid()),
+            },
+        }
+
+        Ok(())
+    }
+
+    fn can_run(&self, command: &str) -> bool {
+        self.commands
+            .iter()
+            .rev()
+            .find(|(_, rule)| rule.matches(command))
+            .is_some_and(|(allowed, _)| *allowed)
+    }
+
+    fn can_access(&self, key: &str) -> bool {
+        self.keys.iter().any(|x| glob::matches(x.as_bytes(), key.as_bytes()))
+    }
+
+    /// Renders the user as an ACL file line.
+    fn describe(&self, name: &str) -> String {
+        let mut rules = vec!["user".to_owned(), name.to_owned()];
+        rules.push(if self.enabled { "on" } else { "off" }.to_owned());
+        if self.nopass {
+            rules.push("nopass".to_owned());
+        }
+        rules.extend(self.passwords.iter().map(|x| format!("#{}", x)));
+        match self.keys.is_empty() {
+            true => rules.push("resetkeys".to_owned()),
+            false => rules.extend(self.keys.iter().map(|x| format!("~{}", x))),
+        }
+        if self.commands.is_empty() {
+            rules.push("-@all".to_owned());
+        }
+        rules.extend(self.commands.iter().map(|(allowed, rule)| {
+            let sign = if *allowed { '+' } else { '-' };
+            match rule {
+                Rule::All => format!("{}@all", sign),
+                Rule::Category(x) => format!("{}@{}", sign, x),
+                Rule::Command(x) => format!("{}{}", sign, x),
+            }
+        }));
+
+        rules.join(" ")
+    }
+}
+
+/// Users accepted by the server and the commands and keys each may use.
+/// Authentication is required unless the default user has no password.
+#[derive(Debug)]
+pub struct Authenticator {
+    users: RwLock<HashMap<String, User>>,
+    /// Users configured on the command line, the ACL file is applied on top.
+    base: HashMap<String, User>,
+    acl_file: Option<String>,
+}
+
+impl Default for Authenticator {
+    fn default() -> Self {
+        let base = HashMap::from([(DEFAULT_USER.to_owned(), User::superuser(None))]);
+
+        Self {
+            users: RwLock::new(base.clone()),
+            base,
+            acl_file: None,
+        }
+    }
+}
+
+impl Authenticator {
+    /// `password` is required from the default user, `users` are additional
+    /// `(name, password)` pairs with full access. Rules from `acl_file` are
+    /// applied on top when it exists.
+    pub fn new(
+        password: Option<String>,
+        users: Vec<(String, String)>,
+        acl_file: Option<String>,
+    ) -> Result<Self, Error> {
+        let mut base: HashMap<String, User> = users
+            .into_iter()
+            .map(|(name, password)| (name, User::superuser(Some(&password))))
+            .collect();
+        base.insert(DEFAULT_USER.to_owned(), User::superuser(password.as_deref()));
+
+        let auth = Self {
+            users: RwLock::new(base.clone()),
+            base,
+            acl_file,
+        };
+        auth.load()?;
+
+        Ok(auth)
+    }
+
+    pub fn required(&self) -> bool {
+        self.initial_user().is_none()
+    }
+
+    /// User a new connection starts as, `None` until it authenticates.
+    pub fn initial_user(&self) -> Option<String> {
+        match self.users.read().unwrap().get(DEFAULT_USER) {
+            Some(user) if user.enabled && user.nopass => Some(DEFAULT_USER.to_owned()),
+            _ => None,
+        }
+    }
+
+    /// Verifies the credentials and returns the authenticated user name.
+    pub fn authenticate(&self, username: Option<&str>, password: &str) -> Option<String> {
+        let username = username.unwrap_or(DEFAULT_USER);
+        let password = hash(password);
+        match self.users.read().unwrap().get(username) {
+            Some(user)
+                if user.enabled
+                    && (user.nopass
+                        || user
+                            .passwords
+                            .iter()
+                            .any(|x| constant_time_eq(x.as_bytes(), password.as_bytes()))) =>
+            {
+                Some(username.to_owned())
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks that `user` may run `command` on all of `keys`, the error is the
+    /// `NOPERM` message sent back to the client.
+    pub fn check(&self, user: &str, command: &str, keys: &[&str]) -> Result<(), String> {
+        let users = self.users.read().unwrap();
+        let user = match users.get(user) {
+            Some(x) if x.enabled && x.can_run(command) => x,
+            _ => {
+                return Err(format!(
+                    "NOPERM User {} has no permissions to run the '{}' command",
+                    user, command
+                ))
+            }
+        };
+        if !keys.iter().all(|x| user.can_access(x)) {
+            return Err("NOPERM No permissions to access a key".to_owned());
+        }
+
+        Ok(())
+    }
+
+    /// Whether `user` may access the key, see `filter_keys`.
+    pub fn can_access(&self, user: &str, key: &str) -> bool {
+        self.users.read().unwrap().get(user).is_some_and(|user| user.can_access(key))
+    }
+
+    /// Drops the keys `user` may not access, used by commands enumerating the keyspace.
+    pub fn filter_keys(&self, user: &str, mut keys: Vec<String>) -> Vec<String> {
+        match self.users.read().unwrap().get(user) {
+            Some(user) => keys.retain(|x| user.can_access(x)),
+            None => keys.clear(),
+        }
+
+        keys
+    }
+
+    /// Replaces the users with the command line ones plus the ACL file rules.
+    pub fn load(&self) -> Result<(), Error> {
+        let mut users = self.base.clone();
+        let content = match &self.acl_file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(content) => content,
+                Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(e),
+            },
+            None => String::new(),
+        };
+
+        for (i, line) in content.lines().enumerate() {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (None, _) => continue,
+                (Some(x), _) if x.starts_with('#') => continue,
+                (Some("user"), Some(name)) => {
+                    let user = users.entry(name.to_owned()).or_default();
+                    for rule in parts {
+                        user.apply(rule)
+                            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("line {}: {}", i + 1, e)))?;
+                    }
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("line {}: expected 'user <name> <rules>'", i + 1),
+                    ))
+                }
+            }
+        }
+
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
+    /// Writes every user to the ACL file.
+    pub fn save(&self) -> Result<(), Error> {
+        let path = match &self.acl_file {
+            Some(path) => path,
+            None => return Err(Error::new(ErrorKind::NotFound, "no ACL file configured")),
+        };
+
+        let mut lines = self.list();
+        lines.push(String::new());
+        fs::write(path, lines.join("\n"))
+    }
+
+    fn list(&self) -> Vec<String> {
+        let users = self.users.read().unwrap();
+        let mut names: Vec<&String> = users.keys().collect();
+        names.sort();
+
+        names.into_iter().map(|x| users[x].describe(x)).collect()
+    }
+
+    /// Runs an `ACL` admin subcommand issued by `user`, errors carry the
+    /// message sent back to the client.
+    pub fn command(&self, user: &str, args: &[String]) -> Result<Vec<String>, String> {
+        let subcommand = args.first().map(|x| x.to_uppercase()).unwrap_or_default();
+        let args = args.get(1..).unwrap_or_default();
+
+        match (subcommand.as_str(), args) {
+            ("SETUSER", [name, rules @ ..]) => {
+                // Rules are applied to a copy outside the lock, so an invalid
+                // rule leaves the user and the lock untouched.
+                let mut updated = self.users.read().unwrap().get(name).cloned().unwrap_or_default();
+                for rule in rules {
+                    updated.apply(rule).map_err(|e| format!("ERR {}", e))?;
+                }
+                self.users.write().unwrap().insert(name.clone(), updated);
+                Ok(Vec::new())
+            }
+            ("DELUSER", names) if !names.is_empty() => {
+                if names.iter().any(|x| x == DEFAULT_USER) {
+                    return Err("ERR The 'default' user cannot be removed".to_owned());
+                }
+                let mut users = self.users.write().unwrap();
+                Ok(names.iter().filter(|x| users.remove(x.as_str()).is_some()).cloned().collect())
+            }
+            ("GETUSER", [name]) => Ok(self
+                .users
+                .read()
+                .unwrap()
+                .get(name)
+                .map(|x| x.describe(name))
+                .into_iter()
+                .collect()),
+            ("LIST", []) => Ok(self.list()),
+            ("USERS", []) => {
+                let mut names: Vec<String> = self.users.read().unwrap().keys().cloned().collect();
+                names.sort();
+                Ok(names)
+            }
+            ("WHOAMI", []) => Ok(vec![user.to_owned()]),
+            ("CAT", []) => Ok(CATEGORIES.iter().map(|x| x.to_string()).collect()),
+            ("CAT", [category]) => match CATEGORIES.contains(&category.to_lowercase().as_str()) {
+                true => Ok(COMMANDS
+                    .iter()
+                    .filter(|(_, x)| x.contains(&category.to_lowercase().as_str()))
+                    .map(|(x, _)| x.to_string())
+                    .collect()),
+                false => Err(format!("ERR Unknown category '{}'", category)),
+            },
+            ("SAVE", []) => self.save().map(|_| Vec::new()).map_err(|e| format!("ERR {}", e)),
+            ("LOAD", []) => self.load().map(|_| Vec::new()).map_err(|e| format!("ERR {}", e)),
+            _ => Err(format!("ERR unknown or malformed ACL subcommand '{}'", subcommand.to_lowercase())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setuser(auth: &Authenticator, args: &[&str]) -> Result<Vec<String>, String> {
+        let args: Vec<String> = ["SETUSER"].iter().chain(args).map(|x| x.to_string()).collect();
+        auth.command(DEFAULT_USER, &args)
+    }
+
+    #[test]
+    fn rejects_empty_and_unknown_rules() {
+        let auth = Authenticator::default();
+        setuser(&auth, &["bob", "on", ">pw"]).unwrap();
+
+        for rule in ["", "é", "?x", "+nosuch", "+@nosuch", "#abc"] {
+            assert!(setuser(&auth, &["bob", "+@read", rule]).is_err(), "{rule:?}");
+        }
+        // Failed updates leave the user as it was and the lock usable.
+        assert!(auth.check("bob", "get", &[]).is_err());
+        assert_eq!(auth.authenticate(Some("bob"), "pw"), Some("bob".to_owned()));
+    }
+
+    #[test]
+    fn last_matching_command_rule_decides() {
+        let auth = Authenticator::default();
+        setuser(&auth, &["alice", "on", "nopass", "~app:*", "+@all", "-@write", "+put"]).unwrap();
+
+        assert!(auth.check("alice", "get", &["app:1"]).is_ok());
+        assert!(auth.check("alice", "put", &["app:1"]).is_ok());
+        assert!(auth.check("alice", "delete", &["app:1"]).is_err());
+        assert!(auth.check("alice", "get", &["other"]).is_err());
+        assert_eq!(
+            auth.command(DEFAULT_USER, &["GETUSER".to_owned(), "alice".to_owned()]).unwrap(),
+            vec!["user alice on nopass ~app:* +@all -@write +put".to_owned()]
+        );
+    }
+}
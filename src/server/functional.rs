@@ -1,77 +1,531 @@
-use std::{sync::Arc, time::Duration};
+use std::{fmt::Display, sync::Arc, time::Duration};
 use std::io::ErrorKind;
 
+use futures::{stream, StreamExt};
+use serde::Serialize;
+
 use tokio::{
-    io,
-    net::TcpListener,
-    sync::mpsc::{unbounded_channel, UnboundedSender},
+    io::{self, AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::{unbounded_channel, UnboundedSender},
+    },
+    task::JoinHandle,
     time::interval,
 };
 
 use crate::{
     persistance::wal::WalWritter,
     proto::{self},
-    storage::storage::Storage,
+    server::auth::Authenticator,
+    storage::{
+        storage::{now_millis, Storage, StoreMode, StoreStatus},
+        value::{Change, ValueError},
+    },
+    tls::TlsAcceptor,
 };
 
+/// Keys visited by a `SCAN` call when the client gives no count.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Per connection state.
+#[derive(Debug, Default)]
+pub struct Session {
+    user: Option<String>,
+    /// Commands queued since `MULTI`, `None` outside of a transaction.
+    queued: Option<Vec<proto::CommandMessage>>,
+    /// Set when a command could not be queued, the next `EXEC` then fails.
+    aborted: bool,
+    /// Watched keys with the version they had, `None` for missing keys.
+    watched: Vec<(String, Option<u64>)>,
+}
+
+impl Session {
+    pub fn new(user: Option<String>) -> Self {
+        Self {
+            user,
+            ..Self::default()
+        }
+    }
+
+    fn reset(&mut self) {
+        self.queued = None;
+        self.aborted = false;
+        self.watched.clear();
+    }
+}
+
 pub async fn initiate_client(
     listener: &TcpListener,
     cc: &Arc<Storage>,
     wal: &WalWritter,
+    auth: &Arc<Authenticator>,
 ) -> Result<(), io::Error> {
-    let (mut stream, _) = listener.accept().await?;
+    let (stream, _) = listener.accept().await?;
+    stream.set_nodelay(true).expect("Failed to set no delay");
+    serve(stream, cc, wal, auth);
+
+    Ok(())
+}
+
+/// Accepts a TCP connection and serves it once the TLS handshake completed.
+pub async fn initiate_tls_client(
+    listener: &TcpListener,
+    acceptor: &TlsAcceptor,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Arc<Authenticator>,
+) -> Result<(), io::Error> {
+    let (stream, peer) = listener.accept().await?;
+    stream.set_nodelay(true).expect("Failed to set no delay");
+    let acceptor = acceptor.clone();
     let cc = cc.clone();
     let wal = wal.clone();
-    stream.set_nodelay(true).expect("Failed to set no delay");
+    let auth = auth.clone();
     tokio::spawn(async move {
-        let mut ticker = interval(Duration::from_secs(5));
+        match acceptor.accept(stream).await {
+            Ok(stream) => serve(stream, &cc, &wal, &auth),
+            Err(e) => eprintln!("TLS handshake with {} failed: {:?}", peer, e),
+        }
+    });
+
+    Ok(())
+}
+
+pub async fn initiate_unix_client(
+    listener: &UnixListener,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Arc<Authenticator>,
+) -> Result<(), io::Error> {
+    let (stream, _) = listener.accept().await?;
+    serve(stream, cc, wal, auth);
+
+    Ok(())
+}
+
+/// Spawns the connection handler for an accepted stream. Frames are read on
+/// the spawned task while replies and keep-alive pings are written from a
+/// separate one, so a partially read frame is never dropped.
+pub fn serve<S>(stream: S, cc: &Arc<Storage>, wal: &WalWritter, auth: &Arc<Authenticator>)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let cc = cc.clone();
+    let wal = wal.clone();
+    let auth = auth.clone();
+    tokio::spawn(async move {
+        let mut session = Session::new(auth.initial_user());
+        let (mut rd, mut wr) = io::split(stream);
         let (tx, mut rx) = unbounded_channel::<proto::FrameMessage>();
+
+        let writer = tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(5));
+            loop {
+                let msg = tokio::select! {
+                    _ = ticker.tick() => proto::CommandMessage::PING().into(),
+                    res = rx.recv() => match res {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                };
+                if proto::nonblocking::marshal(&msg, Box::pin(&mut wr)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Blocking reads run on their own tasks, aborted with the connection.
+        let mut readers: Vec<JoinHandle<()>> = Vec::new();
         loop {
-            let (tcprx, mut tcptx) = stream.split();
-            tokio::select! {
-              _ = ticker.tick() => {
-                let _ = tx.send(proto::CommandMessage::PING().into()).unwrap();
-              },
-              res = rx.recv() => {
-                match res {
-                    Some(mut msg) => {
-                          let _ = proto::nonblocking::marshal(&mut msg, Box::pin(&mut tcptx)).await;
-                      },
-                    None => {},
+            match proto::nonblocking::unmarshal(Box::pin(&mut rd)).await {
+                Ok(msg) if session.queued.is_none() && msg.command.blocks() => {
+                    // Waiting for other connections must not hold up the frames
+                    // after it. Each attempt takes the storage for itself.
+                    let (cc, tx, wal, auth) = (cc.clone(), tx.clone(), wal.clone(), auth.clone());
+                    let mut user = session.user.clone();
+                    readers.retain(|x| !x.is_finished());
+                    readers.push(tokio::spawn(async move {
+                        let _ = handle_message(&msg, &cc, tx, &wal, &auth, &mut user).await;
+                    }));
                 }
-            },
-              msg = proto::nonblocking::unmarshal(Box::pin(tcprx))  => {
-                  match msg {
-                    Ok(msg) => {
-                        handle_message(&msg, &cc, tx.clone(), &wal).await.unwrap();
-                    },
-                    Err(e) if e.kind() == ErrorKind::ConnectionAborted => {
-                        break;
-                    },
-                    Err(e) if e.kind() == ErrorKind::ConnectionReset => {
-                        break;
-                    },
-                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
-                        break;
-                    },
-                    Err(e) => {
-                        eprintln!("{:?}", e)
+                Ok(msg) => {
+                    // Batches and collection commands run with exclusive access
+                    // to the storage, so their `APPLIED` WAL records are logged
+                    // in the order they were applied. Plain values are logged
+                    // with their versions and need no ordering.
+                    let (msg, tx, wal, auth, session) = (&msg, &tx, &wal, &auth, &mut session);
+                    match msg.command {
+                        proto::CommandMessage::BATCH(_) => cc
+                            .atomically(move |cc| async move { handle_batch(msg, &cc, tx.clone(), wal, auth, session).await })
+                            .await
+                            .unwrap(),
+                        _ if msg.command.applied_key().is_some() => cc
+                            .atomically(move |cc| async move { handle_frame(msg, &cc, tx.clone(), wal, auth, session).await })
+                            .await
+                            .unwrap(),
+                        _ => handle_frame(msg, &cc, tx.clone(), wal, auth, session).await.unwrap(),
                     }
                 }
-              }
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted => {
+                    break;
+                }
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => {
+                    break;
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    break;
+                }
             }
         }
+
+        readers.iter().for_each(JoinHandle::abort);
+        writer.abort();
     });
+}
+
+/// Executes the commands of a `BATCH` frame in order and answers with a
+/// single `BATCH` of their replies. The WAL entries of the whole batch are
+/// handed to the log as one frame once every command ran. Blocking reads
+/// return right away, like in transactions.
+pub async fn handle_batch(
+    msg: &proto::FrameMessage,
+    cc: &Arc<Storage>,
+    rw: UnboundedSender<proto::FrameMessage>,
+    wal: &WalWritter,
+    auth: &Authenticator,
+    session: &mut Session,
+) -> Result<(), io::Error> {
+    let commands = match &msg.command {
+        proto::CommandMessage::BATCH(commands) => commands,
+        _ => return Ok(()),
+    };
+
+    let (batch_wal, mut entries) = WalWritter::buffered();
+    let (tx, mut rx) = unbounded_channel::<proto::FrameMessage>();
+    let mut replies = Vec::with_capacity(commands.len());
+    for command in commands {
+        let frame = msg.with_command(command.clone().without_block());
+        match command {
+            proto::CommandMessage::BATCH(_) => {
+                let _ = tx.send(frame.error("ERR nested batches are not supported"));
+            }
+            proto::CommandMessage::SUBSCRIBE(_) => {
+                let _ = tx.send(frame.error("ERR SUBSCRIBE is not allowed in batches"));
+            }
+            _ => handle_frame(&frame, cc, tx.clone(), &batch_wal, auth, session).await?,
+        }
+
+        // Commands answered without a reply, like PING, still take their slot.
+        replies.push(match rx.try_recv() {
+            Ok(reply) => reply.command,
+            Err(_) => proto::CommandMessage::RECV(None),
+        });
+    }
+    wal.write_batch(&mut entries);
+
+    let _ = rw.send(msg.batch(replies));
+    Ok(())
+}
+
+/// Serves a single frame, queueing it instead while a transaction is open.
+pub async fn handle_frame(
+    msg: &proto::FrameMessage,
+    cc: &Arc<Storage>,
+    rw: UnboundedSender<proto::FrameMessage>,
+    wal: &WalWritter,
+    auth: &Authenticator,
+    session: &mut Session,
+) -> Result<(), io::Error> {
+    let transactional = matches!(
+        msg.command,
+        proto::CommandMessage::MULTI()
+            | proto::CommandMessage::EXEC()
+            | proto::CommandMessage::DISCARD()
+            | proto::CommandMessage::WATCH(_)
+            | proto::CommandMessage::UNWATCH()
+    );
+    if !transactional && session.queued.is_none() {
+        return handle_message(msg, cc, rw, wal, auth, &mut session.user).await;
+    }
+    let authorized = match session.user {
+        Some(_) => authorize(&msg.command, auth, &session.user).map(|_| ()),
+        None => Err("NOAUTH authentication required".to_owned()),
+    };
+    if let Err(e) = authorized {
+        session.aborted |= session.queued.is_some();
+        let _ = rw.send(msg.error(&e));
+        return Ok(());
+    }
+
+    let reply = match (&msg.command, session.queued.as_mut()) {
+        (proto::CommandMessage::MULTI(), None) => {
+            session.queued = Some(Vec::new());
+            msg.reply_borrow(None)
+        }
+        (proto::CommandMessage::MULTI(), Some(_)) => msg.error("ERR MULTI calls can not be nested"),
+        (proto::CommandMessage::WATCH(_), Some(_)) => msg.error("ERR WATCH inside MULTI is not allowed"),
+        (proto::CommandMessage::WATCH(keys), None) => {
+            for key in keys {
+                let version = cc.version(key).await;
+                session.watched.push((key.clone(), version));
+            }
+            msg.reply_borrow(None)
+        }
+        (proto::CommandMessage::UNWATCH(), _) => {
+            session.watched.clear();
+            msg.reply_borrow(None)
+        }
+        (proto::CommandMessage::DISCARD(), None) => msg.error("ERR DISCARD without MULTI"),
+        (proto::CommandMessage::DISCARD(), Some(_)) => {
+            session.reset();
+            msg.reply_borrow(None)
+        }
+        (proto::CommandMessage::EXEC(), None) => msg.error("ERR EXEC without MULTI"),
+        (proto::CommandMessage::EXEC(), Some(_)) if session.aborted => {
+            session.reset();
+            msg.error("EXECABORT Transaction discarded because of previous errors")
+        }
+        (proto::CommandMessage::EXEC(), Some(queued)) => {
+            let commands = std::mem::take(queued);
+            let watched = std::mem::take(&mut session.watched);
+            session.reset();
+            exec(msg, commands, watched, cc, wal, auth, &mut session.user).await?
+        }
+        (proto::CommandMessage::AUTH(..), Some(_)) => {
+            session.aborted = true;
+            msg.error("ERR command not allowed inside MULTI")
+        }
+        (command, Some(queued)) => {
+            queued.push(command.clone());
+            msg.reply_borrow(None)
+        }
+        (_, None) => unreachable!(),
+    };
 
+    let _ = rw.send(reply);
     Ok(())
 }
 
+/// Runs the commands of a transaction with exclusive access to the storage
+/// and logs their WAL entries as one frame, unless a watched key changed.
+async fn exec(
+    msg: &proto::FrameMessage,
+    commands: Vec<proto::CommandMessage>,
+    watched: Vec<(String, Option<u64>)>,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Authenticator,
+    user: &mut Option<String>,
+) -> Result<proto::FrameMessage, io::Error> {
+    cc.atomically(|cc| async move {
+        for (key, version) in watched.iter() {
+            if cc.version(key).await != *version {
+                return Ok(msg.reply_borrow(None));
+            }
+        }
+
+        let (exec_wal, mut entries) = WalWritter::buffered();
+        let (tx, mut rx) = unbounded_channel::<proto::FrameMessage>();
+        let mut replies = Vec::with_capacity(commands.len());
+        for command in commands {
+            if let proto::CommandMessage::SUBSCRIBE(_) = command {
+                replies.push(proto::CommandMessage::ERROR("ERR SUBSCRIBE is not allowed in transactions".to_owned()));
+                continue;
+            }
+            handle_message(&msg.with_command(command.without_block()), &cc, tx.clone(), &exec_wal, auth, user).await?;
+            replies.push(match rx.try_recv() {
+                Ok(reply) => reply.command,
+                Err(_) => proto::CommandMessage::RECV(None),
+            });
+        }
+        wal.write_batch(&mut entries);
+
+        Ok(msg.batch(replies))
+    })
+    .await
+}
+
+/// Reply carrying the msgpack encoded result, or the error.
+fn encoded<T: Serialize, E: Display>(msg: &proto::FrameMessage, res: Result<T, E>) -> proto::FrameMessage {
+    match res {
+        Ok(x) => msg.reply_borrow(Some(rmp_serde::encode::to_vec(&x).unwrap())),
+        Err(e) => msg.error(&e.to_string()),
+    }
+}
+
+/// Logs a collection command that changed its key and replies with its result.
+fn applied<T: Serialize, E: Display>(
+    msg: &proto::FrameMessage,
+    res: Result<(T, Change), E>,
+    wal: &WalWritter,
+) -> proto::FrameMessage {
+    let res = res.map(|(x, change)| {
+        wal.write_applied(&msg.command, change);
+        x
+    });
+
+    encoded(msg, res)
+}
+
+/// Checks that the connection may run the command and returns the user it
+/// runs as.
+fn authorize<'a>(
+    command: &proto::CommandMessage,
+    auth: &Authenticator,
+    user: &'a Option<String>,
+) -> Result<&'a str, String> {
+    let (command, keys) = match acl_command(command) {
+        Some(x) => x,
+        None => return Ok(user.as_deref().unwrap_or_default()),
+    };
+    let name = user.as_deref().ok_or_else(|| "NOAUTH authentication required".to_owned())?;
+    auth.check(name, command, &keys)?;
+
+    Ok(name)
+}
+
+/// ACL command name and keys a command is checked against, `None` for the
+/// handshake commands every connection may send.
+fn acl_command(command: &proto::CommandMessage) -> Option<(&'static str, Vec<&str>)> {
+    match command {
+        proto::CommandMessage::GET(key) => Some(("get", vec![key])),
+        proto::CommandMessage::PUT(key, _, _)
+        | proto::CommandMessage::PUTFLAGS(key, _, _, _)
+        | proto::CommandMessage::PUTIF(key, _, _, _, _)
+        | proto::CommandMessage::CAS(key, _, _, _)
+        | proto::CommandMessage::INCR(key, _, _)
+        | proto::CommandMessage::INCRFLOAT(key, _, _) => Some(("put", vec![key])),
+        proto::CommandMessage::GETS(key) => Some(("get", vec![key])),
+        proto::CommandMessage::DELETE(key) => Some(("delete", vec![key])),
+        proto::CommandMessage::EXPIRE(key, _) => Some(("expire", vec![key])),
+        proto::CommandMessage::KEYS() | proto::CommandMessage::SCAN(..) => Some(("keys", vec![])),
+        proto::CommandMessage::MGET(keys) => Some(("get", keys.iter().map(|x| x.as_str()).collect())),
+        proto::CommandMessage::RANGE(..) | proto::CommandMessage::PREFIX(..) => Some(("get", vec![])),
+        proto::CommandMessage::DELPREFIX(_) => Some(("delete", vec![])),
+        proto::CommandMessage::MSET(entries) => Some(("put", entries.iter().map(|x| x.0.as_str()).collect())),
+        proto::CommandMessage::MDEL(keys) => Some(("delete", keys.iter().map(|x| x.as_str()).collect())),
+        proto::CommandMessage::ACL(_) => Some(("acl", vec![])),
+        proto::CommandMessage::WATCH(keys) => Some(("get", keys.iter().map(|x| x.as_str()).collect())),
+        proto::CommandMessage::LRANGE(key, _, _) | proto::CommandMessage::LLEN(key) => Some(("get", vec![key])),
+        proto::CommandMessage::LPUSH(key, _)
+        | proto::CommandMessage::RPUSH(key, _)
+        | proto::CommandMessage::LPOP(key, _)
+        | proto::CommandMessage::RPOP(key, _)
+        | proto::CommandMessage::LTRIM(key, _, _) => Some(("put", vec![key])),
+        proto::CommandMessage::HGET(key, _) | proto::CommandMessage::HGETALL(key) => Some(("get", vec![key])),
+        proto::CommandMessage::HSET(key, _)
+        | proto::CommandMessage::HDEL(key, _)
+        | proto::CommandMessage::HINCRBY(key, _, _)
+        | proto::CommandMessage::HINCRBYFLOAT(key, _, _) => Some(("put", vec![key])),
+        proto::CommandMessage::SISMEMBER(key, _) | proto::CommandMessage::SMEMBERS(key) => Some(("get", vec![key])),
+        proto::CommandMessage::SINTER(keys) | proto::CommandMessage::SUNION(keys) => {
+            Some(("get", keys.iter().map(|x| x.as_str()).collect()))
+        }
+        proto::CommandMessage::SADD(key, _) | proto::CommandMessage::SREM(key, _) => Some(("put", vec![key])),
+        proto::CommandMessage::ZRANK(key, _, _)
+        | proto::CommandMessage::ZRANGE(key, _, _, _)
+        | proto::CommandMessage::ZRANGEBYSCORE(key, _, _, _, _)
+        | proto::CommandMessage::ZSCORE(key, _)
+        | proto::CommandMessage::ZCARD(key) => Some(("get", vec![key])),
+        proto::CommandMessage::ZADD(key, _)
+        | proto::CommandMessage::ZINCRBY(key, _, _)
+        | proto::CommandMessage::ZREM(key, _)
+        | proto::CommandMessage::ZREMRANGEBYRANK(key, _, _)
+        | proto::CommandMessage::ZREMRANGEBYSCORE(key, _, _) => Some(("put", vec![key])),
+        proto::CommandMessage::XLEN(key)
+        | proto::CommandMessage::XRANGE(key, _, _, _, _)
+        | proto::CommandMessage::XPENDING(key, _, _, _, _, _) => Some(("get", vec![key])),
+        proto::CommandMessage::XREAD(streams, _, _) => Some(("get", streams.iter().map(|x| x.0.as_str()).collect())),
+        proto::CommandMessage::XADD(key, _, _, _)
+        | proto::CommandMessage::XTRIM(key, _)
+        | proto::CommandMessage::XGROUPCREATE(key, _, _, _)
+        | proto::CommandMessage::XGROUPDESTROY(key, _)
+        | proto::CommandMessage::XREADGROUP(key, _, _, _, _, _)
+        | proto::CommandMessage::XACK(key, _, _) => Some(("put", vec![key])),
+        proto::CommandMessage::PFCOUNT(keys) => Some(("get", keys.iter().map(|x| x.as_str()).collect())),
+        proto::CommandMessage::PFMERGE(key, sources) => {
+            Some(("put", std::iter::once(key).chain(sources.iter()).map(|x| x.as_str()).collect()))
+        }
+        proto::CommandMessage::BFEXISTS(key, _)
+        | proto::CommandMessage::CMSQUERY(key, _)
+        | proto::CommandMessage::TOPKQUERY(key, _)
+        | proto::CommandMessage::TOPKLIST(key) => Some(("get", vec![key])),
+        proto::CommandMessage::PFADD(key, _)
+        | proto::CommandMessage::BFRESERVE(key, _, _)
+        | proto::CommandMessage::BFADD(key, _)
+        | proto::CommandMessage::CMSINIT(key, _, _)
+        | proto::CommandMessage::CMSINCRBY(key, _)
+        | proto::CommandMessage::TOPKRESERVE(key, _, _, _)
+        | proto::CommandMessage::TOPKADD(key, _) => Some(("put", vec![key])),
+        proto::CommandMessage::JSONGET(key, _) => Some(("get", vec![key])),
+        proto::CommandMessage::INDEXCREATE(..) | proto::CommandMessage::INDEXDROP(_) => Some(("index", vec![])),
+        proto::CommandMessage::INDEXLIST() => Some(("keys", vec![])),
+        proto::CommandMessage::INDEXQUERY(..) => Some(("get", vec![])),
+        proto::CommandMessage::JSONSET(key, _, _, _)
+        | proto::CommandMessage::JSONDEL(key, _)
+        | proto::CommandMessage::JSONARRAPPEND(key, _, _)
+        | proto::CommandMessage::JSONNUMINCRBY(key, _, _) => Some(("put", vec![key])),
+        proto::CommandMessage::TSGET(key)
+        | proto::CommandMessage::TSRANGE(key, _, _, _, _)
+        | proto::CommandMessage::TSINFO(key) => Some(("get", vec![key])),
+        proto::CommandMessage::TSCREATE(key, _) | proto::CommandMessage::TSDELETERULE(key, _) => Some(("put", vec![key])),
+        proto::CommandMessage::TSADD(key, _, _, _) => Some(("put", vec![key])),
+        proto::CommandMessage::TSCREATERULE(key, dest, _, _) => Some(("put", vec![key, dest])),
+        proto::CommandMessage::QINFO(key) => Some(("get", vec![key])),
+        proto::CommandMessage::QCREATE(key, options) => {
+            Some(("put", std::iter::once(key).chain(options.dead_letter.iter()).map(|x| x.as_str()).collect()))
+        }
+        proto::CommandMessage::QPUSH(key, _, _)
+        | proto::CommandMessage::QPOP(key, _)
+        | proto::CommandMessage::QACK(key, _)
+        | proto::CommandMessage::QNACK(key, _, _) => Some(("put", vec![key])),
+        proto::CommandMessage::SCHEDLIST(key) => Some(("get", vec![key])),
+        proto::CommandMessage::SCHEDADD(key, target, _, _) => {
+            Some(("put", std::iter::once(key.as_str()).chain(target.key()).collect()))
+        }
+        proto::CommandMessage::SCHEDCANCEL(key, _) => Some(("put", vec![key])),
+        proto::CommandMessage::PUBLISH(..) => Some(("publish", vec![])),
+        proto::CommandMessage::SUBSCRIBE(_) => Some(("subscribe", vec![])),
+        _ => None,
+    }
+}
+
+/// Serves a single frame on behalf of `user`. Until the connection
+/// authenticates only handshake commands are accepted, afterwards every
+/// command is checked against the user's ACL.
 pub async fn handle_message(
     msg: &proto::FrameMessage,
     cc: &Arc<Storage>,
     rw: UnboundedSender<proto::FrameMessage>,
     wal: &WalWritter,
+    auth: &Authenticator,
+    user: &mut Option<String>,
 ) -> Result<(), io::Error> {
+    if let proto::CommandMessage::AUTH(username, password) = &msg.command {
+        let _ = match auth.authenticate(username.as_deref(), password) {
+            Some(name) => {
+                *user = Some(name);
+                rw.send(msg.reply_borrow(None))
+            }
+            None => rw.send(msg.error("WRONGPASS invalid username-password pair or user is disabled")),
+        };
+        return Ok(());
+    }
+
+    let name = match authorize(&msg.command, auth, user) {
+        Ok(name) => name,
+        Err(e) => {
+            let _ = rw.send(msg.error(&e));
+            return Ok(());
+        }
+    };
+
     match msg.clone().command {
         proto::CommandMessage::CONNECTED() => {
             let _ = rw.send(msg.reply_borrow(None));
@@ -84,34 +538,818 @@ pub async fn handle_message(
             let _ = rw.send(msg.reply_borrow(Some(data)));
         }
         proto::CommandMessage::PUT(key, data, exp) => {
-            match exp {
-                Some(x) => {
-                    cc.write_ex(&key, data, x).await;
-                }
-                None => {
-                    cc.write(&key, data).await;
-                }
+            if let StoreStatus::Stored(item) = cc.store(&key, data, exp, 0, StoreMode::Set).await {
+                wal.write_entries([(key.as_str(), &item)]);
             }
 
-            wal.write(&msg);
+            let _ = rw.send(msg.reply_borrow(None));
+        }
+        proto::CommandMessage::PUTIF(key, data, exp, cond, get) => {
+            let (written, previous) = cc.put_if(&key, data, exp, cond).await;
+            if let Some(item) = written.as_ref() {
+                wal.write_entries([(key.as_str(), item)]);
+            }
+
+            let previous = if get { previous } else { None };
+            let buf = rmp_serde::encode::to_vec(&(written.is_some(), previous)).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::PUTFLAGS(key, data, exp, flags) => {
+            if let StoreStatus::Stored(item) = cc.store(&key, data, exp, flags, StoreMode::Set).await {
+                wal.write_entries([(key.as_str(), &item)]);
+            }
 
             let _ = rw.send(msg.reply_borrow(None));
         }
+        proto::CommandMessage::GETS(key) => {
+            let item = cc.read_item(&key).await.map(|x| (x.value, x.version));
+            let buf = rmp_serde::encode::to_vec(&item).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::CAS(key, data, expected, exp) => {
+            let reply = match cc.compare_and_swap(&key, data, expected, exp).await {
+                Ok(item) => {
+                    wal.write_entries([(key.as_str(), &item)]);
+                    (true, Some(item.version))
+                }
+                Err(current) => (false, current),
+            };
+            let buf = rmp_serde::encode::to_vec(&reply).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::INCR(key, delta, ttl) => {
+            let reply = match cc.incr_by(&key, delta, ttl).await {
+                StoreStatus::Stored(item) => {
+                    wal.write_entries([(key.as_str(), &item)]);
+                    let value: i64 = String::from_utf8_lossy(&item.value).parse().unwrap_or_default();
+                    msg.reply_borrow(Some(rmp_serde::encode::to_vec(&value).unwrap()))
+                }
+                _ => msg.error(&ValueError::NotInteger.to_string()),
+            };
+            let _ = rw.send(reply);
+        }
+        proto::CommandMessage::INCRFLOAT(key, delta, ttl) => {
+            let reply = match cc.incr_by_float(&key, delta, ttl).await {
+                StoreStatus::Stored(item) => {
+                    wal.write_entries([(key.as_str(), &item)]);
+                    let value: f64 = String::from_utf8_lossy(&item.value).parse().unwrap_or_default();
+                    msg.reply_borrow(Some(rmp_serde::encode::to_vec(&value).unwrap()))
+                }
+                _ => msg.error(&ValueError::NotFloat.to_string()),
+            };
+            let _ = rw.send(reply);
+        }
         proto::CommandMessage::KEYS() => {
-            let keys: Vec<String> = cc.keys().await.map(|x| x.clone()).unwrap();
+            let keys = auth.filter_keys(name, cc.keys().await.unwrap());
 
             let buf = rmp_serde::encode::to_vec(&keys).unwrap();
             let _ = rw.send(msg.reply_borrow(Some(buf)));
         }
         proto::CommandMessage::DELETE(key) => {
             cc.delete(&key).await;
-            //     wal.write(&msg.clone());
+            wal.write(msg);
+
+            let _ = rw.send(msg.reply_borrow(None));
+        }
+        proto::CommandMessage::EXPIRE(key, exp) => {
+            let updated = cc.set_expiration(&key, exp).await;
+            if updated {
+                wal.write(msg);
+            }
+
+            let buf = rmp_serde::encode::to_vec(&updated).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::SCAN(cursor, pattern, count, ttl) => {
+            let count = count.unwrap_or(DEFAULT_SCAN_COUNT);
+            let (cursor, keys) = cc.scan(cursor, count, pattern.as_deref(), ttl).await;
+            let keys = auth.filter_keys(name, keys);
+
+            let buf = rmp_serde::encode::to_vec(&(cursor, keys)).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::RANGE(start, end, limit) => {
+            let allowed = |key: &str| auth.check(name, "get", &[key]).is_ok();
+            let pairs = cc.range(&start, end.as_deref(), limit, allowed).await;
+
+            let buf = rmp_serde::encode::to_vec(&pairs).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::PREFIX(prefix, after, limit) => {
+            let allowed = |key: &str| auth.check(name, "get", &[key]).is_ok();
+            let pairs = cc.prefix(&prefix, after.as_deref(), limit, allowed).await;
 
+            let buf = rmp_serde::encode::to_vec(&pairs).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::DELPREFIX(prefix) => {
+            let allowed = |key: &str| auth.check(name, "delete", &[key]).is_ok();
+            let deleted = cc.delete_prefix(&prefix, allowed).await;
+            // The deleted keys are logged instead of the prefix, so keys the
+            // user could not access stay untouched on replay.
+            if !deleted.is_empty() {
+                wal.write(&proto::CommandMessage::MDEL(deleted.clone()).into());
+            }
+
+            let buf = rmp_serde::encode::to_vec(&deleted.len()).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::MGET(keys) => {
+            let values = cc.read_many(&keys).await;
+
+            let buf = rmp_serde::encode::to_vec(&values).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::MSET(entries) => {
+            let keys: Vec<String> = entries.iter().map(|x| x.0.clone()).collect();
+            let items = cc.write_many(entries).await;
+            wal.write_entries(keys.iter().map(|x| x.as_str()).zip(items.iter()));
+
+            let _ = rw.send(msg.reply_borrow(None));
+        }
+        proto::CommandMessage::MDEL(keys) => {
+            let deleted = cc.delete_many(&keys).await;
+            let removed: Vec<String> = keys
+                .into_iter()
+                .zip(deleted.iter())
+                .filter(|(_, x)| **x)
+                .map(|(key, _)| key)
+                .collect();
+            if !removed.is_empty() {
+                wal.write(&proto::CommandMessage::MDEL(removed).into());
+            }
+
+            let buf = rmp_serde::encode::to_vec(&deleted).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::ACL(args) => {
+            let _ = match auth.command(name, &args) {
+                Ok(res) => rw.send(msg.reply_borrow(Some(rmp_serde::encode::to_vec(&res).unwrap()))),
+                Err(e) => rw.send(msg.error(&e)),
+            };
+        }
+        proto::CommandMessage::LPUSH(key, values) | proto::CommandMessage::RPUSH(key, values) => {
+            let front = matches!(msg.command, proto::CommandMessage::LPUSH(..));
+            let res = cc.list_push(&key, values, front).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::LPOP(key, count) | proto::CommandMessage::RPOP(key, count) => {
+            let front = matches!(msg.command, proto::CommandMessage::LPOP(..));
+            let res = cc.list_pop(&key, count, front).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::LTRIM(key, start, stop) => {
+            let res = cc.list_trim(&key, start, stop).await.map(|x| ((), x));
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::LRANGE(key, start, stop) => {
+            let res = cc.list_range(&key, start, stop).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::LLEN(key) => {
+            let res = cc.list_len(&key).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::HSET(key, fields) => {
+            let res = cc.hash_set(&key, fields).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::HDEL(key, fields) => {
+            let res = cc.hash_delete(&key, &fields).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::HINCRBY(key, field, delta) => {
+            let res = cc.hash_incr_by(&key, &field, delta).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::HINCRBYFLOAT(key, field, delta) => {
+            let res = cc.hash_incr_by_float(&key, &field, delta).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::HGET(key, field) => {
+            let res = cc.hash_get(&key, &field).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::HGETALL(key) => {
+            let res = cc.hash_get_all(&key).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::SADD(key, members) => {
+            let res = cc.set_add(&key, members).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::SREM(key, members) => {
+            let res = cc.set_remove(&key, &members).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::SISMEMBER(key, member) => {
+            let res = cc.set_contains(&key, &member).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::SMEMBERS(key) => {
+            let res = cc.set_members(&key).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::SINTER(keys) => {
+            let res = cc.set_intersection(&keys).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::SUNION(keys) => {
+            let res = cc.set_union(&keys).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::ZADD(key, members) => {
+            let res = cc.sorted_set_add(&key, members).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::ZINCRBY(key, delta, member) => {
+            let res = cc.sorted_set_incr_by(&key, member, delta).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::ZREM(key, members) => {
+            let res = cc.sorted_set_remove(&key, &members).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::ZREMRANGEBYRANK(key, start, stop) => {
+            let res = cc.sorted_set_remove_range(&key, start, stop).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::ZREMRANGEBYSCORE(key, min, max) => {
+            let res = cc.sorted_set_remove_by_score(&key, min, max).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::ZRANK(key, member, reverse) => {
+            let res = cc.sorted_set_rank(&key, &member, reverse).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::ZRANGE(key, start, stop, reverse) => {
+            let res = cc.sorted_set_range(&key, start, stop, reverse).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::ZRANGEBYSCORE(key, min, max, offset, count) => {
+            let res = cc.sorted_set_range_by_score(&key, min, max, offset, count).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::ZSCORE(key, member) => {
+            let res = cc.sorted_set_score(&key, &member).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::ZCARD(key) => {
+            let res = cc.sorted_set_len(&key).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::XADD(key, id, fields, max_len) => {
+            // Generated IDs are logged, so the replay adds the same entry.
+            let res = cc.stream_add(&key, id, fields.clone(), max_len).await.map(|(id, change)| {
+                wal.write_applied(&proto::CommandMessage::XADD(key, Some(id), fields, max_len), change);
+                id
+            });
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::XTRIM(key, max_len) => {
+            let res = cc.stream_trim(&key, max_len).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::XGROUPCREATE(key, group, id, create) => {
+            let res = cc.stream_group_create(&key, &group, id, create).await.map(|(id, change)| {
+                wal.write_applied(&proto::CommandMessage::XGROUPCREATE(key, group, Some(id), create), change);
+            });
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::XGROUPDESTROY(key, group) => {
+            let res = cc.stream_group_destroy(&key, &group).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::XREADGROUP(key, group, consumer, id, count, block) => {
+            let logged = proto::CommandMessage::XREADGROUP(key.clone(), group.clone(), consumer.clone(), id, count, None);
+            let res = cc
+                .stream_read_group(&key, &group, &consumer, id, count, block, None, |at, change| {
+                    wal.write_applied(&logged.at(at), change)
+                })
+                .await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::XACK(key, group, ids) => {
+            let res = cc.stream_ack(&key, &group, &ids).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::XLEN(key) => {
+            let res = cc.stream_len(&key).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::XRANGE(key, start, end, count, reverse) => {
+            let res = cc.stream_range(&key, start, end, count, reverse).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::XREAD(streams, count, block) => {
+            let res = cc.stream_read(streams, count, block).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::XPENDING(key, group, start, end, count, consumer) => {
+            let res = cc.stream_pending(&key, &group, start, end, count, consumer.as_deref()).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::PFADD(key, elements) => {
+            let res = cc.hyperloglog_add(&key, elements).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::PFCOUNT(keys) => {
+            let res = cc.hyperloglog_count(&keys).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::PFMERGE(key, sources) => {
+            let res = cc.hyperloglog_merge(&key, &sources).await.map(|change| ((), change));
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::BFRESERVE(key, error_rate, capacity) => {
+            let res = cc.bloom_reserve(&key, error_rate, capacity).await.map(|change| ((), change));
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::BFADD(key, items) => {
+            let res = cc.bloom_add(&key, items).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::BFEXISTS(key, items) => {
+            let res = cc.bloom_exists(&key, &items).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::CMSINIT(key, width, depth) => {
+            let res = cc.count_min_init(&key, width, depth).await.map(|change| ((), change));
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::CMSINCRBY(key, items) => {
+            let res = cc.count_min_incr_by(&key, items).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::CMSQUERY(key, items) => {
+            let res = cc.count_min_query(&key, &items).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::TOPKRESERVE(key, k, width, depth) => {
+            let res = cc.top_k_reserve(&key, k, width, depth).await.map(|change| ((), change));
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::TOPKADD(key, items) => {
+            let res = cc.top_k_add(&key, items).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::TOPKQUERY(key, items) => {
+            let res = cc.top_k_query(&key, &items).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::TOPKLIST(key) => {
+            let res = cc.top_k_list(&key).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::JSONSET(key, path, json, cond) => {
+            let res = cc.json_set(&key, &path, &json, cond).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::JSONGET(key, paths) => {
+            let res = cc.json_get(&key, &paths).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::JSONDEL(key, path) => {
+            let res = cc.json_delete(&key, &path).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::JSONARRAPPEND(key, path, values) => {
+            let res = cc.json_array_append(&key, &path, &values).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::JSONNUMINCRBY(key, path, delta) => {
+            let res = cc.json_number_incr_by(&key, &path, delta).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::INDEXCREATE(index, prefix, path) => {
+            let res = cc.index_create(&index, &prefix, &path).await;
+            if res.is_ok() {
+                wal.write(msg);
+            }
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::INDEXDROP(index) => {
+            let dropped = cc.index_drop(&index).await;
+            if dropped {
+                wal.write(msg);
+            }
+
+            let buf = rmp_serde::encode::to_vec(&dropped).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::INDEXLIST() => {
+            let buf = rmp_serde::encode::to_vec(&cc.index_list().await).unwrap();
+            let _ = rw.send(msg.reply_borrow(Some(buf)));
+        }
+        proto::CommandMessage::INDEXQUERY(index, min, max, limit) => {
+            let allowed = |key: &str| auth.check(name, "get", &[key]).is_ok();
+            let res = cc.index_query(&index, min, max, limit, allowed).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::TSCREATE(key, retention) => {
+            let res = cc.time_series_create(&key, retention).await.map(|change| ((), change));
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::TSADD(key, timestamp, value, retention) => {
+            // Timestamps taken from the clock are logged, so the replay adds the same sample.
+            let res = cc.time_series_add(&key, timestamp, value, retention).await.map(|(timestamp, change)| {
+                wal.write_applied(&proto::CommandMessage::TSADD(key, Some(timestamp), value, retention), change);
+                timestamp
+            });
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::TSGET(key) => {
+            let res = cc.time_series_get(&key).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::TSRANGE(key, from, to, count, aggregation) => {
+            let res = cc.time_series_range(&key, from, to, count, aggregation).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::TSCREATERULE(key, dest, aggregation, bucket) => {
+            let res = cc.time_series_create_rule(&key, &dest, aggregation, bucket).await.map(|change| ((), change));
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::TSDELETERULE(key, dest) => {
+            let res = cc.time_series_delete_rule(&key, &dest).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::TSINFO(key) => {
+            let res = cc.time_series_info(&key).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::QCREATE(key, options) => {
+            let res = cc.queue_create(&key, options).await.map(|change| ((), change));
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::QPUSH(key, payloads, delay) => {
+            // The time of the call is logged, so the replay schedules the messages alike.
+            let now = now_millis();
+            let res = cc.queue_push(&key, payloads, delay, now).await.map(|(ids, change)| {
+                wal.write_applied(&msg.command.at(now), change);
+                ids
+            });
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::QPOP(key, count) => {
+            let now = now_millis();
+            let res = cc.queue_pop(&key, count, now).await.map(|(delivered, change)| {
+                wal.write_applied(&msg.command.at(now), change);
+                delivered
+            });
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::QACK(key, receipts) => {
+            let res = cc.queue_ack(&key, &receipts).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::QNACK(key, receipts, delay) => {
+            let now = now_millis();
+            let res = cc.queue_nack(&key, &receipts, delay, now).await.map(|(rejected, change)| {
+                wal.write_applied(&msg.command.at(now), change);
+                rejected
+            });
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::QINFO(key) => {
+            let res = cc.queue_info(&key).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::SCHEDADD(key, target, payload, delay) => {
+            // The time of the call is logged, so the replay schedules the job alike.
+            let now = now_millis();
+            let res = cc.schedule_add(&key, target, payload, delay, now).await.map(|(id, change)| {
+                wal.write_applied(&msg.command.at(now), change);
+                id
+            });
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::SCHEDCANCEL(key, id) => {
+            let res = cc.schedule_cancel(&key, id).await;
+            let _ = rw.send(applied(msg, res, wal));
+        }
+        proto::CommandMessage::SCHEDLIST(key) => {
+            let res = cc.schedule_list(&key).await;
+            let _ = rw.send(encoded(msg, res));
+        }
+        proto::CommandMessage::PUBLISH(channel, payload) => {
+            let _ = rw.send(encoded(msg, Ok::<_, String>(cc.publish(&channel, payload))));
+        }
+        proto::CommandMessage::SUBSCRIBE(channels) => {
+            // Runs on its own task like blocking reads, until the connection closes.
+            let receivers: Vec<_> = channels.into_iter().map(|x| (cc.subscribe(&x), x)).collect();
             let _ = rw.send(msg.reply_borrow(None));
+            let mut messages = stream::select_all(receivers.into_iter().map(|(rx, channel)| {
+                Box::pin(stream::unfold(rx, move |mut rx| {
+                    let channel = channel.clone();
+                    async move {
+                        loop {
+                            match rx.recv().await {
+                                Ok(payload) => return Some(((channel, payload), rx)),
+                                // Slow subscribers miss the oldest messages.
+                                Err(RecvError::Lagged(_)) => {}
+                                Err(RecvError::Closed) => return None,
+                            }
+                        }
+                    }
+                }))
+            }));
+            while let Some((channel, payload)) = messages.next().await {
+                if rw.send(msg.with_command(proto::CommandMessage::MESSAGE(channel, payload))).is_err() {
+                    break;
+                }
+            }
+        }
+        proto::CommandMessage::RECV(_) | proto::CommandMessage::MESSAGE(..) => {
+            let _ = rw.send(msg.error("ERR unexpected reply frame"));
         }
-        proto::CommandMessage::RECV(_) => todo!(),
         _ => {}
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::CommandMessage::*;
+    use crate::server::auth::DEFAULT_USER;
+
+    /// Connection of `user` to a shared storage.
+    struct Client {
+        cc: Arc<Storage>,
+        auth: Arc<Authenticator>,
+        session: Session,
+    }
+
+    impl Client {
+        fn new(cc: &Arc<Storage>, auth: &Arc<Authenticator>) -> Self {
+            Self {
+                cc: cc.clone(),
+                auth: auth.clone(),
+                session: Session::new(auth.initial_user()),
+            }
+        }
+
+        async fn send(&mut self, command: proto::CommandMessage) -> proto::CommandMessage {
+            let (wal, _entries) = WalWritter::buffered();
+            let (tx, mut rx) = unbounded_channel();
+            let msg: proto::FrameMessage = command.into();
+            match msg.command {
+                BATCH(_) => handle_batch(&msg, &self.cc, tx, &wal, &self.auth, &mut self.session).await,
+                _ => handle_frame(&msg, &self.cc, tx, &wal, &self.auth, &mut self.session).await,
+            }
+            .unwrap();
+            rx.try_recv().map(|x| x.command).unwrap_or(RECV(None))
+        }
+    }
+
+    fn put(key: &str, value: &str) -> proto::CommandMessage {
+        PUT(key.to_owned(), value.as_bytes().to_vec(), None)
+    }
+
+    fn error(reply: &proto::CommandMessage) -> &str {
+        match reply {
+            ERROR(e) => e,
+            x => panic!("expected an error, got {:?}", x),
+        }
+    }
+
+    fn replies(reply: proto::CommandMessage) -> Vec<proto::CommandMessage> {
+        match reply {
+            BATCH(replies) => replies,
+            x => panic!("expected a batch, got {:?}", x),
+        }
+    }
+
+    fn setuser(auth: &Authenticator, args: &[&str]) {
+        let args: Vec<String> = ["SETUSER"].iter().chain(args).map(|x| x.to_string()).collect();
+        auth.command(DEFAULT_USER, &args).unwrap();
+    }
+
+    fn storage() -> (Arc<Storage>, Arc<Authenticator>) {
+        (Arc::new(Storage::new()), Arc::new(Authenticator::default()))
+    }
+
+    fn decoded<T: serde::de::DeserializeOwned>(reply: &proto::CommandMessage) -> T {
+        match reply {
+            RECV(Some(x)) => rmp_serde::from_slice(x).unwrap(),
+            x => panic!("expected data, got {:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_replies_keep_the_command_order() {
+        let (cc, auth) = storage();
+        let mut a = Client::new(&cc, &auth);
+
+        let replies = replies(
+            a.send(BATCH(vec![
+                put("a", "1"),
+                GET("a".to_owned()),
+                INCR("a".to_owned(), 5, None),
+                PING(),
+                GET("missing".to_owned()),
+                DELETE("a".to_owned()),
+                GET("a".to_owned()),
+                LPUSH("l".to_owned(), vec![b"x".to_vec(), b"y".to_vec()]),
+            ]))
+            .await,
+        );
+
+        assert_eq!(replies.len(), 8);
+        assert!(matches!(&replies[0], RECV(None)));
+        assert!(matches!(&replies[1], RECV(Some(x)) if x == b"1"));
+        assert_eq!(decoded::<i64>(&replies[2]), 6);
+        // Commands without a reply still take their slot.
+        assert!(matches!(&replies[3], RECV(None)));
+        assert!(matches!(&replies[4], RECV(Some(x)) if x.is_empty()));
+        assert!(matches!(&replies[5], RECV(None)));
+        assert!(matches!(&replies[6], RECV(Some(x)) if x.is_empty()));
+        assert_eq!(decoded::<usize>(&replies[7]), 2);
+    }
+
+    #[tokio::test]
+    async fn batch_errors_stay_with_their_command() {
+        let (cc, auth) = storage();
+        let mut a = Client::new(&cc, &auth);
+
+        let replies = replies(
+            a.send(BATCH(vec![
+                put("s", "text"),
+                INCR("s".to_owned(), 1, None),
+                LPUSH("s".to_owned(), vec![b"x".to_vec()]),
+                BATCH(vec![put("nested", "1")]),
+                SUBSCRIBE(vec!["news".to_owned()]),
+                put("after", "2"),
+            ]))
+            .await,
+        );
+
+        assert_eq!(replies.len(), 6);
+        assert!(matches!(&replies[0], RECV(None)));
+        assert_eq!(error(&replies[1]), "ERR value is not an integer or out of range");
+        assert!(error(&replies[2]).starts_with("WRONGTYPE"));
+        assert_eq!(error(&replies[3]), "ERR nested batches are not supported");
+        assert_eq!(error(&replies[4]), "ERR SUBSCRIBE is not allowed in batches");
+        assert!(matches!(&replies[5], RECV(None)));
+
+        // Failed commands change nothing, the others still apply.
+        assert_eq!(cc.read("s").await, Some(b"text".to_vec()));
+        assert_eq!(cc.read("nested").await, None);
+        assert_eq!(cc.read("after").await, Some(b"2".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn counter_errors_carry_the_error_prefix() {
+        let (cc, auth) = storage();
+        let mut a = Client::new(&cc, &auth);
+        a.send(put("s", "text")).await;
+
+        assert_eq!(error(&a.send(INCR("s".to_owned(), 1, None)).await), "ERR value is not an integer or out of range");
+        assert_eq!(error(&a.send(INCRFLOAT("s".to_owned(), 1.0, None)).await), "ERR value is not a valid float");
+        assert_eq!(decoded::<i64>(&a.send(INCR("n".to_owned(), -3, None)).await), -3);
+        assert_eq!(decoded::<f64>(&a.send(INCRFLOAT("n".to_owned(), 0.5, None)).await), -2.5);
+    }
+
+    #[tokio::test]
+    async fn commands_need_auth_first() {
+        let cc = Arc::new(Storage::new());
+        let users = vec![("bob".to_owned(), "pw".to_owned())];
+        let auth = Arc::new(Authenticator::new(Some("secret".to_owned()), users, None).unwrap());
+        let mut a = Client::new(&cc, &auth);
+        assert_eq!(a.session.user, None);
+
+        for command in [GET("k".to_owned()), put("k", "v"), MULTI(), WATCH(vec!["k".to_owned()])] {
+            assert_eq!(error(&a.send(command).await), "NOAUTH authentication required");
+        }
+        // Handshakes go through before authenticating.
+        assert!(matches!(a.send(CONNECTED()).await, RECV(None)));
+        assert_eq!(cc.read("k").await, None);
+
+        let wrongpass = "WRONGPASS invalid username-password pair or user is disabled";
+        assert_eq!(error(&a.send(AUTH(None, "wrong".to_owned())).await), wrongpass);
+        assert_eq!(error(&a.send(AUTH(Some("nobody".to_owned()), "secret".to_owned())).await), wrongpass);
+        assert_eq!(error(&a.send(GET("k".to_owned())).await), "NOAUTH authentication required");
+
+        assert!(matches!(a.send(AUTH(None, "secret".to_owned())).await, RECV(None)));
+        assert_eq!(a.session.user.as_deref(), Some(DEFAULT_USER));
+        assert!(matches!(a.send(put("k", "v")).await, RECV(None)));
+        assert!(matches!(a.send(GET("k".to_owned())).await, RECV(Some(x)) if x == b"v"));
+        a.send(MULTI()).await;
+        a.send(put("t", "1")).await;
+        assert_eq!(replies(a.send(EXEC()).await).len(), 1);
+
+        // A failed AUTH keeps the user the connection has.
+        assert_eq!(error(&a.send(AUTH(Some("bob".to_owned()), "wrong".to_owned())).await), wrongpass);
+        assert_eq!(a.session.user.as_deref(), Some(DEFAULT_USER));
+        assert!(matches!(a.send(AUTH(Some("bob".to_owned()), "pw".to_owned())).await, RECV(None)));
+        assert_eq!(a.session.user.as_deref(), Some("bob"));
+
+        // Disabled users can not authenticate.
+        setuser(&auth, &["bob", "off"]);
+        let mut b = Client::new(&cc, &auth);
+        assert_eq!(error(&b.send(AUTH(Some("bob".to_owned()), "pw".to_owned())).await), wrongpass);
+    }
+
+    #[tokio::test]
+    async fn exec_aborts_when_a_watched_key_changed() {
+        let (cc, auth) = storage();
+        let (mut a, mut b) = (Client::new(&cc, &auth), Client::new(&cc, &auth));
+        b.send(put("k", "v")).await;
+
+        a.send(WATCH(vec!["k".to_owned(), "missing".to_owned()])).await;
+        b.send(put("k", "theirs")).await;
+        a.send(MULTI()).await;
+        a.send(put("k", "mine")).await;
+        assert!(matches!(a.send(EXEC()).await, RECV(None)));
+        assert_eq!(cc.read("k").await, Some(b"theirs".to_vec()));
+
+        // Creating a watched key that was missing aborts as well.
+        a.send(WATCH(vec!["missing".to_owned()])).await;
+        b.send(put("missing", "now")).await;
+        a.send(MULTI()).await;
+        a.send(put("k", "mine")).await;
+        assert!(matches!(a.send(EXEC()).await, RECV(None)));
+        assert_eq!(cc.read("k").await, Some(b"theirs".to_vec()));
+
+        // The watches are gone after EXEC, unchanged keys let it run.
+        a.send(WATCH(vec!["k".to_owned()])).await;
+        a.send(MULTI()).await;
+        a.send(put("k", "mine")).await;
+        a.send(GET("k".to_owned())).await;
+        let replies = replies(a.send(EXEC()).await);
+        assert!(matches!(replies.as_slice(), [RECV(None), RECV(Some(x))] if x == b"mine"));
+    }
+
+    #[tokio::test]
+    async fn exec_fails_after_a_command_could_not_be_queued() {
+        let (cc, auth) = storage();
+        let mut a = Client::new(&cc, &auth);
+
+        a.send(MULTI()).await;
+        assert!(matches!(a.send(put("k", "v")).await, RECV(None)));
+        assert_eq!(error(&a.send(AUTH(None, "pw".to_owned())).await), "ERR command not allowed inside MULTI");
+        assert!(error(&a.send(EXEC()).await).starts_with("EXECABORT"));
+        assert_eq!(cc.read("k").await, None);
+        assert_eq!(error(&a.send(EXEC()).await), "ERR EXEC without MULTI");
+
+        // Commands the user may not run abort the transaction too.
+        setuser(&auth, &["alice", "on", ">pw", "~*", "+get"]);
+        a.session.user = Some("alice".to_owned());
+        a.send(MULTI()).await;
+        assert!(error(&a.send(put("k", "v")).await).starts_with("NOPERM"));
+        assert!(error(&a.send(EXEC()).await).starts_with("EXECABORT"));
+        assert_eq!(cc.read("k").await, None);
+    }
+
+    #[tokio::test]
+    async fn nested_multi_keeps_the_transaction_open() {
+        let (cc, auth) = storage();
+        let mut a = Client::new(&cc, &auth);
+
+        a.send(MULTI()).await;
+        assert_eq!(error(&a.send(MULTI()).await), "ERR MULTI calls can not be nested");
+        assert_eq!(error(&a.send(WATCH(vec!["k".to_owned()])).await), "ERR WATCH inside MULTI is not allowed");
+        a.send(put("k", "v")).await;
+        assert_eq!(cc.read("k").await, None);
+
+        assert_eq!(replies(a.send(EXEC()).await).len(), 1);
+        assert_eq!(cc.read("k").await, Some(b"v".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn discard_drops_queued_commands_and_watches() {
+        let (cc, auth) = storage();
+        let (mut a, mut b) = (Client::new(&cc, &auth), Client::new(&cc, &auth));
+
+        assert_eq!(error(&a.send(DISCARD()).await), "ERR DISCARD without MULTI");
+        a.send(WATCH(vec!["k".to_owned()])).await;
+        a.send(MULTI()).await;
+        a.send(put("k", "v")).await;
+        assert!(matches!(a.send(DISCARD()).await, RECV(None)));
+        assert_eq!(cc.read("k").await, None);
+        assert_eq!(error(&a.send(EXEC()).await), "ERR EXEC without MULTI");
+
+        // The watch went with the transaction.
+        b.send(put("k", "theirs")).await;
+        a.send(MULTI()).await;
+        a.send(put("k", "mine")).await;
+        assert_eq!(replies(a.send(EXEC()).await).len(), 1);
+        assert_eq!(cc.read("k").await, Some(b"mine".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn unwatch_forgets_changed_keys() {
+        let (cc, auth) = storage();
+        let (mut a, mut b) = (Client::new(&cc, &auth), Client::new(&cc, &auth));
+
+        a.send(WATCH(vec!["k".to_owned()])).await;
+        b.send(put("k", "theirs")).await;
+        assert!(matches!(a.send(UNWATCH()).await, RECV(None)));
+        a.send(MULTI()).await;
+        a.send(put("k", "mine")).await;
+        assert_eq!(replies(a.send(EXEC()).await).len(), 1);
+        assert_eq!(cc.read("k").await, Some(b"mine".to_vec()));
+    }
+}
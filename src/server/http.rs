@@ -0,0 +1,648 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::Body, header::HeaderValue, server::conn::http1, service::service_fn, Method, Request,
+    Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use tokio::{io, net::TcpListener};
+
+use crate::{
+    persistance::wal::WalWritter,
+    proto,
+    server::auth::{Authenticator, DEFAULT_USER},
+    storage::storage::{Storage, WriteCondition},
+    tls::TlsAcceptor,
+};
+
+/// Largest request body accepted by the gateway.
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+const DEFAULT_PAGE_LIMIT: usize = 100;
+const MAX_PAGE_LIMIT: usize = 1000;
+
+/// Header carrying the TTL in seconds, on writes as input and on reads as the remaining TTL.
+const TTL_HEADER: &str = "x-ttl";
+
+type HttpResponse = Response<Full<Bytes>>;
+
+#[derive(Serialize)]
+struct KeysPage {
+    keys: Vec<String>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    keys: usize,
+    version: &'static str,
+}
+
+/// Accepts a TCP connection and serves it, after the TLS handshake when an
+/// acceptor is given.
+pub async fn initiate_client(
+    listener: &TcpListener,
+    acceptor: Option<&TlsAcceptor>,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Arc<Authenticator>,
+) -> Result<(), io::Error> {
+    let (stream, peer) = listener.accept().await?;
+    let acceptor = acceptor.cloned();
+    let cc = cc.clone();
+    let wal = wal.clone();
+    let auth = auth.clone();
+    stream.set_nodelay(true).expect("Failed to set no delay");
+    tokio::spawn(async move {
+        let service = service_fn(move |req| {
+            let cc = cc.clone();
+            let wal = wal.clone();
+            let auth = auth.clone();
+            async move {
+                // Health checks stay reachable for load balancers without credentials.
+                let user = match req.uri().path() {
+                    "/admin/health" => Some(String::new()),
+                    _ => authenticate(&req, &auth),
+                };
+                Ok::<_, Infallible>(match user {
+                    Some(user) => handle_request(req, &cc, &wal, &auth, &user).await,
+                    None => unauthorized(),
+                })
+            }
+        });
+        let res = match acceptor {
+            Some(acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => http1::Builder::new().serve_connection(TokioIo::new(stream), service).await,
+                Err(e) => {
+                    eprintln!("TLS handshake with {} failed: {:?}", peer, e);
+                    return;
+                }
+            },
+            None => http1::Builder::new().serve_connection(TokioIo::new(stream), service).await,
+        };
+        if let Err(e) = res {
+            eprintln!("{:?}", e);
+        }
+    });
+
+    Ok(())
+}
+
+fn response(status: StatusCode, body: impl Into<Bytes>) -> HttpResponse {
+    let mut res = Response::new(Full::new(body.into()));
+    *res.status_mut() = status;
+    res
+}
+
+fn json<T: Serialize>(status: StatusCode, value: &T) -> HttpResponse {
+    let mut res = response(status, serde_json::to_vec(value).unwrap());
+    res.headers_mut()
+        .insert("content-type", HeaderValue::from_static("application/json"));
+    res
+}
+
+fn error(status: StatusCode, msg: &str) -> HttpResponse {
+    json(status, &HashMap::from([("error", msg)]))
+}
+
+fn empty(status: StatusCode) -> HttpResponse {
+    response(status, Bytes::new())
+}
+
+fn unauthorized() -> HttpResponse {
+    let mut res = error(StatusCode::UNAUTHORIZED, "authentication required");
+    res.headers_mut()
+        .insert("www-authenticate", HeaderValue::from_static("Basic realm=\"cachetcp\""));
+    res
+}
+
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in input.trim_end_matches('=').bytes() {
+        let x = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | x as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+/// Resolves the user from an `Authorization` header, either a bearer token
+/// checked as the default user password or basic credentials.
+fn authenticate<B>(req: &Request<B>, auth: &Authenticator) -> Option<String> {
+    if !auth.required() {
+        return auth.initial_user();
+    }
+
+    let header = req.headers().get("authorization")?.to_str().ok()?;
+    let (scheme, value) = header.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return auth.authenticate(Some(DEFAULT_USER), value.trim());
+    }
+    if scheme.eq_ignore_ascii_case("basic") {
+        let credentials = String::from_utf8(base64_decode(value.trim())?).ok()?;
+        let (username, password) = credentials.split_once(':')?;
+        return auth.authenticate(Some(username), password);
+    }
+
+    None
+}
+
+fn percent_decode(input: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = input.get(i + 1..i + 3)?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                out.push(b' ');
+                i += 1;
+            }
+            x => {
+                out.push(x);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(out).ok()
+}
+
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|x| !x.is_empty())
+        .filter_map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((percent_decode(k, true)?, percent_decode(v, true)?))
+        })
+        .collect()
+}
+
+/// Reads the TTL from the `ttl` (seconds) or `ttl_ms` query parameters, falling
+/// back to the `X-TTL` header. `Err` when a value is present but malformed.
+fn requested_ttl<B>(req: &Request<B>, query: &HashMap<String, String>) -> Result<Option<Duration>, &'static str> {
+    if let Some(ms) = query.get("ttl_ms") {
+        return match ms.parse::<u64>() {
+            Ok(x) if x > 0 => Ok(Some(Duration::from_millis(x))),
+            _ => Err("invalid ttl"),
+        };
+    }
+
+    let secs = match query.get("ttl") {
+        Some(secs) => Some(secs.as_str()),
+        None => match req.headers().get(TTL_HEADER) {
+            Some(header) => Some(header.to_str().map_err(|_| "invalid ttl")?),
+            None => None,
+        },
+    };
+
+    match secs {
+        Some(secs) => secs
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|x| x.is_finite() && *x > 0.0)
+            .map(|x| Some(Duration::from_secs_f64(x)))
+            .ok_or("invalid ttl"),
+        None => Ok(None),
+    }
+}
+
+/// ACL command a route is checked as, key routes are checked with their key.
+fn acl_command(method: &Method, segments: &[&str]) -> Option<&'static str> {
+    match (method, segments) {
+        (&Method::GET, ["keys"]) => Some("keys"),
+        (&Method::GET | &Method::HEAD, ["keys", _]) => Some("get"),
+        (&Method::PUT, ["keys", _]) => Some("put"),
+        (&Method::DELETE, ["keys", _]) => Some("delete"),
+        (_, ["keys", _, "ttl"]) => Some("expire"),
+        (&Method::GET, ["admin", "stats"]) => Some("stats"),
+        (&Method::POST, ["admin", "flush"]) => Some("flush"),
+        (&Method::POST, ["admin", "acl"]) => Some("acl"),
+        _ => None,
+    }
+}
+
+async fn handle_request<B>(
+    req: Request<B>,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Authenticator,
+    user: &str,
+) -> HttpResponse
+where
+    B: Body,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    let path = req.uri().path().to_owned();
+    let query = parse_query(req.uri().query());
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    let key = match segments.as_slice() {
+        ["keys", key] | ["keys", key, "ttl"] => match percent_decode(key, false) {
+            Some(key) if !key.is_empty() => Some(key),
+            _ => return error(StatusCode::BAD_REQUEST, "invalid key"),
+        },
+        _ => None,
+    };
+    if let Some(command) = acl_command(req.method(), &segments) {
+        if let Err(e) = auth.check(user, command, key.as_deref().as_slice()) {
+            return error(StatusCode::FORBIDDEN, &e);
+        }
+    }
+
+    match (req.method().clone(), segments.as_slice()) {
+        (Method::GET, ["keys"]) => list_keys(&query, cc, auth, user).await,
+        (method, ["keys", _]) => {
+            let key = key.unwrap_or_default();
+            match method {
+                Method::GET | Method::HEAD => get_key(&key, method == Method::HEAD, cc).await,
+                Method::PUT => put_key(req, &key, &query, cc, wal).await,
+                Method::DELETE => delete_key(&key, cc, wal).await,
+                _ => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            }
+        }
+        (method, ["keys", _, "ttl"]) => {
+            let key = key.unwrap_or_default();
+            match method {
+                Method::PUT => match requested_ttl(&req, &query) {
+                    Ok(Some(ttl)) => expire_key(&key, Some(ttl), cc, wal).await,
+                    Ok(None) => error(StatusCode::BAD_REQUEST, "missing ttl"),
+                    Err(msg) => error(StatusCode::BAD_REQUEST, msg),
+                },
+                Method::DELETE => expire_key(&key, None, cc, wal).await,
+                _ => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            }
+        }
+        (Method::GET, ["admin", "health"]) => json(StatusCode::OK, &HashMap::from([("status", "ok")])),
+        (Method::GET, ["admin", "stats"]) => json(
+            StatusCode::OK,
+            &Stats {
+                keys: auth.filter_keys(user, cc.keys().await.unwrap_or_default()).len(),
+                version: env!("CARGO_PKG_VERSION"),
+            },
+        ),
+        (Method::POST, ["admin", "flush"]) => {
+            let keys = cc
+                .atomically(|cc| async move {
+                    let keys = auth.filter_keys(user, cc.keys().await.unwrap_or_default());
+                    for key in keys.iter() {
+                        if cc.delete(key).await.is_some() {
+                            wal.write(&proto::CommandMessage::DELETE(key.clone()).into());
+                        }
+                    }
+                    keys
+                })
+                .await;
+            json(StatusCode::OK, &HashMap::from([("deleted", keys.len())]))
+        }
+        (Method::POST, ["admin", "acl"]) => {
+            let args: Vec<String> = match Limited::new(req.into_body(), MAX_BODY_LEN).collect().await {
+                Ok(body) => match serde_json::from_slice(&body.to_bytes()) {
+                    Ok(args) => args,
+                    Err(_) => return error(StatusCode::BAD_REQUEST, "expected a JSON array of strings"),
+                },
+                Err(_) => return error(StatusCode::PAYLOAD_TOO_LARGE, "body too large"),
+            };
+            match auth.command(user, &args) {
+                Ok(res) => json(StatusCode::OK, &res),
+                Err(e) => error(StatusCode::BAD_REQUEST, &e),
+            }
+        }
+        _ => error(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+async fn get_key(key: &str, head: bool, cc: &Arc<Storage>) -> HttpResponse {
+    let item = match cc.read_item(key).await {
+        Some(item) => item,
+        None => return error(StatusCode::NOT_FOUND, "key not found"),
+    };
+
+    let ttl = match item.ttl {
+        Some(x) => x.as_secs().to_string(),
+        None => "-1".to_owned(),
+    };
+    let mut res = match head {
+        true => empty(StatusCode::OK),
+        false => response(StatusCode::OK, item.value),
+    };
+    res.headers_mut()
+        .insert("content-type", HeaderValue::from_static("application/octet-stream"));
+    res.headers_mut()
+        .insert(TTL_HEADER, HeaderValue::from_str(&ttl).unwrap());
+    res
+}
+
+async fn put_key<B>(
+    req: Request<B>,
+    key: &str,
+    query: &HashMap<String, String>,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+) -> HttpResponse
+where
+    B: Body,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    let exp = match requested_ttl(&req, query) {
+        Ok(exp) => exp,
+        Err(msg) => return error(StatusCode::BAD_REQUEST, msg),
+    };
+    let data = match Limited::new(req.into_body(), MAX_BODY_LEN).collect().await {
+        Ok(body) => body.to_bytes().to_vec(),
+        Err(_) => return error(StatusCode::PAYLOAD_TOO_LARGE, "body too large"),
+    };
+
+    // Written and logged with exclusive access to the storage, so the WAL
+    // records writes in the order they were applied.
+    cc.atomically(|cc| async move {
+        if let (Some(item), _) = cc.put_if(key, data, exp, WriteCondition::Always).await {
+            wal.write_entries([(key, &item)]);
+        }
+    })
+    .await;
+
+    empty(StatusCode::NO_CONTENT)
+}
+
+async fn delete_key(key: &str, cc: &Arc<Storage>, wal: &WalWritter) -> HttpResponse {
+    let deleted = cc
+        .atomically(|cc| async move {
+            let deleted = cc.delete(key).await.is_some();
+            if deleted {
+                wal.write(&proto::CommandMessage::DELETE(key.to_owned()).into());
+            }
+            deleted
+        })
+        .await;
+
+    match deleted {
+        true => empty(StatusCode::NO_CONTENT),
+        false => error(StatusCode::NOT_FOUND, "key not found"),
+    }
+}
+
+async fn expire_key(key: &str, exp: Option<Duration>, cc: &Arc<Storage>, wal: &WalWritter) -> HttpResponse {
+    let updated = cc
+        .atomically(|cc| async move {
+            let updated = cc.set_expiration(key, exp).await;
+            if updated {
+                wal.write(&proto::CommandMessage::EXPIRE(key.to_owned(), exp).into());
+            }
+            updated
+        })
+        .await;
+
+    match updated {
+        true => empty(StatusCode::NO_CONTENT),
+        false => error(StatusCode::NOT_FOUND, "key not found"),
+    }
+}
+
+/// Lists keys in lexicographic order. `cursor` is the last key of the previous
+/// page, so pages stay consistent while keys are added or removed.
+async fn list_keys(
+    query: &HashMap<String, String>,
+    cc: &Arc<Storage>,
+    auth: &Authenticator,
+    user: &str,
+) -> HttpResponse {
+    let limit = match query.get("limit").map(|x| x.parse::<usize>()) {
+        Some(Ok(x)) if x > 0 => x.min(MAX_PAGE_LIMIT),
+        Some(_) => return error(StatusCode::BAD_REQUEST, "invalid limit"),
+        None => DEFAULT_PAGE_LIMIT,
+    };
+    let prefix = query.get("prefix").map(|x| x.as_str()).unwrap_or_default();
+    let cursor = query.get("cursor");
+
+    // One key more than the page tells whether another page follows.
+    let allowed = |key: &str| auth.can_access(user, key);
+    let mut keys = cc.prefix_keys(prefix, cursor.map(|x| x.as_str()), Some(limit + 1), allowed).await;
+
+    let next_cursor = match keys.len() > limit {
+        true => {
+            keys.truncate(limit);
+            keys.last().cloned()
+        }
+        false => None,
+    };
+
+    json(StatusCode::OK, &KeysPage { keys, next_cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, uri: &str, body: &str) -> Request<Full<Bytes>> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Full::new(Bytes::from(body.to_owned())))
+            .unwrap()
+    }
+
+    async fn send(cc: &Arc<Storage>, auth: &Authenticator, user: &str, req: Request<Full<Bytes>>) -> (StatusCode, Bytes) {
+        let (wal, _entries) = WalWritter::buffered();
+        let res = handle_request(req, cc, &wal, auth, user).await;
+        (res.status(), res.into_body().collect().await.unwrap().to_bytes())
+    }
+
+    async fn page(cc: &Arc<Storage>, auth: &Authenticator, user: &str, uri: &str) -> (Vec<String>, Option<String>) {
+        let (status, body) = send(cc, auth, user, request(Method::GET, uri, "")).await;
+        assert_eq!(status, StatusCode::OK, "{uri}");
+        let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let keys = page["keys"].as_array().unwrap().iter().map(|x| x.as_str().unwrap().to_owned()).collect();
+        (keys, page["next_cursor"].as_str().map(|x| x.to_owned()))
+    }
+
+    fn setuser(auth: &Authenticator, args: &[&str]) {
+        let args: Vec<String> = ["SETUSER"].iter().chain(args).map(|x| x.to_string()).collect();
+        auth.command(DEFAULT_USER, &args).unwrap();
+    }
+
+    #[test]
+    fn percent_decodes_paths_and_queries() {
+        assert_eq!(percent_decode("a%20b%2Fc", false), Some("a b/c".to_owned()));
+        assert_eq!(percent_decode("a+b", true), Some("a b".to_owned()));
+        assert_eq!(percent_decode("a+b", false), Some("a+b".to_owned()));
+        assert_eq!(percent_decode("%C3%A9", false), Some("é".to_owned()));
+
+        for input in ["%", "%2", "%zz", "%é", "%ff"] {
+            assert_eq!(percent_decode(input, false), None, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn base64_decodes_with_and_without_padding() {
+        assert_eq!(base64_decode("dXNlcjpwYXNz"), Some(b"user:pass".to_vec()));
+        assert_eq!(base64_decode("YQ=="), Some(b"a".to_vec()));
+        assert_eq!(base64_decode("YWI="), Some(b"ab".to_vec()));
+        assert_eq!(base64_decode("YWI"), Some(b"ab".to_vec()));
+        assert_eq!(base64_decode("+/8="), Some(vec![0xfb, 0xff]));
+        assert_eq!(base64_decode(""), Some(Vec::new()));
+        assert_eq!(base64_decode("YW-I"), None);
+    }
+
+    #[test]
+    fn ttl_comes_from_query_before_header() {
+        let ttl = |uri: &str, header: Option<&str>| {
+            let mut req = request(Method::PUT, uri, "");
+            if let Some(header) = header {
+                req.headers_mut().insert(TTL_HEADER, HeaderValue::from_str(header).unwrap());
+            }
+            requested_ttl(&req, &parse_query(req.uri().query()))
+        };
+
+        assert_eq!(ttl("/keys/k", None), Ok(None));
+        assert_eq!(ttl("/keys/k?ttl_ms=1500", None), Ok(Some(Duration::from_millis(1500))));
+        assert_eq!(ttl("/keys/k?ttl=2.5", None), Ok(Some(Duration::from_millis(2500))));
+        assert_eq!(ttl("/keys/k", Some(" 3 ")), Ok(Some(Duration::from_secs(3))));
+        assert_eq!(ttl("/keys/k?ttl=1", Some("3")), Ok(Some(Duration::from_secs(1))));
+        assert_eq!(ttl("/keys/k?ttl_ms=10&ttl=1", None), Ok(Some(Duration::from_millis(10))));
+
+        for uri in ["/keys/k?ttl_ms=0", "/keys/k?ttl_ms=1.5", "/keys/k?ttl=0", "/keys/k?ttl=-1", "/keys/k?ttl=abc", "/keys/k?ttl=inf"] {
+            assert_eq!(ttl(uri, None), Err("invalid ttl"), "{uri}");
+        }
+        assert_eq!(ttl("/keys/k", Some("soon")), Err("invalid ttl"));
+    }
+
+    #[test]
+    fn routes_map_onto_acl_commands() {
+        assert_eq!(acl_command(&Method::GET, &["keys"]), Some("keys"));
+        assert_eq!(acl_command(&Method::HEAD, &["keys", "k"]), Some("get"));
+        assert_eq!(acl_command(&Method::PUT, &["keys", "k"]), Some("put"));
+        assert_eq!(acl_command(&Method::DELETE, &["keys", "k"]), Some("delete"));
+        assert_eq!(acl_command(&Method::PATCH, &["keys", "k", "ttl"]), Some("expire"));
+        assert_eq!(acl_command(&Method::POST, &["admin", "flush"]), Some("flush"));
+        assert_eq!(acl_command(&Method::GET, &["admin", "health"]), None);
+        assert_eq!(acl_command(&Method::POST, &["keys", "k"]), None);
+    }
+
+    #[tokio::test]
+    async fn checks_routes_against_the_user_acl() {
+        let cc = Arc::new(Storage::new());
+        let auth = Authenticator::default();
+        setuser(&auth, &["alice", "on", "nopass", "~app:*", "+get", "+keys"]);
+        cc.write("app:1", b"v".to_vec()).await;
+        cc.write("other", b"v".to_vec()).await;
+
+        let (status, body) = send(&cc, &auth, "alice", request(Method::GET, "/keys/app:1", "")).await;
+        assert_eq!((status, body), (StatusCode::OK, Bytes::from("v")));
+        let (status, _) = send(&cc, &auth, "alice", request(Method::GET, "/keys/app:2", "")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        for (method, uri) in [
+            (Method::GET, "/keys/other"),
+            (Method::PUT, "/keys/app:1"),
+            (Method::DELETE, "/keys/app:1"),
+            (Method::PUT, "/keys/app:1/ttl?ttl=1"),
+            (Method::GET, "/admin/stats"),
+            (Method::POST, "/admin/flush"),
+        ] {
+            let (status, _) = send(&cc, &auth, "alice", request(method.clone(), uri, "")).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{method} {uri}");
+        }
+        assert_eq!(cc.read("app:1").await, Some(b"v".to_vec()));
+
+        // Listings only show the keys the user may access.
+        assert_eq!(page(&cc, &auth, "alice", "/keys").await, (vec!["app:1".to_owned()], None));
+        let (status, _) = send(&cc, &auth, "alice", request(Method::GET, "/admin/health", "")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_paths_and_parameters() {
+        let cc = Arc::new(Storage::new());
+        let auth = Authenticator::default();
+        cc.write("k", b"v".to_vec()).await;
+
+        for (method, uri, body, expected) in [
+            (Method::GET, "/nope", "", StatusCode::NOT_FOUND),
+            (Method::GET, "/keys/k/extra/segments", "", StatusCode::NOT_FOUND),
+            (Method::GET, "/keys/", "", StatusCode::BAD_REQUEST),
+            (Method::GET, "/keys/%zz", "", StatusCode::BAD_REQUEST),
+            (Method::POST, "/keys/k", "", StatusCode::METHOD_NOT_ALLOWED),
+            (Method::GET, "/keys/k/ttl", "", StatusCode::METHOD_NOT_ALLOWED),
+            (Method::GET, "/keys/missing", "", StatusCode::NOT_FOUND),
+            (Method::DELETE, "/keys/missing", "", StatusCode::NOT_FOUND),
+            (Method::DELETE, "/keys/missing/ttl", "", StatusCode::NOT_FOUND),
+            (Method::PUT, "/keys/k?ttl=abc", "v", StatusCode::BAD_REQUEST),
+            (Method::PUT, "/keys/k/ttl", "", StatusCode::BAD_REQUEST),
+            (Method::PUT, "/keys/k/ttl?ttl_ms=-5", "", StatusCode::BAD_REQUEST),
+            (Method::GET, "/keys?limit=0", "", StatusCode::BAD_REQUEST),
+            (Method::GET, "/keys?limit=ten", "", StatusCode::BAD_REQUEST),
+            (Method::POST, "/admin/acl", "{\"not\": \"a list\"}", StatusCode::BAD_REQUEST),
+        ] {
+            let (status, _) = send(&cc, &auth, DEFAULT_USER, request(method.clone(), uri, body)).await;
+            assert_eq!(status, expected, "{method} {uri}");
+        }
+        // Rejected writes leave the key alone.
+        assert_eq!(cc.read("k").await, Some(b"v".to_vec()));
+
+        let (status, _) = send(&cc, &auth, DEFAULT_USER, request(Method::PUT, "/keys/a%2Fb?ttl=5", "x")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(cc.read("a/b").await, Some(b"x".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn pages_through_keys_in_order() {
+        let auth = Authenticator::default();
+        for cc in [Storage::new(), Storage::with_ordered_index()] {
+            let cc = Arc::new(cc);
+            for key in ["a:3", "b:1", "a:1", "a:5", "a:2", "a:4"] {
+                cc.write(key, b"v".to_vec()).await;
+            }
+            cc.list_push("a:6", vec![b"x".to_vec()], false).await.unwrap();
+
+            let mut keys = Vec::new();
+            let mut uri = "/keys?prefix=a:&limit=2".to_owned();
+            let mut pages = 0;
+            loop {
+                let (page, next) = page(&cc, &auth, DEFAULT_USER, &uri).await;
+                assert!(page.len() <= 2);
+                keys.extend(page);
+                pages += 1;
+                match next {
+                    Some(next) => uri = format!("/keys?prefix=a:&limit=2&cursor={}", next),
+                    None => break,
+                }
+            }
+            assert_eq!(keys, vec!["a:1", "a:2", "a:3", "a:4", "a:5", "a:6"]);
+            assert_eq!(pages, 3);
+
+            let (all, next) = page(&cc, &auth, DEFAULT_USER, "/keys").await;
+            assert_eq!((all.len(), next), (7, None));
+            // A cursor before the prefix starts at the prefix, after it ends the listing.
+            assert_eq!(page(&cc, &auth, DEFAULT_USER, "/keys?prefix=b&cursor=a").await, (vec!["b:1".to_owned()], None));
+            assert_eq!(page(&cc, &auth, DEFAULT_USER, "/keys?prefix=a&cursor=b").await, (Vec::new(), None));
+        }
+    }
+}
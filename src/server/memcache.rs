@@ -0,0 +1,443 @@
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::TcpListener,
+};
+
+use crate::{
+    persistance::wal::WalWritter,
+    server::auth::Authenticator,
+    tls::TlsAcceptor,
+    proto::{
+        self,
+        memcache::{read_request, MetaFlags, Request, StoreCommand},
+    },
+    storage::storage::{Item, Storage, StoreMode, StoreStatus},
+};
+
+/// Expiration times above this many seconds are absolute unix timestamps.
+const RELATIVE_EXPTIME_LIMIT: i64 = 60 * 60 * 24 * 30;
+
+/// Accepts a TCP connection and serves it, after the TLS handshake when an
+/// acceptor is given.
+pub async fn initiate_client(
+    listener: &TcpListener,
+    acceptor: Option<&TlsAcceptor>,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Arc<Authenticator>,
+) -> Result<(), io::Error> {
+    let (stream, _) = listener.accept().await?;
+    let acceptor = acceptor.cloned();
+    let cc = cc.clone();
+    let wal = wal.clone();
+    let auth = auth.clone();
+    stream.set_nodelay(true).expect("Failed to set no delay");
+    tokio::spawn(async move {
+        let res = match acceptor {
+            Some(acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => serve(stream, &cc, &wal, &auth).await,
+                Err(e) => Err(e),
+            },
+            None => serve(stream, &cc, &wal, &auth).await,
+        };
+        match res {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {}
+            Err(e) => eprintln!("{:?}", e),
+        }
+    });
+
+    Ok(())
+}
+
+async fn serve<S>(
+    stream: S,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Authenticator,
+) -> Result<(), io::Error>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let started = Instant::now();
+    let mut user = auth.initial_user();
+    let (rd, wr) = io::split(stream);
+    let mut rd = BufReader::new(rd);
+    let mut wr = BufWriter::new(wr);
+
+    while let Some(request) = read_request(&mut rd).await? {
+        if let Request::Quit = request {
+            break;
+        }
+
+        let mut buf = Vec::new();
+        match &user {
+            Some(name) => handle_request(request, &mut buf, cc, wal, auth, name, started).await,
+            None => user = authenticate(request, &mut buf, auth),
+        }
+        wr.write_all(&buf).await?;
+
+        // Replies to pipelined requests are flushed together once the input is drained.
+        if rd.buffer().is_empty() {
+            wr.flush().await?;
+        }
+    }
+    wr.flush().await?;
+
+    Ok(())
+}
+
+/// Follows the memcached ASCII authentication convention, the first `set` of
+/// the connection carries `<username> <password>` as its data.
+fn authenticate(request: Request, buf: &mut Vec<u8>, auth: &Authenticator) -> Option<String> {
+    let data = match request {
+        Request::Store { command: StoreCommand::Set, data, .. } => data,
+        _ => {
+            line(buf, "CLIENT_ERROR unauthenticated");
+            return None;
+        }
+    };
+
+    let credentials = String::from_utf8_lossy(&data);
+    let user = credentials
+        .trim()
+        .split_once(' ')
+        .and_then(|(username, password)| auth.authenticate(Some(username), password));
+    match user {
+        Some(_) => line(buf, "STORED"),
+        None => line(buf, "CLIENT_ERROR authentication failure"),
+    }
+
+    user
+}
+
+/// Converts a memcached exptime into a TTL. Zero never expires, negative values
+/// and past absolute timestamps expire immediately.
+fn ttl_from_exptime(exptime: i64) -> Option<Duration> {
+    match exptime {
+        0 => None,
+        x if x < 0 => Some(Duration::ZERO),
+        x if x > RELATIVE_EXPTIME_LIMIT => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64;
+            Some(Duration::from_secs((x - now).max(0) as u64))
+        }
+        x => Some(Duration::from_secs(x as u64)),
+    }
+}
+
+fn log_item(key: &str, item: &Item, wal: &WalWritter) {
+    wal.write_entries([(key, item)]);
+}
+
+fn log_delete(key: &str, wal: &WalWritter) {
+    wal.write(&proto::CommandMessage::DELETE(key.to_owned()).into());
+}
+
+async fn touch(key: &str, exptime: i64, cc: &Arc<Storage>, wal: &WalWritter) -> bool {
+    let ttl = ttl_from_exptime(exptime);
+    if ttl == Some(Duration::ZERO) {
+        let deleted = cc.delete(key).await.is_some();
+        if deleted {
+            log_delete(key, wal);
+        }
+        return deleted;
+    }
+
+    let updated = cc.set_expiration(key, ttl).await;
+    if updated {
+        wal.write(&proto::CommandMessage::EXPIRE(key.to_owned(), ttl).into());
+    }
+
+    updated
+}
+
+fn line(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.extend_from_slice(b"\r\n");
+}
+
+/// Maps a request onto the ACL command it is checked as, with its keys.
+fn acl_command(request: &Request) -> Option<(&'static str, Vec<&str>)> {
+    match request {
+        Request::Get { keys, .. } => Some(("get", keys.iter().map(|x| x.as_str()).collect())),
+        Request::MetaGet { key, .. } | Request::MetaDebug { key } => Some(("get", vec![key])),
+        Request::Store { key, .. }
+        | Request::Arith { key, .. }
+        | Request::MetaSet { key, .. }
+        | Request::MetaArith { key, .. } => Some(("put", vec![key])),
+        Request::Delete { key, .. } | Request::MetaDelete { key, .. } => Some(("delete", vec![key])),
+        Request::Touch { key, .. } => Some(("expire", vec![key])),
+        Request::FlushAll { .. } => Some(("flush", vec![])),
+        Request::Stats => Some(("stats", vec![])),
+        _ => None,
+    }
+}
+
+async fn handle_request(
+    request: Request,
+    buf: &mut Vec<u8>,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Authenticator,
+    user: &str,
+    started: Instant,
+) {
+    if let Some((command, keys)) = acl_command(&request) {
+        if let Err(e) = auth.check(user, command, &keys) {
+            line(buf, &format!("CLIENT_ERROR {}", e));
+            return;
+        }
+    }
+
+    match request {
+        Request::Get { keys, cas, touch: exptime } => {
+            for key in keys {
+                if let Some(exptime) = exptime {
+                    touch(&key, exptime, cc, wal).await;
+                }
+                if let Some(item) = cc.read_item(&key).await {
+                    match cas {
+                        true => line(buf, &format!("VALUE {} {} {} {}", key, item.flags, item.value.len(), item.version)),
+                        false => line(buf, &format!("VALUE {} {} {}", key, item.flags, item.value.len())),
+                    }
+                    buf.extend_from_slice(&item.value);
+                    line(buf, "");
+                }
+            }
+            line(buf, "END");
+        }
+        Request::Store { command, key, flags, exptime, data, noreply } => {
+            let mode = match command {
+                StoreCommand::Set => StoreMode::Set,
+                StoreCommand::Add => StoreMode::Add,
+                StoreCommand::Replace => StoreMode::Replace,
+                StoreCommand::Append => StoreMode::Append,
+                StoreCommand::Prepend => StoreMode::Prepend,
+                StoreCommand::Cas(x) => StoreMode::Cas(x),
+            };
+            let reply = match cc.store(&key, data, ttl_from_exptime(exptime), flags, mode).await {
+                StoreStatus::Stored(item) => {
+                    log_item(&key, &item, wal);
+                    "STORED"
+                }
+                StoreStatus::Exists => "EXISTS",
+                StoreStatus::NotFound => "NOT_FOUND",
+                _ => "NOT_STORED",
+            };
+            if !noreply {
+                line(buf, reply);
+            }
+        }
+        Request::Delete { key, noreply } => {
+            let reply = match cc.delete(&key).await {
+                Some(_) => {
+                    log_delete(&key, wal);
+                    "DELETED"
+                }
+                None => "NOT_FOUND",
+            };
+            if !noreply {
+                line(buf, reply);
+            }
+        }
+        Request::Arith { key, delta, incr, noreply } => {
+            let reply = match cc.incr(&key, delta, !incr, None).await {
+                StoreStatus::Stored(item) => {
+                    log_item(&key, &item, wal);
+                    String::from_utf8_lossy(&item.value).into_owned()
+                }
+                StoreStatus::NotNumeric => "CLIENT_ERROR cannot increment or decrement non-numeric value".to_owned(),
+                _ => "NOT_FOUND".to_owned(),
+            };
+            if !noreply {
+                line(buf, &reply);
+            }
+        }
+        Request::Touch { key, exptime, noreply } => {
+            let reply = match touch(&key, exptime, cc, wal).await {
+                true => "TOUCHED",
+                false => "NOT_FOUND",
+            };
+            if !noreply {
+                line(buf, reply);
+            }
+        }
+        Request::MetaGet { key, flags } => meta_get(&key, &flags, buf, cc, wal).await,
+        Request::MetaSet { key, data, flags } => meta_set(&key, data, &flags, buf, cc, wal).await,
+        Request::MetaDelete { key, flags } => {
+            let version = flags.get(b'C').and_then(|x| x.parse::<u64>().ok());
+            let (code, quiet) = match cc.delete_if(&key, version).await {
+                StoreStatus::Stored(_) => {
+                    log_delete(&key, wal);
+                    ("HD", true)
+                }
+                StoreStatus::Exists => ("EX", false),
+                _ => ("NF", true),
+            };
+            if !(quiet && flags.has(b'q')) {
+                line(buf, &meta_reply(code, &key, None, &flags));
+            }
+        }
+        Request::MetaArith { key, flags } => meta_arith(&key, &flags, buf, cc, wal).await,
+        Request::MetaDebug { key } => match cc.read_item(&key).await {
+            Some(item) => line(
+                buf,
+                &format!(
+                    "ME {} exp={} la=0 cas={} fetch=no cls=1 size={}",
+                    key,
+                    item.ttl.map(|x| x.as_secs() as i64).unwrap_or(-1),
+                    item.version,
+                    item.value.len()
+                ),
+            ),
+            None => line(buf, "EN"),
+        },
+        Request::MetaNoop => line(buf, "MN"),
+        Request::FlushAll { delay, noreply } => {
+            for key in auth.filter_keys(user, cc.keys().await.unwrap_or_default()) {
+                match delay {
+                    x if x > 0 => {
+                        touch(&key, x, cc, wal).await;
+                    }
+                    _ => {
+                        if cc.delete(&key).await.is_some() {
+                            log_delete(&key, wal);
+                        }
+                    }
+                }
+            }
+            if !noreply {
+                line(buf, "OK");
+            }
+        }
+        Request::Version => line(buf, &format!("VERSION {}", env!("CARGO_PKG_VERSION"))),
+        Request::Verbosity { noreply } => {
+            if !noreply {
+                line(buf, "OK");
+            }
+        }
+        Request::Stats => {
+            let items = auth.filter_keys(user, cc.keys().await.unwrap_or_default()).len();
+            line(buf, &format!("STAT pid {}", std::process::id()));
+            line(buf, &format!("STAT uptime {}", started.elapsed().as_secs()));
+            line(buf, &format!("STAT version {}", env!("CARGO_PKG_VERSION")));
+            line(buf, &format!("STAT curr_items {}", items));
+            line(buf, "END");
+        }
+        Request::Quit => {}
+        Request::Invalid(msg) => line(buf, &format!("CLIENT_ERROR {}", msg)),
+        Request::Unknown => line(buf, "ERROR"),
+    }
+}
+
+/// Builds a meta response line echoing the return flags requested by the client.
+fn meta_reply(code: &str, key: &str, item: Option<&Item>, flags: &MetaFlags) -> String {
+    let mut reply = code.to_owned();
+    for (flag, value) in flags.0.iter() {
+        let token = match (flag, item) {
+            (b'O', _) => format!("O{}", value),
+            (b'k', _) => format!("k{}", key),
+            (b'b', _) => "b".to_owned(),
+            (b'c', Some(item)) => format!("c{}", item.version),
+            (b'f', Some(item)) => format!("f{}", item.flags),
+            (b's', Some(item)) => format!("s{}", item.value.len()),
+            (b't', Some(item)) => format!("t{}", item.ttl.map(|x| x.as_secs() as i64).unwrap_or(-1)),
+            _ => continue,
+        };
+        reply.push(' ');
+        reply.push_str(&token);
+    }
+
+    reply
+}
+
+async fn meta_get(key: &str, flags: &MetaFlags, buf: &mut Vec<u8>, cc: &Arc<Storage>, wal: &WalWritter) {
+    if let Some(exptime) = flags.get(b'T').and_then(|x| x.parse::<i64>().ok()) {
+        touch(key, exptime, cc, wal).await;
+    }
+
+    match cc.read_item(key).await {
+        Some(item) if flags.has(b'v') => {
+            line(buf, &meta_reply(&format!("VA {}", item.value.len()), key, Some(&item), flags));
+            buf.extend_from_slice(&item.value);
+            line(buf, "");
+        }
+        Some(item) => line(buf, &meta_reply("HD", key, Some(&item), flags)),
+        None if flags.has(b'q') => {}
+        None => line(buf, "EN"),
+    }
+}
+
+async fn meta_set(key: &str, data: Vec<u8>, flags: &MetaFlags, buf: &mut Vec<u8>, cc: &Arc<Storage>, wal: &WalWritter) {
+    let exptime = flags.get(b'T').and_then(|x| x.parse::<i64>().ok()).unwrap_or(0);
+    let client_flags = flags.get(b'F').and_then(|x| x.parse::<u32>().ok()).unwrap_or(0);
+    let mode = match flags.get(b'C').and_then(|x| x.parse::<u64>().ok()) {
+        Some(version) => StoreMode::Cas(version),
+        None => match flags.get(b'M') {
+            Some("E") | Some("e") => StoreMode::Add,
+            Some("A") | Some("a") => StoreMode::Append,
+            Some("P") | Some("p") => StoreMode::Prepend,
+            Some("R") | Some("r") => StoreMode::Replace,
+            Some("S") | Some("s") | None => StoreMode::Set,
+            Some(_) => {
+                line(buf, "CLIENT_ERROR invalid mode for ms");
+                return;
+            }
+        },
+    };
+
+    let (code, item) = match cc.store(key, data, ttl_from_exptime(exptime), client_flags, mode).await {
+        StoreStatus::Stored(item) => {
+            log_item(key, &item, wal);
+            ("HD", Some(item))
+        }
+        StoreStatus::Exists => ("EX", None),
+        StoreStatus::NotFound => ("NF", None),
+        _ => ("NS", None),
+    };
+    if !(code == "HD" && flags.has(b'q')) {
+        line(buf, &meta_reply(code, key, item.as_ref(), flags));
+    }
+}
+
+async fn meta_arith(key: &str, flags: &MetaFlags, buf: &mut Vec<u8>, cc: &Arc<Storage>, wal: &WalWritter) {
+    let delta = flags.get(b'D').and_then(|x| x.parse::<u64>().ok()).unwrap_or(1);
+    let decr = match flags.get(b'M') {
+        Some("D") | Some("d") | Some("-") => true,
+        Some("I") | Some("i") | Some("+") | None => false,
+        Some(_) => {
+            line(buf, "CLIENT_ERROR invalid mode for ma");
+            return;
+        }
+    };
+    let vivify = flags.get(b'N').and_then(|x| x.parse::<i64>().ok()).map(|exptime| {
+        let initial = flags.get(b'J').and_then(|x| x.parse::<u64>().ok()).unwrap_or(0);
+        (initial, ttl_from_exptime(exptime))
+    });
+
+    match cc.incr(key, delta, decr, vivify).await {
+        StoreStatus::Stored(item) => {
+            log_item(key, &item, wal);
+            if let Some(exptime) = flags.get(b'T').and_then(|x| x.parse::<i64>().ok()) {
+                touch(key, exptime, cc, wal).await;
+            }
+            if flags.has(b'v') {
+                line(buf, &meta_reply(&format!("VA {}", item.value.len()), key, Some(&item), flags));
+                buf.extend_from_slice(&item.value);
+                line(buf, "");
+            } else if !flags.has(b'q') {
+                line(buf, &meta_reply("HD", key, Some(&item), flags));
+            }
+        }
+        StoreStatus::NotNumeric => line(buf, "CLIENT_ERROR cannot increment or decrement non-numeric value"),
+        _ => line(buf, &meta_reply("NF", key, None, flags)),
+    }
+}
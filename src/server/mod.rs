@@ -1 +1,5 @@
+pub mod auth;
 pub mod functional;
+pub mod http;
+pub mod memcache;
+pub mod resp;
@@ -0,0 +1,1970 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::TcpListener,
+    sync::{broadcast::error::RecvError, mpsc},
+    task::JoinHandle,
+};
+
+use crate::{
+    persistance::wal::WalWritter,
+    server::auth::Authenticator,
+    tls::TlsAcceptor,
+    proto::{
+        self,
+        resp::{read_command, RespValue},
+    },
+    storage::{
+        glob,
+        index::IndexValue,
+        queue::{QueueOptions, Receipt},
+        schedule::Target,
+        sketch::CountMinSketch,
+        storage::{now_millis, Storage, StoreStatus, WriteCondition},
+        stream::{StreamEntry, StreamId},
+        timeseries::{Aggregation, Sample},
+        value::ValueError,
+    },
+};
+
+struct Session {
+    version: u8,
+    closing: bool,
+    user: Option<String>,
+    /// Forwarders of the subscribed channels into `messages`.
+    subscriptions: HashMap<String, JoinHandle<()>>,
+    messages: mpsc::UnboundedSender<(String, Vec<u8>)>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.subscriptions.values().for_each(JoinHandle::abort);
+    }
+}
+
+/// Accepts a TCP connection and serves it, after the TLS handshake when an
+/// acceptor is given.
+pub async fn initiate_client(
+    listener: &TcpListener,
+    acceptor: Option<&TlsAcceptor>,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Arc<Authenticator>,
+) -> Result<(), io::Error> {
+    let (stream, _) = listener.accept().await?;
+    let acceptor = acceptor.cloned();
+    let cc = cc.clone();
+    let wal = wal.clone();
+    let auth = auth.clone();
+    stream.set_nodelay(true).expect("Failed to set no delay");
+    tokio::spawn(async move {
+        let res = match acceptor {
+            Some(acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => serve(stream, &cc, &wal, &auth).await,
+                Err(e) => Err(e),
+            },
+            None => serve(stream, &cc, &wal, &auth).await,
+        };
+        match res {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {}
+            Err(e) => eprintln!("{:?}", e),
+        }
+    });
+
+    Ok(())
+}
+
+async fn serve<S>(
+    stream: S,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Authenticator,
+) -> Result<(), io::Error>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (rd, wr) = io::split(stream);
+    let mut rd = BufReader::new(rd);
+    let mut wr = BufWriter::new(wr);
+    let (messages, mut published) = mpsc::unbounded_channel();
+    let mut session = Session {
+        version: 2,
+        closing: false,
+        user: auth.initial_user(),
+        subscriptions: HashMap::new(),
+        messages,
+    };
+
+    loop {
+        // Subscribed connections forward messages until the client sends
+        // something. Only filling the buffer is cancel safe, so the command
+        // itself is read once input arrived.
+        while !session.subscriptions.is_empty() {
+            tokio::select! {
+                res = rd.fill_buf() => {
+                    res?;
+                    break;
+                }
+                Some((channel, payload)) = published.recv() => {
+                    let mut buf = Vec::new();
+                    message(channel, payload).encode(session.version, &mut buf);
+                    wr.write_all(&buf).await?;
+                    if published.is_empty() {
+                        wr.flush().await?;
+                    }
+                }
+            }
+        }
+        let args = match read_command(&mut rd).await {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                let mut buf = Vec::new();
+                RespValue::err(&format!("Protocol error: {}", e)).encode(session.version, &mut buf);
+                wr.write_all(&buf).await?;
+                wr.flush().await?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Collection commands run with exclusive access to the storage, so
+        // their `APPLIED` WAL records are logged in the order they were
+        // applied. Blocking reads take it for every attempt instead.
+        let reply = match applies(&args) && !blocks(&args) {
+            true => {
+                let session = &mut session;
+                cc.atomically(move |cc| async move { handle_command(args, session, &cc, wal, auth).await })
+                    .await
+            }
+            false => handle_command(args, &mut session, cc, wal, auth).await,
+        };
+        let mut buf = Vec::new();
+        reply.encode(session.version, &mut buf);
+        wr.write_all(&buf).await?;
+
+        // Replies to pipelined commands are flushed together once the input is drained.
+        if rd.buffer().is_empty() || session.closing {
+            wr.flush().await?;
+        }
+        if session.closing {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn message(channel: String, payload: Vec<u8>) -> RespValue {
+    RespValue::Push(vec![
+        RespValue::bulk_str("message"),
+        RespValue::Bulk(channel.into_bytes()),
+        RespValue::Bulk(payload),
+    ])
+}
+
+/// Whether the command may wait for other connections.
+fn blocks(args: &[Vec<u8>]) -> bool {
+    let name = args[0].to_ascii_uppercase();
+    matches!(name.as_slice(), b"XREAD" | b"XREADGROUP") && args.iter().any(|x| x.eq_ignore_ascii_case(b"BLOCK"))
+}
+
+/// Whether the command changes a collection, see `CommandMessage::applied_key`.
+fn applies(args: &[Vec<u8>]) -> bool {
+    let name = args[0].to_ascii_uppercase();
+    matches!(
+        name.as_slice(),
+        b"LPUSH" | b"RPUSH" | b"LPOP" | b"RPOP" | b"LTRIM"
+            | b"HSET" | b"HDEL" | b"HINCRBY" | b"HINCRBYFLOAT"
+            | b"SADD" | b"SREM"
+            | b"ZADD" | b"ZINCRBY" | b"ZREM" | b"ZREMRANGEBYRANK" | b"ZREMRANGEBYSCORE"
+            | b"XADD" | b"XTRIM" | b"XGROUP" | b"XREADGROUP" | b"XACK"
+            | b"PFADD" | b"PFMERGE"
+            | b"BF.RESERVE" | b"BF.ADD" | b"BF.MADD"
+            | b"CMS.INITBYDIM" | b"CMS.INITBYPROB" | b"CMS.INCRBY" | b"TOPK.RESERVE" | b"TOPK.ADD"
+            | b"JSON.SET" | b"JSON.DEL" | b"JSON.FORGET" | b"JSON.ARRAPPEND" | b"JSON.NUMINCRBY"
+            | b"TS.CREATE" | b"TS.ADD" | b"TS.CREATERULE" | b"TS.DELETERULE"
+            | b"Q.CREATE" | b"Q.PUSH" | b"Q.PUSHDELAYED" | b"Q.POP" | b"Q.ACK" | b"Q.NACK"
+            | b"SCHED.ADD" | b"SCHED.CANCEL"
+    )
+}
+
+fn key_of(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).into_owned()
+}
+
+fn parse_int(arg: &[u8]) -> Result<i64, RespValue> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .ok_or_else(|| RespValue::err("value is not an integer or out of range"))
+}
+
+fn parse_u32(arg: &[u8]) -> Result<u32, RespValue> {
+    parse_int(arg)?
+        .try_into()
+        .map_err(|_| RespValue::err("value is not an integer or out of range"))
+}
+
+/// Probability strictly between zero and one.
+fn parse_rate(arg: &[u8]) -> Result<f64, RespValue> {
+    Some(parse_score(arg)?)
+        .filter(|x| *x > 0.0 && *x < 1.0)
+        .ok_or_else(|| RespValue::err("value must be between 0 and 1"))
+}
+
+/// Scores may be infinite but never NaN.
+fn parse_score(arg: &[u8]) -> Result<f64, RespValue> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|x| x.parse::<f64>().ok())
+        .filter(|x| !x.is_nan())
+        .ok_or_else(|| RespValue::err("value is not a valid float"))
+}
+
+/// Score interval bound, exclusive when prefixed with `(`.
+fn parse_score_bound(arg: &[u8]) -> Result<Bound<f64>, RespValue> {
+    let bound = match arg.strip_prefix(b"(") {
+        Some(x) => parse_score(x).map(Bound::Excluded),
+        None => parse_score(arg).map(Bound::Included),
+    };
+
+    bound.map_err(|_| RespValue::err("min or max is not a float"))
+}
+
+/// Stream ID, a bare `<ms>` taking `seq` as its sequence number.
+fn parse_stream_id(arg: &[u8], seq: u64) -> Result<StreamId, RespValue> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|x| StreamId::parse(x, seq))
+        .ok_or_else(|| RespValue::err("Invalid stream ID specified as stream command argument"))
+}
+
+/// Bound of an ID range, `-` and `+` standing for the smallest and greatest IDs.
+fn parse_range_id(arg: &[u8], seq: u64) -> Result<StreamId, RespValue> {
+    match arg {
+        b"-" => Ok(StreamId::MIN),
+        b"+" => Ok(StreamId::MAX),
+        _ => parse_stream_id(arg, seq),
+    }
+}
+
+fn wrong_args(name: &str) -> RespValue {
+    RespValue::err(&format!("wrong number of arguments for '{}' command", name.to_lowercase()))
+}
+
+/// Converts a relative or absolute expiration in milliseconds into a TTL,
+/// deadlines in the past resolve to zero so the key expires right away.
+fn ttl_from_millis(ms: i64, absolute: bool) -> Duration {
+    let ms = if absolute { ms - now_millis() as i64 } else { ms };
+    Duration::from_millis(ms.max(0) as u64)
+}
+
+async fn delete_key(key: &str, cc: &Arc<Storage>, wal: &WalWritter) -> bool {
+    match cc.delete(key).await {
+        Some(_) => {
+            wal.write(&proto::CommandMessage::DELETE(key.to_owned()).into());
+            true
+        }
+        None => false,
+    }
+}
+
+async fn handle_command(
+    args: Vec<Vec<u8>>,
+    session: &mut Session,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Authenticator,
+) -> RespValue {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let args = &args[1..];
+
+    if session.user.is_none() && !matches!(name.as_str(), "AUTH" | "HELLO" | "QUIT") {
+        return RespValue::Error("NOAUTH Authentication required.".to_owned());
+    }
+    // RESP3 clients tell pushed messages apart from replies, RESP2 ones can't.
+    if !session.subscriptions.is_empty()
+        && session.version < 3
+        && !matches!(name.as_str(), "SUBSCRIBE" | "UNSUBSCRIBE" | "PING" | "QUIT")
+    {
+        return RespValue::err(&format!(
+            "Can't execute '{}': only SUBSCRIBE / UNSUBSCRIBE / PING / QUIT are allowed in this context",
+            name.to_lowercase()
+        ));
+    }
+    let user = session.user.clone().unwrap_or_default();
+    if let Some((command, keys)) = acl_command(&name, args) {
+        let keys: Vec<String> = keys.iter().map(|x| key_of(x)).collect();
+        let keys: Vec<&str> = keys.iter().map(|x| x.as_str()).collect();
+        if let Err(e) = auth.check(&user, command, &keys) {
+            return RespValue::Error(e);
+        }
+    }
+
+    let result = match name.as_str() {
+        "PING" => match args.len() {
+            0 => Ok(RespValue::Simple("PONG".to_owned())),
+            1 => Ok(RespValue::Bulk(args[0].clone())),
+            _ => Err(wrong_args(&name)),
+        },
+        "ECHO" if args.len() == 1 => Ok(RespValue::Bulk(args[0].clone())),
+        "QUIT" => {
+            session.closing = true;
+            Ok(RespValue::ok())
+        }
+        "AUTH" => match args {
+            [password] => authenticate(None, password, session, auth),
+            [username, password] => authenticate(Some(username), password, session, auth),
+            _ => Err(wrong_args(&name)),
+        },
+        "HELLO" => hello(args, session, auth),
+        "SELECT" if args.len() == 1 => match parse_int(&args[0]) {
+            Ok(0) => Ok(RespValue::ok()),
+            Ok(_) => Err(RespValue::err("DB index is out of range")),
+            Err(e) => Err(e),
+        },
+        "COMMAND" => Ok(RespValue::Array(Vec::new())),
+        "CLIENT" => Ok(RespValue::ok()),
+        "INFO" => info(cc).await,
+        "DBSIZE" => Ok(RespValue::Integer(
+            auth.filter_keys(&user, cc.keys().await.unwrap_or_default()).len() as i64,
+        )),
+        "GET" if args.len() == 1 => match cc.read(&key_of(&args[0])).await {
+            Some(data) => Ok(RespValue::Bulk(data)),
+            None if cc.kind(&key_of(&args[0])).await.is_some() => Err(RespValue::Error(ValueError::WrongType.to_string())),
+            None => Ok(RespValue::Null),
+        },
+        "MGET" if !args.is_empty() => {
+            let keys: Vec<String> = args.iter().map(|x| key_of(x)).collect();
+            Ok(RespValue::Array(
+                cc.read_many(&keys)
+                    .await
+                    .into_iter()
+                    .map(|x| match x {
+                        Some(data) => RespValue::Bulk(data),
+                        None => RespValue::Null,
+                    })
+                    .collect(),
+            ))
+        }
+        "SET" if args.len() >= 2 => set(args, cc, wal).await,
+        "GETSET" if args.len() == 2 => {
+            let key = key_of(&args[0]);
+            let (written, previous) = cc.put_if(&key, args[1].clone(), None, WriteCondition::Always).await;
+            if let Some(item) = written.as_ref() {
+                wal.write_entries([(key.as_str(), item)]);
+            }
+            Ok(previous.map(RespValue::Bulk).unwrap_or(RespValue::Null))
+        }
+        "SETNX" if args.len() == 2 => {
+            let written = store(&args[0], &args[1], None, WriteCondition::IfAbsent, cc, wal).await;
+            Ok(RespValue::Integer(written as i64))
+        }
+        "SETEX" | "PSETEX" if args.len() == 3 => match parse_int(&args[1]) {
+            Ok(x) if x > 0 => {
+                let ms = if name == "SETEX" { x.saturating_mul(1000) } else { x };
+                let exp = Some(ttl_from_millis(ms, false));
+                store(&args[0], &args[2], exp, WriteCondition::Always, cc, wal).await;
+                Ok(RespValue::ok())
+            }
+            Ok(_) => Err(RespValue::err(&format!("invalid expire time in '{}' command", name.to_lowercase()))),
+            Err(e) => Err(e),
+        },
+        "INCR" | "DECR" if args.len() == 1 => incr(&name, &args[0], b"1", cc, wal).await,
+        "INCRBY" | "DECRBY" | "INCRBYFLOAT" if args.len() == 2 => incr(&name, &args[0], &args[1], cc, wal).await,
+        "MSET" if !args.is_empty() && args.len().is_multiple_of(2) => {
+            let entries: Vec<_> = args
+                .chunks(2)
+                .map(|pair| (key_of(&pair[0]), pair[1].clone(), None))
+                .collect();
+            let keys: Vec<String> = entries.iter().map(|x| x.0.clone()).collect();
+            let items = cc.write_many(entries).await;
+            wal.write_entries(keys.iter().map(|x| x.as_str()).zip(items.iter()));
+            Ok(RespValue::ok())
+        }
+        "DEL" | "UNLINK" if !args.is_empty() => {
+            let keys: Vec<String> = args.iter().map(|x| key_of(x)).collect();
+            let deleted: Vec<String> = keys
+                .iter()
+                .zip(cc.delete_many(&keys).await)
+                .filter(|(_, x)| *x)
+                .map(|(key, _)| key.clone())
+                .collect();
+            let count = deleted.len() as i64;
+            if count > 0 {
+                wal.write(&proto::CommandMessage::MDEL(deleted).into());
+            }
+            Ok(RespValue::Integer(count))
+        }
+        "EXISTS" if !args.is_empty() => {
+            let mut count = 0;
+            for key in args {
+                if cc.kind(&key_of(key)).await.is_some() {
+                    count += 1;
+                }
+            }
+            Ok(RespValue::Integer(count))
+        }
+        "TYPE" if args.len() == 1 => Ok(RespValue::Simple(
+            cc.kind(&key_of(&args[0])).await.unwrap_or("none").to_owned(),
+        )),
+        "LPUSH" | "RPUSH" if args.len() >= 2 => list(&name, args, cc, wal).await,
+        "LPOP" | "RPOP" if args.len() <= 2 => list(&name, args, cc, wal).await,
+        "LRANGE" | "LTRIM" if args.len() == 3 => list(&name, args, cc, wal).await,
+        "LLEN" if args.len() == 1 => list(&name, args, cc, wal).await,
+        "HSET" if args.len() >= 3 && args.len() % 2 == 1 => hash(&name, args, cc, wal).await,
+        "HDEL" if args.len() >= 2 => hash(&name, args, cc, wal).await,
+        "HINCRBY" | "HINCRBYFLOAT" if args.len() == 3 => hash(&name, args, cc, wal).await,
+        "HGET" if args.len() == 2 => hash(&name, args, cc, wal).await,
+        "HGETALL" if args.len() == 1 => hash(&name, args, cc, wal).await,
+        "SADD" | "SREM" if args.len() >= 2 => sets(&name, args, cc, wal).await,
+        "SISMEMBER" if args.len() == 2 => sets(&name, args, cc, wal).await,
+        "SMEMBERS" if args.len() == 1 => sets(&name, args, cc, wal).await,
+        "SINTER" | "SUNION" if !args.is_empty() => sets(&name, args, cc, wal).await,
+        "ZADD" if args.len() >= 3 && args.len() % 2 == 1 => sorted_set(&name, args, cc, wal).await,
+        "ZINCRBY" | "ZREMRANGEBYRANK" | "ZREMRANGEBYSCORE" if args.len() == 3 => sorted_set(&name, args, cc, wal).await,
+        "ZRANK" | "ZREVRANK" | "ZSCORE" if args.len() == 2 => sorted_set(&name, args, cc, wal).await,
+        "ZRANGE" | "ZREVRANGE" if args.len() == 3 || args.len() == 4 => sorted_set(&name, args, cc, wal).await,
+        "ZRANGEBYSCORE" if args.len() >= 3 => sorted_set(&name, args, cc, wal).await,
+        "ZREM" if args.len() >= 2 => sorted_set(&name, args, cc, wal).await,
+        "ZCARD" if args.len() == 1 => sorted_set(&name, args, cc, wal).await,
+        "XADD" if args.len() >= 4 => stream(&name, args, cc, wal).await,
+        "XLEN" if args.len() == 1 => stream(&name, args, cc, wal).await,
+        "XRANGE" | "XREVRANGE" if args.len() == 3 || args.len() == 5 => stream(&name, args, cc, wal).await,
+        "XTRIM" if args.len() >= 3 => stream(&name, args, cc, wal).await,
+        "XREAD" if args.len() >= 3 => stream(&name, args, cc, wal).await,
+        "XGROUP" if args.len() >= 3 => stream_group(&name, args, cc, wal).await,
+        "XREADGROUP" if args.len() >= 6 => stream_group(&name, args, cc, wal).await,
+        "XACK" if args.len() >= 3 => stream_group(&name, args, cc, wal).await,
+        "XPENDING" if args.len() >= 2 => stream_group(&name, args, cc, wal).await,
+        "PFADD" if args.len() >= 2 => hyperloglog(&name, args, cc, wal).await,
+        "PFCOUNT" if !args.is_empty() => hyperloglog(&name, args, cc, wal).await,
+        "PFMERGE" if !args.is_empty() => hyperloglog(&name, args, cc, wal).await,
+        "BF.RESERVE" if args.len() == 3 => bloom(&name, args, cc, wal).await,
+        "BF.ADD" | "BF.EXISTS" if args.len() == 2 => bloom(&name, args, cc, wal).await,
+        "BF.MADD" | "BF.MEXISTS" if args.len() >= 2 => bloom(&name, args, cc, wal).await,
+        "CMS.INITBYDIM" | "CMS.INITBYPROB" if args.len() == 3 => sketch(&name, args, cc, wal).await,
+        "CMS.INCRBY" if args.len() >= 3 && args.len() % 2 == 1 => sketch(&name, args, cc, wal).await,
+        "CMS.QUERY" if args.len() >= 2 => sketch(&name, args, cc, wal).await,
+        "TOPK.RESERVE" if args.len() == 2 || args.len() == 4 => sketch(&name, args, cc, wal).await,
+        "TOPK.ADD" | "TOPK.QUERY" if args.len() >= 2 => sketch(&name, args, cc, wal).await,
+        "TOPK.LIST" if args.len() == 1 || args.len() == 2 => sketch(&name, args, cc, wal).await,
+        "JSON.SET" if args.len() == 3 || args.len() == 4 => json(&name, args, cc, wal).await,
+        "JSON.GET" if !args.is_empty() => json(&name, args, cc, wal).await,
+        "JSON.DEL" | "JSON.FORGET" if args.len() == 1 || args.len() == 2 => json(&name, args, cc, wal).await,
+        "JSON.ARRAPPEND" if args.len() >= 3 => json(&name, args, cc, wal).await,
+        "JSON.NUMINCRBY" if args.len() == 3 => json(&name, args, cc, wal).await,
+        "IDX.CREATE" if args.len() == 3 => index(&name, args, cc, wal, auth, &user).await,
+        "IDX.DROP" if args.len() == 1 => index(&name, args, cc, wal, auth, &user).await,
+        "IDX.LIST" if args.is_empty() => index(&name, args, cc, wal, auth, &user).await,
+        "IDX.GET" if args.len() == 2 || args.len() == 4 => index(&name, args, cc, wal, auth, &user).await,
+        "IDX.RANGE" if args.len() == 3 || args.len() == 5 => index(&name, args, cc, wal, auth, &user).await,
+        "TS.CREATE" if args.len() == 1 || args.len() == 3 => time_series(&name, args, cc, wal).await,
+        "TS.ADD" if args.len() == 3 || args.len() == 5 => time_series(&name, args, cc, wal).await,
+        "TS.GET" | "TS.INFO" if args.len() == 1 => time_series(&name, args, cc, wal).await,
+        "TS.RANGE" if args.len() >= 3 => time_series(&name, args, cc, wal).await,
+        "TS.CREATERULE" if args.len() == 5 => time_series(&name, args, cc, wal).await,
+        "TS.DELETERULE" if args.len() == 2 => time_series(&name, args, cc, wal).await,
+        "Q.CREATE" if args.len() % 2 == 1 => queue(&name, args, cc, wal).await,
+        "Q.PUSH" if args.len() >= 2 => queue(&name, args, cc, wal).await,
+        "Q.PUSHDELAYED" if args.len() >= 3 => queue(&name, args, cc, wal).await,
+        "Q.POP" if args.len() == 1 || args.len() == 2 => queue(&name, args, cc, wal).await,
+        "Q.ACK" | "Q.NACK" if args.len() >= 2 => queue(&name, args, cc, wal).await,
+        "Q.INFO" if args.len() == 1 => queue(&name, args, cc, wal).await,
+        "SCHED.ADD" if args.len() == 5 => schedule(&name, args, cc, wal).await,
+        "SCHED.CANCEL" if args.len() == 2 => schedule(&name, args, cc, wal).await,
+        "SCHED.LIST" if args.len() == 1 => schedule(&name, args, cc, wal).await,
+        "PUBLISH" if args.len() == 2 => Ok(RespValue::Integer(cc.publish(&key_of(&args[0]), args[1].clone()) as i64)),
+        "SUBSCRIBE" if !args.is_empty() => Ok(subscribe(args, session, cc)),
+        "UNSUBSCRIBE" => Ok(unsubscribe(args, session)),
+        "KEYS" if args.len() == 1 => {
+            let keys = auth.filter_keys(&user, cc.keys().await.unwrap_or_default());
+            Ok(RespValue::Array(
+                keys.into_iter()
+                    .filter(|x| glob::matches(&args[0], x.as_bytes()))
+                    .map(|x| RespValue::Bulk(x.into_bytes()))
+                    .collect(),
+            ))
+        }
+        "SCAN" if !args.is_empty() => scan(args, cc, auth, &user).await,
+        "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" if args.len() == 2 => {
+            match parse_int(&args[1]) {
+                Ok(x) => {
+                    let ms = if name.starts_with('P') { x } else { x.saturating_mul(1000) };
+                    let ttl = ttl_from_millis(ms, name.ends_with("AT"));
+                    Ok(RespValue::Integer(expire(&key_of(&args[0]), Some(ttl), cc, wal).await as i64))
+                }
+                Err(e) => Err(e),
+            }
+        }
+        "PERSIST" if args.len() == 1 => {
+            let key = key_of(&args[0]);
+            match cc.ttl(&key).await {
+                Some(Some(_)) => Ok(RespValue::Integer(expire(&key, None, cc, wal).await as i64)),
+                _ => Ok(RespValue::Integer(0)),
+            }
+        }
+        "TTL" | "PTTL" if args.len() == 1 => Ok(RespValue::Integer(
+            match cc.ttl(&key_of(&args[0])).await {
+                None => -2,
+                Some(None) => -1,
+                Some(Some(x)) if name == "TTL" => (x.as_millis() as i64 + 500) / 1000,
+                Some(Some(x)) => x.as_millis() as i64,
+            },
+        )),
+        "FLUSHDB" | "FLUSHALL" => {
+            for key in auth.filter_keys(&user, cc.keys().await.unwrap_or_default()) {
+                delete_key(&key, cc, wal).await;
+            }
+            Ok(RespValue::ok())
+        }
+        "ACL" if !args.is_empty() => acl(args, &user, auth),
+        "ECHO" | "SELECT" | "GET" | "MGET" | "SET" | "GETSET" | "SETNX" | "SETEX" | "PSETEX"
+        | "MSET" | "DEL" | "UNLINK" | "EXISTS" | "TYPE" | "KEYS" | "EXPIRE" | "PEXPIRE"
+        | "EXPIREAT" | "PEXPIREAT" | "PERSIST" | "TTL" | "PTTL" | "ACL" | "SCAN" | "LPUSH" | "RPUSH" | "LPOP"
+        | "RPOP" | "LRANGE" | "LTRIM" | "LLEN" | "HSET" | "HGET" | "HDEL" | "HGETALL" | "HINCRBY" | "HINCRBYFLOAT"
+        | "SADD" | "SREM" | "SISMEMBER" | "SMEMBERS" | "SINTER" | "SUNION" | "ZADD" | "ZINCRBY" | "ZRANK"
+        | "ZREVRANK" | "ZRANGE" | "ZREVRANGE" | "ZRANGEBYSCORE" | "ZREM" | "ZREMRANGEBYRANK" | "ZREMRANGEBYSCORE"
+        | "ZSCORE" | "ZCARD" | "XADD" | "XLEN" | "XRANGE" | "XREVRANGE" | "XTRIM" | "XREAD" | "XGROUP"
+        | "XREADGROUP" | "XACK" | "XPENDING" | "PFADD" | "PFCOUNT" | "PFMERGE" | "BF.RESERVE" | "BF.ADD"
+        | "BF.MADD" | "BF.EXISTS" | "BF.MEXISTS" | "CMS.INITBYDIM" | "CMS.INITBYPROB" | "CMS.INCRBY" | "CMS.QUERY"
+        | "TOPK.RESERVE" | "TOPK.ADD" | "TOPK.QUERY" | "TOPK.LIST" | "JSON.SET" | "JSON.GET" | "JSON.DEL"
+        | "JSON.FORGET" | "JSON.ARRAPPEND" | "JSON.NUMINCRBY" | "IDX.CREATE" | "IDX.DROP" | "IDX.LIST" | "IDX.GET"
+        | "IDX.RANGE" | "TS.CREATE" | "TS.ADD" | "TS.GET" | "TS.INFO" | "TS.RANGE" | "TS.CREATERULE"
+        | "TS.DELETERULE" | "Q.CREATE" | "Q.PUSH" | "Q.PUSHDELAYED" | "Q.POP" | "Q.ACK" | "Q.NACK" | "Q.INFO"
+        | "SCHED.ADD" | "SCHED.CANCEL" | "SCHED.LIST" | "PUBLISH" | "SUBSCRIBE" => {
+            Err(wrong_args(&name))
+        }
+        _ => Err(RespValue::err(&format!("unknown command '{}'", name.to_lowercase()))),
+    };
+
+    result.unwrap_or_else(|e| e)
+}
+
+async fn store(
+    key: &[u8],
+    data: &[u8],
+    exp: Option<Duration>,
+    cond: WriteCondition,
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+) -> bool {
+    let key = key_of(key);
+    match cc.put_if(&key, data.to_vec(), exp, cond).await {
+        (Some(item), _) => {
+            wal.write_entries([(key.as_str(), &item)]);
+            true
+        }
+        (None, _) => false,
+    }
+}
+
+async fn incr(name: &str, key: &[u8], delta: &[u8], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(key);
+    let status = match name {
+        "INCRBYFLOAT" => {
+            let delta = std::str::from_utf8(delta)
+                .ok()
+                .and_then(|x| x.parse::<f64>().ok())
+                .filter(|x| x.is_finite())
+                .ok_or_else(|| RespValue::err("value is not a valid float"))?;
+            cc.incr_by_float(&key, delta, None).await
+        }
+        "DECR" | "DECRBY" => match parse_int(delta)?.checked_neg() {
+            Some(delta) => cc.incr_by(&key, delta, None).await,
+            None => return Err(RespValue::err("decrement would overflow")),
+        },
+        _ => cc.incr_by(&key, parse_int(delta)?, None).await,
+    };
+
+    match status {
+        StoreStatus::Stored(item) => {
+            wal.write_entries([(key.as_str(), &item)]);
+            match name {
+                "INCRBYFLOAT" => Ok(RespValue::Bulk(item.value)),
+                _ => parse_int(&item.value).map(RespValue::Integer),
+            }
+        }
+        _ if name == "INCRBYFLOAT" => Err(RespValue::err("value is not a valid float")),
+        _ => Err(RespValue::err("value is not an integer or out of range")),
+    }
+}
+
+async fn expire(key: &str, exp: Option<Duration>, cc: &Arc<Storage>, wal: &WalWritter) -> bool {
+    if exp == Some(Duration::ZERO) {
+        return delete_key(key, cc, wal).await;
+    }
+
+    let updated = cc.set_expiration(key, exp).await;
+    if updated {
+        wal.write(&proto::CommandMessage::EXPIRE(key.to_owned(), exp).into());
+    }
+
+    updated
+}
+
+async fn set(args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let mut cond = WriteCondition::Always;
+    let mut exp = None;
+    let mut get = false;
+
+    let mut opts = args[2..].iter();
+    while let Some(opt) = opts.next() {
+        let opt = String::from_utf8_lossy(opt).to_uppercase();
+        match opt.as_str() {
+            "NX" if cond == WriteCondition::Always => cond = WriteCondition::IfAbsent,
+            "XX" if cond == WriteCondition::Always => cond = WriteCondition::IfPresent,
+            "GET" => get = true,
+            "EX" | "PX" | "EXAT" | "PXAT" if exp.is_none() => {
+                let value = opts.next().ok_or_else(|| RespValue::err("syntax error"))?;
+                let value = parse_int(value)?;
+                if value <= 0 {
+                    return Err(RespValue::err("invalid expire time in 'set' command"));
+                }
+                let ms = if opt.starts_with('P') { value } else { value.saturating_mul(1000) };
+                exp = Some(ttl_from_millis(ms, opt.ends_with("AT")));
+            }
+            _ => return Err(RespValue::err("syntax error")),
+        }
+    }
+
+    let key = key_of(&args[0]);
+    let (written, previous) = cc.put_if(&key, args[1].clone(), exp, cond).await;
+    if let Some(item) = written.as_ref() {
+        wal.write_entries([(key.as_str(), item)]);
+    }
+
+    Ok(match (get, written.is_some()) {
+        (true, _) => previous.map(RespValue::Bulk).unwrap_or(RespValue::Null),
+        (false, true) => RespValue::ok(),
+        (false, false) => RespValue::Null,
+    })
+}
+
+/// Maps a command onto the ACL command it is checked as, with its key arguments.
+fn acl_command<'a>(name: &str, args: &'a [Vec<u8>]) -> Option<(&'static str, Vec<&'a Vec<u8>>)> {
+    let first = args.iter().take(1).collect();
+    match name {
+        "GET" | "TYPE" | "TTL" | "PTTL" => Some(("get", first)),
+        "MGET" | "EXISTS" => Some(("get", args.iter().collect())),
+        "SET" | "SETNX" | "SETEX" | "PSETEX" | "GETSET" => Some(("put", first)),
+        "INCR" | "DECR" | "INCRBY" | "DECRBY" | "INCRBYFLOAT" => Some(("put", first)),
+        "LRANGE" | "LLEN" => Some(("get", first)),
+        "LPUSH" | "RPUSH" | "LPOP" | "RPOP" | "LTRIM" => Some(("put", first)),
+        "HGET" | "HGETALL" => Some(("get", first)),
+        "HSET" | "HDEL" | "HINCRBY" | "HINCRBYFLOAT" => Some(("put", first)),
+        "SISMEMBER" | "SMEMBERS" => Some(("get", first)),
+        "SINTER" | "SUNION" => Some(("get", args.iter().collect())),
+        "SADD" | "SREM" => Some(("put", first)),
+        "ZRANK" | "ZREVRANK" | "ZRANGE" | "ZREVRANGE" | "ZRANGEBYSCORE" | "ZSCORE" | "ZCARD" => Some(("get", first)),
+        "ZADD" | "ZINCRBY" | "ZREM" | "ZREMRANGEBYRANK" | "ZREMRANGEBYSCORE" => Some(("put", first)),
+        "XLEN" | "XRANGE" | "XREVRANGE" | "XPENDING" => Some(("get", first)),
+        "XREAD" => Some(("get", read_streams(args).map(|(keys, _)| keys.iter().collect()).unwrap_or_default())),
+        "XADD" | "XTRIM" | "XACK" => Some(("put", first)),
+        "XGROUP" => Some(("put", args.iter().skip(1).take(1).collect())),
+        "XREADGROUP" => Some(("put", read_streams(args).map(|(keys, _)| keys.iter().collect()).unwrap_or_default())),
+        "PFCOUNT" => Some(("get", args.iter().collect())),
+        "PFADD" => Some(("put", first)),
+        "PFMERGE" => Some(("put", args.iter().collect())),
+        "BF.EXISTS" | "BF.MEXISTS" | "CMS.QUERY" | "TOPK.QUERY" | "TOPK.LIST" => Some(("get", first)),
+        "BF.RESERVE" | "BF.ADD" | "BF.MADD" | "CMS.INITBYDIM" | "CMS.INITBYPROB" | "CMS.INCRBY" | "TOPK.RESERVE"
+        | "TOPK.ADD" => Some(("put", first)),
+        "JSON.GET" => Some(("get", first)),
+        "JSON.SET" | "JSON.DEL" | "JSON.FORGET" | "JSON.ARRAPPEND" | "JSON.NUMINCRBY" => Some(("put", first)),
+        "IDX.CREATE" | "IDX.DROP" => Some(("index", Vec::new())),
+        "IDX.LIST" => Some(("keys", Vec::new())),
+        "IDX.GET" | "IDX.RANGE" => Some(("get", Vec::new())),
+        "TS.GET" | "TS.INFO" | "TS.RANGE" => Some(("get", first)),
+        "TS.CREATE" | "TS.ADD" | "TS.DELETERULE" => Some(("put", first)),
+        "TS.CREATERULE" => Some(("put", args.iter().take(2).collect())),
+        "Q.INFO" => Some(("get", first)),
+        "Q.PUSH" | "Q.PUSHDELAYED" | "Q.POP" | "Q.ACK" | "Q.NACK" => Some(("put", first)),
+        "Q.CREATE" => {
+            let dead_letter = args.windows(2).filter(|x| x[0].eq_ignore_ascii_case(b"DEADLETTER")).map(|x| &x[1]);
+            Some(("put", args.iter().take(1).chain(dead_letter).collect()))
+        }
+        "SCHED.LIST" => Some(("get", first)),
+        "SCHED.CANCEL" => Some(("put", first)),
+        "SCHED.ADD" => {
+            let channel = args.get(2).is_some_and(|x| x.eq_ignore_ascii_case(b"CHANNEL"));
+            Some(("put", args.iter().take(1).chain(args.get(3).filter(|_| !channel)).collect()))
+        }
+        "PUBLISH" => Some(("publish", Vec::new())),
+        "SUBSCRIBE" => Some(("subscribe", Vec::new())),
+        "MSET" => Some(("put", args.iter().step_by(2).collect())),
+        "DEL" | "UNLINK" => Some(("delete", args.iter().collect())),
+        "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" | "PERSIST" => Some(("expire", first)),
+        "KEYS" | "SCAN" => Some(("keys", Vec::new())),
+        "DBSIZE" | "INFO" => Some(("stats", Vec::new())),
+        "FLUSHDB" | "FLUSHALL" => Some(("flush", Vec::new())),
+        "ACL" => Some(("acl", Vec::new())),
+        _ => None,
+    }
+}
+
+/// List commands, the key is the first argument.
+async fn list(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+    let bulks = |values: Vec<Vec<u8>>| RespValue::Array(values.into_iter().map(RespValue::Bulk).collect());
+
+    match name {
+        "LPUSH" | "RPUSH" => {
+            let values = args[1..].to_vec();
+            let (len, change) = cc.list_push(&key, values.clone(), name == "LPUSH").await.map_err(value_error)?;
+            let command = match name {
+                "LPUSH" => proto::CommandMessage::LPUSH(key, values),
+                _ => proto::CommandMessage::RPUSH(key, values),
+            };
+            wal.write_applied(&command, change);
+            Ok(RespValue::Integer(len as i64))
+        }
+        "LPOP" | "RPOP" => {
+            let count = match args.get(1) {
+                Some(x) => match parse_int(x)? {
+                    x if x >= 0 => Some(x as usize),
+                    _ => return Err(RespValue::err("value is out of range, must be positive")),
+                },
+                None => None,
+            };
+            let exists = cc.kind(&key).await.is_some();
+            let (values, change) = cc.list_pop(&key, count.unwrap_or(1), name == "LPOP").await.map_err(value_error)?;
+            let command = match name {
+                "LPOP" => proto::CommandMessage::LPOP(key, count.unwrap_or(1)),
+                _ => proto::CommandMessage::RPOP(key, count.unwrap_or(1)),
+            };
+            wal.write_applied(&command, change);
+            Ok(match count {
+                Some(_) if exists => bulks(values),
+                Some(_) => RespValue::NullArray,
+                None => values.into_iter().next().map(RespValue::Bulk).unwrap_or(RespValue::Null),
+            })
+        }
+        "LRANGE" => {
+            let values = cc.list_range(&key, parse_int(&args[1])?, parse_int(&args[2])?).await.map_err(value_error)?;
+            Ok(bulks(values))
+        }
+        "LTRIM" => {
+            let (start, stop) = (parse_int(&args[1])?, parse_int(&args[2])?);
+            let change = cc.list_trim(&key, start, stop).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::LTRIM(key, start, stop), change);
+            Ok(RespValue::ok())
+        }
+        _ => Ok(RespValue::Integer(cc.list_len(&key).await.map_err(value_error)? as i64)),
+    }
+}
+
+/// Hash commands, the key is the first argument and fields are utf-8.
+async fn hash(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+
+    match name {
+        "HSET" => {
+            let fields: Vec<(String, Vec<u8>)> = args[1..].chunks(2).map(|x| (key_of(&x[0]), x[1].clone())).collect();
+            let (added, change) = cc.hash_set(&key, fields.clone()).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::HSET(key, fields), change);
+            Ok(RespValue::Integer(added as i64))
+        }
+        "HDEL" => {
+            let fields: Vec<String> = args[1..].iter().map(|x| key_of(x)).collect();
+            let (removed, change) = cc.hash_delete(&key, &fields).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::HDEL(key, fields), change);
+            Ok(RespValue::Integer(removed as i64))
+        }
+        "HINCRBY" => {
+            let (field, delta) = (key_of(&args[1]), parse_int(&args[2])?);
+            let (value, change) = cc.hash_incr_by(&key, &field, delta).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::HINCRBY(key, field, delta), change);
+            Ok(RespValue::Integer(value))
+        }
+        "HINCRBYFLOAT" => {
+            let field = key_of(&args[1]);
+            let delta = std::str::from_utf8(&args[2])
+                .ok()
+                .and_then(|x| x.parse::<f64>().ok())
+                .filter(|x| x.is_finite())
+                .ok_or_else(|| RespValue::err("value is not a valid float"))?;
+            let (value, change) = cc.hash_incr_by_float(&key, &field, delta).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::HINCRBYFLOAT(key, field, delta), change);
+            Ok(RespValue::bulk_str(&value.to_string()))
+        }
+        "HGET" => Ok(match cc.hash_get(&key, &key_of(&args[1])).await.map_err(value_error)? {
+            Some(data) => RespValue::Bulk(data),
+            None => RespValue::Null,
+        }),
+        _ => {
+            let fields = cc.hash_get_all(&key).await.map_err(value_error)?;
+            Ok(RespValue::Array(
+                fields
+                    .into_iter()
+                    .flat_map(|(field, data)| [RespValue::Bulk(field.into_bytes()), RespValue::Bulk(data)])
+                    .collect(),
+            ))
+        }
+    }
+}
+
+/// Set commands, the key is the first argument except for `SINTER` and
+/// `SUNION` where every argument is a key.
+async fn sets(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+    let bulks = |values: Vec<Vec<u8>>| RespValue::Array(values.into_iter().map(RespValue::Bulk).collect());
+
+    match name {
+        "SADD" => {
+            let members = args[1..].to_vec();
+            let (added, change) = cc.set_add(&key, members.clone()).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::SADD(key, members), change);
+            Ok(RespValue::Integer(added as i64))
+        }
+        "SREM" => {
+            let members = args[1..].to_vec();
+            let (removed, change) = cc.set_remove(&key, &members).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::SREM(key, members), change);
+            Ok(RespValue::Integer(removed as i64))
+        }
+        "SISMEMBER" => Ok(RespValue::Integer(cc.set_contains(&key, &args[1]).await.map_err(value_error)? as i64)),
+        "SMEMBERS" => Ok(bulks(cc.set_members(&key).await.map_err(value_error)?)),
+        _ => {
+            let keys: Vec<String> = args.iter().map(|x| key_of(x)).collect();
+            let members = match name {
+                "SINTER" => cc.set_intersection(&keys).await,
+                _ => cc.set_union(&keys).await,
+            };
+            Ok(bulks(members.map_err(value_error)?))
+        }
+    }
+}
+
+/// Sorted set commands, the key is the first argument.
+async fn sorted_set(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+    let members = |members: Vec<(Vec<u8>, f64)>, scores: bool| {
+        RespValue::Array(
+            members
+                .into_iter()
+                .flat_map(|(member, score)| {
+                    let score = scores.then(|| RespValue::bulk_str(&score.to_string()));
+                    std::iter::once(RespValue::Bulk(member)).chain(score)
+                })
+                .collect(),
+        )
+    };
+    let with_scores = |arg: Option<&Vec<u8>>| match arg {
+        Some(x) if x.eq_ignore_ascii_case(b"WITHSCORES") => Ok(true),
+        Some(_) => Err(RespValue::err("syntax error")),
+        None => Ok(false),
+    };
+
+    match name {
+        "ZADD" => {
+            let members = args[1..]
+                .chunks(2)
+                .map(|x| Ok((parse_score(&x[0])?, x[1].clone())))
+                .collect::<Result<Vec<(f64, Vec<u8>)>, RespValue>>()?;
+            let (added, change) = cc.sorted_set_add(&key, members.clone()).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::ZADD(key, members), change);
+            Ok(RespValue::Integer(added as i64))
+        }
+        "ZINCRBY" => {
+            let (delta, member) = (parse_score(&args[1])?, args[2].clone());
+            let (score, change) = cc.sorted_set_incr_by(&key, member.clone(), delta).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::ZINCRBY(key, delta, member), change);
+            Ok(RespValue::bulk_str(&score.to_string()))
+        }
+        "ZREM" => {
+            let members = args[1..].to_vec();
+            let (removed, change) = cc.sorted_set_remove(&key, &members).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::ZREM(key, members), change);
+            Ok(RespValue::Integer(removed as i64))
+        }
+        "ZREMRANGEBYRANK" => {
+            let (start, stop) = (parse_int(&args[1])?, parse_int(&args[2])?);
+            let (removed, change) = cc.sorted_set_remove_range(&key, start, stop).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::ZREMRANGEBYRANK(key, start, stop), change);
+            Ok(RespValue::Integer(removed as i64))
+        }
+        "ZREMRANGEBYSCORE" => {
+            let (min, max) = (parse_score_bound(&args[1])?, parse_score_bound(&args[2])?);
+            let (removed, change) = cc.sorted_set_remove_by_score(&key, min, max).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::ZREMRANGEBYSCORE(key, min, max), change);
+            Ok(RespValue::Integer(removed as i64))
+        }
+        "ZRANK" | "ZREVRANK" => Ok(match cc.sorted_set_rank(&key, &args[1], name == "ZREVRANK").await.map_err(value_error)? {
+            Some(rank) => RespValue::Integer(rank as i64),
+            None => RespValue::Null,
+        }),
+        "ZRANGE" | "ZREVRANGE" => {
+            let scores = with_scores(args.get(3))?;
+            let (start, stop) = (parse_int(&args[1])?, parse_int(&args[2])?);
+            let range = cc.sorted_set_range(&key, start, stop, name == "ZREVRANGE").await.map_err(value_error)?;
+            Ok(members(range, scores))
+        }
+        "ZRANGEBYSCORE" => {
+            let (min, max) = (parse_score_bound(&args[1])?, parse_score_bound(&args[2])?);
+            let mut scores = false;
+            let mut limit = (0, None);
+            let mut opts = args[3..].iter();
+            while let Some(opt) = opts.next() {
+                match String::from_utf8_lossy(opt).to_uppercase().as_str() {
+                    "WITHSCORES" => scores = true,
+                    "LIMIT" => {
+                        let offset = parse_int(opts.next().ok_or_else(|| RespValue::err("syntax error"))?)?;
+                        let count = parse_int(opts.next().ok_or_else(|| RespValue::err("syntax error"))?)?;
+                        // A negative count returns every member after the offset.
+                        limit = (offset, usize::try_from(count).ok());
+                    }
+                    _ => return Err(RespValue::err("syntax error")),
+                }
+            }
+            let offset = match usize::try_from(limit.0) {
+                Ok(x) => x,
+                Err(_) => return Ok(RespValue::Array(Vec::new())),
+            };
+            let range = cc
+                .sorted_set_range_by_score(&key, min, max, offset, limit.1)
+                .await
+                .map_err(value_error)?;
+            Ok(members(range, scores))
+        }
+        "ZSCORE" => Ok(match cc.sorted_set_score(&key, &args[1]).await.map_err(value_error)? {
+            Some(score) => RespValue::bulk_str(&score.to_string()),
+            None => RespValue::Null,
+        }),
+        _ => Ok(RespValue::Integer(cc.sorted_set_len(&key).await.map_err(value_error)? as i64)),
+    }
+}
+
+fn stream_entries(entries: Vec<StreamEntry>) -> RespValue {
+    RespValue::Array(
+        entries
+            .into_iter()
+            .map(|(id, fields)| {
+                let fields = fields
+                    .into_iter()
+                    .flat_map(|(field, data)| [RespValue::Bulk(field.into_bytes()), RespValue::Bulk(data)])
+                    .collect();
+                RespValue::Array(vec![RespValue::bulk_str(&id.to_string()), RespValue::Array(fields)])
+            })
+            .collect(),
+    )
+}
+
+/// Keys of a stream read with the ID given for each.
+type ReadStreams<'a> = (&'a [Vec<u8>], &'a [Vec<u8>]);
+
+/// Keys and IDs following `STREAMS`, one ID for every key.
+fn read_streams(args: &[Vec<u8>]) -> Result<ReadStreams<'_>, RespValue> {
+    let streams = args
+        .iter()
+        .position(|x| x.eq_ignore_ascii_case(b"STREAMS"))
+        .map(|x| &args[x + 1..])
+        .filter(|x| !x.is_empty() && x.len().is_multiple_of(2))
+        .ok_or_else(|| RespValue::err("syntax error, every stream key needs an ID"))?;
+
+    Ok(streams.split_at(streams.len() / 2))
+}
+
+/// `[COUNT count] [BLOCK milliseconds]` options preceding `STREAMS`.
+fn read_options(args: &[Vec<u8>]) -> Result<(Option<usize>, Option<Duration>), RespValue> {
+    let mut count = None;
+    let mut block = None;
+    let mut opts = args.iter();
+    while let Some(opt) = opts.next() {
+        match String::from_utf8_lossy(opt).to_uppercase().as_str() {
+            "STREAMS" => break,
+            "COUNT" => {
+                let value = parse_int(opts.next().ok_or_else(|| RespValue::err("syntax error"))?)?;
+                count = Some(value.max(0) as usize);
+            }
+            "BLOCK" => match parse_int(opts.next().ok_or_else(|| RespValue::err("syntax error"))?)? {
+                x if x >= 0 => block = Some(Duration::from_millis(x as u64)),
+                _ => return Err(RespValue::err("timeout is negative")),
+            },
+            _ => return Err(RespValue::err("syntax error")),
+        }
+    }
+
+    Ok((count, block))
+}
+
+/// Stream commands without consumer groups, the key is the first argument
+/// except for `XREAD`.
+async fn stream(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+
+    match name {
+        "XADD" => {
+            // XADD key [MAXLEN [=|~] len] <*|id> field value [field value ...]
+            let mut rest = &args[1..];
+            let mut max_len = None;
+            if rest[0].eq_ignore_ascii_case(b"MAXLEN") {
+                rest = match rest.get(1).map(|x| x.as_slice()) {
+                    Some(b"=" | b"~") => &rest[2..],
+                    _ => &rest[1..],
+                };
+                let len = rest.first().ok_or_else(|| RespValue::err("syntax error"))?;
+                match parse_int(len)? {
+                    x if x >= 0 => max_len = Some(x as usize),
+                    _ => return Err(RespValue::err("The MAXLEN argument must be >= 0.")),
+                }
+                rest = &rest[1..];
+            }
+            if rest.len() < 3 || rest.len().is_multiple_of(2) {
+                return Err(wrong_args(name));
+            }
+            let id = match rest[0].as_slice() {
+                b"*" => None,
+                x => Some(parse_stream_id(x, 0)?),
+            };
+            let fields: Vec<(String, Vec<u8>)> = rest[1..].chunks(2).map(|x| (key_of(&x[0]), x[1].clone())).collect();
+            let (id, change) = cc.stream_add(&key, id, fields.clone(), max_len).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::XADD(key, Some(id), fields, max_len), change);
+            Ok(RespValue::bulk_str(&id.to_string()))
+        }
+        "XTRIM" => {
+            // XTRIM key MAXLEN [=|~] len
+            let len = match (args.len(), args[1].eq_ignore_ascii_case(b"MAXLEN")) {
+                (3, true) => &args[2],
+                (4, true) if args[2] == b"=" || args[2] == b"~" => &args[3],
+                _ => return Err(RespValue::err("syntax error")),
+            };
+            let max_len = usize::try_from(parse_int(len)?).map_err(|_| RespValue::err("The MAXLEN argument must be >= 0."))?;
+            let (removed, change) = cc.stream_trim(&key, max_len).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::XTRIM(key, max_len), change);
+            Ok(RespValue::Integer(removed as i64))
+        }
+        "XRANGE" | "XREVRANGE" => {
+            let (start, end) = match name {
+                "XRANGE" => (&args[1], &args[2]),
+                _ => (&args[2], &args[1]),
+            };
+            let (start, end) = (parse_range_id(start, 0)?, parse_range_id(end, u64::MAX)?);
+            let count = match args.get(3) {
+                Some(x) if x.eq_ignore_ascii_case(b"COUNT") => Some(parse_int(&args[4])?.max(0) as usize),
+                Some(_) => return Err(RespValue::err("syntax error")),
+                None => None,
+            };
+            let entries = cc.stream_range(&key, start, end, count, name == "XREVRANGE").await.map_err(value_error)?;
+            Ok(stream_entries(entries))
+        }
+        "XREAD" => {
+            // XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]
+            let (count, block) = read_options(args)?;
+            let (keys, ids) = read_streams(args)?;
+            let streams = keys
+                .iter()
+                .zip(ids)
+                .map(|(key, id)| match id.as_slice() {
+                    b"$" => Ok((key_of(key), None)),
+                    x => Ok((key_of(key), Some(parse_stream_id(x, 0)?))),
+                })
+                .collect::<Result<Vec<(String, Option<StreamId>)>, RespValue>>()?;
+            let found = cc.stream_read(streams, count, block).await.map_err(value_error)?;
+            if found.is_empty() {
+                return Ok(RespValue::NullArray);
+            }
+            Ok(RespValue::Array(
+                found
+                    .into_iter()
+                    .map(|(key, entries)| RespValue::Array(vec![RespValue::Bulk(key.into_bytes()), stream_entries(entries)]))
+                    .collect(),
+            ))
+        }
+        _ => Ok(RespValue::Integer(cc.stream_len(&key).await.map_err(value_error)? as i64)),
+    }
+}
+
+/// Consumer group commands of streams.
+async fn stream_group(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+
+    match name {
+        "XGROUP" => {
+            // XGROUP CREATE key group <id|$> [MKSTREAM] | XGROUP DESTROY key group
+            let (key, group) = (key_of(&args[1]), key_of(&args[2]));
+            match String::from_utf8_lossy(&args[0]).to_uppercase().as_str() {
+                "CREATE" if args.len() == 4 || args.len() == 5 => {
+                    let create = match args.get(4) {
+                        Some(x) if x.eq_ignore_ascii_case(b"MKSTREAM") => true,
+                        Some(_) => return Err(RespValue::err("syntax error")),
+                        None => false,
+                    };
+                    let id = match args[3].as_slice() {
+                        b"$" => None,
+                        x => Some(parse_stream_id(x, 0)?),
+                    };
+                    let (id, change) = cc.stream_group_create(&key, &group, id, create).await.map_err(value_error)?;
+                    wal.write_applied(&proto::CommandMessage::XGROUPCREATE(key, group, Some(id), create), change);
+                    Ok(RespValue::ok())
+                }
+                "DESTROY" if args.len() == 3 => {
+                    let (removed, change) = cc.stream_group_destroy(&key, &group).await.map_err(value_error)?;
+                    wal.write_applied(&proto::CommandMessage::XGROUPDESTROY(key, group), change);
+                    Ok(RespValue::Integer(removed as i64))
+                }
+                "CREATE" | "DESTROY" => Err(wrong_args("xgroup")),
+                _ => Err(RespValue::err("unknown XGROUP subcommand")),
+            }
+        }
+        "XREADGROUP" => {
+            // XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] STREAMS key id
+            if !args[0].eq_ignore_ascii_case(b"GROUP") {
+                return Err(RespValue::err("syntax error"));
+            }
+            let (group, consumer) = (key_of(&args[1]), key_of(&args[2]));
+            let (count, block) = read_options(&args[3..])?;
+            let (key, id) = match read_streams(args)? {
+                ([key], [id]) => (key_of(key), id),
+                _ => return Err(RespValue::err("XREADGROUP reads a single stream")),
+            };
+            let id = match id.as_slice() {
+                b">" => None,
+                x => Some(parse_stream_id(x, 0)?),
+            };
+            let logged = proto::CommandMessage::XREADGROUP(key.clone(), group.clone(), consumer.clone(), id, count, None);
+            let entries = cc
+                .stream_read_group(&key, &group, &consumer, id, count, block, None, |at, change| {
+                    wal.write_applied(&logged.at(at), change)
+                })
+                .await
+                .map_err(value_error)?;
+            if id.is_none() && entries.is_empty() {
+                return Ok(RespValue::NullArray);
+            }
+            Ok(RespValue::Array(vec![RespValue::Array(vec![
+                RespValue::Bulk(key.into_bytes()),
+                stream_entries(entries),
+            ])]))
+        }
+        "XACK" => {
+            let (key, group) = (key_of(&args[0]), key_of(&args[1]));
+            let ids = args[2..]
+                .iter()
+                .map(|x| parse_stream_id(x, 0))
+                .collect::<Result<Vec<StreamId>, RespValue>>()?;
+            let (acked, change) = cc.stream_ack(&key, &group, &ids).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::XACK(key, group, ids), change);
+            Ok(RespValue::Integer(acked as i64))
+        }
+        _ => {
+            // XPENDING key group [start end count [consumer]]
+            let (key, group) = (key_of(&args[0]), key_of(&args[1]));
+            if args.len() == 2 {
+                let pending = cc
+                    .stream_pending(&key, &group, StreamId::MIN, StreamId::MAX, None, None)
+                    .await
+                    .map_err(value_error)?;
+                let mut consumers: Vec<(String, i64)> = Vec::new();
+                for (_, x) in pending.iter() {
+                    match consumers.iter_mut().find(|(consumer, _)| *consumer == x.consumer) {
+                        Some((_, count)) => *count += 1,
+                        None => consumers.push((x.consumer.clone(), 1)),
+                    }
+                }
+                consumers.sort();
+                let id = |x: Option<&(StreamId, _)>| match x {
+                    Some((id, _)) => RespValue::bulk_str(&id.to_string()),
+                    None => RespValue::Null,
+                };
+                return Ok(RespValue::Array(vec![
+                    RespValue::Integer(pending.len() as i64),
+                    id(pending.first()),
+                    id(pending.last()),
+                    match consumers.is_empty() {
+                        true => RespValue::NullArray,
+                        false => RespValue::Array(
+                            consumers
+                                .into_iter()
+                                .map(|(consumer, count)| {
+                                    RespValue::Array(vec![
+                                        RespValue::Bulk(consumer.into_bytes()),
+                                        RespValue::bulk_str(&count.to_string()),
+                                    ])
+                                })
+                                .collect(),
+                        ),
+                    },
+                ]));
+            }
+            if args.len() != 5 && args.len() != 6 {
+                return Err(RespValue::err("syntax error"));
+            }
+            let (start, end) = (parse_range_id(&args[2], 0)?, parse_range_id(&args[3], u64::MAX)?);
+            let count = parse_int(&args[4])?.max(0) as usize;
+            let consumer = args.get(5).map(|x| key_of(x));
+            let pending = cc
+                .stream_pending(&key, &group, start, end, Some(count), consumer.as_deref())
+                .await
+                .map_err(value_error)?;
+            let now = now_millis();
+            Ok(RespValue::Array(
+                pending
+                    .into_iter()
+                    .map(|(id, x)| {
+                        RespValue::Array(vec![
+                            RespValue::bulk_str(&id.to_string()),
+                            RespValue::Bulk(x.consumer.into_bytes()),
+                            RespValue::Integer(now.saturating_sub(x.delivered_at) as i64),
+                            RespValue::Integer(x.deliveries as i64),
+                        ])
+                    })
+                    .collect(),
+            ))
+        }
+    }
+}
+
+/// HyperLogLog commands, the key is the first argument.
+async fn hyperloglog(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+
+    match name {
+        "PFADD" => {
+            let elements = args[1..].to_vec();
+            let (changed, change) = cc.hyperloglog_add(&key, elements.clone()).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::PFADD(key, elements), change);
+            Ok(RespValue::Integer(changed as i64))
+        }
+        "PFCOUNT" => {
+            let keys: Vec<String> = args.iter().map(|x| key_of(x)).collect();
+            Ok(RespValue::Integer(cc.hyperloglog_count(&keys).await.map_err(value_error)? as i64))
+        }
+        _ => {
+            let sources: Vec<String> = args.iter().map(|x| key_of(x)).collect();
+            let change = cc.hyperloglog_merge(&key, &sources).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::PFMERGE(key, sources), change);
+            Ok(RespValue::ok())
+        }
+    }
+}
+
+/// Bloom filter commands, the key is the first argument.
+async fn bloom(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+    let flags = |flags: Vec<bool>| RespValue::Array(flags.into_iter().map(|x| RespValue::Integer(x as i64)).collect());
+
+    match name {
+        "BF.RESERVE" => {
+            let error_rate = parse_rate(&args[1])?;
+            let capacity = parse_int(&args[2])?
+                .try_into()
+                .map_err(|_| RespValue::err("value is not an integer or out of range"))?;
+            let change = cc.bloom_reserve(&key, error_rate, capacity).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::BFRESERVE(key, error_rate, capacity), change);
+            Ok(RespValue::ok())
+        }
+        "BF.ADD" | "BF.MADD" => {
+            let items = args[1..].to_vec();
+            let (added, change) = cc.bloom_add(&key, items.clone()).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::BFADD(key, items), change);
+            Ok(match name {
+                "BF.ADD" => RespValue::Integer(added[0] as i64),
+                _ => flags(added),
+            })
+        }
+        "BF.EXISTS" => Ok(RespValue::Integer(cc.bloom_exists(&key, &args[1..]).await.map_err(value_error)?[0] as i64)),
+        _ => Ok(flags(cc.bloom_exists(&key, &args[1..]).await.map_err(value_error)?)),
+    }
+}
+
+/// Count-Min sketch and top-k commands, the key is the first argument.
+async fn sketch(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+
+    match name {
+        "CMS.INITBYDIM" | "CMS.INITBYPROB" => {
+            let (width, depth) = match name {
+                "CMS.INITBYDIM" => (parse_u32(&args[1])?, parse_u32(&args[2])?),
+                _ => CountMinSketch::dimensions(parse_rate(&args[1])?, parse_rate(&args[2])?)
+                    .ok_or_else(|| RespValue::err("value must be between 0 and 1"))?,
+            };
+            let change = cc.count_min_init(&key, width, depth).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::CMSINIT(key, width, depth), change);
+            Ok(RespValue::ok())
+        }
+        "CMS.INCRBY" => {
+            let items = args[1..]
+                .chunks(2)
+                .map(|x| {
+                    let delta = parse_int(&x[1])?
+                        .try_into()
+                        .map_err(|_| RespValue::err("value is not an integer or out of range"))?;
+                    Ok((x[0].clone(), delta))
+                })
+                .collect::<Result<Vec<(Vec<u8>, u64)>, RespValue>>()?;
+            let (counts, change) = cc.count_min_incr_by(&key, items.clone()).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::CMSINCRBY(key, items), change);
+            Ok(RespValue::Array(counts.into_iter().map(|x| RespValue::Integer(x as i64)).collect()))
+        }
+        "CMS.QUERY" => {
+            let counts = cc.count_min_query(&key, &args[1..]).await.map_err(value_error)?;
+            Ok(RespValue::Array(counts.into_iter().map(|x| RespValue::Integer(x as i64)).collect()))
+        }
+        "TOPK.RESERVE" => {
+            // TOPK.RESERVE key k [width depth], the sketch defaults to ten
+            // counters per item of the top.
+            let k = parse_u32(&args[1])?;
+            let (width, depth) = match args.len() {
+                4 => (parse_u32(&args[2])?, parse_u32(&args[3])?),
+                _ => (k.saturating_mul(10), 5),
+            };
+            let change = cc.top_k_reserve(&key, k, width, depth).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::TOPKRESERVE(key, k, width, depth), change);
+            Ok(RespValue::ok())
+        }
+        "TOPK.ADD" => {
+            let items = args[1..].to_vec();
+            let (expelled, change) = cc.top_k_add(&key, items.clone()).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::TOPKADD(key, items), change);
+            Ok(RespValue::Array(
+                expelled.into_iter().map(|x| x.map(RespValue::Bulk).unwrap_or(RespValue::Null)).collect(),
+            ))
+        }
+        "TOPK.QUERY" => {
+            let found = cc.top_k_query(&key, &args[1..]).await.map_err(value_error)?;
+            Ok(RespValue::Array(found.into_iter().map(|x| RespValue::Integer(x as i64)).collect()))
+        }
+        _ => {
+            let with_count = match args.get(1) {
+                Some(x) if x.eq_ignore_ascii_case(b"WITHCOUNT") => true,
+                Some(_) => return Err(RespValue::err("syntax error")),
+                None => false,
+            };
+            let top = cc.top_k_list(&key).await.map_err(value_error)?;
+            Ok(RespValue::Array(
+                top.into_iter()
+                    .flat_map(|(item, count)| {
+                        let count = with_count.then_some(RespValue::Integer(count as i64));
+                        std::iter::once(RespValue::Bulk(item)).chain(count)
+                    })
+                    .collect(),
+            ))
+        }
+    }
+}
+
+/// JSON document commands, the key is the first argument and the path the
+/// second, the root when left out.
+async fn json(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let path = args.get(1).map(|x| key_of(x)).unwrap_or_else(|| "$".to_owned());
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+
+    match name {
+        "JSON.SET" => {
+            let cond = match args.get(3) {
+                None => WriteCondition::Always,
+                Some(x) if x.eq_ignore_ascii_case(b"NX") => WriteCondition::IfAbsent,
+                Some(x) if x.eq_ignore_ascii_case(b"XX") => WriteCondition::IfPresent,
+                Some(_) => return Err(RespValue::err("syntax error")),
+            };
+            let json = args[2].clone();
+            let (written, change) = cc.json_set(&key, &path, &json, cond).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::JSONSET(key, path, json, cond), change);
+            Ok(match written {
+                true => RespValue::ok(),
+                false => RespValue::Null,
+            })
+        }
+        "JSON.GET" => {
+            let paths: Vec<String> = args[1..].iter().map(|x| key_of(x)).collect();
+            let json = cc.json_get(&key, &paths).await.map_err(value_error)?;
+            Ok(json.map(RespValue::Bulk).unwrap_or(RespValue::Null))
+        }
+        "JSON.DEL" | "JSON.FORGET" => {
+            let (removed, change) = cc.json_delete(&key, &path).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::JSONDEL(key, path), change);
+            Ok(RespValue::Integer(removed as i64))
+        }
+        "JSON.ARRAPPEND" => {
+            let values = args[2..].to_vec();
+            let (len, change) = cc.json_array_append(&key, &path, &values).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::JSONARRAPPEND(key, path, values), change);
+            Ok(RespValue::Integer(len as i64))
+        }
+        _ => {
+            let delta = parse_score(&args[2])?;
+            let (number, change) = cc.json_number_incr_by(&key, &path, delta).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::JSONNUMINCRBY(key, path, delta), change);
+            Ok(RespValue::Bulk(number))
+        }
+    }
+}
+
+/// Indexed field value, a JSON scalar or else the argument as a string.
+fn parse_index_value(arg: &[u8]) -> IndexValue {
+    serde_json::from_slice(arg)
+        .ok()
+        .and_then(|x| IndexValue::of(&x))
+        .unwrap_or_else(|| IndexValue::String(key_of(arg)))
+}
+
+/// Bound of a field value range, `-` and `+` for no bound and exclusive when
+/// prefixed with `(`.
+fn parse_index_bound(arg: &[u8]) -> Bound<IndexValue> {
+    match arg {
+        b"-" | b"+" => Bound::Unbounded,
+        _ => match arg.strip_prefix(b"(") {
+            Some(x) => Bound::Excluded(parse_index_value(x)),
+            None => Bound::Included(parse_index_value(arg)),
+        },
+    }
+}
+
+/// Secondary index commands, the index name is the first argument. Queries
+/// only return keys the user may read.
+async fn index(
+    name: &str,
+    args: &[Vec<u8>],
+    cc: &Arc<Storage>,
+    wal: &WalWritter,
+    auth: &Authenticator,
+    user: &str,
+) -> Result<RespValue, RespValue> {
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+    let keys = |keys: Vec<String>| RespValue::Array(keys.into_iter().map(|x| RespValue::Bulk(x.into_bytes())).collect());
+
+    match name {
+        "IDX.CREATE" => {
+            let (index, prefix, path) = (key_of(&args[0]), key_of(&args[1]), key_of(&args[2]));
+            cc.index_create(&index, &prefix, &path).await.map_err(value_error)?;
+            wal.write(&proto::CommandMessage::INDEXCREATE(index, prefix, path).into());
+            Ok(RespValue::ok())
+        }
+        "IDX.DROP" => {
+            let index = key_of(&args[0]);
+            let dropped = cc.index_drop(&index).await;
+            if dropped {
+                wal.write(&proto::CommandMessage::INDEXDROP(index).into());
+            }
+            Ok(RespValue::Integer(dropped as i64))
+        }
+        "IDX.LIST" => Ok(RespValue::Array(
+            cc.index_list()
+                .await
+                .into_iter()
+                .map(|(index, prefix, path)| {
+                    RespValue::Array(vec![
+                        RespValue::bulk_str(&index),
+                        RespValue::bulk_str(&prefix),
+                        RespValue::bulk_str(&path),
+                    ])
+                })
+                .collect(),
+        )),
+        _ => {
+            // IDX.GET index value [LIMIT count], IDX.RANGE index min max [LIMIT count]
+            let (min, max, rest) = match name {
+                "IDX.GET" => {
+                    let value = parse_index_value(&args[1]);
+                    (Bound::Included(value.clone()), Bound::Included(value), &args[2..])
+                }
+                _ => (parse_index_bound(&args[1]), parse_index_bound(&args[2]), &args[3..]),
+            };
+            let limit = match rest {
+                [] => None,
+                [option, count] if option.eq_ignore_ascii_case(b"LIMIT") => Some(
+                    parse_int(count)?
+                        .try_into()
+                        .map_err(|_| RespValue::err("value is not an integer or out of range"))?,
+                ),
+                _ => return Err(RespValue::err("syntax error")),
+            };
+            let allowed = |key: &str| auth.check(user, "get", &[key]).is_ok();
+            Ok(keys(cc.index_query(&key_of(&args[0]), min, max, limit, allowed).await.map_err(value_error)?))
+        }
+    }
+}
+
+fn parse_u64(arg: &[u8]) -> Result<u64, RespValue> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|x| x.parse::<u64>().ok())
+        .ok_or_else(|| RespValue::err("value is not an integer or out of range"))
+}
+
+/// Bound of a timestamp range, `-` and `+` standing for the smallest and
+/// greatest timestamps.
+fn parse_timestamp_bound(arg: &[u8]) -> Result<u64, RespValue> {
+    match arg {
+        b"-" => Ok(0),
+        b"+" => Ok(u64::MAX),
+        _ => parse_u64(arg),
+    }
+}
+
+/// Aggregation name and bucket duration in milliseconds.
+fn parse_aggregation(name: &[u8], bucket: &[u8]) -> Result<(Aggregation, u64), RespValue> {
+    let aggregation = std::str::from_utf8(name)
+        .ok()
+        .and_then(Aggregation::parse)
+        .ok_or_else(|| RespValue::err("unknown aggregation type"))?;
+
+    Ok((aggregation, parse_u64(bucket)?))
+}
+
+/// Time series commands, the key is the first argument.
+async fn time_series(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+    let sample = |(t, x): Sample| RespValue::Array(vec![RespValue::Integer(t as i64), RespValue::bulk_str(&x.to_string())]);
+    let retention = |args: &[Vec<u8>]| match args {
+        [] => Ok(0),
+        [option, ms] if option.eq_ignore_ascii_case(b"RETENTION") => parse_u64(ms),
+        _ => Err(RespValue::err("syntax error")),
+    };
+
+    match name {
+        "TS.CREATE" => {
+            let retention = retention(&args[1..])?;
+            let change = cc.time_series_create(&key, retention).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::TSCREATE(key, retention), change);
+            Ok(RespValue::ok())
+        }
+        "TS.ADD" => {
+            // TS.ADD key <*|timestamp> value [RETENTION ms]
+            let timestamp = match args[1].as_slice() {
+                b"*" => None,
+                x => Some(parse_u64(x)?),
+            };
+            let (value, retention) = (parse_score(&args[2])?, retention(&args[3..])?);
+            let (timestamp, change) = cc.time_series_add(&key, timestamp, value, retention).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::TSADD(key, Some(timestamp), value, retention), change);
+            Ok(RespValue::Integer(timestamp as i64))
+        }
+        "TS.GET" => {
+            let last = cc.time_series_get(&key).await.map_err(value_error)?;
+            Ok(last.map(sample).unwrap_or(RespValue::Array(Vec::new())))
+        }
+        "TS.RANGE" => {
+            // TS.RANGE key from to [COUNT count] [AGGREGATION aggregation bucket]
+            let (from, to) = (parse_timestamp_bound(&args[1])?, parse_timestamp_bound(&args[2])?);
+            let (mut count, mut aggregation) = (None, None);
+            let mut rest = &args[3..];
+            while !rest.is_empty() {
+                rest = match rest {
+                    [option, x, rest @ ..] if option.eq_ignore_ascii_case(b"COUNT") => {
+                        count = Some(parse_u64(x)? as usize);
+                        rest
+                    }
+                    [option, x, bucket, rest @ ..] if option.eq_ignore_ascii_case(b"AGGREGATION") => {
+                        aggregation = Some(parse_aggregation(x, bucket)?);
+                        rest
+                    }
+                    _ => return Err(RespValue::err("syntax error")),
+                };
+            }
+            let samples = cc.time_series_range(&key, from, to, count, aggregation).await.map_err(value_error)?;
+            Ok(RespValue::Array(samples.into_iter().map(sample).collect()))
+        }
+        "TS.CREATERULE" => {
+            // TS.CREATERULE source dest AGGREGATION aggregation bucket
+            if !args[2].eq_ignore_ascii_case(b"AGGREGATION") {
+                return Err(RespValue::err("syntax error"));
+            }
+            let (dest, (aggregation, bucket)) = (key_of(&args[1]), parse_aggregation(&args[3], &args[4])?);
+            let change = cc.time_series_create_rule(&key, &dest, aggregation, bucket).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::TSCREATERULE(key, dest, aggregation, bucket), change);
+            Ok(RespValue::ok())
+        }
+        "TS.DELETERULE" => {
+            let dest = key_of(&args[1]);
+            let (removed, change) = cc.time_series_delete_rule(&key, &dest).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::TSDELETERULE(key, dest), change);
+            Ok(RespValue::Integer(removed as i64))
+        }
+        _ => {
+            let info = cc.time_series_info(&key).await.map_err(value_error)?;
+            let info = info.ok_or_else(|| value_error(ValueError::NoSuchKey))?;
+            let timestamp = |x: Option<Sample>| RespValue::Integer(x.map(|(t, _)| t as i64).unwrap_or(-1));
+            let rules = info
+                .rules
+                .into_iter()
+                .map(|x| {
+                    RespValue::Array(vec![
+                        RespValue::Bulk(x.dest.into_bytes()),
+                        RespValue::Integer(x.bucket as i64),
+                        RespValue::bulk_str(x.aggregation.name()),
+                    ])
+                })
+                .collect();
+            Ok(RespValue::Array(vec![
+                RespValue::bulk_str("totalSamples"),
+                RespValue::Integer(info.samples as i64),
+                RespValue::bulk_str("retentionTime"),
+                RespValue::Integer(info.retention as i64),
+                RespValue::bulk_str("firstTimestamp"),
+                timestamp(info.first),
+                RespValue::bulk_str("lastTimestamp"),
+                timestamp(info.last),
+                RespValue::bulk_str("rules"),
+                RespValue::Array(rules),
+            ]))
+        }
+    }
+}
+
+/// Queue commands, the key is the first argument.
+async fn queue(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+    let parse_receipts = |args: &[Vec<u8>]| {
+        args.iter()
+            .map(|x| {
+                let receipt = std::str::from_utf8(x).ok().and_then(Receipt::parse);
+                receipt.ok_or_else(|| RespValue::err("invalid receipt"))
+            })
+            .collect::<Result<Vec<Receipt>, RespValue>>()
+    };
+    // Commands are logged with the time of the call, so the replay applies them alike.
+    let now = now_millis();
+
+    match name {
+        "Q.CREATE" => {
+            // Q.CREATE key [VISIBILITY ms] [MAXATTEMPTS count] [DEADLETTER key]
+            let mut options = QueueOptions::default();
+            for x in args[1..].chunks(2) {
+                match x[0].to_ascii_uppercase().as_slice() {
+                    b"VISIBILITY" => options.visibility = parse_u64(&x[1])?,
+                    b"MAXATTEMPTS" => options.max_attempts = parse_u32(&x[1])?,
+                    b"DEADLETTER" => options.dead_letter = Some(key_of(&x[1])),
+                    _ => return Err(RespValue::err("syntax error")),
+                }
+            }
+            let change = cc.queue_create(&key, options.clone()).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::QCREATE(key, options), change);
+            Ok(RespValue::ok())
+        }
+        "Q.PUSH" | "Q.PUSHDELAYED" => {
+            // Q.PUSH key payload [payload ...], Q.PUSHDELAYED key ms payload [payload ...]
+            let (delay, payloads) = match name {
+                "Q.PUSH" => (0, args[1..].to_vec()),
+                _ => (parse_u64(&args[1])?, args[2..].to_vec()),
+            };
+            let (ids, change) = cc.queue_push(&key, payloads.clone(), delay, now).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::QPUSH(key, payloads, delay).at(now), change);
+            Ok(RespValue::Array(ids.into_iter().map(|x| RespValue::Integer(x as i64)).collect()))
+        }
+        "Q.POP" => {
+            let count = match args.get(1) {
+                Some(x) => parse_u64(x)? as usize,
+                None => 1,
+            };
+            let (delivered, change) = cc.queue_pop(&key, count, now).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::QPOP(key, count).at(now), change);
+            Ok(RespValue::Array(
+                delivered
+                    .into_iter()
+                    .map(|x| {
+                        RespValue::Array(vec![
+                            RespValue::Integer(x.receipt.id as i64),
+                            RespValue::bulk_str(&x.receipt.to_string()),
+                            RespValue::Bulk(x.payload),
+                            RespValue::Integer(x.attempts as i64),
+                        ])
+                    })
+                    .collect(),
+            ))
+        }
+        "Q.ACK" => {
+            // Q.ACK key receipt [receipt ...]
+            let receipts = parse_receipts(&args[1..])?;
+            let (acked, change) = cc.queue_ack(&key, &receipts).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::QACK(key, receipts), change);
+            Ok(RespValue::Integer(acked as i64))
+        }
+        "Q.NACK" => {
+            // Q.NACK key receipt [receipt ...] [DELAY ms]
+            let (receipts, delay) = match &args[1..] {
+                [receipts @ .., option, ms] if option.eq_ignore_ascii_case(b"DELAY") => (receipts, parse_u64(ms)?),
+                receipts => (receipts, 0),
+            };
+            let receipts = parse_receipts(receipts)?;
+            let (rejected, change) = cc.queue_nack(&key, &receipts, delay, now).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::QNACK(key, receipts, delay).at(now), change);
+            Ok(RespValue::Integer(rejected as i64))
+        }
+        _ => {
+            let info = cc.queue_info(&key).await.map_err(value_error)?;
+            let info = info.ok_or_else(|| value_error(ValueError::NoSuchKey))?;
+            Ok(RespValue::Array(vec![
+                RespValue::bulk_str("ready"),
+                RespValue::Integer(info.ready as i64),
+                RespValue::bulk_str("delayed"),
+                RespValue::Integer(info.delayed as i64),
+                RespValue::bulk_str("inFlight"),
+                RespValue::Integer(info.in_flight as i64),
+                RespValue::bulk_str("visibility"),
+                RespValue::Integer(info.options.visibility as i64),
+                RespValue::bulk_str("maxAttempts"),
+                RespValue::Integer(info.options.max_attempts as i64),
+                RespValue::bulk_str("deadLetter"),
+                info.options.dead_letter.map(|x| RespValue::Bulk(x.into_bytes())).unwrap_or(RespValue::Null),
+            ]))
+        }
+    }
+}
+
+/// Schedule commands, the key is the first argument.
+async fn schedule(name: &str, args: &[Vec<u8>], cc: &Arc<Storage>, wal: &WalWritter) -> Result<RespValue, RespValue> {
+    let key = key_of(&args[0]);
+    let value_error = |e: ValueError| RespValue::Error(e.to_string());
+
+    match name {
+        "SCHED.ADD" => {
+            // SCHED.ADD key ms QUEUE|STREAM|CHANNEL target payload
+            let delay = parse_u64(&args[1])?;
+            let target = match args[2].to_ascii_uppercase().as_slice() {
+                b"QUEUE" => Target::Queue(key_of(&args[3])),
+                b"STREAM" => Target::Stream(key_of(&args[3])),
+                b"CHANNEL" => Target::Channel(key_of(&args[3])),
+                _ => return Err(RespValue::err("syntax error")),
+            };
+            let payload = args[4].clone();
+            let now = now_millis();
+            let (id, change) = cc
+                .schedule_add(&key, target.clone(), payload.clone(), delay, now)
+                .await
+                .map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::SCHEDADD(key, target, payload, delay).at(now), change);
+            Ok(RespValue::Integer(id as i64))
+        }
+        "SCHED.CANCEL" => {
+            let id = parse_u64(&args[1])?;
+            let (cancelled, change) = cc.schedule_cancel(&key, id).await.map_err(value_error)?;
+            wal.write_applied(&proto::CommandMessage::SCHEDCANCEL(key, id), change);
+            Ok(RespValue::Integer(cancelled as i64))
+        }
+        _ => {
+            let jobs = cc.schedule_list(&key).await.map_err(value_error)?.unwrap_or_default();
+            Ok(RespValue::Array(
+                jobs.into_iter()
+                    .map(|x| {
+                        let (kind, target) = match x.target {
+                            Target::Queue(target) => ("queue", target),
+                            Target::Stream(target) => ("stream", target),
+                            Target::Channel(target) => ("channel", target),
+                        };
+                        RespValue::Array(vec![
+                            RespValue::Integer(x.id as i64),
+                            RespValue::Integer(x.due_at as i64),
+                            RespValue::bulk_str(kind),
+                            RespValue::Bulk(target.into_bytes()),
+                            RespValue::Bulk(x.payload),
+                            RespValue::Boolean(x.undelivered),
+                        ])
+                    })
+                    .collect(),
+            ))
+        }
+    }
+}
+
+/// `SUBSCRIBE channel [channel ...]`, confirms every channel with the number
+/// of channels the connection is subscribed to.
+fn subscribe(args: &[Vec<u8>], session: &mut Session, cc: &Arc<Storage>) -> RespValue {
+    let replies = args
+        .iter()
+        .map(|x| {
+            let channel = key_of(x);
+            if !session.subscriptions.contains_key(&channel) {
+                let mut rx = cc.subscribe(&channel);
+                let (tx, forwarded) = (session.messages.clone(), channel.clone());
+                let forwarder = tokio::spawn(async move {
+                    loop {
+                        match rx.recv().await {
+                            Ok(payload) => {
+                                if tx.send((forwarded.clone(), payload)).is_err() {
+                                    break;
+                                }
+                            }
+                            // Slow subscribers miss the oldest messages.
+                            Err(RecvError::Lagged(_)) => {}
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                });
+                session.subscriptions.insert(channel.clone(), forwarder);
+            }
+            RespValue::Push(vec![
+                RespValue::bulk_str("subscribe"),
+                RespValue::Bulk(channel.into_bytes()),
+                RespValue::Integer(session.subscriptions.len() as i64),
+            ])
+        })
+        .collect();
+
+    RespValue::Replies(replies)
+}
+
+/// `UNSUBSCRIBE [channel ...]`, from every channel without arguments.
+fn unsubscribe(args: &[Vec<u8>], session: &mut Session) -> RespValue {
+    let mut channels: Vec<String> = args.iter().map(|x| key_of(x)).collect();
+    if channels.is_empty() {
+        channels = session.subscriptions.keys().cloned().collect();
+        channels.sort();
+    }
+    if channels.is_empty() {
+        return RespValue::Push(vec![RespValue::bulk_str("unsubscribe"), RespValue::Null, RespValue::Integer(0)]);
+    }
+
+    let replies = channels
+        .into_iter()
+        .map(|channel| {
+            if let Some(forwarder) = session.subscriptions.remove(&channel) {
+                forwarder.abort();
+            }
+            RespValue::Push(vec![
+                RespValue::bulk_str("unsubscribe"),
+                RespValue::Bulk(channel.into_bytes()),
+                RespValue::Integer(session.subscriptions.len() as i64),
+            ])
+        })
+        .collect();
+
+    RespValue::Replies(replies)
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]`
+async fn scan(args: &[Vec<u8>], cc: &Arc<Storage>, auth: &Authenticator, user: &str) -> Result<RespValue, RespValue> {
+    let cursor = std::str::from_utf8(&args[0])
+        .ok()
+        .and_then(|x| x.parse::<u64>().ok())
+        .ok_or_else(|| RespValue::err("invalid cursor"))?;
+
+    let mut pattern = None;
+    let mut count = 10;
+    let mut kind = None;
+    let mut opts = args[1..].iter();
+    while let Some(opt) = opts.next() {
+        let value = opts.next().ok_or_else(|| RespValue::err("syntax error"))?;
+        match key_of(opt).to_uppercase().as_str() {
+            "MATCH" => pattern = Some(key_of(value)),
+            "COUNT" => match parse_int(value)? {
+                x if x > 0 => count = x as usize,
+                _ => return Err(RespValue::err("syntax error")),
+            },
+            "TYPE" => kind = Some(key_of(value).to_lowercase()),
+            _ => return Err(RespValue::err("syntax error")),
+        }
+    }
+
+    let (cursor, keys) = cc.scan(cursor, count, pattern.as_deref(), None).await;
+    let mut keys = auth.filter_keys(user, keys);
+    if let Some(kind) = kind {
+        let mut matching = Vec::new();
+        for key in keys {
+            if cc.kind(&key).await == Some(kind.as_str()) {
+                matching.push(key);
+            }
+        }
+        keys = matching;
+    }
+
+    Ok(RespValue::Array(vec![
+        RespValue::bulk_str(&cursor.to_string()),
+        RespValue::Array(keys.into_iter().map(|x| RespValue::Bulk(x.into_bytes())).collect()),
+    ]))
+}
+
+fn acl(args: &[Vec<u8>], user: &str, auth: &Authenticator) -> Result<RespValue, RespValue> {
+    let args: Vec<String> = args.iter().map(|x| key_of(x)).collect();
+    let res = auth.command(user, &args).map_err(RespValue::Error)?;
+
+    Ok(match args[0].to_uppercase().as_str() {
+        "SETUSER" | "SAVE" | "LOAD" => RespValue::ok(),
+        "DELUSER" => RespValue::Integer(res.len() as i64),
+        "WHOAMI" => RespValue::bulk_str(&res[0]),
+        "GETUSER" => match res.first() {
+            Some(x) => RespValue::bulk_str(x),
+            None => RespValue::Null,
+        },
+        _ => RespValue::Array(res.iter().map(|x| RespValue::bulk_str(x)).collect()),
+    })
+}
+
+fn authenticate(
+    username: Option<&Vec<u8>>,
+    password: &[u8],
+    session: &mut Session,
+    auth: &Authenticator,
+) -> Result<RespValue, RespValue> {
+    let username = username.map(|x| key_of(x));
+    match auth.authenticate(username.as_deref(), &key_of(password)) {
+        Some(user) => {
+            session.user = Some(user);
+            Ok(RespValue::ok())
+        }
+        None => Err(RespValue::Error(
+            "WRONGPASS invalid username-password pair or user is disabled.".to_owned(),
+        )),
+    }
+}
+
+/// `HELLO [protover [AUTH username password]]`, the protocol version is only
+/// switched once the optional credentials were accepted.
+fn hello(args: &[Vec<u8>], session: &mut Session, auth: &Authenticator) -> Result<RespValue, RespValue> {
+    let version = match args.first() {
+        Some(version) => match parse_int(version) {
+            Ok(x) if x == 2 || x == 3 => Some(x as u8),
+            _ => {
+                return Err(RespValue::Error(
+                    "NOPROTO unsupported protocol version".to_owned(),
+                ))
+            }
+        },
+        None => None,
+    };
+
+    match args.get(1..).unwrap_or_default() {
+        [] => {}
+        [opt, username, password] if opt.eq_ignore_ascii_case(b"AUTH") => {
+            authenticate(Some(username), password, session, auth)?;
+        }
+        _ => return Err(RespValue::err("syntax error in HELLO option")),
+    }
+    if session.user.is_none() {
+        return Err(RespValue::Error(
+            "NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time".to_owned(),
+        ));
+    }
+    if let Some(version) = version {
+        session.version = version;
+    }
+
+    Ok(RespValue::Map(vec![
+        (RespValue::bulk_str("server"), RespValue::bulk_str("cachetcp")),
+        (RespValue::bulk_str("version"), RespValue::bulk_str(env!("CARGO_PKG_VERSION"))),
+        (RespValue::bulk_str("proto"), RespValue::Integer(session.version as i64)),
+        (RespValue::bulk_str("mode"), RespValue::bulk_str("standalone")),
+        (RespValue::bulk_str("role"), RespValue::bulk_str("master")),
+        (RespValue::bulk_str("modules"), RespValue::Array(Vec::new())),
+    ]))
+}
+
+async fn info(cc: &Arc<Storage>) -> Result<RespValue, RespValue> {
+    let keys = cc.keys().await.unwrap_or_default().len();
+    let info = format!(
+        "# Server\r\nredis_version:7.0.0\r\ncachetcp_version:{}\r\nredis_mode:standalone\r\n\r\n# Keyspace\r\ndb0:keys={},expires=0,avg_ttl=0\r\n",
+        env!("CARGO_PKG_VERSION"),
+        keys
+    );
+
+    Ok(RespValue::Bulk(info.into_bytes()))
+}
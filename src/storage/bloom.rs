@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    sketch::{hash64, packed, MAX_SIZE},
+    storage::Storage,
+    value::{Change, Value, ValueError},
+};
+
+/// False positive rate of filters created implicitly by `bloom_add`.
+pub const DEFAULT_ERROR_RATE: f64 = 0.01;
+/// Capacity of filters created implicitly by `bloom_add`.
+pub const DEFAULT_CAPACITY: u64 = 100;
+
+/// Bloom filter sized for a number of items at a false positive rate. It does
+/// not grow, adding more items than its capacity raises the rate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    hashes: u32,
+    #[serde(with = "packed")]
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    fn new(error_rate: f64, capacity: u64) -> Result<Self, ValueError> {
+        if !(error_rate > 0.0 && error_rate < 1.0) {
+            return Err(ValueError::InvalidArgument("error rate must be between 0 and 1"));
+        }
+        if capacity == 0 {
+            return Err(ValueError::InvalidArgument("capacity must be positive"));
+        }
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(capacity as f64) * error_rate.ln() / (ln2 * ln2)).ceil();
+        if bits / 8.0 > MAX_SIZE as f64 {
+            return Err(ValueError::InvalidArgument("filter would be too large"));
+        }
+        let hashes = (bits / capacity as f64 * ln2).round().max(1.0);
+
+        Ok(Self {
+            hashes: hashes as u32,
+            bits: vec![0; (bits as usize).div_ceil(8)],
+        })
+    }
+
+    /// Bits of the item, derived from two hashes.
+    fn positions(&self, item: &[u8]) -> impl Iterator<Item = usize> {
+        let (h1, h2) = (hash64(item, 0), hash64(item, 1));
+        let len = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    /// Sets the bits of the item, returns whether any was unset before.
+    fn add(&mut self, item: &[u8]) -> bool {
+        let positions: Vec<usize> = self.positions(item).collect();
+        let mut added = false;
+        for x in positions {
+            added |= self.bits[x / 8] & (1 << (x % 8)) == 0;
+            self.bits[x / 8] |= 1 << (x % 8);
+        }
+
+        added
+    }
+
+    fn contains(&self, item: &[u8]) -> bool {
+        self.positions(item).all(|x| self.bits[x / 8] & (1 << (x % 8)) != 0)
+    }
+}
+
+fn bloom(value: &mut Value) -> Result<&mut BloomFilter, ValueError> {
+    match value {
+        Value::Bloom(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+impl Storage {
+    /// Creates a filter holding `capacity` items at the given false positive rate.
+    pub async fn bloom_reserve(&self, key: &str, error_rate: f64, capacity: u64) -> Result<Change, ValueError> {
+        let filter = BloomFilter::new(error_rate, capacity)?;
+        self.create(key, Value::Bloom(filter)).await
+    }
+
+    /// Adds the items, creating a filter with the default sizing when the key
+    /// is missing, and returns for each whether it was new to the filter.
+    pub async fn bloom_add(&self, key: &str, items: Vec<Vec<u8>>) -> Result<(Vec<bool>, Change), ValueError> {
+        let init = || BloomFilter::new(DEFAULT_ERROR_RATE, DEFAULT_CAPACITY).map(Value::Bloom);
+        self.try_update(key, init, |value| {
+            let filter = bloom(value)?;
+            let added: Vec<bool> = items.iter().map(|x| filter.add(x)).collect();
+            let changed = added.contains(&true);
+            Ok((added, changed))
+        })
+        .await
+    }
+
+    /// Whether each of the items may have been added, false for missing keys.
+    pub async fn bloom_exists(&self, key: &str, items: &[Vec<u8>]) -> Result<Vec<bool>, ValueError> {
+        let found = self
+            .inspect(key, |value| match value {
+                Value::Bloom(filter) => Ok(items.iter().map(|x| filter.contains(x)).collect()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(found.unwrap_or_else(|| vec![false; items.len()]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn false_positives_stay_near_the_error_rate() {
+        let mut filter = BloomFilter::new(0.01, 1_000).unwrap();
+        for x in 0..1_000u64 {
+            filter.add(&x.to_be_bytes());
+        }
+
+        // No false negatives, and twice the rate leaves room for variance.
+        assert!((0..1_000u64).all(|x| filter.contains(&x.to_be_bytes())));
+        let false_positives = (1_000..101_000u64).filter(|x| filter.contains(&x.to_be_bytes())).count();
+        assert!(false_positives < 2_000, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn sizing_rejects_invalid_arguments() {
+        assert!(BloomFilter::new(0.0, 10).is_err());
+        assert!(BloomFilter::new(1.0, 10).is_err());
+        assert!(BloomFilter::new(f64::NAN, 10).is_err());
+        assert!(BloomFilter::new(0.01, 0).is_err());
+        assert!(BloomFilter::new(1e-300, u64::MAX).is_err());
+    }
+
+    #[tokio::test]
+    async fn adds_report_new_items() {
+        let cc = Storage::new();
+        let (added, _) = cc.bloom_add("b", vec![b"a".to_vec(), b"b".to_vec(), b"a".to_vec()]).await.unwrap();
+        assert_eq!(added, [true, true, false]);
+
+        let items = [b"a".to_vec(), b"c".to_vec()];
+        assert_eq!(cc.bloom_exists("b", &items).await.unwrap(), [true, false]);
+        assert_eq!(cc.bloom_exists("missing", &items).await.unwrap(), [false, false]);
+        assert!(cc.bloom_reserve("b", 0.01, 10).await.is_err());
+    }
+}
@@ -0,0 +1,163 @@
+/// Matches `input` against a Redis style glob pattern supporting `*`, `?`,
+/// `[abc]`, `[^abc]`, `[a-z]` and `\` escapes.
+pub fn matches(pattern: &[u8], input: &[u8]) -> bool {
+    let (mut p, mut i) = (0usize, 0usize);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while i < input.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    backtrack = Some((p, i));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    i += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, input[i]) {
+                        if matched {
+                            p = next;
+                            i += 1;
+                            continue;
+                        }
+                    } else if input[i] == b'[' {
+                        p += 1;
+                        i += 1;
+                        continue;
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == input[i] {
+                        p += 2;
+                        i += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == input[i] {
+                        p += 1;
+                        i += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        match backtrack {
+            Some((bp, bi)) => {
+                p = bp + 1;
+                i = bi + 1;
+                backtrack = Some((bp, bi + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|x| *x == b'*')
+}
+
+/// Evaluates the character class starting at `pattern[start] == '['`, returns
+/// whether `c` matched and the index right after the closing bracket. `None`
+/// when the class is not terminated.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    while p < pattern.len() {
+        match pattern[p] {
+            b']' if !first => return Some((matched != negate, p + 1)),
+            b'\\' if p + 1 < pattern.len() => {
+                matched |= pattern[p + 1] == c;
+                p += 2;
+            }
+            lo if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' => {
+                let hi = pattern[p + 2];
+                let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                matched |= lo <= c && c <= hi;
+                p += 3;
+            }
+            x => {
+                matched |= x == c;
+                p += 1;
+            }
+        }
+        first = false;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pattern: &str, input: &str) -> bool {
+        matches(pattern.as_bytes(), input.as_bytes())
+    }
+
+    #[test]
+    fn star_matches_any_run() {
+        assert!(check("*", ""));
+        assert!(check("*", "anything"));
+        assert!(check("user:*", "user:"));
+        assert!(check("user:*", "user:42"));
+        assert!(check("*:42", "user:42"));
+        assert!(check("a*b*c", "axxbyyc"));
+        assert!(check("a*b*c", "abbbc"));
+        assert!(check("**", "x"));
+        assert!(!check("user:*", "users:42"));
+        assert!(!check("a*b", "acbc"));
+        assert!(!check("", "a"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_byte() {
+        assert!(check("h?llo", "hello"));
+        assert!(check("h?llo", "hallo"));
+        assert!(check("???", "abc"));
+        assert!(!check("h?llo", "hllo"));
+        assert!(!check("h?llo", "heello"));
+        assert!(!check("?", ""));
+    }
+
+    #[test]
+    fn classes_match_sets_ranges_and_negations() {
+        assert!(check("h[ae]llo", "hallo"));
+        assert!(check("h[ae]llo", "hello"));
+        assert!(!check("h[ae]llo", "hillo"));
+        assert!(check("h[^e]llo", "hallo"));
+        assert!(!check("h[^e]llo", "hello"));
+        assert!(check("key[0-9]", "key7"));
+        assert!(check("key[9-0]", "key7"));
+        assert!(!check("key[0-9]", "keyx"));
+        assert!(!check("key[0-9]", "key"));
+        // A leading `]` is part of the class, a trailing `-` is literal.
+        assert!(check("[]]", "]"));
+        assert!(check("[a-]", "-"));
+        assert!(check("[\\]]", "]"));
+        // An unterminated class is a literal `[`.
+        assert!(check("[abc", "[abc"));
+        assert!(!check("[abc", "a"));
+    }
+
+    #[test]
+    fn escapes_match_literally() {
+        assert!(check("a\\*b", "a*b"));
+        assert!(!check("a\\*b", "axb"));
+        assert!(check("a\\?", "a?"));
+        assert!(!check("a\\?", "ab"));
+        assert!(check("\\[x]", "[x]"));
+        assert!(check("a\\\\b", "a\\b"));
+        // A trailing backslash matches itself.
+        assert!(check("a\\", "a\\"));
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use super::{
+    storage::Storage,
+    value::{Change, Value, ValueError},
+};
+
+fn hash(value: &mut Value) -> Result<&mut HashMap<String, Vec<u8>>, ValueError> {
+    match value {
+        Value::Hash(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+fn new_hash() -> Value {
+    Value::Hash(HashMap::new())
+}
+
+impl Storage {
+    /// Sets the fields of the hash and returns how many of them were new.
+    pub async fn hash_set(&self, key: &str, fields: Vec<(String, Vec<u8>)>) -> Result<(usize, Change), ValueError> {
+        self.update(key, new_hash, |value| {
+            let hash = hash(value)?;
+            let changed = !fields.is_empty();
+            let mut added = 0;
+            for (field, data) in fields {
+                if hash.insert(field, data).is_none() {
+                    added += 1;
+                }
+            }
+            Ok((added, changed))
+        })
+        .await
+    }
+
+    pub async fn hash_get(&self, key: &str, field: &str) -> Result<Option<Vec<u8>>, ValueError> {
+        let data = self
+            .inspect(key, |value| match value {
+                Value::Hash(hash) => Ok(hash.get(field).cloned()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(data.flatten())
+    }
+
+    /// Removes the fields and returns how many of them existed.
+    pub async fn hash_delete(&self, key: &str, fields: &[String]) -> Result<(usize, Change), ValueError> {
+        self.update(key, new_hash, |value| {
+            let hash = hash(value)?;
+            let removed = fields.iter().filter(|x| hash.remove(*x).is_some()).count();
+            Ok((removed, removed > 0))
+        })
+        .await
+    }
+
+    /// Every field with its value, in no particular order.
+    pub async fn hash_get_all(&self, key: &str) -> Result<Vec<(String, Vec<u8>)>, ValueError> {
+        let fields = self
+            .inspect(key, |value| match value {
+                Value::Hash(hash) => Ok(hash.iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(fields.unwrap_or_default())
+    }
+
+    /// Adds `delta` to the integer held by the field, a missing field counts as zero.
+    pub async fn hash_incr_by(&self, key: &str, field: &str, delta: i64) -> Result<(i64, Change), ValueError> {
+        self.update(key, new_hash, |value| {
+            let hash = hash(value)?;
+            let current = match hash.get(field) {
+                Some(x) => std::str::from_utf8(x)
+                    .ok()
+                    .and_then(|x| x.parse::<i64>().ok())
+                    .ok_or(ValueError::NotInteger)?,
+                None => 0,
+            };
+            let result = current.checked_add(delta).ok_or(ValueError::NotInteger)?;
+            hash.insert(field.to_owned(), result.to_string().into_bytes());
+            Ok((result, true))
+        })
+        .await
+    }
+
+    /// Floating point variant of `hash_incr_by`.
+    pub async fn hash_incr_by_float(&self, key: &str, field: &str, delta: f64) -> Result<(f64, Change), ValueError> {
+        self.update(key, new_hash, |value| {
+            let hash = hash(value)?;
+            let current = match hash.get(field) {
+                Some(x) => std::str::from_utf8(x)
+                    .ok()
+                    .and_then(|x| x.parse::<f64>().ok())
+                    .ok_or(ValueError::NotFloat)?,
+                None => 0.0,
+            };
+            let result = current + delta;
+            if !result.is_finite() {
+                return Err(ValueError::NotFloat);
+            }
+            hash.insert(field.to_owned(), result.to_string().into_bytes());
+            Ok((result, true))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, Vec<u8>)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.as_bytes().to_vec())).collect()
+    }
+
+    #[tokio::test]
+    async fn sets_count_only_new_fields() {
+        let cc = Storage::new();
+        assert_eq!(cc.hash_set("h", fields(&[("a", "1"), ("b", "2")])).await.unwrap().0, 2);
+        assert_eq!(cc.hash_set("h", fields(&[("a", "3"), ("c", "4")])).await.unwrap().0, 1);
+        assert_eq!(cc.hash_get("h", "a").await.unwrap(), Some(b"3".to_vec()));
+        assert_eq!(cc.hash_get("h", "x").await.unwrap(), None);
+
+        let removed = cc.hash_delete("h", &["a".to_owned(), "x".to_owned()]).await.unwrap().0;
+        assert_eq!(removed, 1);
+        let mut all = cc.hash_get_all("h").await.unwrap();
+        all.sort();
+        assert_eq!(all, fields(&[("b", "2"), ("c", "4")]));
+        assert!(cc.hash_get_all("missing").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn increments_reject_non_numbers_and_overflows() {
+        let cc = Storage::new();
+        cc.hash_set("h", fields(&[("s", "x"), ("max", &i64::MAX.to_string())])).await.unwrap();
+
+        assert_eq!(cc.hash_incr_by("h", "n", 5).await.unwrap().0, 5);
+        assert_eq!(cc.hash_incr_by("h", "n", -7).await.unwrap().0, -2);
+        assert_eq!(cc.hash_incr_by("h", "s", 1).await.unwrap_err(), ValueError::NotInteger);
+        assert_eq!(cc.hash_incr_by("h", "max", 1).await.unwrap_err(), ValueError::NotInteger);
+
+        assert_eq!(cc.hash_incr_by_float("h", "n", 0.5).await.unwrap().0, -1.5);
+        assert_eq!(cc.hash_get("h", "n").await.unwrap(), Some(b"-1.5".to_vec()));
+        assert_eq!(cc.hash_incr_by_float("h", "n", f64::INFINITY).await.unwrap_err(), ValueError::NotFloat);
+        assert_eq!(cc.hash_incr_by_float("h", "s", 1.0).await.unwrap_err(), ValueError::NotFloat);
+    }
+}
@@ -0,0 +1,284 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{
+    sketch::{hash64, packed},
+    storage::Storage,
+    value::{Change, Value, ValueError},
+};
+
+/// Bits of the hash selecting the register.
+const PRECISION: u32 = 14;
+const REGISTERS: usize = 1 << PRECISION;
+/// Registers kept in the sparse form before switching to the dense one.
+const SPARSE_LIMIT: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Registers {
+    /// Index and value of the non-zero registers, ordered by index.
+    Sparse(Vec<(u16, u8)>),
+    Dense(Vec<u8>),
+}
+
+/// HyperLogLog cardinality estimator with a standard error of 0.81%. Small
+/// sets keep only their non-zero registers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HyperLogLog {
+    registers: Registers,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: Registers::Sparse(Vec::new()),
+        }
+    }
+}
+
+impl HyperLogLog {
+    /// Raises the register to `rank`, returns whether it changed.
+    fn raise(&mut self, index: u16, rank: u8) -> bool {
+        match &mut self.registers {
+            Registers::Dense(registers) => {
+                let changed = registers[index as usize] < rank;
+                if changed {
+                    registers[index as usize] = rank;
+                }
+                changed
+            }
+            Registers::Sparse(registers) => {
+                match registers.binary_search_by_key(&index, |(i, _)| *i) {
+                    Ok(x) if registers[x].1 >= rank => return false,
+                    Ok(x) => registers[x].1 = rank,
+                    Err(x) => registers.insert(x, (index, rank)),
+                }
+                if registers.len() > SPARSE_LIMIT {
+                    let mut dense = vec![0; REGISTERS];
+                    for (i, rank) in registers.iter() {
+                        dense[*i as usize] = *rank;
+                    }
+                    self.registers = Registers::Dense(dense);
+                }
+                true
+            }
+        }
+    }
+
+    /// Adds the element, returns whether the estimate may have changed.
+    fn add(&mut self, element: &[u8]) -> bool {
+        let hash = hash64(element, 0);
+        let index = (hash >> (64 - PRECISION)) as u16;
+        // Position of the first set bit after the index bits, the guard bit
+        // caps the rank for hashes ending in zeros.
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() + 1;
+
+        self.raise(index, rank as u8)
+    }
+
+    fn non_zero(&self) -> Box<dyn Iterator<Item = (u16, u8)> + '_> {
+        match &self.registers {
+            Registers::Sparse(registers) => Box::new(registers.iter().copied()),
+            Registers::Dense(registers) => Box::new(
+                registers
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, rank)| **rank > 0)
+                    .map(|(i, rank)| (i as u16, *rank)),
+            ),
+        }
+    }
+
+    fn merge(&mut self, other: &HyperLogLog) {
+        for (index, rank) in other.non_zero() {
+            self.raise(index, rank);
+        }
+    }
+
+    fn count(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let (mut zeros, mut sum) = (REGISTERS, 0.0);
+        for (_, rank) in self.non_zero() {
+            zeros -= 1;
+            sum += 2f64.powi(-(rank as i32));
+        }
+        sum += zeros as f64;
+
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let estimate = alpha * m * m / sum;
+        // Linear counting is more accurate while many registers are empty.
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+
+        estimate.round() as u64
+    }
+}
+
+/// Persisted as bytes, a tag followed by either the index and value of every
+/// non-zero register or all the registers, whichever is shorter.
+impl Serialize for HyperLogLog {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let registers: Vec<(u16, u8)> = self.non_zero().collect();
+        let mut bytes = Vec::new();
+        match &self.registers {
+            Registers::Dense(dense) if registers.len() * 3 >= REGISTERS => {
+                bytes.push(1);
+                bytes.extend_from_slice(dense);
+            }
+            _ => {
+                bytes.push(0);
+                for (index, rank) in registers {
+                    bytes.extend_from_slice(&index.to_be_bytes());
+                    bytes.push(rank);
+                }
+            }
+        }
+
+        packed::serialize(&bytes, s)
+    }
+}
+
+impl<'de> Deserialize<'de> for HyperLogLog {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let bytes = packed::deserialize(d)?;
+        let mut hll = HyperLogLog::default();
+        match bytes.split_first() {
+            Some((1, dense)) if dense.len() == REGISTERS => hll.registers = Registers::Dense(dense.to_vec()),
+            Some((0, sparse)) if sparse.len() % 3 == 0 => {
+                for x in sparse.chunks_exact(3) {
+                    hll.raise(u16::from_be_bytes([x[0], x[1]]) % REGISTERS as u16, x[2]);
+                }
+            }
+            _ => return Err(de::Error::custom("invalid HyperLogLog registers")),
+        }
+
+        Ok(hll)
+    }
+}
+
+fn hyperloglog(value: &mut Value) -> Result<&mut HyperLogLog, ValueError> {
+    match value {
+        Value::HyperLogLog(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+fn new_hyperloglog() -> Value {
+    Value::HyperLogLog(HyperLogLog::default())
+}
+
+/// Union of the HyperLogLogs held by the values, missing keys count as empty.
+fn union(values: Vec<Option<&Value>>) -> Result<HyperLogLog, ValueError> {
+    let mut union = HyperLogLog::default();
+    for value in values {
+        match value {
+            Some(Value::HyperLogLog(x)) => union.merge(x),
+            Some(_) => return Err(ValueError::WrongType),
+            None => {}
+        }
+    }
+
+    Ok(union)
+}
+
+impl Storage {
+    /// Adds the elements, returns whether the estimated cardinality may have changed.
+    pub async fn hyperloglog_add(&self, key: &str, elements: Vec<Vec<u8>>) -> Result<(bool, Change), ValueError> {
+        self.update(key, new_hyperloglog, |value| {
+            let hll = hyperloglog(value)?;
+            let changed = elements.iter().fold(false, |changed, x| hll.add(x) | changed);
+            Ok((changed, changed))
+        })
+        .await
+    }
+
+    /// Estimated number of distinct elements added to any of the keys.
+    pub async fn hyperloglog_count(&self, keys: &[String]) -> Result<u64, ValueError> {
+        self.inspect_many(keys, |values| Ok(union(values)?.count())).await
+    }
+
+    /// Merges the sources into the HyperLogLog at `key`, creating it when missing.
+    pub async fn hyperloglog_merge(&self, key: &str, sources: &[String]) -> Result<Change, ValueError> {
+        let sources = self.inspect_many(sources, union).await?;
+        let ((), change) = self
+            .update(key, new_hyperloglog, |value| {
+                hyperloglog(value)?.merge(&sources);
+                Ok(((), true))
+            })
+            .await?;
+
+        Ok(change)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(range: std::ops::Range<u64>) -> HyperLogLog {
+        let mut hll = HyperLogLog::default();
+        for x in range {
+            hll.add(&x.to_be_bytes());
+        }
+        hll
+    }
+
+    fn error(hll: &HyperLogLog, cardinality: u64) -> f64 {
+        (hll.count() as f64 - cardinality as f64).abs() / cardinality as f64
+    }
+
+    #[test]
+    fn estimates_stay_within_the_error_bounds() {
+        // Several times the 0.81% standard error, so the check is not flaky.
+        for cardinality in [100, 1_000, 10_000, 200_000] {
+            let hll = filled(0..cardinality);
+            assert!(error(&hll, cardinality) < 0.03, "{} estimated as {}", cardinality, hll.count());
+        }
+        assert_eq!(HyperLogLog::default().count(), 0);
+    }
+
+    #[test]
+    fn sparse_registers_turn_dense_past_the_limit() {
+        let sparse = filled(0..500);
+        assert!(matches!(sparse.registers, Registers::Sparse(_)));
+        let dense = filled(0..5_000);
+        assert!(matches!(dense.registers, Registers::Dense(_)));
+
+        // Adding known elements changes nothing.
+        let mut again = dense.clone();
+        assert!(!(0..5_000u64).any(|x| again.add(&x.to_be_bytes())));
+        assert_eq!(again, dense);
+    }
+
+    #[test]
+    fn merges_estimate_the_union() {
+        let mut union = filled(0..60_000);
+        union.merge(&filled(40_000..100_000));
+        assert!(error(&union, 100_000) < 0.03);
+
+        // Merging a sparse set into a dense one and back gives the same registers.
+        let mut small = filled(0..300);
+        small.merge(&union);
+        assert_eq!(small.count(), union.count());
+    }
+
+    #[test]
+    fn registers_survive_serialization() {
+        for hll in [filled(0..100), filled(0..50_000)] {
+            let bytes = rmp_serde::to_vec(&hll).unwrap();
+            let restored: HyperLogLog = rmp_serde::from_slice(&bytes).unwrap();
+            assert_eq!(restored.count(), hll.count());
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_reject_keys_of_another_type() {
+        let cc = Storage::new();
+        cc.hyperloglog_add("h", vec![b"a".to_vec()]).await.unwrap();
+        cc.write("s", b"x".to_vec()).await;
+
+        let keys = ["h".to_owned(), "missing".to_owned()];
+        assert_eq!(cc.hyperloglog_count(&keys).await.unwrap(), 1);
+        let keys = ["h".to_owned(), "s".to_owned()];
+        assert_eq!(cc.hyperloglog_count(&keys).await.unwrap_err(), ValueError::WrongType);
+    }
+}
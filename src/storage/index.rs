@@ -0,0 +1,312 @@
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Bound,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    json::{parse_path, select, Segment},
+    storage::Storage,
+    value::{Value, ValueError},
+};
+
+/// Value of an indexed field. Values of different types never compare equal,
+/// booleans sort before numbers and numbers before strings, so a range
+/// between two numbers only selects numbers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum IndexValue {
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+impl IndexValue {
+    /// Value of a scalar JSON node, objects, arrays and nulls are not indexed.
+    pub fn of(node: &serde_json::Value) -> Option<Self> {
+        match node {
+            serde_json::Value::Bool(x) => Some(IndexValue::Bool(*x)),
+            serde_json::Value::Number(x) => x.as_f64().map(IndexValue::Number),
+            serde_json::Value::String(x) => Some(IndexValue::String(x.clone())),
+            _ => None,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            IndexValue::Bool(_) => 0,
+            IndexValue::Number(_) => 1,
+            IndexValue::String(_) => 2,
+        }
+    }
+}
+
+impl PartialEq for IndexValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexValue {}
+
+impl PartialOrd for IndexValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IndexValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (IndexValue::Bool(a), IndexValue::Bool(b)) => a.cmp(b),
+            // Adding zero turns negative zero into zero, so both are equal.
+            (IndexValue::Number(a), IndexValue::Number(b)) => (a + 0.0).total_cmp(&(b + 0.0)),
+            (IndexValue::String(a), IndexValue::String(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+/// Name, key prefix and path of an index.
+pub type IndexDefinition = (String, String, String);
+
+/// Keys starting with `prefix` ordered by the scalar their JSON value holds
+/// at `path`. Keys whose value is not JSON or lacks the field are left out.
+#[derive(Debug, Clone)]
+struct Index {
+    prefix: String,
+    path: String,
+    segments: Vec<Segment>,
+    entries: BTreeSet<(IndexValue, String)>,
+    values: HashMap<String, IndexValue>,
+}
+
+impl Index {
+    fn insert(&mut self, key: &str, document: &serde_json::Value) {
+        self.remove(key);
+        if let Some(value) = select(document, &self.segments).and_then(IndexValue::of) {
+            self.entries.insert((value.clone(), key.to_owned()));
+            self.values.insert(key.to_owned(), value);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(value) = self.values.remove(key) {
+            self.entries.remove(&(value, key.to_owned()));
+        }
+    }
+}
+
+/// JSON held by the value, string values are parsed.
+fn document(value: &Value) -> Option<Cow<'_, serde_json::Value>> {
+    match value {
+        Value::Json(x) => Some(Cow::Borrowed(x.as_json())),
+        Value::Bytes(x) => serde_json::from_slice(x).ok().map(Cow::Owned),
+        _ => None,
+    }
+}
+
+/// Secondary indexes of a keyspace by name, kept up to date as keys are
+/// written and removed.
+#[derive(Debug, Clone, Default)]
+pub struct Indexes {
+    indexes: BTreeMap<String, Index>,
+}
+
+impl Indexes {
+    /// Indexes the value written at `key`.
+    pub fn insert(&mut self, key: &str, value: &Value) {
+        let mut covering = self.indexes.values_mut().filter(|x| key.starts_with(&x.prefix)).peekable();
+        if covering.peek().is_none() {
+            return;
+        }
+
+        match document(value) {
+            Some(document) => covering.for_each(|x| x.insert(key, &document)),
+            None => covering.for_each(|x| x.remove(key)),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        for index in self.indexes.values_mut().filter(|x| key.starts_with(&x.prefix)) {
+            index.remove(key);
+        }
+    }
+
+    /// Declares an index and builds it from the current values.
+    pub fn create<'a>(
+        &mut self,
+        name: &str,
+        prefix: &str,
+        path: &str,
+        values: impl Iterator<Item = (&'a String, &'a Value)>,
+    ) -> Result<(), ValueError> {
+        if self.indexes.contains_key(name) {
+            return Err(ValueError::IndexExists);
+        }
+        let mut index = Index {
+            prefix: prefix.to_owned(),
+            path: path.to_owned(),
+            segments: parse_path(path)?,
+            entries: BTreeSet::new(),
+            values: HashMap::new(),
+        };
+        for (key, value) in values.filter(|(key, _)| key.starts_with(prefix)) {
+            if let Some(document) = document(value) {
+                index.insert(key, &document);
+            }
+        }
+        self.indexes.insert(name.to_owned(), index);
+
+        Ok(())
+    }
+
+    pub fn drop_index(&mut self, name: &str) -> bool {
+        self.indexes.remove(name).is_some()
+    }
+
+    pub fn definitions(&self) -> Vec<IndexDefinition> {
+        self.indexes
+            .iter()
+            .map(|(name, x)| (name.clone(), x.prefix.clone(), x.path.clone()))
+            .collect()
+    }
+
+    /// Keys with field values between `min` and `max`, ordered by value and
+    /// then by key.
+    fn query<F>(&self, name: &str, min: Bound<IndexValue>, max: Bound<IndexValue>, limit: Option<usize>, filter: F) -> Result<Vec<String>, ValueError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let index = self.indexes.get(name).ok_or(ValueError::NoSuchIndex)?;
+        // Entries are ordered by value and then by key, the empty key sorts
+        // before every key of the value. Excluded values are skipped below.
+        let start = match &min {
+            Bound::Included(x) | Bound::Excluded(x) => Bound::Included((x.clone(), String::new())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let keys = index
+            .entries
+            .range((start, Bound::Unbounded))
+            .skip_while(|(value, _)| matches!(&min, Bound::Excluded(x) if value == x))
+            .take_while(|(value, _)| match &max {
+                Bound::Included(x) => value <= x,
+                Bound::Excluded(x) => value < x,
+                Bound::Unbounded => true,
+            })
+            .map(|(_, key)| key)
+            .filter(|x| filter(x))
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+
+        Ok(keys)
+    }
+}
+
+impl Storage {
+    /// Declares an index over the field at the JSON `path` of the keys starting
+    /// with `prefix`, built from the current values and maintained from then on.
+    pub async fn index_create(&self, name: &str, prefix: &str, path: &str) -> Result<(), ValueError> {
+        self.with_indexes(|indexes, values| indexes.create(name, prefix, path, values)).await
+    }
+
+    /// Removes the index, returns whether it existed.
+    pub async fn index_drop(&self, name: &str) -> bool {
+        self.with_indexes(|indexes, _| indexes.drop_index(name)).await
+    }
+
+    pub async fn index_list(&self) -> Vec<IndexDefinition> {
+        self.with_indexes(|indexes, _| indexes.definitions()).await
+    }
+
+    /// Keys of the index with field values between `min` and `max`, up to
+    /// `limit` of the ones `filter` accepts.
+    pub async fn index_query<F>(&self, name: &str, min: Bound<IndexValue>, max: Bound<IndexValue>, limit: Option<usize>, filter: F) -> Result<Vec<String>, ValueError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.with_indexes(|indexes, _| indexes.query(name, min, max, limit, filter)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::storage::WriteCondition;
+
+    async fn query(cc: &Storage, min: Bound<IndexValue>, max: Bound<IndexValue>) -> Vec<String> {
+        cc.index_query("age", min, max, None, |_| true).await.unwrap()
+    }
+
+    fn number(x: f64) -> IndexValue {
+        IndexValue::Number(x)
+    }
+
+    #[test]
+    fn values_order_by_type_then_value() {
+        let mut values = [
+            IndexValue::String("a".to_owned()),
+            number(2.0),
+            IndexValue::Bool(true),
+            number(-1.0),
+            IndexValue::Bool(false),
+        ];
+        values.sort();
+        assert_eq!(values[0], IndexValue::Bool(false));
+        assert_eq!(values[2], number(-1.0));
+        assert_eq!(values[4], IndexValue::String("a".to_owned()));
+        assert_eq!(number(-0.0), number(0.0));
+        assert_eq!(IndexValue::of(&serde_json::json!(null)), None);
+        assert_eq!(IndexValue::of(&serde_json::json!([1])), None);
+    }
+
+    #[tokio::test]
+    async fn indexes_follow_writes_and_deletes() {
+        let cc = Storage::new();
+        cc.write("user:1", br#"{"age":30}"#.to_vec()).await;
+        cc.write("user:2", b"not json".to_vec()).await;
+        cc.write("other:1", br#"{"age":20}"#.to_vec()).await;
+        cc.index_create("age", "user:", "$.age").await.unwrap();
+        assert_eq!(cc.index_create("age", "user:", "$.age").await.unwrap_err(), ValueError::IndexExists);
+        assert_eq!(query(&cc, Bound::Unbounded, Bound::Unbounded).await, ["user:1"]);
+
+        cc.json_set("user:3", "$", br#"{"age":25}"#, WriteCondition::Always).await.unwrap();
+        cc.json_set("user:4", "$", br#"{"name":"x"}"#, WriteCondition::Always).await.unwrap();
+        cc.write("user:2", br#"{"age":"unknown"}"#.to_vec()).await;
+        assert_eq!(query(&cc, Bound::Unbounded, Bound::Unbounded).await, ["user:3", "user:1", "user:2"]);
+
+        // Replaced and removed values leave the index.
+        cc.write("user:1", br#"{"age":40}"#.to_vec()).await;
+        cc.delete("user:3").await;
+        cc.write("user:2", b"plain".to_vec()).await;
+        assert_eq!(query(&cc, Bound::Unbounded, Bound::Unbounded).await, ["user:1"]);
+        assert_eq!(query(&cc, Bound::Included(number(0.0)), Bound::Excluded(number(40.0))).await, Vec::<String>::new());
+
+        assert!(cc.index_drop("age").await);
+        assert_eq!(
+            cc.index_query("age", Bound::Unbounded, Bound::Unbounded, None, |_| true).await.unwrap_err(),
+            ValueError::NoSuchIndex
+        );
+    }
+
+    #[tokio::test]
+    async fn queries_honour_bounds_limits_and_filters() {
+        let cc = Storage::new();
+        for (key, age) in [("u:a", "10"), ("u:b", "20"), ("u:c", "20"), ("u:d", "30"), ("u:e", "true")] {
+            cc.write(key, format!(r#"{{"age":{}}}"#, age).into_bytes()).await;
+        }
+        cc.index_create("age", "u:", "age").await.unwrap();
+
+        // Numeric ranges never select values of another type.
+        assert_eq!(query(&cc, Bound::Included(number(10.0)), Bound::Unbounded).await, ["u:a", "u:b", "u:c", "u:d"]);
+        assert_eq!(query(&cc, Bound::Excluded(number(10.0)), Bound::Excluded(number(30.0))).await, ["u:b", "u:c"]);
+        assert_eq!(query(&cc, Bound::Excluded(number(20.0)), Bound::Included(number(30.0))).await, ["u:d"]);
+        assert_eq!(query(&cc, Bound::Unbounded, Bound::Excluded(number(0.0))).await, ["u:e"]);
+
+        let limited = cc.index_query("age", Bound::Unbounded, Bound::Unbounded, Some(2), |x| x != "u:a").await.unwrap();
+        assert_eq!(limited, ["u:e", "u:b"]);
+    }
+}
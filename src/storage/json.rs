@@ -0,0 +1,394 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Number;
+
+use super::{
+    storage::{Storage, WriteCondition},
+    value::{Change, Value, ValueError},
+};
+
+/// JSON document, validated when written and persisted as its JSON text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct JsonDocument(serde_json::Value);
+
+impl JsonDocument {
+    pub fn as_json(&self) -> &serde_json::Value {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for JsonDocument {
+    type Error = serde_json::Error;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        serde_json::from_str(&text).map(JsonDocument)
+    }
+}
+
+impl From<JsonDocument> for String {
+    fn from(document: JsonDocument) -> Self {
+        document.0.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Segment {
+    Key(String),
+    /// Array position, negative ones counting from the end.
+    Index(i64),
+}
+
+/// Parses a path selecting a single node: `$` for the root followed by
+/// `.key`, `['key']` or `[index]` segments. The leading `$` may be left out,
+/// and `.` alone stands for the root too. Wildcards and filters are not
+/// supported.
+pub(super) fn parse_path(path: &str) -> Result<Vec<Segment>, ValueError> {
+    let invalid = ValueError::InvalidArgument("invalid JSON path");
+    let rest = match path.strip_prefix('$') {
+        Some(rest) => rest.to_owned(),
+        None if path == "." => String::new(),
+        None if path.starts_with(['.', '[']) => path.to_owned(),
+        None => format!(".{}", path),
+    };
+
+    let mut segments = Vec::new();
+    let mut rest = rest.as_str();
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        match c {
+            '.' => {
+                let end = rest.find(['.', '[']).unwrap_or(rest.len());
+                let key = &rest[..end];
+                if key.is_empty() || key.contains(['*', ']']) {
+                    return Err(invalid);
+                }
+                segments.push(Segment::Key(key.to_owned()));
+                rest = &rest[end..];
+            }
+            '[' if rest.starts_with(['\'', '"']) => {
+                let quote = &rest[..1];
+                let end = rest[1..].find(quote).ok_or(invalid)? + 1;
+                segments.push(Segment::Key(rest[1..end].to_owned()));
+                rest = rest[end + 1..].strip_prefix(']').ok_or(invalid)?;
+            }
+            '[' => {
+                let end = rest.find(']').ok_or(invalid)?;
+                segments.push(Segment::Index(rest[..end].trim().parse().map_err(|_| invalid)?));
+                rest = &rest[end + 1..];
+            }
+            _ => return Err(invalid),
+        }
+    }
+
+    Ok(segments)
+}
+
+fn position(len: usize, index: i64) -> Option<usize> {
+    let index = match index < 0 {
+        true => len as i64 + index,
+        false => index,
+    };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+pub(super) fn select<'a>(node: &'a serde_json::Value, path: &[Segment]) -> Option<&'a serde_json::Value> {
+    path.iter().try_fold(node, |node, segment| match (node, segment) {
+        (serde_json::Value::Object(map), Segment::Key(key)) => map.get(key),
+        (serde_json::Value::Array(items), Segment::Index(i)) => items.get(position(items.len(), *i)?),
+        _ => None,
+    })
+}
+
+fn select_mut<'a>(node: &'a mut serde_json::Value, path: &[Segment]) -> Option<&'a mut serde_json::Value> {
+    path.iter().try_fold(node, |node, segment| match (node, segment) {
+        (serde_json::Value::Object(map), Segment::Key(key)) => map.get_mut(key),
+        (serde_json::Value::Array(items), Segment::Index(i)) => {
+            let len = items.len();
+            items.get_mut(position(len, *i)?)
+        }
+        _ => None,
+    })
+}
+
+fn parse_json(json: &[u8]) -> Result<serde_json::Value, ValueError> {
+    serde_json::from_slice(json).map_err(|_| ValueError::InvalidArgument("invalid JSON"))
+}
+
+fn document(value: &mut Value) -> Result<&mut serde_json::Value, ValueError> {
+    match value {
+        Value::Json(x) => Ok(&mut x.0),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+/// Writes `value` at the path unless `cond` rules it out. Object members are
+/// created, array elements must exist already.
+fn set(root: &mut serde_json::Value, path: &[Segment], value: serde_json::Value, cond: WriteCondition) -> Result<bool, ValueError> {
+    let allowed = |exists: bool| match cond {
+        WriteCondition::Always => true,
+        WriteCondition::IfAbsent => !exists,
+        WriteCondition::IfPresent => exists,
+    };
+    let Some((last, parent)) = path.split_last() else {
+        let written = allowed(true);
+        if written {
+            *root = value;
+        }
+        return Ok(written);
+    };
+
+    match (select_mut(root, parent).ok_or(ValueError::NoSuchPath)?, last) {
+        (serde_json::Value::Object(map), Segment::Key(key)) => {
+            let written = allowed(map.contains_key(key));
+            if written {
+                map.insert(key.clone(), value);
+            }
+            Ok(written)
+        }
+        (serde_json::Value::Array(items), Segment::Index(i)) => {
+            let i = position(items.len(), *i).ok_or(ValueError::NoSuchPath)?;
+            let written = allowed(true);
+            if written {
+                items[i] = value;
+            }
+            Ok(written)
+        }
+        _ => Err(ValueError::NoSuchPath),
+    }
+}
+
+fn remove(root: &mut serde_json::Value, path: &[Segment]) -> bool {
+    let Some((last, parent)) = path.split_last() else {
+        return false;
+    };
+
+    match (select_mut(root, parent), last) {
+        (Some(serde_json::Value::Object(map)), Segment::Key(key)) => map.remove(key).is_some(),
+        (Some(serde_json::Value::Array(items)), Segment::Index(i)) => match position(items.len(), *i) {
+            Some(i) => {
+                items.remove(i);
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Adds `delta` to the number, keeping integers integral while the result fits.
+fn add(number: &Number, delta: f64) -> Result<Number, ValueError> {
+    let integral = number.as_i64().filter(|_| delta.fract() == 0.0 && delta.abs() < i64::MAX as f64);
+    if let Some(sum) = integral.and_then(|x| x.checked_add(delta as i64)) {
+        return Ok(sum.into());
+    }
+
+    Number::from_f64(number.as_f64().unwrap_or_default() + delta).ok_or(ValueError::NotFloat)
+}
+
+impl Storage {
+    /// Writes the JSON text at the path, the root path creating the key when
+    /// missing. Returns whether it was written, which `cond` decides on by
+    /// whether the path exists.
+    pub async fn json_set(&self, key: &str, path: &str, json: &[u8], cond: WriteCondition) -> Result<(bool, Change), ValueError> {
+        let (path, value) = (parse_path(path)?, parse_json(json)?);
+        let created = AtomicBool::new(false);
+        let init = || match path.is_empty() {
+            true => {
+                created.store(true, Ordering::Relaxed);
+                Ok(Value::Json(JsonDocument(serde_json::Value::Null)))
+            }
+            false => Err(ValueError::NoSuchKey),
+        };
+
+        self.try_update(key, init, |x| {
+            let root = document(x)?;
+            let written = match created.load(Ordering::Relaxed) {
+                true => cond != WriteCondition::IfPresent && set(root, &path, value, WriteCondition::Always)?,
+                false => set(root, &path, value, cond)?,
+            };
+            Ok((written, written))
+        })
+        .await
+    }
+
+    /// JSON text of the node selected by the path, or with several paths an
+    /// object mapping each path to its node. No paths select the root.
+    pub async fn json_get(&self, key: &str, paths: &[String]) -> Result<Option<Vec<u8>>, ValueError> {
+        let selected = paths.iter().map(|x| parse_path(x)).collect::<Result<Vec<_>, _>>()?;
+        self.inspect(key, |value| {
+            let root = match value {
+                Value::Json(x) => &x.0,
+                _ => return Err(ValueError::WrongType),
+            };
+            let node = |path: &[Segment]| select(root, path).ok_or(ValueError::NoSuchPath);
+            let fragment = match selected.len() {
+                0 => root.clone(),
+                1 => node(&selected[0])?.clone(),
+                _ => {
+                    let mut nodes = serde_json::Map::new();
+                    for (path, segments) in paths.iter().zip(selected.iter()) {
+                        nodes.insert(path.clone(), node(segments)?.clone());
+                    }
+                    serde_json::Value::Object(nodes)
+                }
+            };
+            Ok(serde_json::to_vec(&fragment).unwrap())
+        })
+        .await
+    }
+
+    /// Removes the node at the path, the root path removing the key. Returns
+    /// how many nodes were removed.
+    pub async fn json_delete(&self, key: &str, path: &str) -> Result<(usize, Change), ValueError> {
+        let path = parse_path(path)?;
+        if path.is_empty() {
+            let change = self
+                .remove_if(key, |value| match value {
+                    Value::Json(_) => Ok(true),
+                    _ => Err(ValueError::WrongType),
+                })
+                .await?;
+            return Ok((change.is_changed() as usize, change));
+        }
+
+        let removed = self
+            .try_update(key, || Err(ValueError::NoSuchKey), |value| {
+                let removed = remove(document(value)?, &path);
+                Ok((removed as usize, removed))
+            })
+            .await;
+
+        match removed {
+            Err(ValueError::NoSuchKey) => Ok((0, Change::Unchanged)),
+            removed => removed,
+        }
+    }
+
+    /// Appends the JSON values to the array at the path, returns its new length.
+    pub async fn json_array_append(&self, key: &str, path: &str, values: &[Vec<u8>]) -> Result<(usize, Change), ValueError> {
+        let path = parse_path(path)?;
+        let values = values.iter().map(|x| parse_json(x)).collect::<Result<Vec<_>, _>>()?;
+
+        self.try_update(key, || Err(ValueError::NoSuchKey), |value| {
+            match select_mut(document(value)?, &path).ok_or(ValueError::NoSuchPath)? {
+                serde_json::Value::Array(items) => {
+                    let changed = !values.is_empty();
+                    items.extend(values);
+                    Ok((items.len(), changed))
+                }
+                _ => Err(ValueError::InvalidArgument("value at path is not an array")),
+            }
+        })
+        .await
+    }
+
+    /// Adds `delta` to the number at the path, returns the JSON text of the result.
+    pub async fn json_number_incr_by(&self, key: &str, path: &str, delta: f64) -> Result<(Vec<u8>, Change), ValueError> {
+        let path = parse_path(path)?;
+        if !delta.is_finite() {
+            return Err(ValueError::NotFloat);
+        }
+
+        self.try_update(key, || Err(ValueError::NoSuchKey), |value| {
+            let node = select_mut(document(value)?, &path).ok_or(ValueError::NoSuchPath)?;
+            match node {
+                serde_json::Value::Number(number) => {
+                    *number = add(number, delta)?;
+                    Ok((number.to_string().into_bytes(), true))
+                }
+                _ => Err(ValueError::InvalidArgument("value at path is not a number")),
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(cc: &Storage, key: &str, path: &str) -> Result<Option<String>, ValueError> {
+        let json = cc.json_get(key, &[path.to_owned()]).await?;
+        Ok(json.map(|x| String::from_utf8(x).unwrap()))
+    }
+
+    #[test]
+    fn paths_parse_into_segments() {
+        let key = |x: &str| Segment::Key(x.to_owned());
+        assert_eq!(parse_path("$.a.b[0]").unwrap(), [key("a"), key("b"), Segment::Index(0)]);
+        assert_eq!(parse_path("a['x.y'][-1]").unwrap(), [key("a"), key("x.y"), Segment::Index(-1)]);
+        assert_eq!(parse_path("[\"q\"]").unwrap(), [key("q")]);
+        assert!(parse_path("$").unwrap().is_empty());
+        assert!(parse_path(".").unwrap().is_empty());
+
+        for path in ["$.", "$[x]", "$.*", "$[0", "$['a'", "$..a", "$x"] {
+            assert!(parse_path(path).is_err(), "{} parsed", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_honour_the_condition() {
+        let cc = Storage::new();
+        assert!(!cc.json_set("j", "$", b"{}", WriteCondition::IfPresent).await.unwrap().0);
+        assert_eq!(get(&cc, "j", "$").await.unwrap(), None);
+        assert_eq!(cc.json_set("j", "$.a", b"1", WriteCondition::Always).await.unwrap_err(), ValueError::NoSuchKey);
+
+        assert!(cc.json_set("j", "$", br#"{"a":[1,2]}"#, WriteCondition::Always).await.unwrap().0);
+        assert!(!cc.json_set("j", "$.a", b"3", WriteCondition::IfAbsent).await.unwrap().0);
+        assert!(cc.json_set("j", "$.b", b"true", WriteCondition::IfAbsent).await.unwrap().0);
+        assert!(cc.json_set("j", "$.a[-1]", b"5", WriteCondition::IfPresent).await.unwrap().0);
+        assert_eq!(get(&cc, "j", "$").await.unwrap().unwrap(), r#"{"a":[1,5],"b":true}"#);
+
+        // Array elements and parents must exist.
+        assert_eq!(cc.json_set("j", "$.a[2]", b"0", WriteCondition::Always).await.unwrap_err(), ValueError::NoSuchPath);
+        assert_eq!(cc.json_set("j", "$.c.d", b"0", WriteCondition::Always).await.unwrap_err(), ValueError::NoSuchPath);
+        assert!(cc.json_set("j", "$", b"{", WriteCondition::Always).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_select_one_or_many_nodes() {
+        let cc = Storage::new();
+        cc.json_set("j", "$", br#"{"a":{"b":[1,2,3]},"c":"x"}"#, WriteCondition::Always).await.unwrap();
+
+        assert_eq!(get(&cc, "j", "a.b[-1]").await.unwrap().unwrap(), "3");
+        assert_eq!(get(&cc, "j", "$.a.b[3]").await.unwrap_err(), ValueError::NoSuchPath);
+        assert_eq!(get(&cc, "missing", "$").await.unwrap(), None);
+        let many = cc.json_get("j", &["$.c".to_owned(), "a.b[0]".to_owned()]).await.unwrap().unwrap();
+        assert_eq!(many, br#"{"$.c":"x","a.b[0]":1}"#);
+    }
+
+    #[tokio::test]
+    async fn deletes_remove_nodes_or_the_key() {
+        let cc = Storage::new();
+        cc.json_set("j", "$", br#"{"a":[1,2,3],"b":1}"#, WriteCondition::Always).await.unwrap();
+
+        assert_eq!(cc.json_delete("j", "$.a[-2]").await.unwrap().0, 1);
+        assert_eq!(cc.json_delete("j", "$.a[5]").await.unwrap().0, 0);
+        assert_eq!(cc.json_delete("j", "$.x").await.unwrap().0, 0);
+        assert_eq!(get(&cc, "j", "$").await.unwrap().unwrap(), r#"{"a":[1,3],"b":1}"#);
+
+        assert_eq!(cc.json_delete("j", "$").await.unwrap().0, 1);
+        assert_eq!(get(&cc, "j", "$").await.unwrap(), None);
+        assert_eq!(cc.json_delete("j", "$.a").await.unwrap().0, 0);
+    }
+
+    #[tokio::test]
+    async fn numbers_stay_integral_while_they_fit() {
+        let cc = Storage::new();
+        let max = format!(r#"{{"n":1,"m":{},"s":"x","l":[]}}"#, i64::MAX);
+        cc.json_set("j", "$", max.as_bytes(), WriteCondition::Always).await.unwrap();
+
+        assert_eq!(cc.json_number_incr_by("j", "$.n", 2.0).await.unwrap().0, b"3");
+        assert_eq!(cc.json_number_incr_by("j", "$.n", 0.5).await.unwrap().0, b"3.5");
+        assert_eq!(cc.json_number_incr_by("j", "$.m", 1.0).await.unwrap().0, b"9.223372036854776e18");
+        assert!(cc.json_number_incr_by("j", "$.s", 1.0).await.is_err());
+        assert_eq!(cc.json_number_incr_by("j", "$.n", f64::INFINITY).await.unwrap_err(), ValueError::NotFloat);
+
+        let values = [b"1".to_vec(), br#"{"a":null}"#.to_vec()];
+        assert_eq!(cc.json_array_append("j", "$.l", &values).await.unwrap().0, 2);
+        assert!(cc.json_array_append("j", "$.s", &values).await.is_err());
+        assert_eq!(get(&cc, "j", "$.l").await.unwrap().unwrap(), r#"[1,{"a":null}]"#);
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::{hash_map, BTreeSet, HashMap},
+    ops::Bound,
+    sync::Arc,
+};
+
+use tokio::sync::Notify;
+
+use super::{index::Indexes, value::Value};
+
+/// Values the expiry loop has to visit once a point in time passes.
+pub trait Expiring {
+    /// Unix time in milliseconds the value is due at, if any.
+    fn deadline(&self) -> Option<u64>;
+}
+
+/// Position of a key in the scan order. FNV-1a is stable across processes, so
+/// cursors handed to clients stay valid after a restart.
+pub fn scan_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325u64, |acc, x| {
+        (acc ^ x as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Key to value map that also keeps its keys ordered by `scan_hash`, so the
+/// keyspace can be walked incrementally with cursors that survive writes.
+/// Optionally the keys are kept in lexicographic order too. Secondary
+/// indexes over the values are updated as keys are inserted and removed, and
+/// so are the deadlines of the values, see `Expiring`.
+#[derive(Debug, Clone)]
+pub struct Keyspace<V> {
+    map: HashMap<String, V>,
+    index: BTreeSet<(u64, String)>,
+    ordered: Option<BTreeSet<String>>,
+    indexes: Indexes,
+    /// Deadlines with their keys, earliest first.
+    deadlines: BTreeSet<(u64, String)>,
+    /// Deadline of each key in `deadlines`.
+    due_at: HashMap<String, u64>,
+    /// Notified when a deadline earlier than all others appears.
+    rescheduled: Arc<Notify>,
+}
+
+impl<V> Default for Keyspace<V> {
+    fn default() -> Self {
+        Self {
+            map: HashMap::new(),
+            index: BTreeSet::new(),
+            ordered: None,
+            indexes: Indexes::default(),
+            deadlines: BTreeSet::new(),
+            due_at: HashMap::new(),
+            rescheduled: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl<V: AsRef<Value> + Expiring> Keyspace<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keyspace maintaining the lexicographic index used by `ordered_keys`.
+    pub fn with_ordered_index() -> Self {
+        Self {
+            ordered: Some(BTreeSet::new()),
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.map.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        self.map.get_mut(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        self.indexes.insert(&key, value.as_ref());
+        self.schedule(&key, value.deadline());
+        match self.map.entry(key) {
+            hash_map::Entry::Occupied(mut x) => Some(x.insert(value)),
+            hash_map::Entry::Vacant(x) => {
+                self.index.insert((scan_hash(x.key()), x.key().clone()));
+                if let Some(ordered) = self.ordered.as_mut() {
+                    ordered.insert(x.key().clone());
+                }
+                x.insert(value);
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let value = self.map.remove(key)?;
+        self.indexes.remove(key);
+        self.schedule(key, None);
+        self.index.remove(&(scan_hash(key), key.to_owned()));
+        if let Some(ordered) = self.ordered.as_mut() {
+            ordered.remove(key);
+        }
+        Some(value)
+    }
+
+    /// Updates the secondary indexes and the deadline after the value of the
+    /// key was modified in place through `get_mut`.
+    pub fn reindex(&mut self, key: &str) {
+        if let Some(value) = self.map.get(key) {
+            self.indexes.insert(key, value.as_ref());
+            let deadline = value.deadline();
+            self.schedule(key, deadline);
+        }
+    }
+
+    /// Moves the key to its new deadline, waking the expiry loop when it is
+    /// due before every other one.
+    fn schedule(&mut self, key: &str, deadline: Option<u64>) {
+        if self.due_at.get(key).copied() == deadline {
+            return;
+        }
+        if let Some(at) = self.due_at.remove(key) {
+            self.deadlines.remove(&(at, key.to_owned()));
+        }
+        if let Some(at) = deadline {
+            if self.deadline().is_none_or(|first| at < first) {
+                self.rescheduled.notify_one();
+            }
+            self.deadlines.insert((at, key.to_owned()));
+            self.due_at.insert(key.to_owned(), at);
+        }
+    }
+
+    /// Earliest deadline of the values.
+    pub fn deadline(&self) -> Option<u64> {
+        self.deadlines.first().map(|(at, _)| *at)
+    }
+
+    /// Keys of the values due by `now`, earliest first.
+    pub fn due(&self, now: u64) -> Vec<String> {
+        self.deadlines
+            .iter()
+            .take_while(|(at, _)| *at <= now)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+
+    /// Notified when a deadline earlier than every other one appears.
+    pub fn rescheduled(&self) -> Arc<Notify> {
+        self.rescheduled.clone()
+    }
+
+    /// Secondary indexes together with every value they may be built from.
+    pub fn indexed(&mut self) -> (&mut Indexes, impl Iterator<Item = (&String, &Value)>) {
+        (&mut self.indexes, self.map.iter().map(|(key, x)| (key, x.as_ref())))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.map.keys()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.map.iter()
+    }
+
+    /// Removes and returns every entry `pred` holds for.
+    pub fn extract_if<F>(&mut self, mut pred: F) -> Vec<(String, V)>
+    where
+        F: FnMut(&String, &mut V) -> bool,
+    {
+        let extracted: Vec<(String, V)> = self.map.extract_if(|k, v| pred(k, v)).collect();
+        for (key, _) in extracted.iter() {
+            self.indexes.remove(key);
+            self.schedule(key, None);
+            self.index.remove(&(scan_hash(key), key.clone()));
+            if let Some(ordered) = self.ordered.as_mut() {
+                ordered.remove(key);
+            }
+        }
+
+        extracted
+    }
+
+    /// Keys from `start` onwards in lexicographic order. Without the ordered
+    /// index every key is collected and sorted first.
+    pub fn ordered_keys<'a>(&'a self, start: Bound<&str>) -> Box<dyn Iterator<Item = &'a String> + 'a> {
+        match &self.ordered {
+            Some(ordered) => Box::new(ordered.range::<str, _>((start, Bound::Unbounded))),
+            None => {
+                let mut keys: Vec<&String> = self
+                    .map
+                    .keys()
+                    .filter(|x| match start {
+                        Bound::Included(s) => x.as_str() >= s,
+                        Bound::Excluded(s) => x.as_str() > s,
+                        Bound::Unbounded => true,
+                    })
+                    .collect();
+                keys.sort_unstable();
+                Box::new(keys.into_iter())
+            }
+        }
+    }
+
+    /// Visits about `count` keys starting at `cursor` in scan order and returns
+    /// the ones `filter` accepts together with the cursor to continue from,
+    /// `0` once the whole keyspace was visited. Keys sharing a hash are never
+    /// split across calls, so every key present for the whole scan is returned
+    /// exactly once.
+    pub fn scan<F>(&self, cursor: u64, count: usize, mut filter: F) -> (u64, Vec<String>)
+    where
+        F: FnMut(&str, &V) -> bool,
+    {
+        let mut keys = Vec::new();
+        let mut last: Option<u64> = None;
+        for (visited, (hash, key)) in self.index.range((cursor, String::new())..).enumerate() {
+            if visited >= count.max(1) && last != Some(*hash) {
+                return (*hash, keys);
+            }
+            last = Some(*hash);
+            if let Some(value) = self.map.get(key) {
+                if filter(key, value) {
+                    keys.push(key.clone());
+                }
+            }
+        }
+
+        (0, keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    struct Plain(Value);
+
+    impl AsRef<Value> for Plain {
+        fn as_ref(&self) -> &Value {
+            &self.0
+        }
+    }
+
+    impl Expiring for Plain {
+        fn deadline(&self) -> Option<u64> {
+            None
+        }
+    }
+
+    fn keyspace(keys: impl IntoIterator<Item = String>) -> Keyspace<Plain> {
+        let mut keyspace = Keyspace::new();
+        for key in keys {
+            keyspace.insert(key, Plain(Value::Bytes(Vec::new())));
+        }
+        keyspace
+    }
+
+    /// Runs a whole scan, calling `between` before each continuation.
+    fn scan_all<F>(keyspace: &mut Keyspace<Plain>, count: usize, mut between: F) -> Vec<String>
+    where
+        F: FnMut(&mut Keyspace<Plain>, usize),
+    {
+        let (mut cursor, mut keys) = keyspace.scan(0, count, |_, _| true);
+        let mut calls = 1;
+        while cursor != 0 {
+            between(keyspace, calls);
+            let (next, found) = keyspace.scan(cursor, count, |_, _| true);
+            keys.extend(found);
+            cursor = next;
+            calls += 1;
+        }
+        keys
+    }
+
+    #[test]
+    fn scan_yields_every_key_once() {
+        let all: Vec<String> = (0..100).map(|x| format!("key:{}", x)).collect();
+        for count in [0, 1, 7, 100, 1000] {
+            let mut keyspace = keyspace(all.clone());
+            let mut found = scan_all(&mut keyspace, count, |_, _| {});
+            found.sort();
+            let mut expected = all.clone();
+            expected.sort();
+            assert_eq!(found, expected, "count {count}");
+        }
+
+        assert_eq!(keyspace(Vec::new()).scan(0, 10, |_, _| true), (0, Vec::new()));
+    }
+
+    #[test]
+    fn scan_survives_inserts_and_removes() {
+        let stable: Vec<String> = (0..100).map(|x| format!("stable:{}", x)).collect();
+        let removed: Vec<String> = (0..50).map(|x| format!("removed:{}", x)).collect();
+        let mut keyspace = keyspace(stable.iter().chain(removed.iter()).cloned());
+
+        let found = scan_all(&mut keyspace, 5, |keyspace, call| {
+            for x in 0..3 {
+                keyspace.insert(format!("added:{}:{}", call, x), Plain(Value::Bytes(Vec::new())));
+            }
+            if let Some(key) = removed.get(call) {
+                keyspace.remove(key);
+            }
+        });
+
+        let unique: HashSet<&String> = found.iter().collect();
+        assert_eq!(unique.len(), found.len(), "a key was returned twice");
+        assert!(stable.iter().all(|x| unique.contains(x)));
+    }
+
+    #[test]
+    fn scan_filters_without_losing_its_place() {
+        let mut keyspace = keyspace((0..20).map(|x| format!("key:{}", x)));
+        keyspace.insert("other".to_owned(), Plain(Value::Bytes(Vec::new())));
+
+        let (mut cursor, mut found) = (0, Vec::new());
+        loop {
+            let (next, keys) = keyspace.scan(cursor, 3, |key, _| key == "other");
+            found.extend(keys);
+            if next == 0 {
+                break;
+            }
+            assert!(next > cursor);
+            cursor = next;
+        }
+        assert_eq!(found, vec!["other".to_owned()]);
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+
+use super::{
+    storage::Storage,
+    value::{Change, Value, ValueError},
+};
+
+fn list(value: &mut Value) -> Result<&mut VecDeque<Vec<u8>>, ValueError> {
+    match value {
+        Value::List(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+fn new_list() -> Value {
+    Value::List(VecDeque::new())
+}
+
+/// Resolves inclusive `start` and `stop` indexes, negative ones counting from
+/// the end, against a sequence of `len` elements. `None` when the range is empty.
+pub(super) fn bounds(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        return None;
+    }
+
+    Some((start as usize, stop as usize))
+}
+
+impl Storage {
+    /// Pushes the values one after another to the head of the list when
+    /// `front` is set, to its tail otherwise, and returns the new length.
+    pub async fn list_push(&self, key: &str, values: Vec<Vec<u8>>, front: bool) -> Result<(usize, Change), ValueError> {
+        self.update(key, new_list, |value| {
+            let list = list(value)?;
+            let changed = !values.is_empty();
+            for x in values {
+                match front {
+                    true => list.push_front(x),
+                    false => list.push_back(x),
+                }
+            }
+            Ok((list.len(), changed))
+        })
+        .await
+    }
+
+    /// Removes and returns up to `count` values from the head or the tail.
+    pub async fn list_pop(&self, key: &str, count: usize, front: bool) -> Result<(Vec<Vec<u8>>, Change), ValueError> {
+        self.update(key, new_list, |value| {
+            let list = list(value)?;
+            let count = count.min(list.len());
+            let popped: Vec<Vec<u8>> = match front {
+                true => list.drain(..count).collect(),
+                false => list.drain(list.len() - count..).rev().collect(),
+            };
+            let changed = !popped.is_empty();
+            Ok((popped, changed))
+        })
+        .await
+    }
+
+    /// Values between the inclusive indexes, negative ones counting from the end.
+    pub async fn list_range(&self, key: &str, start: i64, stop: i64) -> Result<Vec<Vec<u8>>, ValueError> {
+        let values = self
+            .inspect(key, |value| match value {
+                Value::List(list) => Ok(match bounds(list.len(), start, stop) {
+                    Some((start, stop)) => list.range(start..=stop).cloned().collect(),
+                    None => Vec::new(),
+                }),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(values.unwrap_or_default())
+    }
+
+    pub async fn list_len(&self, key: &str) -> Result<usize, ValueError> {
+        let len = self
+            .inspect(key, |value| match value {
+                Value::List(list) => Ok(list.len()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(len.unwrap_or_default())
+    }
+
+    /// Keeps only the values between the inclusive indexes.
+    pub async fn list_trim(&self, key: &str, start: i64, stop: i64) -> Result<Change, ValueError> {
+        let ((), change) = self
+            .update(key, new_list, |value| {
+                let list = list(value)?;
+                let len = list.len();
+                match bounds(len, start, stop) {
+                    Some((start, stop)) => {
+                        list.truncate(stop + 1);
+                        list.drain(..start);
+                    }
+                    None => list.clear(),
+                }
+                Ok(((), list.len() != len))
+            })
+            .await?;
+
+        Ok(change)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|x| x.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn bounds_clamp_to_the_sequence() {
+        assert_eq!(bounds(5, 0, -1), Some((0, 4)));
+        assert_eq!(bounds(5, -2, 100), Some((3, 4)));
+        assert_eq!(bounds(5, -100, 1), Some((0, 1)));
+        assert_eq!(bounds(5, 3, 2), None);
+        assert_eq!(bounds(5, 5, 10), None);
+        assert_eq!(bounds(5, 0, -6), None);
+        assert_eq!(bounds(0, 0, -1), None);
+    }
+
+    #[tokio::test]
+    async fn pushes_and_pops_work_at_both_ends() {
+        let cc = Storage::new();
+        assert_eq!(cc.list_push("l", values(&["a", "b"]), true).await.unwrap().0, 2);
+        assert_eq!(cc.list_push("l", values(&["c", "d"]), false).await.unwrap().0, 4);
+        assert_eq!(cc.list_range("l", 0, -1).await.unwrap(), values(&["b", "a", "c", "d"]));
+
+        assert_eq!(cc.list_pop("l", 1, true).await.unwrap().0, values(&["b"]));
+        assert_eq!(cc.list_pop("l", 2, false).await.unwrap().0, values(&["d", "c"]));
+        assert_eq!(cc.list_pop("l", 10, true).await.unwrap().0, values(&["a"]));
+        assert!(cc.list_pop("l", 1, true).await.unwrap().0.is_empty());
+        assert_eq!(cc.list_len("l").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn trims_keep_the_range() {
+        let cc = Storage::new();
+        cc.list_push("l", values(&["a", "b", "c", "d", "e"]), false).await.unwrap();
+
+        assert!(cc.list_trim("l", 1, -2).await.unwrap().is_changed());
+        assert_eq!(cc.list_range("l", 0, -1).await.unwrap(), values(&["b", "c", "d"]));
+        assert!(!cc.list_trim("l", 0, 100).await.unwrap().is_changed());
+        assert_eq!(cc.list_range("l", -1, -1).await.unwrap(), values(&["d"]));
+
+        cc.list_trim("l", 2, 1).await.unwrap();
+        assert_eq!(cc.list_len("l").await.unwrap(), 0);
+
+        cc.write("s", b"x".to_vec()).await;
+        assert_eq!(cc.list_push("s", values(&["a"]), true).await.unwrap_err(), ValueError::WrongType);
+        assert_eq!(cc.list_range("s", 0, -1).await.unwrap_err(), ValueError::WrongType);
+    }
+}
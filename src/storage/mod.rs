@@ -1,10 +1,24 @@
-use std::{collections::HashMap, fmt::Debug, sync::Arc};
-
-use tokio::sync::Mutex;
-
-type CacheStorage = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+use std::fmt::Debug;
 
+pub mod bloom;
+pub mod glob;
+pub mod hash;
+pub mod hyperloglog;
+pub mod index;
+pub mod json;
+pub mod keyspace;
+pub mod list;
+pub mod pubsub;
+pub mod queue;
+pub mod schedule;
+pub mod set;
+pub mod sketch;
+#[allow(clippy::module_inception)]
 pub mod storage;
+pub mod stream;
+pub mod timeseries;
+pub mod value;
+pub mod zset;
 
 
 pub trait Cache: Clone + Debug + Sync + Send {
@@ -0,0 +1,71 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+};
+
+use tokio::sync::broadcast;
+
+use super::storage::Storage;
+
+/// Messages a subscriber may fall behind by before it misses some.
+const CAPACITY: usize = 1024;
+
+/// Subscribers of each channel. Messages are not stored, a channel without
+/// subscribers drops them.
+#[derive(Debug, Default)]
+pub struct Channels {
+    senders: Mutex<HashMap<String, broadcast::Sender<Vec<u8>>>>,
+}
+
+impl Storage {
+    /// Sends the payload to the current subscribers of the channel, returns
+    /// how many there were.
+    pub fn publish(&self, channel: &str, payload: Vec<u8>) -> usize {
+        let mut senders = self.channels.senders.lock().unwrap();
+        let sent = match senders.get(channel) {
+            Some(sender) => sender.send(payload).unwrap_or_default(),
+            None => return 0,
+        };
+        if sent == 0 {
+            senders.remove(channel);
+        }
+
+        sent
+    }
+
+    /// Receiver of the messages published to the channel from now on.
+    pub fn subscribe(&self, channel: &str) -> broadcast::Receiver<Vec<u8>> {
+        let mut senders = self.channels.senders.lock().unwrap();
+        match senders.get(channel) {
+            Some(sender) => sender.subscribe(),
+            None => {
+                let (sender, receiver) = broadcast::channel(CAPACITY);
+                senders.insert(channel.to_owned(), sender);
+                receiver
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn messages_reach_the_subscribers_of_the_channel() {
+        let cc = Storage::new();
+        assert_eq!(cc.publish("news", b"lost".to_vec()), 0);
+
+        let mut first = cc.subscribe("news");
+        let mut second = cc.subscribe("news");
+        let mut other = cc.subscribe("other");
+        assert_eq!(cc.publish("news", b"hello".to_vec()), 2);
+
+        assert_eq!(first.recv().await.unwrap(), b"hello");
+        assert_eq!(second.recv().await.unwrap(), b"hello");
+        assert!(other.try_recv().is_err());
+
+        drop((first, second));
+        assert_eq!(cc.publish("news", b"gone".to_vec()), 0);
+    }
+}
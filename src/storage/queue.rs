@@ -0,0 +1,584 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    storage::{now_millis, Storage},
+    value::{Change, Value, ValueError},
+};
+
+/// Milliseconds a popped message stays hidden by default.
+pub const DEFAULT_VISIBILITY: u64 = 30_000;
+
+/// Settings of a queue.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueOptions {
+    /// Milliseconds a popped message stays hidden before it is delivered again.
+    pub visibility: u64,
+    /// Deliveries after which a message is dead lettered instead of delivered
+    /// again, zero for no limit.
+    pub max_attempts: u32,
+    /// Queue receiving dead lettered messages, which are dropped without one.
+    /// While the key holds another type, rejections that would dead letter
+    /// fail and messages timing out stay in the queue.
+    pub dead_letter: Option<String>,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        Self {
+            visibility: DEFAULT_VISIBILITY,
+            max_attempts: 0,
+            dead_letter: None,
+        }
+    }
+}
+
+/// Token of a single delivery of a message, the message ID and a number
+/// counting the deliveries of the queue. Acknowledging or rejecting takes the
+/// token of the latest delivery, so a consumer whose visibility timeout passed
+/// can not settle the message once it was delivered to another one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Receipt {
+    pub id: u64,
+    pub delivery: u64,
+}
+
+impl Receipt {
+    /// Parses `<id>-<delivery>`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (id, delivery) = s.split_once('-')?;
+        Some(Self {
+            id: id.parse().ok()?,
+            delivery: delivery.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for Receipt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.id, self.delivery)
+    }
+}
+
+/// Message handed to a consumer, to be acknowledged with its receipt.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub receipt: Receipt,
+    pub payload: Vec<u8>,
+    /// Number of deliveries, this one included.
+    pub attempts: u32,
+}
+
+/// Message counts of a queue with its settings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueInfo {
+    pub ready: usize,
+    pub delayed: usize,
+    pub in_flight: usize,
+    pub options: QueueOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Message {
+    payload: Vec<u8>,
+    attempts: u32,
+    /// Unix time in milliseconds the message becomes visible at, for messages
+    /// in flight the end of their visibility timeout.
+    visible_at: u64,
+    in_flight: bool,
+    /// Number of the latest delivery, zero before the first one.
+    delivery: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct QueueState {
+    options: QueueOptions,
+    next_id: u64,
+    deliveries: u64,
+    messages: BTreeMap<u64, Message>,
+}
+
+/// Messages ordered by the time they become visible, then by ID. Popping a
+/// message hides it for the visibility timeout instead of removing it, it
+/// is removed once acknowledged and otherwise becomes visible again when
+/// the timeout passes or it is rejected.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "QueueState", into = "QueueState")]
+pub struct Queue {
+    options: QueueOptions,
+    next_id: u64,
+    /// Deliveries made so far, numbering the receipts.
+    deliveries: u64,
+    messages: BTreeMap<u64, Message>,
+    /// Visibility times and IDs of the messages waiting to be popped.
+    waiting: BTreeSet<(u64, u64)>,
+    /// Timeouts and IDs of the messages in flight.
+    in_flight: BTreeSet<(u64, u64)>,
+}
+
+impl From<QueueState> for Queue {
+    fn from(state: QueueState) -> Self {
+        let mut queue = Queue {
+            options: state.options,
+            next_id: state.next_id,
+            deliveries: state.deliveries,
+            ..Default::default()
+        };
+        for (id, message) in state.messages {
+            queue.schedule(id, message);
+        }
+        queue
+    }
+}
+
+impl From<Queue> for QueueState {
+    fn from(queue: Queue) -> Self {
+        QueueState {
+            options: queue.options,
+            next_id: queue.next_id,
+            deliveries: queue.deliveries,
+            messages: queue.messages,
+        }
+    }
+}
+
+impl Queue {
+    fn new(options: QueueOptions) -> Self {
+        Queue {
+            options,
+            ..Default::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// End of the earliest visibility timeout, when messages in flight have to
+    /// be released.
+    pub fn deadline(&self) -> Option<u64> {
+        self.in_flight.first().map(|(at, _)| *at)
+    }
+
+    fn schedule(&mut self, id: u64, message: Message) {
+        match message.in_flight {
+            true => self.in_flight.insert((message.visible_at, id)),
+            false => self.waiting.insert((message.visible_at, id)),
+        };
+        self.messages.insert(id, message);
+    }
+
+    fn unschedule(&mut self, id: u64) -> Option<Message> {
+        let message = self.messages.remove(&id)?;
+        match message.in_flight {
+            true => self.in_flight.remove(&(message.visible_at, id)),
+            false => self.waiting.remove(&(message.visible_at, id)),
+        };
+        Some(message)
+    }
+
+    pub(super) fn push(&mut self, payloads: Vec<Vec<u8>>, visible_at: u64) -> Vec<u64> {
+        let mut ids = Vec::with_capacity(payloads.len());
+        for payload in payloads {
+            let id = self.next_id;
+            self.next_id += 1;
+            let message = Message {
+                payload,
+                attempts: 0,
+                visible_at,
+                in_flight: false,
+                delivery: 0,
+            };
+            self.schedule(id, message);
+            ids.push(id);
+        }
+
+        ids
+    }
+
+    /// Hides up to `count` visible messages until the visibility timeout passes.
+    fn pop(&mut self, count: usize, now: u64) -> Vec<Delivery> {
+        let ids: Vec<u64> = self
+            .waiting
+            .iter()
+            .take_while(|(at, _)| *at <= now)
+            .take(count)
+            .map(|(_, id)| *id)
+            .collect();
+
+        let mut delivered = Vec::with_capacity(ids.len());
+        for id in ids {
+            let mut message = self.unschedule(id).unwrap();
+            self.deliveries += 1;
+            message.attempts = message.attempts.saturating_add(1);
+            message.visible_at = now.saturating_add(self.options.visibility);
+            message.in_flight = true;
+            message.delivery = self.deliveries;
+            delivered.push(Delivery {
+                receipt: Receipt {
+                    id,
+                    delivery: message.delivery,
+                },
+                payload: message.payload.clone(),
+                attempts: message.attempts,
+            });
+            self.schedule(id, message);
+        }
+
+        delivered
+    }
+
+    /// The message of the latest delivery the receipt is for.
+    fn delivered(&self, receipt: &Receipt) -> Option<&Message> {
+        self.messages
+            .get(&receipt.id)
+            .filter(|x| x.delivery > 0 && x.delivery == receipt.delivery)
+    }
+
+    fn exhausted(&self, message: &Message) -> bool {
+        self.options.max_attempts > 0 && message.attempts >= self.options.max_attempts
+    }
+
+    /// Makes the message in flight visible at `visible_at`, unless it used up
+    /// its attempts and `keep` is not set, then it is removed and its payload
+    /// returned.
+    fn release(&mut self, id: u64, visible_at: u64, keep: bool) -> Option<Vec<u8>> {
+        let mut message = self.unschedule(id)?;
+        if !keep && self.exhausted(&message) {
+            return Some(message.payload);
+        }
+        message.visible_at = visible_at;
+        message.in_flight = false;
+        self.schedule(id, message);
+
+        None
+    }
+
+    /// Releases the messages whose visibility timeout passed by `now`, see
+    /// `release` for `keep`.
+    fn timeout(&mut self, now: u64, keep: bool) -> (usize, Vec<Vec<u8>>) {
+        let ids: Vec<(u64, u64)> = self.in_flight.iter().take_while(|(at, _)| *at <= now).copied().collect();
+        let dead = ids.iter().filter_map(|(at, id)| self.release(*id, *at, keep)).collect();
+
+        (ids.len(), dead)
+    }
+
+    /// Removes the messages of the deliveries, in flight or not, unless they
+    /// were delivered again since. Returns how many were removed.
+    fn ack(&mut self, receipts: &[Receipt]) -> usize {
+        let ids: BTreeSet<u64> = receipts.iter().filter(|x| self.delivered(x).is_some()).map(|x| x.id).collect();
+        ids.iter().filter(|id| self.unschedule(**id).is_some()).count()
+    }
+
+    /// Whether rejecting the deliveries would dead letter any of them.
+    fn dead_letters(&self, receipts: &[Receipt]) -> bool {
+        receipts
+            .iter()
+            .filter_map(|x| self.delivered(x))
+            .any(|x| x.in_flight && self.exhausted(x))
+    }
+
+    /// Releases the messages of the deliveries still in flight to be visible
+    /// at `visible_at`, returns how many were in flight.
+    fn reject(&mut self, receipts: &[Receipt], visible_at: u64) -> (usize, Vec<Vec<u8>>) {
+        let (mut rejected, mut dead) = (0, Vec::new());
+        for receipt in receipts {
+            if self.delivered(receipt).is_some_and(|x| x.in_flight) {
+                rejected += 1;
+                dead.extend(self.release(receipt.id, visible_at, false));
+            }
+        }
+
+        (rejected, dead)
+    }
+
+    fn info(&self, now: u64) -> QueueInfo {
+        let ready = self.waiting.iter().take_while(|(at, _)| *at <= now).count();
+        QueueInfo {
+            ready,
+            delayed: self.waiting.len() - ready,
+            in_flight: self.in_flight.len(),
+            options: self.options.clone(),
+        }
+    }
+}
+
+pub(super) fn queue(value: &mut Value) -> Result<&mut Queue, ValueError> {
+    match value {
+        Value::Queue(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+pub(super) fn new_queue() -> Value {
+    Value::Queue(Queue::default())
+}
+
+fn is_queue(value: &Value) -> Result<bool, ValueError> {
+    Ok(matches!(value, Value::Queue(_)))
+}
+
+impl Storage {
+    /// Creates an empty queue with the given settings. The dead letter queue
+    /// may be missing but not hold another type.
+    pub async fn queue_create(&self, key: &str, options: QueueOptions) -> Result<Change, ValueError> {
+        if options.dead_letter.as_deref() == Some(key) {
+            return Err(ValueError::InvalidArgument("dead letter queue must be another key"));
+        }
+        self.atomically(|cc| async move {
+            if let Some(dead_letter) = options.dead_letter.as_deref() {
+                if cc.inspect(dead_letter, is_queue).await? == Some(false) {
+                    return Err(ValueError::WrongType);
+                }
+            }
+            cc.create(key, Value::Queue(Queue::new(options))).await
+        })
+        .await
+    }
+
+    /// Applies `f` to the queue and moves the payloads it returns into its
+    /// dead letter queue in the same step, creating it when missing. `f` is
+    /// told whether the dead letter queue can take payloads, it can not when
+    /// the key holds another type.
+    async fn queue_update<T, F>(&self, key: &str, now: u64, f: F) -> Result<(T, Change), ValueError>
+    where
+        F: FnOnce(&mut Queue, bool) -> Result<(T, bool, Vec<Vec<u8>>), ValueError>,
+    {
+        self.atomically(|cc| async move {
+            let dead_letter = cc
+                .inspect(key, |value| match value {
+                    Value::Queue(queue) => Ok(queue.options.dead_letter.clone()),
+                    _ => Err(ValueError::WrongType),
+                })
+                .await?
+                .ok_or(ValueError::NoSuchKey)?;
+            let writable = match dead_letter.as_deref() {
+                Some(dead_letter) => cc.inspect(dead_letter, is_queue).await?.unwrap_or(true),
+                None => true,
+            };
+
+            let ((result, dead), change) = cc
+                .try_update(key, || Err(ValueError::NoSuchKey), |value| {
+                    let (result, changed, dead) = f(queue(value)?, writable)?;
+                    Ok(((result, dead), changed))
+                })
+                .await?;
+            if let Some(dead_letter) = dead_letter.filter(|_| !dead.is_empty()) {
+                cc.update(&dead_letter, new_queue, |value| {
+                    queue(value)?.push(dead, now);
+                    Ok(((), true))
+                })
+                .await?;
+            }
+
+            Ok((result, change))
+        })
+        .await
+    }
+
+    /// Adds messages becoming visible after `delay` milliseconds and returns
+    /// their IDs. A missing key is created with the default settings.
+    pub async fn queue_push(&self, key: &str, payloads: Vec<Vec<u8>>, delay: u64, now: u64) -> Result<(Vec<u64>, Change), ValueError> {
+        self.update(key, new_queue, |value| {
+            let ids = queue(value)?.push(payloads, now.saturating_add(delay));
+            let changed = !ids.is_empty();
+            Ok((ids, changed))
+        })
+        .await
+    }
+
+    /// Delivers up to `count` visible messages, hiding them for the visibility
+    /// timeout of the queue. Missing keys have no messages.
+    pub async fn queue_pop(&self, key: &str, count: usize, now: u64) -> Result<(Vec<Delivery>, Change), ValueError> {
+        let popped = self
+            .try_update(key, || Err(ValueError::NoSuchKey), |value| {
+                let delivered = queue(value)?.pop(count, now);
+                let changed = !delivered.is_empty();
+                Ok((delivered, changed))
+            })
+            .await;
+
+        match popped {
+            Err(ValueError::NoSuchKey) => Ok((Vec::new(), Change::Unchanged)),
+            popped => popped,
+        }
+    }
+
+    /// Removes the messages of the deliveries, whether in flight or not,
+    /// unless they were delivered again since. Returns how many were removed.
+    pub async fn queue_ack(&self, key: &str, receipts: &[Receipt]) -> Result<(usize, Change), ValueError> {
+        let acked = self
+            .try_update(key, || Err(ValueError::NoSuchKey), |value| {
+                let acked = queue(value)?.ack(receipts);
+                Ok((acked, acked > 0))
+            })
+            .await;
+
+        match acked {
+            Err(ValueError::NoSuchKey) => Ok((0, Change::Unchanged)),
+            acked => acked,
+        }
+    }
+
+    /// Makes the messages of the deliveries still in flight visible again
+    /// after `delay` milliseconds, or dead letters the ones that used up their
+    /// attempts. Returns how many were in flight. Fails without changes when
+    /// a message would be dead lettered to a key holding another type.
+    pub async fn queue_nack(&self, key: &str, receipts: &[Receipt], delay: u64, now: u64) -> Result<(usize, Change), ValueError> {
+        let rejected = self
+            .queue_update(key, now, |queue, writable| {
+                if !writable && queue.dead_letters(receipts) {
+                    return Err(ValueError::WrongType);
+                }
+                let (rejected, dead) = queue.reject(receipts, now.saturating_add(delay));
+                Ok((rejected, rejected > 0, dead))
+            })
+            .await;
+
+        match rejected {
+            Err(ValueError::NoSuchKey) => Ok((0, Change::Unchanged)),
+            rejected => rejected,
+        }
+    }
+
+    /// Releases the messages whose visibility timeout passed by `now`, like
+    /// `queue_nack` without a delay. Messages are kept instead of dead
+    /// lettered to a key holding another type. Run by the expiry loop.
+    pub async fn queue_timeout(&self, key: &str, now: u64) -> Result<(usize, Change), ValueError> {
+        self.queue_update(key, now, |queue, writable| {
+            let (released, dead) = queue.timeout(now, !writable);
+            Ok((released, released > 0, dead))
+        })
+        .await
+    }
+
+    pub async fn queue_info(&self, key: &str) -> Result<Option<QueueInfo>, ValueError> {
+        let now = now_millis();
+        self.inspect(key, |value| match value {
+            Value::Queue(queue) => Ok(queue.info(now)),
+            _ => Err(ValueError::WrongType),
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(visibility: u64, max_attempts: u32, dead_letter: Option<&str>) -> QueueOptions {
+        QueueOptions {
+            visibility,
+            max_attempts,
+            dead_letter: dead_letter.map(str::to_owned),
+        }
+    }
+
+    async fn pop(cc: &Storage, key: &str, now: u64) -> Vec<Delivery> {
+        cc.queue_pop(key, 10, now).await.unwrap().0
+    }
+
+    async fn len(cc: &Storage, key: &str) -> usize {
+        let info = cc.queue_info(key).await.unwrap().unwrap();
+        info.ready + info.delayed + info.in_flight
+    }
+
+    #[tokio::test]
+    async fn delayed_messages_become_visible_after_their_delay() {
+        let cc = Storage::new();
+        let now = now_millis();
+        cc.queue_push("q", vec![b"a".to_vec()], 60_000, now).await.unwrap();
+
+        assert!(pop(&cc, "q", now + 59_999).await.is_empty());
+        let info = cc.queue_info("q").await.unwrap().unwrap();
+        assert_eq!((info.ready, info.delayed), (0, 1));
+        assert_eq!(pop(&cc, "q", now + 60_000).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn popped_messages_reappear_after_the_visibility_timeout() {
+        let cc = Storage::new();
+        cc.queue_create("q", options(100, 0, None)).await.unwrap();
+        cc.queue_push("q", vec![b"a".to_vec(), b"b".to_vec()], 0, 0).await.unwrap();
+
+        let first = pop(&cc, "q", 0).await;
+        assert_eq!(first.iter().map(|x| x.payload.as_slice()).collect::<Vec<_>>(), [b"a", b"b"]);
+        assert!(pop(&cc, "q", 99).await.is_empty());
+
+        assert_eq!(cc.queue_timeout("q", 99).await.unwrap().0, 0);
+        assert_eq!(cc.queue_timeout("q", 100).await.unwrap().0, 2);
+        let again = pop(&cc, "q", 100).await;
+        assert_eq!(again.iter().map(|x| x.attempts).collect::<Vec<_>>(), [2, 2]);
+
+        // Receipts of the first deliveries no longer settle the messages.
+        let stale: Vec<Receipt> = first.iter().map(|x| x.receipt).collect();
+        assert_eq!(cc.queue_ack("q", &stale).await.unwrap().0, 0);
+        assert_eq!(cc.queue_nack("q", &stale, 0, 100).await.unwrap().0, 0);
+        let current: Vec<Receipt> = again.iter().map(|x| x.receipt).collect();
+        assert_eq!(cc.queue_ack("q", &current).await.unwrap().0, 2);
+        assert_eq!(len(&cc, "q").await, 0);
+    }
+
+    #[tokio::test]
+    async fn rejected_messages_are_delivered_again_after_the_delay() {
+        let cc = Storage::new();
+        cc.queue_push("q", vec![b"a".to_vec()], 0, 0).await.unwrap();
+        let receipt = pop(&cc, "q", 0).await[0].receipt;
+
+        assert_eq!(cc.queue_nack("q", &[receipt], 50, 10).await.unwrap().0, 1);
+        assert_eq!(cc.queue_nack("q", &[receipt], 50, 10).await.unwrap().0, 0);
+        assert!(pop(&cc, "q", 59).await.is_empty());
+        assert_eq!(pop(&cc, "q", 60).await[0].attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn exhausted_messages_move_to_the_dead_letter_queue() {
+        let cc = Storage::new();
+        cc.queue_create("q", options(100, 2, Some("dlq"))).await.unwrap();
+        cc.queue_push("q", vec![b"a".to_vec(), b"b".to_vec()], 0, 0).await.unwrap();
+
+        pop(&cc, "q", 0).await;
+        cc.queue_timeout("q", 100).await.unwrap();
+        let second = pop(&cc, "q", 100).await;
+        assert_eq!(second.len(), 2);
+
+        cc.queue_nack("q", &[second[0].receipt], 0, 150).await.unwrap();
+        cc.queue_timeout("q", 200).await.unwrap();
+        assert_eq!(len(&cc, "q").await, 0);
+
+        let dead = pop(&cc, "dlq", 200).await;
+        assert_eq!(dead.iter().map(|x| x.payload.as_slice()).collect::<Vec<_>>(), [b"a", b"b"]);
+        assert_eq!(dead[0].attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_kept_while_the_dead_letter_key_holds_another_type() {
+        let cc = Storage::new();
+        cc.write("dlq", b"v".to_vec()).await;
+        let res = cc.queue_create("q", options(100, 1, Some("dlq"))).await;
+        assert_eq!(res, Err(ValueError::WrongType));
+        let res = cc.queue_create("q", options(100, 1, Some("q"))).await;
+        assert!(matches!(res, Err(ValueError::InvalidArgument(_))));
+
+        cc.delete("dlq").await;
+        cc.queue_create("q", options(100, 1, Some("dlq"))).await.unwrap();
+        cc.write("dlq", b"v".to_vec()).await;
+        cc.queue_push("q", vec![b"a".to_vec()], 0, 0).await.unwrap();
+        let receipt = pop(&cc, "q", 0).await[0].receipt;
+
+        assert_eq!(cc.queue_nack("q", &[receipt], 0, 10).await, Err(ValueError::WrongType));
+        assert_eq!(cc.queue_info("q").await.unwrap().unwrap().in_flight, 1);
+
+        assert_eq!(cc.queue_timeout("q", 100).await.unwrap().0, 1);
+        assert_eq!(cc.queue_info("q").await.unwrap().unwrap().ready, 1);
+        assert_eq!(cc.read("dlq").await, Some(b"v".to_vec()));
+    }
+}
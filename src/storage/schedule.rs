@@ -0,0 +1,379 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    queue::{new_queue, queue},
+    storage::Storage,
+    stream::{new_stream, stream},
+    value::{Change, Value, ValueError},
+};
+
+/// Where a scheduled payload is delivered to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Target {
+    /// Pushed as a message into the queue at the key, created when missing.
+    Queue(String),
+    /// Appended as an entry with a single `payload` field to the stream at
+    /// the key, waking the readers blocked on it.
+    Stream(String),
+    /// Published to the subscribers of the channel at the delivery time.
+    Channel(String),
+}
+
+impl Target {
+    /// Key the payload is written to, `None` for channels.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Target::Queue(key) | Target::Stream(key) => Some(key),
+            Target::Channel(_) => None,
+        }
+    }
+
+    /// Whether the target key can take the payload, a missing key can.
+    fn accepts(&self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (Target::Queue(_), Value::Queue(_)) | (Target::Stream(_), Value::Stream(_)) | (Target::Channel(_), _)
+        )
+    }
+}
+
+/// Payload waiting for its delivery time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    /// Unix time in milliseconds the payload is delivered at.
+    pub due_at: u64,
+    pub target: Target,
+    pub payload: Vec<u8>,
+    /// Set when the target held another type at the delivery time, the job
+    /// is then kept until cancelled.
+    pub undelivered: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Scheduled {
+    due_at: u64,
+    target: Target,
+    payload: Vec<u8>,
+    #[serde(default)]
+    undelivered: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScheduleState {
+    next_id: u64,
+    jobs: BTreeMap<u64, Scheduled>,
+}
+
+/// Payloads to deliver to other keys or channels at given times. The expiry
+/// loop delivers them once their time passes, see `Storage::schedule_fire`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "ScheduleState", into = "ScheduleState")]
+pub struct Schedule {
+    next_id: u64,
+    jobs: BTreeMap<u64, Scheduled>,
+    /// Delivery times and IDs of the jobs not delivered yet.
+    due: BTreeSet<(u64, u64)>,
+}
+
+impl From<ScheduleState> for Schedule {
+    fn from(state: ScheduleState) -> Self {
+        let due = state
+            .jobs
+            .iter()
+            .filter(|(_, job)| !job.undelivered)
+            .map(|(id, job)| (job.due_at, *id))
+            .collect();
+        Schedule {
+            next_id: state.next_id,
+            jobs: state.jobs,
+            due,
+        }
+    }
+}
+
+impl From<Schedule> for ScheduleState {
+    fn from(schedule: Schedule) -> Self {
+        ScheduleState {
+            next_id: schedule.next_id,
+            jobs: schedule.jobs,
+        }
+    }
+}
+
+impl Schedule {
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Delivery time of the earliest job.
+    pub fn deadline(&self) -> Option<u64> {
+        self.due.first().map(|(at, _)| *at)
+    }
+
+    fn add(&mut self, target: Target, payload: Vec<u8>, due_at: u64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.due.insert((due_at, id));
+        let job = Scheduled {
+            due_at,
+            target,
+            payload,
+            undelivered: false,
+        };
+        self.jobs.insert(id, job);
+
+        id
+    }
+
+    fn cancel(&mut self, id: u64) -> bool {
+        match self.jobs.remove(&id) {
+            Some(job) => {
+                self.due.remove(&(job.due_at, id));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// IDs of the jobs due by `now` with their targets and payloads, earliest first.
+    fn due(&self, now: u64) -> Vec<(u64, Target, Vec<u8>)> {
+        self.due
+            .iter()
+            .take_while(|(at, _)| *at <= now)
+            .map(|(_, id)| (*id, self.jobs[id].target.clone(), self.jobs[id].payload.clone()))
+            .collect()
+    }
+
+    /// Removes the jobs due by `now`, except the `undelivered` ones which are
+    /// kept but no longer due. Returns how many jobs were due.
+    fn fire(&mut self, now: u64, undelivered: &[u64]) -> usize {
+        let due: Vec<(u64, u64)> = self.due.iter().take_while(|(at, _)| *at <= now).copied().collect();
+        for (at, id) in due.iter() {
+            self.due.remove(&(*at, *id));
+            match undelivered.contains(id) {
+                true => self.jobs.get_mut(id).unwrap().undelivered = true,
+                false => {
+                    self.jobs.remove(id);
+                }
+            }
+        }
+
+        due.len()
+    }
+
+    /// Jobs ordered by delivery time, then by ID.
+    fn list(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self
+            .jobs
+            .iter()
+            .map(|(id, job)| Job {
+                id: *id,
+                due_at: job.due_at,
+                target: job.target.clone(),
+                payload: job.payload.clone(),
+                undelivered: job.undelivered,
+            })
+            .collect();
+        jobs.sort_by_key(|x| (x.due_at, x.id));
+
+        jobs
+    }
+}
+
+fn schedule(value: &mut Value) -> Result<&mut Schedule, ValueError> {
+    match value {
+        Value::Schedule(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+fn new_schedule() -> Value {
+    Value::Schedule(Schedule::default())
+}
+
+impl Storage {
+    /// Schedules the payload for delivery to `target` after `delay`
+    /// milliseconds and returns the job ID. A missing key is created, the
+    /// target key may be missing but not hold another type.
+    pub async fn schedule_add(
+        &self,
+        key: &str,
+        target: Target,
+        payload: Vec<u8>,
+        delay: u64,
+        now: u64,
+    ) -> Result<(u64, Change), ValueError> {
+        if target.key() == Some(key) {
+            return Err(ValueError::InvalidArgument("target must be another key"));
+        }
+        self.atomically(|cc| async move {
+            if let Some(target_key) = target.key() {
+                if cc.inspect(target_key, |value| Ok(target.accepts(value))).await? == Some(false) {
+                    return Err(ValueError::WrongType);
+                }
+            }
+            cc.update(key, new_schedule, |value| {
+                let id = schedule(value)?.add(target, payload, now.saturating_add(delay));
+                Ok((id, true))
+            })
+            .await
+        })
+        .await
+    }
+
+    /// Removes a job, delivered or not, returns whether it was scheduled.
+    pub async fn schedule_cancel(&self, key: &str, id: u64) -> Result<(bool, Change), ValueError> {
+        let cancelled = self
+            .try_update(key, || Err(ValueError::NoSuchKey), |value| {
+                let cancelled = schedule(value)?.cancel(id);
+                Ok((cancelled, cancelled))
+            })
+            .await;
+
+        match cancelled {
+            Err(ValueError::NoSuchKey) => Ok((false, Change::Unchanged)),
+            cancelled => cancelled,
+        }
+    }
+
+    /// Jobs of the schedule ordered by delivery time.
+    pub async fn schedule_list(&self, key: &str) -> Result<Option<Vec<Job>>, ValueError> {
+        self.inspect(key, |value| match value {
+            Value::Schedule(schedule) => Ok(schedule.list()),
+            _ => Err(ValueError::WrongType),
+        })
+        .await
+    }
+
+    /// Delivers the jobs due by `now` in the same step as removing them and
+    /// returns how many were delivered. Jobs whose target holds another type
+    /// are kept as undelivered instead. Run by the expiry loop.
+    pub async fn schedule_fire(&self, key: &str, now: u64) -> Result<(usize, Change), ValueError> {
+        let (fired, appended) = self
+            .atomically(|cc| async move {
+                let jobs = cc
+                    .inspect(key, |value| match value {
+                        Value::Schedule(schedule) => Ok(schedule.due(now)),
+                        _ => Err(ValueError::WrongType),
+                    })
+                    .await?
+                    .ok_or(ValueError::NoSuchKey)?;
+
+                let (mut undelivered, mut appended) = (Vec::new(), false);
+                for (id, target, payload) in jobs {
+                    let res = match target {
+                        Target::Queue(target) => {
+                            cc.update(&target, new_queue, |value| {
+                                queue(value)?.push(vec![payload], now);
+                                Ok(((), true))
+                            })
+                            .await
+                        }
+                        Target::Stream(target) => {
+                            let res = cc
+                                .update(&target, new_stream, |value| {
+                                    let fields = vec![("payload".to_owned(), payload)];
+                                    stream(value)?.append(fields, now).ok_or(ValueError::StreamId)?;
+                                    Ok(((), true))
+                                })
+                                .await;
+                            appended |= res.is_ok();
+                            res
+                        }
+                        Target::Channel(channel) => {
+                            cc.publish(&channel, payload);
+                            Ok(((), Change::Unchanged))
+                        }
+                    };
+                    if res.is_err() {
+                        undelivered.push(id);
+                    }
+                }
+
+                let undelivered = &undelivered;
+                let (due, change) = cc
+                    .try_update(key, || Err(ValueError::NoSuchKey), |value| {
+                        let due = schedule(value)?.fire(now, undelivered);
+                        Ok((due, due > 0))
+                    })
+                    .await?;
+
+                Ok::<_, ValueError>(((due - undelivered.len(), change), appended))
+            })
+            .await?;
+        if appended {
+            self.appended.notify_waiters();
+        }
+
+        Ok(fired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn payloads(cc: &Storage, key: &str, now: u64) -> Vec<Vec<u8>> {
+        cc.queue_pop(key, 10, now).await.unwrap().0.into_iter().map(|x| x.payload).collect()
+    }
+
+    #[tokio::test]
+    async fn jobs_are_delivered_in_order_once_due() {
+        let cc = Storage::new();
+        cc.schedule_add("s", Target::Queue("q".to_owned()), b"late".to_vec(), 200, 1_000).await.unwrap();
+        cc.schedule_add("s", Target::Queue("q".to_owned()), b"early".to_vec(), 100, 1_000).await.unwrap();
+
+        assert_eq!(cc.schedule_fire("s", 1_099).await.unwrap().0, 0);
+        assert_eq!(cc.schedule_fire("s", 1_200).await.unwrap().0, 2);
+        assert_eq!(payloads(&cc, "q", 1_200).await, [b"early".to_vec(), b"late".to_vec()]);
+
+        // The schedule is removed with its last job.
+        assert_eq!(cc.schedule_list("s").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn payloads_reach_streams_and_channels() {
+        let cc = Storage::new();
+        let mut rx = cc.subscribe("c");
+        cc.schedule_add("s", Target::Stream("x".to_owned()), b"a".to_vec(), 0, 0).await.unwrap();
+        cc.schedule_add("s", Target::Channel("c".to_owned()), b"b".to_vec(), 0, 0).await.unwrap();
+
+        assert_eq!(cc.schedule_fire("s", 0).await.unwrap().0, 2);
+        assert_eq!(cc.stream_len("x").await.unwrap(), 1);
+        assert_eq!(rx.try_recv().unwrap(), b"b");
+    }
+
+    #[tokio::test]
+    async fn undeliverable_jobs_are_kept_until_cancelled() {
+        let cc = Storage::new();
+        cc.write("t", b"x".to_vec()).await;
+        let err = cc.schedule_add("s", Target::Queue("t".to_owned()), b"a".to_vec(), 0, 0).await.unwrap_err();
+        assert_eq!(err, ValueError::WrongType);
+
+        let (id, _) = cc.schedule_add("s", Target::Queue("q".to_owned()), b"a".to_vec(), 10, 0).await.unwrap();
+        cc.write("q", b"x".to_vec()).await;
+        assert_eq!(cc.schedule_fire("s", 10).await.unwrap().0, 0);
+
+        let jobs = cc.schedule_list("s").await.unwrap().unwrap();
+        assert_eq!((jobs.len(), jobs[0].undelivered, jobs[0].payload.as_slice()), (1, true, b"a".as_slice()));
+        // Kept jobs are no longer due, so the expiry loop does not retry them.
+        assert_eq!(cc.inspect("s", |x| Ok(schedule_deadline(x))).await.unwrap(), Some(None));
+
+        assert!(cc.schedule_cancel("s", id).await.unwrap().0);
+        assert_eq!(cc.schedule_list("s").await.unwrap(), None);
+    }
+
+    fn schedule_deadline(value: &Value) -> Option<u64> {
+        match value {
+            Value::Schedule(x) => x.deadline(),
+            _ => None,
+        }
+    }
+}
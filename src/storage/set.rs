@@ -0,0 +1,160 @@
+use std::collections::HashSet;
+
+use super::{
+    storage::Storage,
+    value::{Change, Value, ValueError},
+};
+
+fn set(value: &mut Value) -> Result<&mut HashSet<Vec<u8>>, ValueError> {
+    match value {
+        Value::Set(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+fn new_set() -> Value {
+    Value::Set(HashSet::new())
+}
+
+/// Sets held by the values, missing keys count as empty sets.
+fn sets(values: Vec<Option<&Value>>) -> Result<Vec<Option<&HashSet<Vec<u8>>>>, ValueError> {
+    values
+        .into_iter()
+        .map(|value| match value {
+            Some(Value::Set(x)) => Ok(Some(x)),
+            Some(_) => Err(ValueError::WrongType),
+            None => Ok(None),
+        })
+        .collect()
+}
+
+impl Storage {
+    /// Adds the members to the set and returns how many of them were new.
+    pub async fn set_add(&self, key: &str, members: Vec<Vec<u8>>) -> Result<(usize, Change), ValueError> {
+        self.update(key, new_set, |value| {
+            let set = set(value)?;
+            let mut added = 0;
+            for member in members {
+                if set.insert(member) {
+                    added += 1;
+                }
+            }
+            Ok((added, added > 0))
+        })
+        .await
+    }
+
+    /// Removes the members from the set and returns how many of them existed.
+    pub async fn set_remove(&self, key: &str, members: &[Vec<u8>]) -> Result<(usize, Change), ValueError> {
+        self.update(key, new_set, |value| {
+            let set = set(value)?;
+            let removed = members.iter().filter(|x| set.remove(*x)).count();
+            Ok((removed, removed > 0))
+        })
+        .await
+    }
+
+    pub async fn set_contains(&self, key: &str, member: &[u8]) -> Result<bool, ValueError> {
+        let found = self
+            .inspect(key, |value| match value {
+                Value::Set(set) => Ok(set.contains(member)),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(found.unwrap_or_default())
+    }
+
+    /// Every member of the set, in no particular order.
+    pub async fn set_members(&self, key: &str) -> Result<Vec<Vec<u8>>, ValueError> {
+        let members = self
+            .inspect(key, |value| match value {
+                Value::Set(set) => Ok(set.iter().cloned().collect()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(members.unwrap_or_default())
+    }
+
+    /// Members present in every one of the sets.
+    pub async fn set_intersection(&self, keys: &[String]) -> Result<Vec<Vec<u8>>, ValueError> {
+        self.inspect_many(keys, |values| {
+            // A missing key makes the intersection empty.
+            let mut sets: Vec<&HashSet<Vec<u8>>> = match sets(values)?.into_iter().collect() {
+                Some(x) => x,
+                None => return Ok(Vec::new()),
+            };
+            sets.sort_by_key(|x| x.len());
+            let (smallest, others) = match sets.split_first() {
+                Some(x) => x,
+                None => return Ok(Vec::new()),
+            };
+
+            Ok(smallest
+                .iter()
+                .filter(|member| others.iter().all(|x| x.contains(*member)))
+                .cloned()
+                .collect())
+        })
+        .await
+    }
+
+    /// Members present in any of the sets.
+    pub async fn set_union(&self, keys: &[String]) -> Result<Vec<Vec<u8>>, ValueError> {
+        self.inspect_many(keys, |values| {
+            let mut union: HashSet<&Vec<u8>> = HashSet::new();
+            for set in sets(values)?.into_iter().flatten() {
+                union.extend(set.iter());
+            }
+
+            Ok(union.into_iter().cloned().collect())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|x| x.as_bytes().to_vec()).collect()
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn sorted(mut items: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        items.sort();
+        items
+    }
+
+    #[tokio::test]
+    async fn adds_and_removes_count_distinct_members() {
+        let cc = Storage::new();
+        assert_eq!(cc.set_add("s", members(&["a", "b", "a"])).await.unwrap().0, 2);
+        assert!(!cc.set_add("s", members(&["b"])).await.unwrap().1.is_changed());
+        assert!(cc.set_contains("s", b"a").await.unwrap());
+        assert!(!cc.set_contains("missing", b"a").await.unwrap());
+
+        assert_eq!(cc.set_remove("s", &members(&["a", "x"])).await.unwrap().0, 1);
+        assert_eq!(cc.set_members("s").await.unwrap(), members(&["b"]));
+    }
+
+    #[tokio::test]
+    async fn intersections_and_unions_treat_missing_keys_as_empty() {
+        let cc = Storage::new();
+        cc.set_add("a", members(&["1", "2", "3"])).await.unwrap();
+        cc.set_add("b", members(&["2", "3", "4"])).await.unwrap();
+        cc.write("s", b"x".to_vec()).await;
+
+        assert_eq!(sorted(cc.set_intersection(&keys(&["a", "b"])).await.unwrap()), members(&["2", "3"]));
+        assert!(cc.set_intersection(&keys(&["a", "missing"])).await.unwrap().is_empty());
+        assert_eq!(sorted(cc.set_union(&keys(&["a", "b", "missing"])).await.unwrap()), members(&["1", "2", "3", "4"]));
+
+        assert_eq!(cc.set_intersection(&keys(&["missing", "s"])).await.unwrap_err(), ValueError::WrongType);
+        assert_eq!(cc.set_union(&keys(&["a", "s"])).await.unwrap_err(), ValueError::WrongType);
+    }
+}
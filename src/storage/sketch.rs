@@ -0,0 +1,326 @@
+use std::{cmp::Reverse, fmt};
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::{
+    storage::Storage,
+    value::{Change, Value, ValueError},
+};
+
+/// Largest buffer a sketch or filter may allocate.
+pub(super) const MAX_SIZE: usize = 512 << 20;
+
+/// Stable 64 bit hash, FNV-1a finished with the SplitMix64 mixer so every
+/// output bit depends on every input bit. Persisted sketches depend on it,
+/// so it must never change.
+pub(super) fn hash64(data: &[u8], seed: u64) -> u64 {
+    let mut x = data
+        .iter()
+        .fold(0xcbf29ce484222325u64 ^ seed, |acc, x| (acc ^ *x as u64).wrapping_mul(0x100000001b3));
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+        while let Some(x) = seq.next_element()? {
+            bytes.push(x);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Serializes bytes as a msgpack bin rather than an array of integers.
+pub(super) mod packed {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        d.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+/// Serializes counters as the little endian bytes of each.
+mod packed_counters {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(counters: &[u64], s: S) -> Result<S::Ok, S::Error> {
+        let bytes: Vec<u8> = counters.iter().flat_map(|x| x.to_le_bytes()).collect();
+        s.serialize_bytes(&bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u64>, D::Error> {
+        let bytes = d.deserialize_byte_buf(BytesVisitor)?;
+        if bytes.len() % 8 != 0 {
+            return Err(de::Error::invalid_length(bytes.len(), &"a multiple of 8 bytes"));
+        }
+        Ok(bytes.chunks_exact(8).map(|x| u64::from_le_bytes(x.try_into().unwrap())).collect())
+    }
+}
+
+/// Count-Min sketch, `depth` rows of `width` counters. Estimates never
+/// undercount, they overcount by at most `e / width` of the total count with
+/// probability `1 - e^-depth`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CountMinSketch {
+    width: u32,
+    depth: u32,
+    #[serde(with = "packed_counters")]
+    counters: Vec<u64>,
+}
+
+impl CountMinSketch {
+    fn new(width: u32, depth: u32) -> Result<Self, ValueError> {
+        let len = width as usize * depth as usize;
+        if len == 0 {
+            return Err(ValueError::InvalidArgument("width and depth must be positive"));
+        }
+        if len.saturating_mul(8) > MAX_SIZE {
+            return Err(ValueError::InvalidArgument("sketch would be too large"));
+        }
+
+        Ok(Self {
+            width,
+            depth,
+            counters: vec![0; len],
+        })
+    }
+
+    /// Width and depth keeping the overcount within `error` of the total
+    /// count with the given `probability` of exceeding it.
+    pub fn dimensions(error: f64, probability: f64) -> Option<(u32, u32)> {
+        if !(error > 0.0 && error < 1.0 && probability > 0.0 && probability < 1.0) {
+            return None;
+        }
+        let width = (std::f64::consts::E / error).ceil();
+        let depth = (1.0 / probability).ln().ceil().max(1.0);
+
+        Some((width as u32, depth as u32))
+    }
+
+    fn cells(&self, item: &[u8]) -> impl Iterator<Item = usize> {
+        let (h1, h2) = (hash64(item, 0), hash64(item, 1));
+        let width = self.width as u64;
+        (0..self.depth as u64).map(move |row| (row * width + h1.wrapping_add(row.wrapping_mul(h2)) % width) as usize)
+    }
+
+    fn incr_by(&mut self, item: &[u8], delta: u64) -> u64 {
+        let cells: Vec<usize> = self.cells(item).collect();
+        for cell in cells.iter() {
+            self.counters[*cell] = self.counters[*cell].saturating_add(delta);
+        }
+        cells.into_iter().map(|x| self.counters[x]).min().unwrap_or_default()
+    }
+
+    fn query(&self, item: &[u8]) -> u64 {
+        self.cells(item).map(|x| self.counters[x]).min().unwrap_or_default()
+    }
+}
+
+/// The `k` items with the highest counts estimated by a Count-Min sketch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopK {
+    k: u32,
+    sketch: CountMinSketch,
+    /// Items with their estimated counts, highest first.
+    top: Vec<(Vec<u8>, u64)>,
+}
+
+impl TopK {
+    /// Adds the item and returns the one it pushed out of the top.
+    fn add(&mut self, item: Vec<u8>) -> Option<Vec<u8>> {
+        let count = self.sketch.incr_by(&item, 1);
+        let expelled = match self.top.iter().position(|(x, _)| *x == item) {
+            Some(position) => {
+                self.top[position].1 = count;
+                None
+            }
+            None if self.top.len() < self.k as usize => {
+                self.top.push((item, count));
+                None
+            }
+            None if self.top.last().is_some_and(|(_, min)| count > *min) => {
+                let expelled = self.top.pop().map(|(x, _)| x);
+                self.top.push((item, count));
+                expelled
+            }
+            None => None,
+        };
+        self.top.sort_by_key(|(_, count)| Reverse(*count));
+
+        expelled
+    }
+}
+
+fn count_min(value: &mut Value) -> Result<&mut CountMinSketch, ValueError> {
+    match value {
+        Value::CountMin(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+fn top_k(value: &mut Value) -> Result<&mut TopK, ValueError> {
+    match value {
+        Value::TopK(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+impl Storage {
+    /// Creates a Count-Min sketch of `depth` rows of `width` counters.
+    pub async fn count_min_init(&self, key: &str, width: u32, depth: u32) -> Result<Change, ValueError> {
+        let sketch = CountMinSketch::new(width, depth)?;
+        self.create(key, Value::CountMin(sketch)).await
+    }
+
+    /// Adds the increments to the counts of the items and returns their new estimates.
+    pub async fn count_min_incr_by(&self, key: &str, items: Vec<(Vec<u8>, u64)>) -> Result<(Vec<u64>, Change), ValueError> {
+        self.try_update(key, || Err(ValueError::NoSuchKey), |value| {
+            let sketch = count_min(value)?;
+            let counts: Vec<u64> = items.iter().map(|(item, delta)| sketch.incr_by(item, *delta)).collect();
+            Ok((counts, !items.is_empty()))
+        })
+        .await
+    }
+
+    /// Estimated counts of the items.
+    pub async fn count_min_query(&self, key: &str, items: &[Vec<u8>]) -> Result<Vec<u64>, ValueError> {
+        let counts = self
+            .inspect(key, |value| match value {
+                Value::CountMin(sketch) => Ok(items.iter().map(|x| sketch.query(x)).collect()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        counts.ok_or(ValueError::NoSuchKey)
+    }
+
+    /// Creates a top-k list of `k` items counted by a `width` by `depth` sketch.
+    pub async fn top_k_reserve(&self, key: &str, k: u32, width: u32, depth: u32) -> Result<Change, ValueError> {
+        if k == 0 {
+            return Err(ValueError::InvalidArgument("k must be positive"));
+        }
+        let top = TopK {
+            k,
+            sketch: CountMinSketch::new(width, depth)?,
+            top: Vec::new(),
+        };
+        self.create(key, Value::TopK(top)).await
+    }
+
+    /// Counts the items and returns for each the item it pushed out of the top, if any.
+    pub async fn top_k_add(&self, key: &str, items: Vec<Vec<u8>>) -> Result<(Vec<Option<Vec<u8>>>, Change), ValueError> {
+        self.try_update(key, || Err(ValueError::NoSuchKey), |value| {
+            let top = top_k(value)?;
+            let changed = !items.is_empty();
+            Ok((items.into_iter().map(|x| top.add(x)).collect(), changed))
+        })
+        .await
+    }
+
+    /// Whether each of the items is in the top.
+    pub async fn top_k_query(&self, key: &str, items: &[Vec<u8>]) -> Result<Vec<bool>, ValueError> {
+        let found = self
+            .inspect(key, |value| match value {
+                Value::TopK(top) => Ok(items.iter().map(|x| top.top.iter().any(|(item, _)| item == x)).collect()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        found.ok_or(ValueError::NoSuchKey)
+    }
+
+    /// Items of the top with their estimated counts, highest first.
+    pub async fn top_k_list(&self, key: &str) -> Result<Vec<(Vec<u8>, u64)>, ValueError> {
+        let top = self
+            .inspect(key, |value| match value {
+                Value::TopK(top) => Ok(top.top.clone()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        top.ok_or(ValueError::NoSuchKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_never_undercount() {
+        let (width, depth) = CountMinSketch::dimensions(0.01, 0.01).unwrap();
+        assert_eq!((width, depth), (272, 5));
+        let mut sketch = CountMinSketch::new(width, depth).unwrap();
+        let mut total = 0;
+        for x in 0..2_000u64 {
+            sketch.incr_by(&x.to_be_bytes(), x % 7 + 1);
+            total += x % 7 + 1;
+        }
+
+        // The overcount bound holds for all but a small share of the items.
+        let bound = total as f64 * 0.01;
+        let mut exceeding = 0;
+        for x in 0..2_000u64 {
+            let (estimate, count) = (sketch.query(&x.to_be_bytes()), x % 7 + 1);
+            assert!(estimate >= count);
+            if (estimate - count) as f64 > bound {
+                exceeding += 1;
+            }
+        }
+        assert!(exceeding < 2_000 / 20, "{} items exceed the bound", exceeding);
+    }
+
+    #[test]
+    fn dimensions_reject_invalid_arguments() {
+        assert_eq!(CountMinSketch::dimensions(0.0, 0.01), None);
+        assert_eq!(CountMinSketch::dimensions(0.01, 1.0), None);
+        assert!(CountMinSketch::new(0, 5).is_err());
+        assert!(CountMinSketch::new(u32::MAX, u32::MAX).is_err());
+    }
+
+    #[tokio::test]
+    async fn top_k_keeps_the_heavy_hitters() {
+        let cc = Storage::new();
+        cc.top_k_reserve("t", 2, 100, 4).await.unwrap();
+        let mut items = Vec::new();
+        for (item, count) in [("a", 10), ("b", 5), ("c", 1), ("d", 1)] {
+            items.extend(vec![item.as_bytes().to_vec(); count]);
+        }
+        cc.top_k_add("t", items).await.unwrap();
+
+        let top = cc.top_k_list("t").await.unwrap();
+        assert_eq!(top, [(b"a".to_vec(), 10), (b"b".to_vec(), 5)]);
+        let items = [b"b".to_vec(), b"c".to_vec()];
+        assert_eq!(cc.top_k_query("t", &items).await.unwrap(), [true, false]);
+
+        // A later item overtaking the top pushes the lowest one out.
+        let (expelled, _) = cc.top_k_add("t", vec![b"c".to_vec(); 6]).await.unwrap();
+        assert_eq!(expelled.into_iter().flatten().collect::<Vec<_>>(), [b"b".to_vec()]);
+        assert_eq!(cc.top_k_add("missing", vec![b"a".to_vec()]).await.unwrap_err(), ValueError::NoSuchKey);
+    }
+}
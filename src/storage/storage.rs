@@ -1,28 +1,91 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
-use std::future::Future;
+use std::{
+    future::Future,
+    ops::Bound,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use std::ops::Add;
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::Mutex;
-use tokio::time::Instant;
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard};
+use tokio::time::{timeout, Instant};
 
-use crate::proto::CommandMessage::DELETE;
+use serde::{Deserialize, Serialize};
+
+use crate::proto::CommandMessage::{DELETE, QTIMEOUT, SCHEDFIRE};
 use crate::proto::FrameMessage;
+use crate::storage::{
+    glob,
+    index::Indexes,
+    keyspace::{Expiring, Keyspace},
+    pubsub::Channels,
+    value::{Change, Value, ValueError},
+};
+
+/// Current Unix time in milliseconds.
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
 
-use super::CacheStorage;
+/// Where a walk over the keys starting with `prefix` begins, after the key
+/// `after` when paginating.
+fn prefix_start<'a>(prefix: &'a str, after: Option<&'a str>) -> Bound<&'a str> {
+    match after {
+        Some(after) if after >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix),
+    }
+}
 
-#[derive(Debug, Clone, Eq, PartialOrd, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 struct Entry {
     expires_at: Option<Instant>,
-    value: Vec<u8>,
+    value: Value,
     key: String,
+    flags: u32,
+    version: u64,
+}
+
+impl Entry {
+    fn item(&self) -> Item {
+        Item {
+            value: self.value.as_bytes().cloned().unwrap_or_default(),
+            flags: self.flags,
+            version: self.version,
+            ttl: self.expires_at.map(|at| at.saturating_duration_since(Instant::now())),
+        }
+    }
+}
+
+impl Expiring for Entry {
+    /// The expiration of the key, or the deadline of its value when earlier.
+    fn deadline(&self) -> Option<u64> {
+        let expires_at = self.expires_at.map(|at| {
+            let ttl = at.saturating_duration_since(Instant::now()).as_nanos().div_ceil(1_000_000);
+            now_millis().saturating_add(ttl as u64)
+        });
+        match (expires_at, self.value.deadline()) {
+            (Some(x), Some(y)) => Some(x.min(y)),
+            (x, y) => x.or(y),
+        }
+    }
+}
+
+impl AsRef<Value> for Entry {
+    fn as_ref(&self) -> &Value {
+        &self.value
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 impl Ord for Entry {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        return self.expires_at.cmp(&other.expires_at).reverse();
+        self.expires_at.cmp(&other.expires_at).reverse()
     }
 
     fn max(self, other: Self) -> Self
@@ -40,70 +103,1049 @@ impl Ord for Entry {
     }
 }
 
+/// Precondition checked against the current key state before a write is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriteCondition {
+    Always,
+    IfAbsent,
+    IfPresent,
+}
+
+/// Copy of an entry together with its metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub value: Vec<u8>,
+    /// Opaque client flags, used by the memcached protocol.
+    pub flags: u32,
+    /// Unique token that changes on every write of the key.
+    pub version: u64,
+    pub ttl: Option<Duration>,
+}
+
+/// Memcached style store semantics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreMode {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    Cas(u64),
+}
+
+/// Restricts the keys returned by a scan by their remaining time to live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TtlFilter {
+    /// Keys without an expiration.
+    Persistent,
+    /// Keys with an expiration.
+    Volatile,
+    /// Keys expiring within the duration.
+    Below(Duration),
+    /// Persistent keys and keys expiring after the duration.
+    Above(Duration),
+}
+
+impl TtlFilter {
+    fn matches(&self, ttl: Option<Duration>) -> bool {
+        match (self, ttl) {
+            (TtlFilter::Persistent, ttl) => ttl.is_none(),
+            (TtlFilter::Volatile, ttl) => ttl.is_some(),
+            (TtlFilter::Below(x), Some(ttl)) => ttl < *x,
+            (TtlFilter::Below(_), None) => false,
+            (TtlFilter::Above(x), Some(ttl)) => ttl > *x,
+            (TtlFilter::Above(_), None) => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreStatus {
+    Stored(Item),
+    NotStored,
+    Exists,
+    NotFound,
+    NotNumeric,
+}
+
+/// Hands the keyspace moved out by `Storage::atomically` back to the locked
+/// storage, also when the transaction is dropped or panics.
+struct Restore {
+    guard: Option<OwnedMutexGuard<Keyspace<Entry>>>,
+    stash: Arc<Mutex<Keyspace<Entry>>>,
+}
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let mut guard = self.guard.take().unwrap();
+        if let Ok(mut stash) = self.stash.try_lock() {
+            *guard = std::mem::take(&mut *stash);
+            return;
+        }
+
+        // An operation of the dropped transaction still holds the keyspace,
+        // the storage stays locked until it is handed back.
+        let stash = self.stash.clone();
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                *guard = std::mem::take(&mut *stash.lock().await);
+            });
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Storage {
-    cc: CacheStorage,
-    stash: Arc<Mutex<HashMap<String, Entry>>>,
+    stash: Arc<Mutex<Keyspace<Entry>>>,
+    versions: Arc<AtomicU64>,
+    /// Woken whenever an entry is appended to a stream, so blocked stream
+    /// reads can try again.
+    pub(super) appended: Arc<Notify>,
+    pub(super) channels: Arc<Channels>,
+    /// See `Keyspace::rescheduled`.
+    rescheduled: Arc<Notify>,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Storage {
     pub fn new() -> Self {
-        let cc = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+        Self::with_keyspace(Keyspace::new())
+    }
+
+    /// Storage keeping its keys in lexicographic order as well, which makes
+    /// `range`, `prefix` and `delete_prefix` proportional to their result.
+    pub fn with_ordered_index() -> Self {
+        Self::with_keyspace(Keyspace::with_ordered_index())
+    }
+
+    fn with_keyspace(keyspace: Keyspace<Entry>) -> Self {
         Self {
-            cc,
-            stash: Arc::new(Mutex::new(HashMap::new())),
+            rescheduled: keyspace.rescheduled(),
+            stash: Arc::new(Mutex::new(keyspace)),
+            versions: Arc::new(AtomicU64::new(0)),
+            appended: Arc::new(Notify::new()),
+            channels: Arc::new(Channels::default()),
+        }
+    }
+
+    fn next_version(&self) -> u64 {
+        self.versions.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn entry(&self, key: &str, value: Vec<u8>, expires_at: Option<Instant>, flags: u32) -> Entry {
+        Entry {
+            key: key.to_owned(),
+            value: Value::Bytes(value),
+            expires_at,
+            flags,
+            version: self.next_version(),
+        }
+    }
+
+    /// Applies `f` to the collection at `key`, starting from `init()` when the
+    /// key is missing. `f` returns its result and whether it modified the
+    /// value. Keys left with an empty collection are removed.
+    pub(super) async fn update<T, F>(&self, key: &str, init: fn() -> Value, f: F) -> Result<(T, Change), ValueError>
+    where
+        F: FnOnce(&mut Value) -> Result<(T, bool), ValueError>,
+    {
+        self.try_update(key, || Ok(init()), f).await
+    }
+
+    /// `update` for commands that fail on missing keys, `init` returns the
+    /// error instead of a value.
+    pub(super) async fn try_update<T, I, F>(&self, key: &str, init: I, f: F) -> Result<(T, Change), ValueError>
+    where
+        I: FnOnce() -> Result<Value, ValueError>,
+        F: FnOnce(&mut Value) -> Result<(T, bool), ValueError>,
+    {
+        let mut g = self.stash.lock().await;
+        let (result, changed) = match g.get_mut(key) {
+            Some(entry) => f(&mut entry.value)?,
+            None => {
+                let mut value = init()?;
+                let (result, changed) = f(&mut value)?;
+                if changed {
+                    let entry = Entry {
+                        key: key.to_owned(),
+                        value,
+                        expires_at: None,
+                        flags: 0,
+                        version: 0,
+                    };
+                    g.insert(key.to_owned(), entry);
+                }
+                (result, changed)
+            }
+        };
+        if !changed {
+            return Ok((result, Change::Unchanged));
+        }
+
+        let entry = g.get_mut(key).unwrap();
+        if entry.value.is_empty_collection() {
+            g.remove(key);
+            return Ok((result, Change::Removed));
+        }
+        entry.version = self.next_version();
+        let version = entry.version;
+        g.reindex(key);
+
+        Ok((result, Change::Written(version)))
+    }
+
+    /// Inserts the value at `key` unless the key exists.
+    pub(super) async fn create(&self, key: &str, value: Value) -> Result<Change, ValueError> {
+        let mut g = self.stash.lock().await;
+        if g.contains_key(key) {
+            return Err(ValueError::KeyExists);
+        }
+        let entry = Entry {
+            key: key.to_owned(),
+            value,
+            expires_at: None,
+            flags: 0,
+            version: self.next_version(),
+        };
+        let change = Change::Written(entry.version);
+        g.insert(key.to_owned(), entry);
+
+        Ok(change)
+    }
+
+    /// Removes the key when `f` accepts its value.
+    pub(super) async fn remove_if<F>(&self, key: &str, f: F) -> Result<Change, ValueError>
+    where
+        F: FnOnce(&Value) -> Result<bool, ValueError>,
+    {
+        let mut g = self.stash.lock().await;
+        match g.get(key) {
+            Some(entry) if f(&entry.value)? => {
+                g.remove(key);
+                Ok(Change::Removed)
+            }
+            _ => Ok(Change::Unchanged),
+        }
+    }
+
+    /// Applies `f` to the secondary indexes, with the values of every key for
+    /// building new ones.
+    pub(super) async fn with_indexes<T, F>(&self, f: F) -> T
+    where
+        F: for<'a> FnOnce(&mut Indexes, &mut dyn Iterator<Item = (&'a String, &'a Value)>) -> T,
+    {
+        let mut g = self.stash.lock().await;
+        let (indexes, mut values) = g.indexed();
+        f(indexes, &mut values)
+    }
+
+    /// Applies `f` to the values at `keys` under a single lock acquisition,
+    /// `None` for missing keys.
+    pub(super) async fn inspect_many<T, F>(&self, keys: &[String], f: F) -> Result<T, ValueError>
+    where
+        F: FnOnce(Vec<Option<&Value>>) -> Result<T, ValueError>,
+    {
+        let g = self.stash.lock().await;
+        f(keys.iter().map(|x| g.get(x).map(|entry| &entry.value)).collect())
+    }
+
+    /// Applies `f` to the value at `key`, `None` when the key is missing.
+    pub(super) async fn inspect<T, F>(&self, key: &str, f: F) -> Result<Option<T>, ValueError>
+    where
+        F: FnOnce(&Value) -> Result<T, ValueError>,
+    {
+        match self.stash.lock().await.get(key) {
+            Some(entry) => f(&entry.value).map(Some),
+            None => Ok(None),
         }
     }
 
+    /// Writes the value and returns the one it replaced.
     pub async fn write(&self, key: &str, data: Vec<u8>) -> Option<Vec<u8>> {
-        let res = self.stash.lock().await.insert(key.to_owned(), Entry { key: key.clone().to_owned(), value: data.clone(), expires_at: None });
-        println!("{:?}", res);
-        return None;
+        let entry = self.entry(key, data, None, 0);
+        self.stash.lock().await.insert(key.to_owned(), entry).and_then(|x| x.value.into_bytes())
     }
 
+    /// Writes the value expiring after `exp` and returns the one it replaced.
     pub async fn write_ex(&self, key: &str, data: Vec<u8>, exp: Duration) -> Option<Vec<u8>> {
-        let res = self.stash.lock().await.insert(key.to_owned(), Entry { key: key.clone().to_owned(), value: data.clone(), expires_at: Some(Instant::now().add(exp)) });
+        let entry = self.entry(key, data, Some(Instant::now().add(exp)), 0);
+        self.stash.lock().await.insert(key.to_owned(), entry).and_then(|x| x.value.into_bytes())
+    }
+
+    /// Writes the value only when `cond` holds for the key, returns whether it was written.
+    pub async fn write_if(&self, key: &str, data: Vec<u8>, exp: Option<Duration>, cond: WriteCondition) -> bool {
+        self.put_if(key, data, exp, cond).await.0.is_some()
+    }
+
+    /// Writes the value only when `cond` holds for the key. Returns the written
+    /// item, if any, and the value the key held before, written or not.
+    pub async fn put_if(&self, key: &str, data: Vec<u8>, exp: Option<Duration>, cond: WriteCondition) -> (Option<Item>, Option<Vec<u8>>) {
+        let mut g = self.stash.lock().await;
+        let exists = g.contains_key(key);
+        let previous = g.get(key).and_then(|x| x.value.as_bytes().cloned());
+        match cond {
+            WriteCondition::IfAbsent if exists => return (None, previous),
+            WriteCondition::IfPresent if !exists => return (None, previous),
+            _ => {}
+        }
+
+        let entry = self.entry(key, data, exp.map(|x| Instant::now().add(x)), 0);
+        let item = entry.item();
+        g.insert(key.to_owned(), entry);
+
+        (Some(item), previous)
+    }
+
+    /// Puts back an entry read from the WAL or a snapshot with its original
+    /// version, later writes get higher versions. Records older than the entry
+    /// already restored for the key are skipped, returns whether it was put back.
+    pub async fn restore(&self, key: &str, item: Item) -> bool {
+        self.restore_value(key, Value::Bytes(item.value), item.ttl, item.flags, item.version).await
+    }
+
+    /// `restore` for values of any type.
+    pub async fn restore_value(&self, key: &str, value: Value, ttl: Option<Duration>, flags: u32, version: u64) -> bool {
+        self.versions.fetch_max(version, Ordering::Relaxed);
+        let mut g = self.stash.lock().await;
+        if g.get(key).is_some_and(|current| current.version > version) {
+            return false;
+        }
+        let entry = Entry {
+            key: key.to_owned(),
+            value,
+            expires_at: ttl.map(|x| Instant::now().add(x)),
+            flags,
+            version,
+        };
+        g.insert(key.to_owned(), entry);
+
+        true
+    }
+
+    /// Sets the version of an existing key to the one recorded in the WAL.
+    pub async fn restore_version(&self, key: &str, version: u64) {
+        self.versions.fetch_max(version, Ordering::Relaxed);
+        if let Some(entry) = self.stash.lock().await.get_mut(key) {
+            entry.version = version;
+        }
+    }
+
+    /// Applies a memcached style store. Append and prepend keep the flags and
+    /// expiration of the existing entry.
+    pub async fn store(&self, key: &str, data: Vec<u8>, exp: Option<Duration>, flags: u32, mode: StoreMode) -> StoreStatus {
+        let mut g = self.stash.lock().await;
+        let entry = match (mode, g.get(key)) {
+            (StoreMode::Append | StoreMode::Prepend, Some(current)) if current.value.as_bytes().is_none() => {
+                return StoreStatus::NotStored
+            }
+            (StoreMode::Add, Some(_)) => return StoreStatus::NotStored,
+            (StoreMode::Replace | StoreMode::Append | StoreMode::Prepend, None) => return StoreStatus::NotStored,
+            (StoreMode::Cas(_), None) => return StoreStatus::NotFound,
+            (StoreMode::Cas(version), Some(current)) if current.version != version => return StoreStatus::Exists,
+            (StoreMode::Append, Some(current)) => {
+                let mut value = current.value.as_bytes().cloned().unwrap_or_default();
+                value.extend(data);
+                self.entry(key, value, current.expires_at, current.flags)
+            }
+            (StoreMode::Prepend, Some(current)) => {
+                let mut value = data;
+                value.extend_from_slice(current.value.as_bytes().map(|x| x.as_slice()).unwrap_or_default());
+                self.entry(key, value, current.expires_at, current.flags)
+            }
+            _ => self.entry(key, data, exp.map(|x| Instant::now().add(x)), flags),
+        };
+
+        let item = entry.item();
+        g.insert(key.to_owned(), entry);
+
+        StoreStatus::Stored(item)
+    }
+
+    /// Writes the value only when the key is at version `expected`. On a
+    /// mismatch returns the current version, `None` when the key is missing.
+    pub async fn compare_and_swap(&self, key: &str, data: Vec<u8>, expected: u64, exp: Option<Duration>) -> Result<Item, Option<u64>> {
+        let mut g = self.stash.lock().await;
+        match g.get(key) {
+            Some(current) if current.version == expected => {}
+            current => return Err(current.map(|x| x.version)),
+        }
+
+        let entry = self.entry(key, data, exp.map(|x| Instant::now().add(x)), 0);
+        let item = entry.item();
+        g.insert(key.to_owned(), entry);
+
+        Ok(item)
+    }
+
+    /// Increments or decrements an unsigned decimal value. Increments wrap
+    /// around on overflow and decrements stop at zero. A missing key is created
+    /// with the initial value and expiration of `vivify` when given.
+    pub async fn incr(&self, key: &str, delta: u64, decr: bool, vivify: Option<(u64, Option<Duration>)>) -> StoreStatus {
+        let mut g = self.stash.lock().await;
+        let entry = match g.get(key) {
+            Some(current) => {
+                let value = current.value.as_bytes().and_then(|x| std::str::from_utf8(x).ok());
+                let value = match value.and_then(|x| x.trim().parse::<u64>().ok()) {
+                    Some(x) => x,
+                    None => return StoreStatus::NotNumeric,
+                };
+                let value = if decr { value.saturating_sub(delta) } else { value.wrapping_add(delta) };
+                self.entry(key, value.to_string().into_bytes(), current.expires_at, current.flags)
+            }
+            None => match vivify {
+                Some((initial, exp)) => self.entry(key, initial.to_string().into_bytes(), exp.map(|x| Instant::now().add(x)), 0),
+                None => return StoreStatus::NotFound,
+            },
+        };
+
+        let item = entry.item();
+        g.insert(key.to_owned(), entry);
+
+        StoreStatus::Stored(item)
+    }
+
+    /// Adds `delta` to a signed decimal value. A missing key counts as zero and
+    /// is created expiring after `ttl`, existing keys keep their expiration.
+    /// Overflows and non integer values leave the key untouched.
+    pub async fn incr_by(&self, key: &str, delta: i64, ttl: Option<Duration>) -> StoreStatus {
+        self.update_number(key, ttl, |current| {
+            let value = match current {
+                Some(x) => std::str::from_utf8(x).ok()?.parse::<i64>().ok()?,
+                None => 0,
+            };
+            value.checked_add(delta).map(|x| x.to_string())
+        })
+        .await
+    }
+
+    /// Floating point variant of `incr_by`, results that are not finite are rejected.
+    pub async fn incr_by_float(&self, key: &str, delta: f64, ttl: Option<Duration>) -> StoreStatus {
+        self.update_number(key, ttl, |current| {
+            let value = match current {
+                Some(x) => std::str::from_utf8(x).ok()?.parse::<f64>().ok()?,
+                None => 0.0,
+            };
+            let value = value + delta;
+            value.is_finite().then(|| value.to_string())
+        })
+        .await
+    }
+
+    async fn update_number<F>(&self, key: &str, ttl: Option<Duration>, f: F) -> StoreStatus
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<String>,
+    {
+        let mut g = self.stash.lock().await;
+        let current = g.get(key);
+        let value = match current.map(|x| x.value.as_bytes()) {
+            Some(None) => return StoreStatus::NotNumeric,
+            Some(Some(x)) => f(Some(x)),
+            None => f(None),
+        };
+        let value = match value {
+            Some(x) => x.into_bytes(),
+            None => return StoreStatus::NotNumeric,
+        };
+        let entry = match current {
+            Some(current) => self.entry(key, value, current.expires_at, current.flags),
+            None => self.entry(key, value, ttl.map(|x| Instant::now().add(x)), 0),
+        };
+
+        let item = entry.item();
+        g.insert(key.to_owned(), entry);
 
-        return None;
+        StoreStatus::Stored(item)
     }
 
+    /// Deletes the key when `version` is `None` or matches the current entry.
+    pub async fn delete_if(&self, key: &str, version: Option<u64>) -> StoreStatus {
+        let mut g = self.stash.lock().await;
+        match (g.get(key), version) {
+            (None, _) => StoreStatus::NotFound,
+            (Some(current), Some(version)) if current.version != version => StoreStatus::Exists,
+            _ => StoreStatus::Stored(g.remove(key).unwrap().item()),
+        }
+    }
+
+    /// Version of the entry, `None` when the key is missing.
+    pub async fn version(&self, key: &str) -> Option<u64> {
+        self.stash.lock().await.get(key).map(|x| x.version)
+    }
+
+    /// Runs `f` with exclusive access to the keyspace. `f` gets a storage
+    /// operating on the locked keyspace, every other caller waits until the
+    /// future it returns completes, so its operations apply as a single unit.
+    pub async fn atomically<F, Fut, R>(&self, f: F) -> R
+    where
+        F: FnOnce(Arc<Storage>) -> Fut,
+        Fut: Future<Output = R>,
+    {
+        let mut guard = self.stash.clone().lock_owned().await;
+        let scoped = Arc::new(Storage {
+            stash: Arc::new(Mutex::new(std::mem::take(&mut *guard))),
+            versions: self.versions.clone(),
+            appended: self.appended.clone(),
+            channels: self.channels.clone(),
+            rescheduled: self.rescheduled.clone(),
+        });
+        let _restore = Restore {
+            guard: Some(guard),
+            stash: scoped.stash.clone(),
+        };
+
+        f(scoped).await
+    }
+
+    /// Value of a string key, `None` for missing keys and other types.
     pub async fn read(&self, key: &str) -> Option<Vec<u8>> {
-        match self.stash.lock().await.get(key.clone()) {
-            Some(x) => Some(x.clone().value),
-            None => None,
+        self.stash.lock().await.get(key).and_then(|x| x.value.as_bytes().cloned())
+    }
+
+    /// Type of the value held by the key.
+    pub async fn kind(&self, key: &str) -> Option<&'static str> {
+        self.stash.lock().await.get(key).map(|x| x.value.kind())
+    }
+
+    /// Value of any type with its remaining time to live and version.
+    pub async fn read_value(&self, key: &str) -> Option<(Value, Option<Duration>, u64)> {
+        self.stash.lock().await.get(key).map(|x| {
+            let ttl = x.expires_at.map(|at| at.saturating_duration_since(Instant::now()));
+            (x.value.clone(), ttl, x.version)
+        })
+    }
+
+    /// Value of a string key with its metadata.
+    pub async fn read_item(&self, key: &str) -> Option<Item> {
+        self.stash.lock().await.get(key).filter(|x| x.value.as_bytes().is_some()).map(|x| x.item())
+    }
+
+    /// Reads several keys under a single lock acquisition, misses are `None`.
+    pub async fn read_many(&self, keys: &[String]) -> Vec<Option<Vec<u8>>> {
+        let g = self.stash.lock().await;
+        keys.iter().map(|x| g.get(x).and_then(|x| x.value.as_bytes().cloned())).collect()
+    }
+
+    /// Writes several entries, each with its own expiration, under a single
+    /// lock acquisition. Returns the written items in order.
+    pub async fn write_many(&self, entries: Vec<(String, Vec<u8>, Option<Duration>)>) -> Vec<Item> {
+        let now = Instant::now();
+        let entries: Vec<Entry> = entries
+            .into_iter()
+            .map(|(key, data, exp)| self.entry(&key, data, exp.map(|x| now.add(x)), 0))
+            .collect();
+        let items = entries.iter().map(|x| x.item()).collect();
+
+        let mut g = self.stash.lock().await;
+        for entry in entries {
+            g.insert(entry.key.clone(), entry);
         }
+
+        items
+    }
+
+    /// Deletes several keys under a single lock acquisition, returns whether each existed.
+    pub async fn delete_many(&self, keys: &[String]) -> Vec<bool> {
+        let mut g = self.stash.lock().await;
+        keys.iter().map(|x| g.remove(x).is_some()).collect()
     }
 
     pub async fn keys(&self) -> Option<Vec<String>> {
-        Some(self.stash.lock().await.keys().map(|x| x.clone()).collect())
+        Some(self.stash.lock().await.keys().cloned().collect())
+    }
+
+    /// Key-value pairs with keys in `[start, end)` in lexicographic order, up
+    /// to `limit` of the keys `filter` accepts.
+    pub async fn range<F>(&self, start: &str, end: Option<&str>, limit: Option<usize>, filter: F) -> Vec<(String, Vec<u8>)>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let g = self.stash.lock().await;
+        g.ordered_keys(Bound::Included(start))
+            .take_while(|x| end.is_none_or(|end| x.as_str() < end))
+            .filter(|x| filter(x))
+            .filter_map(|x| g.get(x).and_then(|entry| Some((x.clone(), entry.value.as_bytes()?.clone()))))
+            .take(limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    /// Key-value pairs of the keys starting with `prefix` in lexicographic
+    /// order, continuing after the key `after` when paginating.
+    pub async fn prefix<F>(&self, prefix: &str, after: Option<&str>, limit: Option<usize>, filter: F) -> Vec<(String, Vec<u8>)>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let g = self.stash.lock().await;
+        g.ordered_keys(prefix_start(prefix, after))
+            .take_while(|x| x.starts_with(prefix))
+            .filter(|x| filter(x))
+            .filter_map(|x| g.get(x).and_then(|entry| Some((x.clone(), entry.value.as_bytes()?.clone()))))
+            .take(limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    /// `prefix` for keys of any type, without their values.
+    pub async fn prefix_keys<F>(&self, prefix: &str, after: Option<&str>, limit: Option<usize>, filter: F) -> Vec<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let g = self.stash.lock().await;
+        g.ordered_keys(prefix_start(prefix, after))
+            .take_while(|x| x.starts_with(prefix))
+            .filter(|x| filter(x))
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
     }
 
-    pub async fn delete(&self, key: &str) -> Option<Vec<u8>> {
-        match self.stash.lock().await.remove(key.clone()) {
-            None => None,
-            Some(x) => Some(x.value)
+    /// Deletes the keys starting with `prefix` that `filter` accepts and returns them.
+    pub async fn delete_prefix<F>(&self, prefix: &str, filter: F) -> Vec<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut g = self.stash.lock().await;
+        let keys: Vec<String> = g
+            .ordered_keys(Bound::Included(prefix))
+            .take_while(|x| x.starts_with(prefix))
+            .filter(|x| filter(x))
+            .cloned()
+            .collect();
+        for key in keys.iter() {
+            g.remove(key);
         }
+
+        keys
     }
 
-    async fn wait(&self) -> Vec<String> {
+    /// Walks the keyspace incrementally, see `Keyspace::scan`. `pattern` is a
+    /// glob matched against the keys and `ttl` filters on the remaining TTL.
+    pub async fn scan(&self, cursor: u64, count: usize, pattern: Option<&str>, ttl: Option<TtlFilter>) -> (u64, Vec<String>) {
+        let now = Instant::now();
+        self.stash.lock().await.scan(cursor, count, |key, entry| {
+            pattern.is_none_or(|x| glob::matches(x.as_bytes(), key.as_bytes()))
+                && ttl.is_none_or(|x| x.matches(entry.expires_at.map(|at| at.saturating_duration_since(now))))
+        })
+    }
+
+    /// Removes the key and returns the value it held.
+    pub async fn delete(&self, key: &str) -> Option<Value> {
+        self.stash.lock().await.remove(key).map(|x| x.value)
+    }
+
+    /// Remaining time to live: `None` when the key is missing, `Some(None)` when it never expires.
+    pub async fn ttl(&self, key: &str) -> Option<Option<Duration>> {
+        self.stash
+            .lock()
+            .await
+            .get(key)
+            .map(|x| x.expires_at.map(|at| at.saturating_duration_since(Instant::now())))
+    }
+
+    /// Replaces the expiration of an existing key, `None` makes it persistent.
+    pub async fn set_expiration(&self, key: &str, exp: Option<Duration>) -> bool {
         let mut g = self.stash.lock().await;
-        let res = g.extract_if(|x, entry| {
-            match entry.expires_at {
-                None => { false }
-                Some(x) => {
-                    Instant::now() >= x
+        match g.get_mut(key) {
+            Some(entry) => {
+                entry.expires_at = exp.map(|x| Instant::now().add(x));
+                g.reindex(key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Waits until the earliest deadline of the keyspace passes, see
+    /// `expire`. Cancel safe.
+    pub async fn expiring(&self) {
+        loop {
+            // Notified after the deadline is read, for one earlier than it.
+            let rescheduled = self.rescheduled.notified();
+            let deadline = self.stash.lock().await.deadline();
+            let now = now_millis();
+            match deadline {
+                Some(at) if at <= now => return,
+                Some(at) => {
+                    let _ = timeout(Duration::from_millis(at - now), rescheduled).await;
                 }
+                None => rescheduled.await,
             }
+        }
+    }
+
+    /// Removes the expired keys and updates the values whose deadline passed
+    /// with exclusive access to the keyspace, sending the WAL records of both
+    /// to `tx` in the order they were applied.
+    pub async fn expire(&self, tx: &UnboundedSender<FrameMessage>) {
+        self.atomically(|cc| async move {
+            let (now, instant) = (now_millis(), Instant::now());
+            let due = cc.stash.lock().await.due(now);
+            for key in due {
+                let mut g = cc.stash.lock().await;
+                let due = match g.get(&key) {
+                    Some(entry) if entry.expires_at.is_some_and(|at| at <= instant) => Due::Expired,
+                    Some(Entry { value: Value::Queue(_), .. }) => Due::Queue,
+                    Some(Entry { value: Value::Schedule(_), .. }) => Due::Schedule,
+                    // Expiring within the millisecond.
+                    _ => continue,
+                };
+                let (command, res) = match due {
+                    Due::Expired => {
+                        g.remove(&key);
+                        let _ = tx.send(DELETE(key).into());
+                        continue;
+                    }
+                    Due::Queue => {
+                        drop(g);
+                        (QTIMEOUT(key.clone(), now), cc.queue_timeout(&key, now).await)
+                    }
+                    Due::Schedule => {
+                        drop(g);
+                        (SCHEDFIRE(key.clone(), now), cc.schedule_fire(&key, now).await)
+                    }
+                };
+                if let Ok((_, change)) = res {
+                    if change.is_changed() {
+                        let _ = tx.send(command.applied(change).into());
+                    }
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// What the expiry loop does with a key that is due.
+enum Due {
+    /// The key expired and is removed.
+    Expired,
+    /// Messages of the queue reached the end of their visibility timeout.
+    Queue,
+    /// Jobs of the schedule are due for delivery.
+    Schedule,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(value: &[u8], version: u64) -> Item {
+        Item {
+            value: value.to_vec(),
+            flags: 0,
+            version,
+            ttl: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn restore_skips_stale_records() {
+        let cc = Storage::new();
+        assert!(cc.restore("k", item(b"new", 7)).await);
+        assert!(!cc.restore("k", item(b"old", 3)).await);
+        assert_eq!(cc.read("k").await, Some(b"new".to_vec()));
+        assert_eq!(cc.version("k").await, Some(7));
+
+        assert!(cc.restore("k", item(b"newer", 9)).await);
+        assert_eq!(cc.read("k").await, Some(b"newer".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn expire_handles_due_keys_in_deadline_order() {
+        let cc = Storage::new();
+        cc.write_ex("k", b"v".to_vec(), Duration::from_millis(20)).await;
+        cc.write_ex("later", b"v".to_vec(), Duration::from_secs(60)).await;
+        let options = crate::storage::queue::QueueOptions {
+            visibility: 0,
+            ..Default::default()
+        };
+        cc.queue_create("q", options).await.unwrap();
+        cc.queue_push("q", vec![b"a".to_vec()], 0, 0).await.unwrap();
+        cc.queue_pop("q", 1, now_millis()).await.unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::time::timeout(Duration::from_secs(1), cc.expiring()).await.unwrap();
+        cc.expire(&tx).await;
+        match rx.try_recv().unwrap().command {
+            crate::proto::CommandMessage::APPLIED(_, command) => assert!(matches!(*command, QTIMEOUT(..))),
+            command => panic!("unexpected {:?}", command),
+        }
+        assert!(rx.try_recv().is_err());
+
+        tokio::time::timeout(Duration::from_secs(1), cc.expiring()).await.unwrap();
+        cc.expire(&tx).await;
+        assert!(matches!(rx.try_recv().unwrap().command, DELETE(key) if key == "k"));
+        assert_eq!(cc.read("k").await, None);
+        assert_eq!(cc.read("later").await, Some(b"v".to_vec()));
+
+        // Nothing else is due for a minute.
+        assert!(tokio::time::timeout(Duration::from_millis(50), cc.expiring()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn atomically_restores_keyspace_when_cancelled_under_contention() {
+        let cc = Arc::new(Storage::new());
+        cc.write("k", b"v".to_vec()).await;
+
+        // The transaction is cancelled while the keyspace it moved out is
+        // still locked by one of its operations.
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let storage = cc.clone();
+        let task = tokio::spawn(async move {
+            storage
+                .atomically(|scoped| async move {
+                    let _ = tx.send(scoped.stash.clone().lock_owned().await);
+                    std::future::pending::<()>().await
+                })
+                .await
         });
+        let held = rx.await.unwrap();
+        task.abort();
+        let _ = task.await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(held);
 
-        let keys: Vec<String> = res.into_iter().map(|(x, _)| { x }).collect();
+        assert_eq!(cc.read("k").await, Some(b"v".to_vec()));
+    }
 
-        keys
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|x| x.to_string()).collect()
     }
 
-    pub async fn expire(&self, mut tx: &UnboundedSender<FrameMessage>) {
-        self.wait().await.iter().for_each(|x| {
-            tx.send(DELETE(x.clone()).into()).unwrap();
-        })
+    #[tokio::test]
+    async fn read_many_misses_missing_keys_and_other_types() {
+        let cc = Storage::new();
+        cc.write("a", b"1".to_vec()).await;
+        cc.list_push("l", vec![b"x".to_vec()], true).await.unwrap();
+
+        let values = cc.read_many(&keys(&["a", "missing", "l", "a"])).await;
+        assert_eq!(values, vec![Some(b"1".to_vec()), None, None, Some(b"1".to_vec())]);
+        assert!(cc.read_many(&[]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn write_many_replaces_values_of_any_type() {
+        let cc = Storage::new();
+        cc.write("a", b"old".to_vec()).await;
+        cc.list_push("l", vec![b"x".to_vec()], true).await.unwrap();
+        let before = cc.version("a").await.unwrap();
+
+        let items = cc
+            .write_many(vec![
+                ("a".to_owned(), b"1".to_vec(), None),
+                ("l".to_owned(), b"2".to_vec(), Some(Duration::from_secs(60))),
+                ("new".to_owned(), b"3".to_vec(), None),
+            ])
+            .await;
+
+        assert_eq!(items.iter().map(|x| x.value.as_slice()).collect::<Vec<_>>(), vec![b"1", b"2", b"3"]);
+        assert!(items[0].version > before);
+        assert!(items.windows(2).all(|x| x[0].version < x[1].version));
+        assert_eq!(cc.read_many(&keys(&["a", "l", "new"])).await, vec![Some(b"1".to_vec()), Some(b"2".to_vec()), Some(b"3".to_vec())]);
+        assert_eq!(cc.kind("l").await, Some("string"));
+        assert!(cc.ttl("l").await.unwrap().is_some());
+        assert_eq!(cc.ttl("a").await, Some(None));
+    }
+
+    #[tokio::test]
+    async fn delete_many_reports_each_key_once() {
+        let cc = Storage::new();
+        cc.write("a", b"1".to_vec()).await;
+        cc.write("b", b"2".to_vec()).await;
+        cc.list_push("l", vec![b"x".to_vec()], true).await.unwrap();
+
+        let deleted = cc.delete_many(&keys(&["a", "missing", "l", "a"])).await;
+        assert_eq!(deleted, vec![true, false, true, false]);
+        assert_eq!(deleted.iter().filter(|x| **x).count(), 2);
+        assert_eq!(cc.keys().await, Some(vec!["b".to_owned()]));
+    }
+
+    /// Storages with and without the ordered index holding the same keys.
+    async fn ordered_storages() -> [Storage; 2] {
+        let storages = [Storage::new(), Storage::with_ordered_index()];
+        for cc in storages.iter() {
+            for key in ["b", "a:2", "a", "a:1", "c", "a:10"] {
+                cc.write(key, key.as_bytes().to_vec()).await;
+            }
+            cc.list_push("a:3", vec![b"x".to_vec()], true).await.unwrap();
+        }
+        storages
+    }
+
+    fn names(pairs: Vec<(String, Vec<u8>)>) -> Vec<String> {
+        for (key, value) in pairs.iter() {
+            assert_eq!(key.as_bytes(), value.as_slice());
+        }
+        pairs.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[tokio::test]
+    async fn range_includes_start_and_excludes_end() {
+        for cc in ordered_storages().await {
+            let all = |_: &str| true;
+            assert_eq!(names(cc.range("a:1", Some("b"), None, all).await), vec!["a:1", "a:10", "a:2"]);
+            assert_eq!(names(cc.range("a:1", Some("a:2"), None, all).await), vec!["a:1", "a:10"]);
+            assert_eq!(names(cc.range("a:10", None, None, all).await), vec!["a:10", "a:2", "b", "c"]);
+            assert_eq!(names(cc.range("", None, Some(2), all).await), vec!["a", "a:1"]);
+            assert!(cc.range("b", Some("b"), None, all).await.is_empty());
+            assert!(cc.range("c", Some("a"), None, all).await.is_empty());
+            // The limit counts the keys the filter accepts.
+            assert_eq!(names(cc.range("a", None, Some(2), |x| x != "a:1").await), vec!["a", "a:10"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn prefix_pages_after_a_key() {
+        for cc in ordered_storages().await {
+            let all = |_: &str| true;
+            assert_eq!(names(cc.prefix("a:", None, None, all).await), vec!["a:1", "a:10", "a:2"]);
+            assert_eq!(names(cc.prefix("a:", Some("a:1"), None, all).await), vec!["a:10", "a:2"]);
+            assert_eq!(names(cc.prefix("a:", Some("a:10"), Some(1), all).await), vec!["a:2"]);
+            // A key before the prefix starts at the prefix, one past it ends the walk.
+            assert_eq!(names(cc.prefix("a:", Some("a"), Some(1), all).await), vec!["a:1"]);
+            assert!(cc.prefix("a:", Some("a:3"), None, all).await.is_empty());
+            assert!(cc.prefix("d", None, None, all).await.is_empty());
+            // The empty prefix matches every key.
+            assert_eq!(names(cc.prefix("", None, None, all).await), vec!["a", "a:1", "a:10", "a:2", "b", "c"]);
+            assert_eq!(names(cc.prefix("", None, None, |x| x.len() == 1).await), vec!["a", "b", "c"]);
+
+            assert_eq!(cc.prefix_keys("a:", Some("a:1"), None, all).await, vec!["a:10", "a:2", "a:3"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_prefix_removes_accepted_keys_of_any_type() {
+        for cc in ordered_storages().await {
+            assert_eq!(cc.delete_prefix("a:", |x| x != "a:2").await, vec!["a:1", "a:10", "a:3"]);
+            assert_eq!(cc.kind("a:3").await, None);
+            assert_eq!(cc.read("a:2").await, Some(b"a:2".to_vec()));
+            assert!(cc.delete_prefix("d", |_| true).await.is_empty());
+
+            assert_eq!(cc.delete_prefix("", |_| true).await, vec!["a", "a:2", "b", "c"]);
+            assert_eq!(cc.keys().await, Some(Vec::new()));
+        }
+    }
+
+    #[tokio::test]
+    async fn conditional_writes_check_the_key() {
+        let cc = Storage::new();
+
+        assert!(!cc.write_if("k", b"1".to_vec(), None, WriteCondition::IfPresent).await);
+        assert_eq!(cc.read("k").await, None);
+        assert!(cc.write_if("k", b"1".to_vec(), None, WriteCondition::IfAbsent).await);
+        assert!(!cc.write_if("k", b"2".to_vec(), None, WriteCondition::IfAbsent).await);
+        assert_eq!(cc.read("k").await, Some(b"1".to_vec()));
+        assert!(cc.write_if("k", b"3".to_vec(), Some(Duration::from_secs(60)), WriteCondition::IfPresent).await);
+        assert!(cc.ttl("k").await.unwrap().is_some());
+        assert!(cc.write_if("k", b"4".to_vec(), None, WriteCondition::Always).await);
+        assert_eq!(cc.read("k").await, Some(b"4".to_vec()));
+        assert_eq!(cc.ttl("k").await, Some(None));
+    }
+
+    #[tokio::test]
+    async fn put_if_returns_the_previous_value_either_way() {
+        let cc = Storage::new();
+
+        let (written, previous) = cc.put_if("k", b"1".to_vec(), None, WriteCondition::IfPresent).await;
+        assert_eq!((written, previous), (None, None));
+        let (written, previous) = cc.put_if("k", b"1".to_vec(), None, WriteCondition::IfAbsent).await;
+        assert_eq!((written.map(|x| x.value), previous), (Some(b"1".to_vec()), None));
+
+        // Skipped writes still return the current value, for GET.
+        let (written, previous) = cc.put_if("k", b"2".to_vec(), None, WriteCondition::IfAbsent).await;
+        assert_eq!((written, previous), (None, Some(b"1".to_vec())));
+        let version = cc.version("k").await.unwrap();
+        let (written, previous) = cc.put_if("k", b"3".to_vec(), None, WriteCondition::Always).await;
+        assert!(written.unwrap().version > version);
+        assert_eq!(previous, Some(b"1".to_vec()));
+
+        // Values of other types count as present but have no previous value.
+        cc.list_push("l", vec![b"x".to_vec()], true).await.unwrap();
+        let (written, previous) = cc.put_if("l", b"v".to_vec(), None, WriteCondition::IfAbsent).await;
+        assert_eq!((written, previous), (None, None));
+        let (written, previous) = cc.put_if("l", b"v".to_vec(), None, WriteCondition::IfPresent).await;
+        assert_eq!((written.is_some(), previous), (true, None));
+        assert_eq!(cc.read("l").await, Some(b"v".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn write_returns_the_replaced_value() {
+        let cc = Storage::new();
+        assert_eq!(cc.write("k", b"1".to_vec()).await, None);
+        assert_eq!(cc.write("k", b"2".to_vec()).await, Some(b"1".to_vec()));
+        assert_eq!(cc.write_ex("k", b"3".to_vec(), Duration::from_secs(60)).await, Some(b"2".to_vec()));
+
+        cc.list_push("l", vec![b"x".to_vec()], true).await.unwrap();
+        assert_eq!(cc.write("l", b"v".to_vec()).await, None);
+        assert_eq!(cc.read("l").await, Some(b"v".to_vec()));
+    }
+
+    fn stored(status: StoreStatus) -> Vec<u8> {
+        match status {
+            StoreStatus::Stored(item) => item.value,
+            x => panic!("expected a stored value, got {:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn incr_by_rejects_overflow_and_non_numeric_values() {
+        let cc = Storage::new();
+        assert_eq!(stored(cc.incr_by("n", 5, None).await), b"5");
+        assert_eq!(stored(cc.incr_by("n", -7, None).await), b"-2");
+
+        cc.write("max", i64::MAX.to_string().into_bytes()).await;
+        assert_eq!(cc.incr_by("max", 1, None).await, StoreStatus::NotNumeric);
+        assert_eq!(cc.read("max").await, Some(i64::MAX.to_string().into_bytes()));
+        assert_eq!(cc.incr_by("n", i64::MIN, None).await, StoreStatus::NotNumeric);
+
+        for value in ["abc", "1.5", " 1", ""] {
+            cc.write("s", value.as_bytes().to_vec()).await;
+            assert_eq!(cc.incr_by("s", 1, None).await, StoreStatus::NotNumeric, "{value:?}");
+            assert_eq!(cc.read("s").await, Some(value.as_bytes().to_vec()));
+        }
+        cc.list_push("l", vec![b"1".to_vec()], true).await.unwrap();
+        assert_eq!(cc.incr_by("l", 1, None).await, StoreStatus::NotNumeric);
+    }
+
+    #[tokio::test]
+    async fn incr_by_float_rejects_non_finite_and_non_numeric_values() {
+        let cc = Storage::new();
+        assert_eq!(stored(cc.incr_by_float("f", 1.5, None).await), b"1.5");
+        cc.write("i", b"3".to_vec()).await;
+        assert_eq!(stored(cc.incr_by_float("i", 0.25, None).await), b"3.25");
+
+        assert_eq!(cc.incr_by_float("f", f64::INFINITY, None).await, StoreStatus::NotNumeric);
+        assert_eq!(cc.incr_by_float("f", f64::NAN, None).await, StoreStatus::NotNumeric);
+        cc.write("big", f64::MAX.to_string().into_bytes()).await;
+        assert_eq!(cc.incr_by_float("big", f64::MAX, None).await, StoreStatus::NotNumeric);
+        assert_eq!(cc.read("f").await, Some(b"1.5".to_vec()));
+
+        cc.write("s", b"abc".to_vec()).await;
+        assert_eq!(cc.incr_by_float("s", 1.0, None).await, StoreStatus::NotNumeric);
+    }
+
+    #[tokio::test]
+    async fn counters_only_take_the_ttl_when_created() {
+        let cc = Storage::new();
+        let ttl = Some(Duration::from_secs(60));
+
+        cc.incr_by("n", 1, ttl).await;
+        let created = cc.ttl("n").await.unwrap().unwrap();
+        assert!(created <= Duration::from_secs(60) && created > Duration::from_secs(59));
+        cc.incr_by("n", 1, Some(Duration::from_secs(3600))).await;
+        assert!(cc.ttl("n").await.unwrap().unwrap() <= created);
+
+        // Existing keys keep having no expiration.
+        cc.write("p", b"1".to_vec()).await;
+        cc.incr_by("p", 1, ttl).await;
+        cc.incr_by_float("p", 0.5, ttl).await;
+        assert_eq!(cc.ttl("p").await, Some(None));
+        assert_eq!(cc.read("p").await, Some(b"2.5".to_vec()));
+
+        cc.incr_by_float("f", 0.5, ttl).await;
+        assert!(cc.ttl("f").await.unwrap().is_some());
     }
 }
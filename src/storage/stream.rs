@@ -0,0 +1,556 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    ops::Bound,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::{timeout_at, Instant};
+
+use super::{
+    storage::{now_millis, Storage},
+    value::{Change, Value, ValueError},
+};
+
+/// Stream entry ID, the milliseconds it was added at and a sequence number
+/// among the entries added within the same millisecond.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    /// Parses `<ms>-<seq>`, a bare `<ms>` gets `seq` as its sequence number.
+    pub fn parse(s: &str, seq: u64) -> Option<Self> {
+        match s.split_once('-') {
+            Some((ms, seq)) => Some(Self::new(ms.parse().ok()?, seq.parse().ok()?)),
+            None => Some(Self::new(s.parse().ok()?, seq)),
+        }
+    }
+
+    /// Smallest ID greater than this one.
+    fn next(&self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(Self::new(self.ms, seq)),
+            None => Some(Self::new(self.ms.checked_add(1)?, 0)),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// Entry ID with its fields.
+pub type StreamEntry = (StreamId, Vec<(String, Vec<u8>)>);
+
+/// Entry delivered to a consumer of a group and not acknowledged yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pending {
+    pub consumer: String,
+    /// Unix time in milliseconds of the last delivery.
+    pub delivered_at: u64,
+    pub deliveries: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Group {
+    last_delivered: StreamId,
+    pending: BTreeMap<StreamId, Pending>,
+}
+
+/// Append-only log of entries ordered by ID, with its consumer groups.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Vec<(String, Vec<u8>)>>,
+    /// Greatest ID ever added, entries trimmed since included.
+    last_id: StreamId,
+    groups: BTreeMap<String, Group>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// ID for an entry added at `now`, greater than every ID of the stream.
+    fn next_id(&self, now: u64) -> Option<StreamId> {
+        match now {
+            ms if ms > self.last_id.ms => Some(StreamId::new(ms, 0)),
+            _ => self.last_id.next(),
+        }
+    }
+
+    /// Appends an entry with an ID generated for `now`, returns the ID.
+    pub(super) fn append(&mut self, fields: Vec<(String, Vec<u8>)>, now: u64) -> Option<StreamId> {
+        let id = self.next_id(now)?;
+        self.entries.insert(id, fields);
+        self.last_id = id;
+
+        Some(id)
+    }
+
+    /// Up to `count` entries with an ID greater than `id`.
+    fn after(&self, id: StreamId, count: Option<usize>) -> Vec<StreamEntry> {
+        self.entries
+            .range((Bound::Excluded(id), Bound::Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect()
+    }
+
+    /// Removes the oldest entries until at most `max_len` are left.
+    fn trim(&mut self, max_len: usize) -> usize {
+        let excess = self.entries.len().saturating_sub(max_len);
+        for _ in 0..excess {
+            self.entries.pop_first();
+        }
+
+        excess
+    }
+}
+
+pub(super) fn stream(value: &mut Value) -> Result<&mut Stream, ValueError> {
+    match value {
+        Value::Stream(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+pub(super) fn new_stream() -> Value {
+    Value::Stream(Stream::default())
+}
+
+impl Storage {
+    /// Appends an entry with the given ID, or a generated one when `None`, and
+    /// returns its ID. With `max_len` the oldest entries are trimmed after.
+    pub async fn stream_add(
+        &self,
+        key: &str,
+        id: Option<StreamId>,
+        fields: Vec<(String, Vec<u8>)>,
+        max_len: Option<usize>,
+    ) -> Result<(StreamId, Change), ValueError> {
+        let res = self
+            .update(key, new_stream, |value| {
+                let stream = stream(value)?;
+                let id = match id {
+                    Some(id) if id > stream.last_id => id,
+                    Some(_) => return Err(ValueError::StreamId),
+                    None => stream.next_id(now_millis()).ok_or(ValueError::StreamId)?,
+                };
+                stream.entries.insert(id, fields);
+                stream.last_id = id;
+                if let Some(max_len) = max_len {
+                    stream.trim(max_len);
+                }
+                Ok((id, true))
+            })
+            .await?;
+        self.appended.notify_waiters();
+
+        Ok(res)
+    }
+
+    pub async fn stream_len(&self, key: &str) -> Result<usize, ValueError> {
+        let len = self
+            .inspect(key, |value| match value {
+                Value::Stream(stream) => Ok(stream.len()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(len.unwrap_or_default())
+    }
+
+    /// Up to `count` entries with IDs between the inclusive `start` and `end`,
+    /// from the newest when `reverse` is set.
+    pub async fn stream_range(
+        &self,
+        key: &str,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<StreamEntry>, ValueError> {
+        let entries = self
+            .inspect(key, |value| match value {
+                Value::Stream(stream) if start <= end => {
+                    let range = stream.entries.range(start..=end);
+                    let range: Box<dyn Iterator<Item = _>> = match reverse {
+                        true => Box::new(range.rev()),
+                        false => Box::new(range),
+                    };
+                    Ok(range
+                        .take(count.unwrap_or(usize::MAX))
+                        .map(|(id, fields)| (*id, fields.clone()))
+                        .collect())
+                }
+                Value::Stream(_) => Ok(Vec::new()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(entries.unwrap_or_default())
+    }
+
+    /// Removes the oldest entries until at most `max_len` are left, returns
+    /// how many were removed.
+    pub async fn stream_trim(&self, key: &str, max_len: usize) -> Result<(usize, Change), ValueError> {
+        self.update(key, new_stream, |value| {
+            let removed = stream(value)?.trim(max_len);
+            Ok((removed, removed > 0))
+        })
+        .await
+    }
+
+    /// Up to `count` entries of each stream with an ID greater than the one
+    /// given for it, `None` standing for the last ID at the time of the call.
+    /// Streams without such entries are left out. See `blocking` for `block`.
+    pub async fn stream_read(
+        &self,
+        streams: Vec<(String, Option<StreamId>)>,
+        count: Option<usize>,
+        block: Option<Duration>,
+    ) -> Result<Vec<(String, Vec<StreamEntry>)>, ValueError> {
+        let keys: Vec<String> = streams.iter().map(|(key, _)| key.clone()).collect();
+        let after: Vec<StreamId> = self
+            .inspect_many(&keys, |values| {
+                values
+                    .into_iter()
+                    .zip(streams.iter())
+                    .map(|(value, (_, id))| match (value, id) {
+                        (Some(Value::Stream(_)) | None, Some(id)) => Ok(*id),
+                        (Some(Value::Stream(stream)), None) => Ok(stream.last_id),
+                        (None, None) => Ok(StreamId::MIN),
+                        (Some(_), _) => Err(ValueError::WrongType),
+                    })
+                    .collect()
+            })
+            .await?;
+
+        self.blocking(block, |found: &Vec<_>| !found.is_empty(), || {
+            self.inspect_many(&keys, |values| {
+                let mut found = Vec::new();
+                for ((key, value), id) in keys.iter().zip(values).zip(after.iter()) {
+                    let entries = match value {
+                        Some(Value::Stream(stream)) => stream.after(*id, count),
+                        Some(_) => return Err(ValueError::WrongType),
+                        None => continue,
+                    };
+                    if !entries.is_empty() {
+                        found.push((key.clone(), entries));
+                    }
+                }
+                Ok(found)
+            })
+        })
+        .await
+    }
+
+    /// Creates a consumer group delivering the entries after `id`, or after
+    /// the last entry when `None`, and returns that ID. A missing stream is
+    /// created when `create` is set.
+    pub async fn stream_group_create(
+        &self,
+        key: &str,
+        group: &str,
+        id: Option<StreamId>,
+        create: bool,
+    ) -> Result<(StreamId, Change), ValueError> {
+        let init = || match create {
+            true => Ok(new_stream()),
+            false => Err(ValueError::NoSuchKey),
+        };
+
+        self.try_update(key, init, |value| {
+            let stream = stream(value)?;
+            if stream.groups.contains_key(group) {
+                return Err(ValueError::GroupExists);
+            }
+            let id = id.unwrap_or(stream.last_id);
+            let created = Group {
+                last_delivered: id,
+                pending: BTreeMap::new(),
+            };
+            stream.groups.insert(group.to_owned(), created);
+            Ok((id, true))
+        })
+        .await
+    }
+
+    /// Removes the consumer group with its pending entries, returns whether it existed.
+    pub async fn stream_group_destroy(&self, key: &str, group: &str) -> Result<(bool, Change), ValueError> {
+        self.try_update(key, || Err(ValueError::NoSuchKey), |value| {
+            let removed = stream(value)?.groups.remove(group).is_some();
+            Ok((removed, removed))
+        })
+        .await
+    }
+
+    /// Delivers up to `count` entries the group did not deliver yet to the
+    /// consumer and adds them to its pending entries. With an ID, the pending
+    /// entries of the consumer after it are returned again instead, entries
+    /// trimmed since with no fields. See `blocking` for `block`, which only
+    /// applies to new entries. New entries are delivered at `now`, the server
+    /// clock at every attempt when `None`. Deliveries are passed to `log` with
+    /// the time used and exclusive access to the keyspace, so a read that
+    /// waited is logged in order with the writes around it.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn stream_read_group<L>(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+        id: Option<StreamId>,
+        count: Option<usize>,
+        block: Option<Duration>,
+        now: Option<u64>,
+        log: L,
+    ) -> Result<Vec<StreamEntry>, ValueError>
+    where
+        L: Fn(u64, Change),
+    {
+        if let Some(id) = id {
+            let entries = self
+                .inspect(key, |value| match value {
+                    Value::Stream(stream) => {
+                        let group = stream.groups.get(group).ok_or(ValueError::NoGroup)?;
+                        Ok(group
+                            .pending
+                            .range((Bound::Excluded(id), Bound::Unbounded))
+                            .filter(|(_, pending)| pending.consumer == consumer)
+                            .take(count.unwrap_or(usize::MAX))
+                            .map(|(id, _)| (*id, stream.entries.get(id).cloned().unwrap_or_default()))
+                            .collect())
+                    }
+                    _ => Err(ValueError::WrongType),
+                })
+                .await?;
+            return entries.ok_or(ValueError::NoGroup);
+        }
+
+        let log = &log;
+        self.blocking(block, |found: &Vec<_>| !found.is_empty(), || {
+            self.atomically(|cc| async move {
+                let delivered_at = now.unwrap_or_else(now_millis);
+                let (entries, change) = cc
+                    .try_update(key, || Err(ValueError::NoGroup), |value| {
+                        let stream = stream(value)?;
+                        let last_delivered = stream.groups.get(group).ok_or(ValueError::NoGroup)?.last_delivered;
+                        let entries = stream.after(last_delivered, count);
+                        let group = stream.groups.get_mut(group).ok_or(ValueError::NoGroup)?;
+                        for (id, _) in entries.iter() {
+                            let pending = Pending {
+                                consumer: consumer.to_owned(),
+                                delivered_at,
+                                deliveries: 1,
+                            };
+                            group.pending.insert(*id, pending);
+                            group.last_delivered = *id;
+                        }
+                        let changed = !entries.is_empty();
+                        Ok((entries, changed))
+                    })
+                    .await?;
+                log(delivered_at, change);
+
+                Ok(entries)
+            })
+        })
+        .await
+    }
+
+    /// Removes the entries from the pending entries of the group, returns
+    /// how many of them were pending.
+    pub async fn stream_ack(&self, key: &str, group: &str, ids: &[StreamId]) -> Result<(usize, Change), ValueError> {
+        self.update(key, new_stream, |value| {
+            let acked = match stream(value)?.groups.get_mut(group) {
+                Some(group) => ids.iter().filter(|id| group.pending.remove(id).is_some()).count(),
+                None => 0,
+            };
+            Ok((acked, acked > 0))
+        })
+        .await
+    }
+
+    /// Up to `count` pending entries of the group with IDs between the
+    /// inclusive `start` and `end`, only those of `consumer` when given.
+    pub async fn stream_pending(
+        &self,
+        key: &str,
+        group: &str,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        consumer: Option<&str>,
+    ) -> Result<Vec<(StreamId, Pending)>, ValueError> {
+        let pending = self
+            .inspect(key, |value| match value {
+                Value::Stream(stream) => {
+                    let group = stream.groups.get(group).ok_or(ValueError::NoGroup)?;
+                    if start > end {
+                        return Ok(Vec::new());
+                    }
+                    Ok(group
+                        .pending
+                        .range(start..=end)
+                        .filter(|(_, pending)| consumer.is_none_or(|x| pending.consumer == x))
+                        .take(count.unwrap_or(usize::MAX))
+                        .map(|(id, pending)| (*id, pending.clone()))
+                        .collect())
+                }
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        pending.ok_or(ValueError::NoGroup)
+    }
+
+    /// Runs `read` until `ready` holds for its result. Without `block` the
+    /// first result is returned right away, otherwise the read is repeated
+    /// whenever an entry is appended to a stream until `block` elapsed, a
+    /// zero duration blocking for as long as it takes.
+    async fn blocking<T, R, F, Fut>(&self, block: Option<Duration>, ready: R, mut read: F) -> Result<T, ValueError>
+    where
+        R: Fn(&T) -> bool,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ValueError>>,
+    {
+        let deadline = block.filter(|x| !x.is_zero()).map(|x| Instant::now() + x);
+        loop {
+            // Registered before reading, so an append in between is not missed.
+            let appended = self.appended.notified();
+            tokio::pin!(appended);
+            appended.as_mut().enable();
+
+            let found = read().await?;
+            if block.is_none() || ready(&found) {
+                return Ok(found);
+            }
+            match deadline {
+                Some(at) if timeout_at(at, appended.as_mut()).await.is_err() => return Ok(found),
+                Some(_) => {}
+                None => appended.as_mut().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+
+    async fn stream_with(cc: &Storage, ids: &[u64]) {
+        for ms in ids {
+            let fields = vec![("n".to_owned(), ms.to_string().into_bytes())];
+            cc.stream_add("s", Some(StreamId::new(*ms, 0)), fields, None).await.unwrap();
+        }
+        cc.stream_group_create("s", "g", Some(StreamId::MIN), true).await.unwrap();
+    }
+
+    fn ids(entries: &[StreamEntry]) -> Vec<u64> {
+        entries.iter().map(|(id, _)| id.ms).collect()
+    }
+
+    #[tokio::test]
+    async fn group_delivers_entries_once_and_tracks_them_as_pending() {
+        let cc = Storage::new();
+        stream_with(&cc, &[1, 2, 3]).await;
+
+        let logged = Mutex::new(Vec::new());
+        let read = cc
+            .stream_read_group("s", "g", "alice", None, Some(2), None, Some(100), |at, change| {
+                logged.lock().unwrap().push((at, change.is_changed()))
+            })
+            .await
+            .unwrap();
+        assert_eq!(ids(&read), [1, 2]);
+        assert_eq!(*logged.lock().unwrap(), [(100, true)]);
+
+        let read = cc.stream_read_group("s", "g", "bob", None, None, None, Some(200), |_, _| {}).await.unwrap();
+        assert_eq!(ids(&read), [3]);
+        let read = cc.stream_read_group("s", "g", "bob", None, None, None, Some(300), |_, _| {}).await.unwrap();
+        assert!(read.is_empty());
+
+        let pending = cc.stream_pending("s", "g", StreamId::MIN, StreamId::MAX, None, None).await.unwrap();
+        let pending: Vec<(u64, &str, u64)> =
+            pending.iter().map(|(id, x)| (id.ms, x.consumer.as_str(), x.delivered_at)).collect();
+        assert_eq!(pending, [(1, "alice", 100), (2, "alice", 100), (3, "bob", 200)]);
+
+        // The history of a consumer is read back from its pending entries.
+        let history = cc
+            .stream_read_group("s", "g", "alice", Some(StreamId::new(1, 0)), None, None, None, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(ids(&history), [2]);
+    }
+
+    #[tokio::test]
+    async fn acked_entries_leave_the_pending_entries() {
+        let cc = Storage::new();
+        stream_with(&cc, &[1, 2]).await;
+        cc.stream_read_group("s", "g", "alice", None, None, None, None, |_, _| {}).await.unwrap();
+
+        let (acked, _) = cc.stream_ack("s", "g", &[StreamId::new(1, 0), StreamId::new(9, 0)]).await.unwrap();
+        assert_eq!(acked, 1);
+        let (acked, change) = cc.stream_ack("s", "g", &[StreamId::new(1, 0)]).await.unwrap();
+        assert_eq!(acked, 0);
+        assert!(!change.is_changed());
+
+        let pending = cc.stream_pending("s", "g", StreamId::MIN, StreamId::MAX, None, Some("alice")).await.unwrap();
+        assert_eq!(pending.iter().map(|(id, _)| id.ms).collect::<Vec<_>>(), [2]);
+        let pending = cc.stream_pending("s", "g", StreamId::MIN, StreamId::MAX, None, Some("bob")).await.unwrap();
+        assert!(pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_groups_are_rejected() {
+        let cc = Storage::new();
+        stream_with(&cc, &[1]).await;
+
+        let res = cc.stream_read_group("s", "other", "alice", None, None, None, None, |_, _| {}).await;
+        assert_eq!(res, Err(ValueError::NoGroup));
+        let res = cc.stream_pending("s", "other", StreamId::MIN, StreamId::MAX, None, None).await;
+        assert_eq!(res, Err(ValueError::NoGroup));
+        let res = cc.stream_group_create("s", "g", None, false).await;
+        assert_eq!(res, Err(ValueError::GroupExists));
+    }
+
+    #[tokio::test]
+    async fn blocked_group_read_wakes_on_append() {
+        let cc = Arc::new(Storage::new());
+        stream_with(&cc, &[]).await;
+
+        let reader = cc.clone();
+        let read = tokio::spawn(async move {
+            let block = Some(Duration::from_secs(5));
+            reader.stream_read_group("s", "g", "alice", None, None, block, None, |_, _| {}).await
+        });
+        tokio::task::yield_now().await;
+        cc.stream_add("s", Some(StreamId::new(7, 0)), Vec::new(), None).await.unwrap();
+
+        assert_eq!(ids(&read.await.unwrap().unwrap()), [7]);
+    }
+}
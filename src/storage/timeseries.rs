@@ -0,0 +1,508 @@
+use std::{collections::BTreeMap, ops::RangeInclusive};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{
+    sketch::packed,
+    storage::{now_millis, Storage},
+    value::{Change, Value, ValueError},
+};
+
+/// Sample timestamp in Unix milliseconds with its value.
+pub type Sample = (u64, f64);
+
+/// Function reducing the samples of a bucket to a single value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Aggregation {
+    Avg,
+    Sum,
+    Min,
+    Max,
+    Count,
+    First,
+    Last,
+}
+
+impl Aggregation {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "avg" => Some(Aggregation::Avg),
+            "sum" => Some(Aggregation::Sum),
+            "min" => Some(Aggregation::Min),
+            "max" => Some(Aggregation::Max),
+            "count" => Some(Aggregation::Count),
+            "first" => Some(Aggregation::First),
+            "last" => Some(Aggregation::Last),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aggregation::Avg => "avg",
+            Aggregation::Sum => "sum",
+            Aggregation::Min => "min",
+            Aggregation::Max => "max",
+            Aggregation::Count => "count",
+            Aggregation::First => "first",
+            Aggregation::Last => "last",
+        }
+    }
+
+    /// Aggregate of the values, `None` when there are none.
+    fn apply(&self, values: impl Iterator<Item = f64>) -> Option<f64> {
+        let (mut count, mut sum, mut min, mut max) = (0u64, 0.0, f64::INFINITY, f64::NEG_INFINITY);
+        let (mut first, mut last) = (None, None);
+        for x in values {
+            count += 1;
+            sum += x;
+            min = min.min(x);
+            max = max.max(x);
+            first = first.or(Some(x));
+            last = Some(x);
+        }
+        if count == 0 {
+            return None;
+        }
+
+        Some(match self {
+            Aggregation::Avg => sum / count as f64,
+            Aggregation::Sum => sum,
+            Aggregation::Min => min,
+            Aggregation::Max => max,
+            Aggregation::Count => count as f64,
+            Aggregation::First => first?,
+            Aggregation::Last => last?,
+        })
+    }
+}
+
+/// Downsampling rule, aggregates of the samples of each bucket of `bucket`
+/// milliseconds are written to the series at `dest`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    pub dest: String,
+    pub aggregation: Aggregation,
+    pub bucket: u64,
+}
+
+/// Summary of a series as reported by `TS.INFO`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeSeriesInfo {
+    pub samples: usize,
+    pub retention: u64,
+    pub first: Option<Sample>,
+    pub last: Option<Sample>,
+    pub rules: Vec<Rule>,
+}
+
+/// Samples ordered by timestamp. They are persisted delta encoded, each
+/// timestamp as a varint of its distance to the previous one and each value
+/// as the bytes of its XOR with the previous value that are not zero, so
+/// regular intervals and slowly changing values take a few bytes a sample.
+#[derive(Debug, Clone, Default)]
+struct Samples(BTreeMap<u64, f64>);
+
+impl PartialEq for Samples {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len()
+            && self.0.iter().zip(other.0.iter()).all(|(a, b)| a.0 == b.0 && a.1.to_bits() == b.1.to_bits())
+    }
+}
+
+impl Eq for Samples {}
+
+fn write_varint(buf: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        buf.push(x as u8 | 0x80);
+        x >>= 7;
+    }
+    buf.push(x as u8);
+}
+
+fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut x = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = buf.split_first()?;
+        *buf = rest;
+        x |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(x);
+        }
+    }
+
+    None
+}
+
+impl Samples {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let (mut ts, mut bits) = (0u64, 0u64);
+        for (t, x) in self.0.iter() {
+            write_varint(&mut buf, t - ts);
+            // A header byte with the number of leading and trailing zero
+            // bytes of the XOR, followed by the bytes in between.
+            let xor = (x.to_bits() ^ bits).to_be_bytes();
+            let leading = xor.iter().take_while(|x| **x == 0).count();
+            let trailing = match leading {
+                8 => 0,
+                _ => xor.iter().rev().take_while(|x| **x == 0).count(),
+            };
+            buf.push(((leading << 4) | trailing) as u8);
+            buf.extend_from_slice(&xor[leading..8 - trailing]);
+            (ts, bits) = (*t, x.to_bits());
+        }
+
+        buf
+    }
+
+    fn decode(mut buf: &[u8]) -> Option<Self> {
+        let mut samples = BTreeMap::new();
+        let (mut ts, mut bits) = (0u64, 0u64);
+        while !buf.is_empty() {
+            ts = ts.checked_add(read_varint(&mut buf)?)?;
+            let (header, rest) = buf.split_first()?;
+            let (leading, trailing) = ((header >> 4) as usize, (header & 0x0f) as usize);
+            let len = 8usize.checked_sub(leading + trailing)?;
+            let mut xor = [0u8; 8];
+            xor[leading..leading + len].copy_from_slice(rest.get(..len)?);
+            buf = &rest[len..];
+            bits ^= u64::from_be_bytes(xor);
+            samples.insert(ts, f64::from_bits(bits));
+        }
+
+        Some(Samples(samples))
+    }
+}
+
+impl Serialize for Samples {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        packed::serialize(&self.encode(), s)
+    }
+}
+
+impl<'de> Deserialize<'de> for Samples {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let buf = packed::deserialize(d)?;
+        Samples::decode(&buf).ok_or_else(|| de::Error::custom("invalid time series samples"))
+    }
+}
+
+/// Samples ordered by timestamp, each timestamp holding a single value.
+/// With a retention, samples older than that before the newest one are
+/// dropped as samples are added.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeSeries {
+    /// Milliseconds of samples kept, zero to keep them all.
+    retention: u64,
+    samples: Samples,
+    rules: Vec<Rule>,
+}
+
+impl TimeSeries {
+    pub fn len(&self) -> usize {
+        self.samples.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.0.is_empty()
+    }
+
+    fn last(&self) -> Option<Sample> {
+        self.samples.0.last_key_value().map(|(t, x)| (*t, *x))
+    }
+
+    /// Timestamp of the oldest sample the retention keeps.
+    fn oldest(&self) -> u64 {
+        match (self.retention, self.last()) {
+            (0, _) | (_, None) => 0,
+            (retention, Some((t, _))) => t.saturating_sub(retention),
+        }
+    }
+
+    fn add(&mut self, timestamp: u64, value: f64) -> Result<(), ValueError> {
+        if timestamp < self.oldest() {
+            return Err(ValueError::InvalidArgument("timestamp is older than the retention"));
+        }
+        self.samples.0.insert(timestamp, value);
+        let oldest = self.oldest();
+        while self.samples.0.first_key_value().is_some_and(|(t, _)| *t < oldest) {
+            self.samples.0.pop_first();
+        }
+
+        Ok(())
+    }
+
+    /// Aggregates of the samples within `range` per bucket of `bucket`
+    /// milliseconds aligned to the epoch, stamped with the bucket start.
+    fn aggregate(&self, range: RangeInclusive<u64>, aggregation: Aggregation, bucket: u64) -> Vec<Sample> {
+        let mut buckets: Vec<(u64, Vec<f64>)> = Vec::new();
+        for (t, x) in self.samples.0.range(range) {
+            let start = t - t % bucket;
+            match buckets.last_mut() {
+                Some((last, values)) if *last == start => values.push(*x),
+                _ => buckets.push((start, vec![*x])),
+            }
+        }
+
+        buckets
+            .into_iter()
+            .filter_map(|(start, values)| Some((start, aggregation.apply(values.into_iter())?)))
+            .collect()
+    }
+}
+
+fn time_series(value: &mut Value) -> Result<&mut TimeSeries, ValueError> {
+    match value {
+        Value::TimeSeries(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+impl Storage {
+    /// Creates an empty series keeping `retention` milliseconds of samples,
+    /// zero keeping them all.
+    pub async fn time_series_create(&self, key: &str, retention: u64) -> Result<Change, ValueError> {
+        let series = TimeSeries {
+            retention,
+            ..Default::default()
+        };
+        self.create(key, Value::TimeSeries(series)).await
+    }
+
+    /// Adds a sample at `timestamp`, or now when `None`, replacing the value
+    /// of an existing one, and returns its timestamp. A missing key is created
+    /// with `retention`. The buckets of the sample in the destinations of the
+    /// rules are updated in the same step, open buckets included.
+    pub async fn time_series_add(
+        &self,
+        key: &str,
+        timestamp: Option<u64>,
+        value: f64,
+        retention: u64,
+    ) -> Result<(u64, Change), ValueError> {
+        if !value.is_finite() {
+            return Err(ValueError::NotFloat);
+        }
+        let timestamp = timestamp.unwrap_or_else(now_millis);
+        let init = || {
+            Ok(Value::TimeSeries(TimeSeries {
+                retention,
+                ..Default::default()
+            }))
+        };
+
+        self.atomically(|cc| async move {
+            let (compacted, change) = cc
+                .try_update(key, init, |x| {
+                    let series = time_series(x)?;
+                    series.add(timestamp, value)?;
+                    let compacted: Vec<(String, Sample)> = series
+                        .rules
+                        .iter()
+                        .filter_map(|rule| {
+                            let start = timestamp - timestamp % rule.bucket;
+                            let bucket = start..=start.saturating_add(rule.bucket - 1);
+                            let sample = series.aggregate(bucket, rule.aggregation, rule.bucket).pop()?;
+                            Some((rule.dest.clone(), sample))
+                        })
+                        .collect();
+                    Ok((compacted, true))
+                })
+                .await?;
+            // Destinations removed since, or holding another type, are skipped.
+            for (dest, (t, x)) in compacted {
+                let _ = cc
+                    .try_update(&dest, || Err(ValueError::NoSuchKey), |value| {
+                        time_series(value)?.add(t, x)?;
+                        Ok(((), true))
+                    })
+                    .await;
+            }
+
+            Ok((timestamp, change))
+        })
+        .await
+    }
+
+    /// Newest sample of the series, `None` for empty series and missing keys.
+    pub async fn time_series_get(&self, key: &str) -> Result<Option<Sample>, ValueError> {
+        let last = self
+            .inspect(key, |value| match value {
+                Value::TimeSeries(series) => Ok(series.last()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(last.flatten())
+    }
+
+    /// Up to `count` samples with timestamps between the inclusive `from` and
+    /// `to`, or with `aggregation` their aggregates per bucket.
+    pub async fn time_series_range(
+        &self,
+        key: &str,
+        from: u64,
+        to: u64,
+        count: Option<usize>,
+        aggregation: Option<(Aggregation, u64)>,
+    ) -> Result<Vec<Sample>, ValueError> {
+        if aggregation.is_some_and(|(_, bucket)| bucket == 0) {
+            return Err(ValueError::InvalidArgument("bucket duration must be positive"));
+        }
+        let samples = self
+            .inspect(key, |value| match value {
+                Value::TimeSeries(_) if from > to => Ok(Vec::new()),
+                Value::TimeSeries(series) => {
+                    let samples = match aggregation {
+                        Some((aggregation, bucket)) => series.aggregate(from..=to, aggregation, bucket),
+                        None => series.samples.0.range(from..=to).map(|(t, x)| (*t, *x)).collect(),
+                    };
+                    Ok(samples.into_iter().take(count.unwrap_or(usize::MAX)).collect())
+                }
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(samples.unwrap_or_default())
+    }
+
+    /// Adds a rule writing the aggregates of the samples added to `key` from
+    /// now on into the series at `dest`, which must exist. Aggregates written
+    /// to `dest` are not downsampled further by its own rules.
+    pub async fn time_series_create_rule(&self, key: &str, dest: &str, aggregation: Aggregation, bucket: u64) -> Result<Change, ValueError> {
+        if bucket == 0 {
+            return Err(ValueError::InvalidArgument("bucket duration must be positive"));
+        }
+        if key == dest {
+            return Err(ValueError::InvalidArgument("source and destination must differ"));
+        }
+        match self.kind(dest).await {
+            Some("timeseries") => {}
+            Some(_) => return Err(ValueError::WrongType),
+            None => return Err(ValueError::NoSuchKey),
+        }
+
+        let (_, change) = self
+            .try_update(key, || Err(ValueError::NoSuchKey), |value| {
+                let series = time_series(value)?;
+                if series.rules.iter().any(|x| x.dest == dest) {
+                    return Err(ValueError::InvalidArgument("compaction rule already exists"));
+                }
+                series.rules.push(Rule {
+                    dest: dest.to_owned(),
+                    aggregation,
+                    bucket,
+                });
+                Ok(((), true))
+            })
+            .await?;
+
+        Ok(change)
+    }
+
+    /// Removes the rule writing into `dest`, returns whether it existed.
+    pub async fn time_series_delete_rule(&self, key: &str, dest: &str) -> Result<(bool, Change), ValueError> {
+        self.try_update(key, || Err(ValueError::NoSuchKey), |value| {
+            let rules = &mut time_series(value)?.rules;
+            let len = rules.len();
+            rules.retain(|x| x.dest != dest);
+            let removed = rules.len() < len;
+            Ok((removed, removed))
+        })
+        .await
+    }
+
+    pub async fn time_series_info(&self, key: &str) -> Result<Option<TimeSeriesInfo>, ValueError> {
+        self.inspect(key, |value| match value {
+            Value::TimeSeries(series) => Ok(TimeSeriesInfo {
+                samples: series.len(),
+                retention: series.retention,
+                first: series.samples.0.first_key_value().map(|(t, x)| (*t, *x)),
+                last: series.last(),
+                rules: series.rules.clone(),
+            }),
+            _ => Err(ValueError::WrongType),
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn add(cc: &Storage, key: &str, samples: &[Sample]) {
+        for (t, x) in samples {
+            cc.time_series_add(key, Some(*t), *x, 0).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn samples_survive_the_delta_encoding() {
+        let values = [0.0, -0.0, 1.5, 1.5, -273.15, f64::MAX, f64::MIN_POSITIVE, 42.0];
+        let samples = Samples(values.iter().enumerate().map(|(i, x)| (i as u64 * 1_000 + u32::MAX as u64, *x)).collect());
+
+        let buf = samples.encode();
+        assert_eq!(Samples::decode(&buf), Some(samples));
+        assert_eq!(Samples::decode(&buf[..buf.len() - 1]), None);
+        // Repeated values at regular intervals take two bytes a sample.
+        let regular = Samples((0..100u64).map(|t| (t * 10, 1.0)).collect());
+        assert!(regular.encode().len() < 2 * 100 + 8);
+    }
+
+    #[test]
+    fn retention_drops_samples_older_than_the_newest() {
+        let mut series = TimeSeries {
+            retention: 100,
+            ..Default::default()
+        };
+        for t in [0, 50, 100, 150] {
+            series.add(t, t as f64).unwrap();
+        }
+
+        assert_eq!(series.samples.0.keys().copied().collect::<Vec<_>>(), [50, 100, 150]);
+        assert!(series.add(49, 0.0).is_err());
+        series.add(50, 5.0).unwrap();
+        assert_eq!(series.samples.0[&50], 5.0);
+    }
+
+    #[tokio::test]
+    async fn ranges_aggregate_per_aligned_bucket() {
+        let cc = Storage::new();
+        add(&cc, "ts", &[(3, 1.0), (7, 3.0), (12, 5.0), (25, 2.0), (29, 4.0)]).await;
+
+        let range = |aggregation| cc.time_series_range("ts", 0, 100, None, aggregation);
+        assert_eq!(range(Some((Aggregation::Avg, 10))).await.unwrap(), [(0, 2.0), (10, 5.0), (20, 3.0)]);
+        assert_eq!(range(Some((Aggregation::Count, 10))).await.unwrap(), [(0, 2.0), (10, 1.0), (20, 2.0)]);
+        assert_eq!(range(Some((Aggregation::First, 20))).await.unwrap(), [(0, 1.0), (20, 2.0)]);
+        assert_eq!(range(Some((Aggregation::Max, 1_000))).await.unwrap(), [(0, 5.0)]);
+        assert!(range(Some((Aggregation::Sum, 0))).await.is_err());
+
+        assert_eq!(cc.time_series_range("ts", 7, 25, Some(2), None).await.unwrap(), [(7, 3.0), (12, 5.0)]);
+        assert!(cc.time_series_range("ts", 25, 7, None, None).await.unwrap().is_empty());
+        assert!(cc.time_series_range("missing", 0, 100, None, None).await.unwrap().is_empty());
+        assert_eq!(cc.time_series_add("ts", Some(1), f64::NAN, 0).await.unwrap_err(), ValueError::NotFloat);
+    }
+
+    #[tokio::test]
+    async fn rules_keep_open_buckets_up_to_date() {
+        let cc = Storage::new();
+        cc.time_series_create("ts", 0).await.unwrap();
+        cc.time_series_create("sum", 0).await.unwrap();
+        cc.time_series_create_rule("ts", "sum", Aggregation::Sum, 10).await.unwrap();
+        assert!(cc.time_series_create_rule("ts", "sum", Aggregation::Avg, 10).await.is_err());
+        assert_eq!(cc.time_series_create_rule("ts", "missing", Aggregation::Avg, 10).await.unwrap_err(), ValueError::NoSuchKey);
+
+        add(&cc, "ts", &[(1, 1.0), (5, 2.0), (11, 4.0)]).await;
+        assert_eq!(cc.time_series_range("sum", 0, 100, None, None).await.unwrap(), [(0, 3.0), (10, 4.0)]);
+        // Replacing a sample recomputes its bucket.
+        add(&cc, "ts", &[(5, 10.0)]).await;
+        assert_eq!(cc.time_series_get("sum").await.unwrap(), Some((10, 4.0)));
+        assert_eq!(cc.time_series_range("sum", 0, 9, None, None).await.unwrap(), [(0, 11.0)]);
+
+        assert!(cc.time_series_delete_rule("ts", "sum").await.unwrap().0);
+        add(&cc, "ts", &[(21, 1.0)]).await;
+        assert_eq!(cc.time_series_info("sum").await.unwrap().unwrap().samples, 2);
+    }
+}
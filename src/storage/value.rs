@@ -0,0 +1,179 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    bloom::BloomFilter,
+    hyperloglog::HyperLogLog,
+    json::JsonDocument,
+    queue::Queue,
+    schedule::Schedule,
+    sketch::{CountMinSketch, TopK},
+    stream::Stream,
+    timeseries::TimeSeries,
+    zset::SortedSet,
+};
+
+/// Value held by a key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Value {
+    Bytes(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashMap<String, Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    SortedSet(SortedSet),
+    Stream(Stream),
+    HyperLogLog(HyperLogLog),
+    Bloom(BloomFilter),
+    CountMin(CountMinSketch),
+    TopK(TopK),
+    Json(JsonDocument),
+    TimeSeries(TimeSeries),
+    Queue(Queue),
+    Schedule(Schedule),
+}
+
+impl Value {
+    /// Type name as reported by `TYPE`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::Bytes(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Stream(_) => "stream",
+            Value::HyperLogLog(_) => "hyperloglog",
+            Value::Bloom(_) => "bloom",
+            Value::CountMin(_) => "cms",
+            Value::TopK(_) => "topk",
+            Value::Json(_) => "json",
+            Value::TimeSeries(_) => "timeseries",
+            Value::Queue(_) => "queue",
+            Value::Schedule(_) => "schedule",
+        }
+    }
+
+    /// Unix time in milliseconds at which the value has to be updated by the
+    /// expiry loop, see `Storage::expire`.
+    pub fn deadline(&self) -> Option<u64> {
+        match self {
+            Value::Queue(x) => x.deadline(),
+            Value::Schedule(x) => x.deadline(),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&Vec<u8>> {
+        match self {
+            Value::Bytes(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            Value::Bytes(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// Collections are removed from the keyspace once they become empty.
+    /// Streams are kept, their last ID and groups outlive the entries, and so
+    /// are sketches and JSON documents, which are never empty, and time
+    /// series and queues, whose settings outlive their contents.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::Bytes(_)
+            | Value::Stream(_)
+            | Value::HyperLogLog(_)
+            | Value::Bloom(_)
+            | Value::CountMin(_)
+            | Value::TopK(_)
+            | Value::Json(_)
+            | Value::TimeSeries(_)
+            | Value::Queue(_) => false,
+            Value::List(x) => x.is_empty(),
+            Value::Hash(x) => x.is_empty(),
+            Value::Set(x) => x.is_empty(),
+            Value::SortedSet(x) => x.is_empty(),
+            Value::Schedule(x) => x.is_empty(),
+        }
+    }
+}
+
+/// Failure of a command operating on a typed value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueError {
+    /// The key holds a value of another type.
+    WrongType,
+    /// The value is not an integer or the result would overflow.
+    NotInteger,
+    /// The value is not a number or the result would not be finite.
+    NotFloat,
+    /// The command requires the key to exist.
+    NoSuchKey,
+    /// The stream entry ID is not greater than the last one of the stream.
+    StreamId,
+    /// The stream or its consumer group does not exist.
+    NoGroup,
+    /// A consumer group with the name already exists.
+    GroupExists,
+    /// The command creates the key, which exists already.
+    KeyExists,
+    /// The path selects nothing in the JSON document.
+    NoSuchPath,
+    /// An index with the name already exists.
+    IndexExists,
+    /// The index does not exist.
+    NoSuchIndex,
+    /// An argument is out of its valid range.
+    InvalidArgument(&'static str),
+}
+
+impl fmt::Display for ValueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ValueError::WrongType => "WRONGTYPE Operation against a key holding the wrong kind of value",
+            ValueError::NotInteger => "ERR value is not an integer or out of range",
+            ValueError::NotFloat => "ERR value is not a valid float",
+            ValueError::NoSuchKey => "ERR no such key",
+            ValueError::StreamId => "ERR The ID specified in XADD is equal or smaller than the target stream top item",
+            ValueError::NoGroup => "NOGROUP No such key or consumer group",
+            ValueError::GroupExists => "BUSYGROUP Consumer Group name already exists",
+            ValueError::KeyExists => "ERR key already exists",
+            ValueError::NoSuchPath => "ERR path does not exist",
+            ValueError::IndexExists => "ERR index already exists",
+            ValueError::NoSuchIndex => "ERR no such index",
+            ValueError::InvalidArgument(e) => return write!(f, "ERR {}", e),
+        })
+    }
+}
+
+impl std::error::Error for ValueError {}
+
+/// Effect of a collection command on its key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Unchanged,
+    /// The key was written and has this version now.
+    Written(u64),
+    /// The key was removed because its collection became empty.
+    Removed,
+}
+
+impl Change {
+    pub fn is_changed(&self) -> bool {
+        *self != Change::Unchanged
+    }
+
+    pub fn version(&self) -> Option<u64> {
+        match self {
+            Change::Written(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    ops::Bound,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    list::bounds,
+    storage::Storage,
+    value::{Change, Value, ValueError},
+};
+
+/// Score totally ordered by `f64::total_cmp`. Negative zero is stored as zero
+/// so both compare equal, NaN is rejected before it gets here.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl Score {
+    fn new(x: f64) -> Self {
+        Score(x + 0.0)
+    }
+}
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, ties broken by the member bytes. Ranks are
+/// found by walking the ordered index, so they cost O(n).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<(Vec<u8>, f64)>", into = "Vec<(Vec<u8>, f64)>")]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, Score>,
+    ordered: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl From<Vec<(Vec<u8>, f64)>> for SortedSet {
+    fn from(members: Vec<(Vec<u8>, f64)>) -> Self {
+        let mut set = SortedSet::default();
+        for (member, score) in members {
+            set.insert(member, score);
+        }
+        set
+    }
+}
+
+impl From<SortedSet> for Vec<(Vec<u8>, f64)> {
+    fn from(set: SortedSet) -> Self {
+        set.ordered.into_iter().map(|(score, member)| (member, score.0)).collect()
+    }
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Sets the score of the member, returns whether it was new.
+    fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        let score = Score::new(score);
+        match self.scores.insert(member.clone(), score) {
+            Some(previous) => {
+                self.ordered.remove(&(previous, member.clone()));
+                self.ordered.insert((score, member));
+                false
+            }
+            None => {
+                self.ordered.insert((score, member));
+                true
+            }
+        }
+    }
+
+    fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.ordered.remove(&(score, member.to_vec())),
+            None => false,
+        }
+    }
+
+    fn by_score(&self, min: Bound<f64>, max: Bound<f64>) -> impl Iterator<Item = &(Score, Vec<u8>)> {
+        self.ordered.iter().filter(move |(score, _)| {
+            let above = match min {
+                Bound::Included(x) => score.0 >= x,
+                Bound::Excluded(x) => score.0 > x,
+                Bound::Unbounded => true,
+            };
+            let below = match max {
+                Bound::Included(x) => score.0 <= x,
+                Bound::Excluded(x) => score.0 < x,
+                Bound::Unbounded => true,
+            };
+            above && below
+        })
+    }
+}
+
+fn sorted_set(value: &mut Value) -> Result<&mut SortedSet, ValueError> {
+    match value {
+        Value::SortedSet(x) => Ok(x),
+        _ => Err(ValueError::WrongType),
+    }
+}
+
+fn new_sorted_set() -> Value {
+    Value::SortedSet(SortedSet::default())
+}
+
+impl Storage {
+    /// Sets the scores of the members and returns how many of them were new.
+    pub async fn sorted_set_add(&self, key: &str, members: Vec<(f64, Vec<u8>)>) -> Result<(usize, Change), ValueError> {
+        if members.iter().any(|(score, _)| score.is_nan()) {
+            return Err(ValueError::NotFloat);
+        }
+
+        self.update(key, new_sorted_set, |value| {
+            let set = sorted_set(value)?;
+            let changed = !members.is_empty();
+            let mut added = 0;
+            for (score, member) in members {
+                if set.insert(member, score) {
+                    added += 1;
+                }
+            }
+            Ok((added, changed))
+        })
+        .await
+    }
+
+    /// Adds `delta` to the score of the member, a missing member starts at zero.
+    pub async fn sorted_set_incr_by(&self, key: &str, member: Vec<u8>, delta: f64) -> Result<(f64, Change), ValueError> {
+        self.update(key, new_sorted_set, |value| {
+            let set = sorted_set(value)?;
+            let score = set.scores.get(&member).map(|x| x.0).unwrap_or_default() + delta;
+            if score.is_nan() {
+                return Err(ValueError::NotFloat);
+            }
+            set.insert(member, score);
+            Ok((score, true))
+        })
+        .await
+    }
+
+    pub async fn sorted_set_remove(&self, key: &str, members: &[Vec<u8>]) -> Result<(usize, Change), ValueError> {
+        self.update(key, new_sorted_set, |value| {
+            let set = sorted_set(value)?;
+            let removed = members.iter().filter(|x| set.remove(x)).count();
+            Ok((removed, removed > 0))
+        })
+        .await
+    }
+
+    pub async fn sorted_set_score(&self, key: &str, member: &[u8]) -> Result<Option<f64>, ValueError> {
+        let score = self
+            .inspect(key, |value| match value {
+                Value::SortedSet(set) => Ok(set.scores.get(member).map(|x| x.0)),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(score.flatten())
+    }
+
+    pub async fn sorted_set_len(&self, key: &str) -> Result<usize, ValueError> {
+        let len = self
+            .inspect(key, |value| match value {
+                Value::SortedSet(set) => Ok(set.len()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(len.unwrap_or_default())
+    }
+
+    /// Position of the member by ascending score, or descending when `reverse` is set.
+    pub async fn sorted_set_rank(&self, key: &str, member: &[u8], reverse: bool) -> Result<Option<usize>, ValueError> {
+        let rank = self
+            .inspect(key, |value| match value {
+                Value::SortedSet(set) => Ok(set.scores.get(member).map(|score| {
+                    let rank = set.ordered.range(..(*score, member.to_vec())).count();
+                    match reverse {
+                        true => set.len() - 1 - rank,
+                        false => rank,
+                    }
+                })),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(rank.flatten())
+    }
+
+    /// Members with their scores between the inclusive ranks, negative ones
+    /// counting from the end. Ranks are descending when `reverse` is set.
+    pub async fn sorted_set_range(&self, key: &str, start: i64, stop: i64, reverse: bool) -> Result<Vec<(Vec<u8>, f64)>, ValueError> {
+        let members = self
+            .inspect(key, |value| match value {
+                Value::SortedSet(set) => Ok(match bounds(set.len(), start, stop) {
+                    Some((start, stop)) => {
+                        let members: Box<dyn Iterator<Item = &(Score, Vec<u8>)>> = match reverse {
+                            true => Box::new(set.ordered.iter().rev()),
+                            false => Box::new(set.ordered.iter()),
+                        };
+                        members
+                            .skip(start)
+                            .take(stop - start + 1)
+                            .map(|(score, member)| (member.clone(), score.0))
+                            .collect()
+                    }
+                    None => Vec::new(),
+                }),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(members.unwrap_or_default())
+    }
+
+    /// Members with their scores between `min` and `max` by ascending score,
+    /// skipping `offset` of them and returning up to `count`.
+    pub async fn sorted_set_range_by_score(
+        &self,
+        key: &str,
+        min: Bound<f64>,
+        max: Bound<f64>,
+        offset: usize,
+        count: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, f64)>, ValueError> {
+        let members = self
+            .inspect(key, |value| match value {
+                Value::SortedSet(set) => Ok(set
+                    .by_score(min, max)
+                    .skip(offset)
+                    .take(count.unwrap_or(usize::MAX))
+                    .map(|(score, member)| (member.clone(), score.0))
+                    .collect()),
+                _ => Err(ValueError::WrongType),
+            })
+            .await?;
+
+        Ok(members.unwrap_or_default())
+    }
+
+    /// Removes the members between the inclusive ranks, returns how many were removed.
+    pub async fn sorted_set_remove_range(&self, key: &str, start: i64, stop: i64) -> Result<(usize, Change), ValueError> {
+        self.update(key, new_sorted_set, |value| {
+            let set = sorted_set(value)?;
+            let members: Vec<Vec<u8>> = match bounds(set.len(), start, stop) {
+                Some((start, stop)) => set
+                    .ordered
+                    .iter()
+                    .skip(start)
+                    .take(stop - start + 1)
+                    .map(|(_, member)| member.clone())
+                    .collect(),
+                None => Vec::new(),
+            };
+            for member in members.iter() {
+                set.remove(member);
+            }
+            Ok((members.len(), !members.is_empty()))
+        })
+        .await
+    }
+
+    /// Removes the members scored between `min` and `max`, returns how many were removed.
+    pub async fn sorted_set_remove_by_score(&self, key: &str, min: Bound<f64>, max: Bound<f64>) -> Result<(usize, Change), ValueError> {
+        self.update(key, new_sorted_set, |value| {
+            let set = sorted_set(value)?;
+            let members: Vec<Vec<u8>> = set.by_score(min, max).map(|(_, member)| member.clone()).collect();
+            for member in members.iter() {
+                set.remove(member);
+            }
+            Ok((members.len(), !members.is_empty()))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn scored(cc: &Storage, members: &[(f64, &str)]) {
+        let members = members.iter().map(|(score, x)| (*score, x.as_bytes().to_vec())).collect();
+        cc.sorted_set_add("z", members).await.unwrap();
+    }
+
+    fn names(members: Vec<(Vec<u8>, f64)>) -> Vec<String> {
+        members.into_iter().map(|(x, _)| String::from_utf8(x).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn ranks_break_score_ties_by_member() {
+        let cc = Storage::new();
+        scored(&cc, &[(2.0, "c"), (1.0, "b"), (1.0, "a"), (-0.0, "z")]).await;
+
+        assert_eq!(cc.sorted_set_rank("z", b"z", false).await.unwrap(), Some(0));
+        assert_eq!(cc.sorted_set_rank("z", b"a", false).await.unwrap(), Some(1));
+        assert_eq!(cc.sorted_set_rank("z", b"b", false).await.unwrap(), Some(2));
+        assert_eq!(cc.sorted_set_rank("z", b"b", true).await.unwrap(), Some(1));
+        assert_eq!(cc.sorted_set_rank("z", b"c", true).await.unwrap(), Some(0));
+        assert_eq!(cc.sorted_set_rank("z", b"x", false).await.unwrap(), None);
+        assert_eq!(cc.sorted_set_rank("missing", b"x", false).await.unwrap(), None);
+
+        // Negative zero is stored as zero.
+        assert_eq!(cc.sorted_set_score("z", b"z").await.unwrap().map(f64::is_sign_negative), Some(false));
+        assert_eq!(cc.sorted_set_add("z", vec![(f64::NAN, b"n".to_vec())]).await.unwrap_err(), ValueError::NotFloat);
+    }
+
+    #[tokio::test]
+    async fn rank_ranges_clamp_to_the_set() {
+        let cc = Storage::new();
+        scored(&cc, &[(1.0, "a"), (2.0, "b"), (3.0, "c"), (4.0, "d")]).await;
+
+        assert_eq!(names(cc.sorted_set_range("z", 0, -1, false).await.unwrap()), ["a", "b", "c", "d"]);
+        assert_eq!(names(cc.sorted_set_range("z", -2, 100, false).await.unwrap()), ["c", "d"]);
+        assert_eq!(names(cc.sorted_set_range("z", -100, 0, false).await.unwrap()), ["a"]);
+        assert_eq!(names(cc.sorted_set_range("z", 0, 1, true).await.unwrap()), ["d", "c"]);
+        assert!(cc.sorted_set_range("z", 2, 1, false).await.unwrap().is_empty());
+        assert!(cc.sorted_set_range("z", 4, 10, false).await.unwrap().is_empty());
+        assert!(cc.sorted_set_range("missing", 0, -1, false).await.unwrap().is_empty());
+
+        assert_eq!(cc.sorted_set_remove_range("z", -2, -1).await.unwrap().0, 2);
+        assert_eq!(names(cc.sorted_set_range("z", 0, -1, false).await.unwrap()), ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn score_ranges_honour_exclusive_and_infinite_bounds() {
+        let cc = Storage::new();
+        scored(&cc, &[(f64::NEG_INFINITY, "lo"), (1.0, "a"), (2.0, "b"), (3.0, "c"), (f64::INFINITY, "hi")]).await;
+
+        let range = |min, max, offset, count| cc.sorted_set_range_by_score("z", min, max, offset, count);
+        assert_eq!(names(range(Bound::Unbounded, Bound::Unbounded, 0, None).await.unwrap()).len(), 5);
+        assert_eq!(names(range(Bound::Excluded(1.0), Bound::Included(3.0), 0, None).await.unwrap()), ["b", "c"]);
+        assert_eq!(names(range(Bound::Included(1.0), Bound::Excluded(3.0), 0, None).await.unwrap()), ["a", "b"]);
+        assert_eq!(
+            names(range(Bound::Included(f64::NEG_INFINITY), Bound::Excluded(2.0), 1, Some(1)).await.unwrap()),
+            ["a"]
+        );
+        assert_eq!(names(range(Bound::Included(3.0), Bound::Included(f64::INFINITY), 0, None).await.unwrap()), ["c", "hi"]);
+        assert!(range(Bound::Excluded(2.0), Bound::Excluded(2.0), 0, None).await.unwrap().is_empty());
+
+        let (removed, _) = cc.sorted_set_remove_by_score("z", Bound::Unbounded, Bound::Excluded(2.0)).await.unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(cc.sorted_set_len("z").await.unwrap(), 3);
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    io::{Error, ErrorKind},
+    sync::Arc,
+};
+
+use tokio_rustls::rustls::{
+    self,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// TLS settings used by the client to reach the server.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// PEM file with the CA certificates trusted to sign the server certificate.
+    pub ca: String,
+    /// PEM certificate chain and private key presented for mutual TLS.
+    pub identity: Option<(String, String)>,
+    /// Name checked against the server certificate, defaults to the host of the address.
+    pub server_name: Option<String>,
+}
+
+fn invalid(path: &str, e: impl std::fmt::Display) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("{}: {}", path, e))
+}
+
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| invalid(path, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(path, e))?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates found"));
+    }
+
+    Ok(certs)
+}
+
+pub fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, Error> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| invalid(path, e))
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| invalid(path, e))?;
+    }
+
+    Ok(roots)
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Builds the server side configuration. When `client_ca` is set every client
+/// must present a certificate signed by it.
+pub fn server_config(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Arc<ServerConfig>, Error> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(ca)?), provider())
+                .build()
+                .map_err(|e| invalid(ca, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| invalid(cert, e))?;
+
+    Ok(Arc::new(config))
+}
+
+pub fn acceptor(cert: &str, key: &str, client_ca: Option<&str>) -> Result<TlsAcceptor, Error> {
+    Ok(TlsAcceptor::from(server_config(cert, key, client_ca)?))
+}
+
+pub fn client_config(options: &TlsOptions) -> Result<Arc<ClientConfig>, Error> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+        .with_root_certificates(load_roots(&options.ca)?);
+
+    let config = match &options.identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| invalid(cert, e))?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// Resolves the name verified against the server certificate from the options
+/// or the host part of `addr`.
+pub fn server_name(addr: &str, options: &TlsOptions) -> Result<ServerName<'static>, Error> {
+    let host = match &options.server_name {
+        Some(name) => name.as_str(),
+        None => match addr.rsplit_once(':') {
+            Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => addr,
+        },
+    };
+
+    ServerName::try_from(host.to_owned()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
@@ -0,0 +1,82 @@
+use std::{io::ErrorKind, sync::Arc};
+
+use cachetcp::{
+    client::asyncronius::Client,
+    persistance::wal::WalWritter,
+    server::{self, auth::Authenticator},
+    storage::storage::Storage,
+};
+use tokio::{net::TcpListener, sync::mpsc::unbounded_channel};
+
+async fn start_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let storage = Arc::new(Storage::new());
+    let (tx, mut rx) = unbounded_channel();
+    let wal = WalWritter::new(tx);
+    let auth = Arc::new(Authenticator::default());
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+    tokio::spawn(async move {
+        loop {
+            server::functional::initiate_client(&listener, &storage, &wal, &auth)
+                .await
+                .unwrap();
+        }
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn pipeline_replies_in_order() {
+    let addr = start_server().await;
+    let c = Client::new(&addr, None).await;
+    c.connected().await.unwrap();
+
+    assert!(c.pipeline().execute().await.unwrap().is_empty());
+
+    let replies = c
+        .pipeline()
+        .put("a", b"1".to_vec(), None)
+        .get("a")
+        .incr_by("a", 2, None)
+        .get("a")
+        .delete("a")
+        .get("a")
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(replies.len(), 6);
+    assert_eq!(replies[0].as_ref().unwrap(), &None);
+    assert_eq!(replies[1].as_ref().unwrap(), &Some(b"1".to_vec()));
+    let incremented: i64 = rmp_serde::from_slice(replies[2].as_ref().unwrap().as_ref().unwrap()).unwrap();
+    assert_eq!(incremented, 3);
+    assert_eq!(replies[3].as_ref().unwrap(), &Some(b"3".to_vec()));
+    assert_eq!(replies[4].as_ref().unwrap(), &None);
+    assert_eq!(replies[5].as_ref().unwrap(), &Some(Vec::new()));
+}
+
+#[tokio::test]
+async fn pipeline_errors_stay_with_their_command() {
+    let addr = start_server().await;
+    let c = Client::new(&addr, None).await;
+    c.connected().await.unwrap();
+
+    let replies = c
+        .pipeline()
+        .put("s", b"text".to_vec(), None)
+        .incr_by("s", 1, None)
+        .put("after", b"2".to_vec(), None)
+        .get("after")
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(replies.len(), 4);
+    assert!(replies[0].is_ok());
+    assert_eq!(replies[1].as_ref().unwrap_err().kind(), ErrorKind::Other);
+    assert!(replies[2].is_ok());
+    assert_eq!(replies[3].as_ref().unwrap(), &Some(b"2".to_vec()));
+    assert_eq!(c.get("s").await.unwrap(), Some(b"text".to_vec()));
+}
@@ -0,0 +1,144 @@
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+
+use cachetcp::{
+    client::asyncronius::Client,
+    persistance::wal::WalWritter,
+    server::{self, auth::Authenticator},
+    storage::storage::Storage,
+    tls::{self, TlsOptions},
+};
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use tokio::{net::TcpListener, sync::mpsc::unbounded_channel};
+
+/// Self-signed CA plus server and client certificates written as PEM files.
+struct Pki {
+    dir: PathBuf,
+}
+
+impl Pki {
+    fn generate(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("cachetcp-tls-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, format!("cachetcp test CA {}", name));
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+        fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        let leaves = [
+            ("server", vec!["localhost".to_owned()], ExtendedKeyUsagePurpose::ServerAuth),
+            ("client", vec!["client".to_owned()], ExtendedKeyUsagePurpose::ClientAuth),
+        ];
+        for (leaf, names, usage) in leaves {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(names).unwrap();
+            params.extended_key_usages = vec![usage];
+            let cert = params.signed_by(&key, &ca).unwrap();
+            fs::write(dir.join(format!("{}.pem", leaf)), cert.pem()).unwrap();
+            fs::write(dir.join(format!("{}.key", leaf)), key.serialize_pem()).unwrap();
+        }
+
+        Self { dir }
+    }
+
+    fn path(&self, file: &str) -> String {
+        self.dir.join(file).to_string_lossy().into_owned()
+    }
+
+    fn client_options(&self, identity: bool) -> TlsOptions {
+        TlsOptions {
+            ca: self.path("ca.pem"),
+            identity: identity.then(|| (self.path("client.pem"), self.path("client.key"))),
+            server_name: Some("localhost".to_owned()),
+        }
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn start_server(pki: &Pki, mutual: bool) -> String {
+    let client_ca = pki.path("ca.pem");
+    let acceptor = tls::acceptor(
+        &pki.path("server.pem"),
+        &pki.path("server.key"),
+        mutual.then_some(client_ca.as_str()),
+    )
+    .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let storage = Arc::new(Storage::new());
+    let (tx, mut rx) = unbounded_channel();
+    let wal = WalWritter::new(tx);
+    let auth = Arc::new(Authenticator::default());
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+    tokio::spawn(async move {
+        loop {
+            server::functional::initiate_tls_client(&listener, &acceptor, &storage, &wal, &auth)
+                .await
+                .unwrap();
+        }
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn put_and_get_over_tls() {
+    let pki = Pki::generate("roundtrip");
+    let addr = start_server(&pki, false).await;
+
+    let c = Client::new_tls(&addr, &pki.client_options(false), None).await.unwrap();
+    c.connected().await.unwrap();
+    c.put("key", b"value".to_vec(), Some(Duration::from_secs(60)))
+        .await
+        .unwrap();
+
+    assert_eq!(c.get("key").await.unwrap(), Some(b"value".to_vec()));
+}
+
+#[tokio::test]
+async fn mutual_tls_accepts_client_certificate() {
+    let pki = Pki::generate("mutual");
+    let addr = start_server(&pki, true).await;
+
+    let c = Client::new_tls(&addr, &pki.client_options(true), None).await.unwrap();
+    c.connected().await.unwrap();
+    c.put("key", b"value".to_vec(), None).await.unwrap();
+
+    assert_eq!(c.get("key").await.unwrap(), Some(b"value".to_vec()));
+}
+
+#[tokio::test]
+async fn mutual_tls_rejects_client_without_certificate() {
+    let pki = Pki::generate("anonymous");
+    let addr = start_server(&pki, true).await;
+
+    // With TLS 1.3 the server only rejects the client after its handshake
+    // finished, so the failure surfaces on the first call.
+    let res = match Client::new_tls(&addr, &pki.client_options(false), None).await {
+        Ok(c) => c.connected().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn client_rejects_untrusted_server() {
+    let pki = Pki::generate("server");
+    let other = Pki::generate("other");
+    let addr = start_server(&pki, false).await;
+
+    assert!(Client::new_tls(&addr, &other.client_options(false), None).await.is_err());
+}